@@ -0,0 +1,13 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    // protobuf types for non-Rust peers, see src/network/proto.rs
+    #[cfg(feature = "protobuf")]
+    {
+        println!("cargo:rerun-if-changed=proto/network.proto");
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("no vendored protoc for this platform");
+        prost_build::Config::new()
+            .protoc_executable(protoc)
+            .compile_protos(&["proto/network.proto"], &["proto/"])
+            .expect("failed to compile proto/network.proto");
+    }
+}
@@ -1,20 +1,35 @@
 use pyo3::prelude::*;
 pub mod network;
-use network::{data_reader::DataReaderConfig, data_writer::DataWriterConfig, io_loop::ZmqConfig, py_interface::*, remote_transfer_handler::TransferConfig};
+use network::{data_reader::{AckStrategy, DataReaderConfig, DeliveryGuarantee, FullQueuePolicy, GapPolicy, HealthStatus}, data_writer::DataWriterConfig, error::DecodeErrorPolicy, io_loop::{IOHandlerType, SocketStats, ZmqConfig}, metrics::{ChannelStats, JobStats}, partitioner::PartitionerType, rate_limiter::RateLimit, py_interface::*, remote_transfer_handler::TransferConfig, threads::ThreadConfig};
 
 #[pymodule]
 fn volga_rust(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyLocalChannel>()?;
     m.add_class::<PyRemoteChannel>()?;
+    m.add_class::<PyInMemoryChannel>()?;
     m.add_class::<PyDataReader>()?;
     m.add_class::<PyDataWriter>()?;
     m.add_class::<PyTransferReceiver>()?;
     m.add_class::<PyTransferSender>()?;
     m.add_class::<PyIOLoop>()?;
+    m.add_class::<PyInMemoryTransport>()?;
+    m.add_class::<IOHandlerType>()?;
     m.add_class::<DataReaderConfig>()?;
+    m.add_class::<DeliveryGuarantee>()?;
+    m.add_class::<AckStrategy>()?;
+    m.add_class::<GapPolicy>()?;
+    m.add_class::<FullQueuePolicy>()?;
+    m.add_class::<DecodeErrorPolicy>()?;
+    m.add_class::<HealthStatus>()?;
     m.add_class::<DataWriterConfig>()?;
+    m.add_class::<PartitionerType>()?;
+    m.add_class::<RateLimit>()?;
     m.add_class::<TransferConfig>()?;
     m.add_class::<ZmqConfig>()?;
+    m.add_class::<ThreadConfig>()?;
+    m.add_class::<ChannelStats>()?;
+    m.add_class::<JobStats>()?;
+    m.add_class::<SocketStats>()?;
     Ok(())
 }
 
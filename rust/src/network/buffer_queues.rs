@@ -1,97 +1,1201 @@
-use std::{collections::{HashMap, HashSet, VecDeque}, sync::{atomic::{AtomicU32, AtomicU8, Ordering}, Arc, Mutex, RwLock}};
+use std::{collections::{HashMap, HashSet, VecDeque}, sync::{atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering}, Arc, Condvar, Mutex, RwLock}, time::{Duration, Instant}};
 
-use super::{buffer_utils::{get_buffer_id, new_buffer_with_meta}, channel::{Channel}, io_loop::Bytes};
+use super::{buffer_utils::{get_buffer_flags, get_buffer_id, is_buffer_expired, new_buffer_with_meta_and_flags, new_expired_buffer, BUFFER_FLAG_EXPIRED, BUFFER_FLAG_FRAGMENT, BUFFER_FLAG_PRIORITY}, channel::{Channel}, clock::{Clock, SystemClock}, data_writer::DataWriterConfig, io_loop::Bytes, metrics::{MetricsRecorder, NUM_COMPACTED, NUM_PUSH_REJECTED, QUEUE_DEPTH}, rate_limiter::RateLimiter, error::{poisoned, NetworkError, NetworkResult}, trace::buffer_span};
 
 
 // pub const MAX_BUFFERS_PER_CHANNEL: usize = 10;
 
-pub struct BufferQueue {
+// buffer acked by every subscriber that has not shut down
+fn acked_by_open(acked: &HashSet<usize>, closed: &HashSet<usize>, subscribers: usize) -> bool {
+    (0..subscribers).all(|subscriber| acked.contains(&subscriber) || closed.contains(&subscriber))
+}
+
+const PUSH_TIMEOUT_POLL: Duration = Duration::from_millis(50);
+
+// last writer epoch handed out in this process, so queues created within the same clock tick still get distinct epochs
+static LAST_WRITER_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+// Wall clock micros of queue creation, at least one past the previous epoch of this process. A restarted writer
+// (new process or channel re-added) starts a new epoch, so readers can tell its buffer ids start over from 0.
+// Readers take any epoch they have not seen for a restart, so a clock behind the previous writer's is fine
+fn next_writer_epoch(now_micros: u64) -> u64 {
+    let prev = LAST_WRITER_EPOCH.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| Some((last + 1).max(now_micros))).unwrap();
+    (prev + 1).max(now_micros)
+}
+
+// where schedule_next takes next buffer from
+enum ScheduleFrom {
+    Retained(usize),
+    Priority(usize),
+    Queue(usize)
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum CompactedPush {
+    Queued,
+    // took place of a queued buffer with the same key
+    Replaced,
+    Full
+}
+
+pub struct BufferQueue<C: Clock = SystemClock> {
     v: VecDeque<Box<Bytes>>,
     index: u32,
-    buffer_id_seq: u32,
-    pop_requests: HashSet<u32>,
-    max_buffers_per_channel: usize
+    buffer_id_seq: u64,
+    // stamped into every buffer, see next_writer_epoch
+    writer_epoch: u64,
+    pop_requests: HashSet<u64>,
+    max_buffers_per_channel: usize,
+
+    // ids of BUFFER_FLAG_PRIORITY buffers not scheduled yet, in push order
+    priority: VecDeque<u64>,
+    // priority buffers scheduled before index reached them, index skips them
+    scheduled_ahead: HashSet<u64>,
+
+    // last acked (popped) buffers kept for replay, bounded by retention
+    retained: VecDeque<Box<Bytes>>,
+    retention: usize,
+    // if set, schedule_next serves from retained starting at this index before going back to v
+    retained_index: Option<usize>,
+
+    // Readers each receiving every buffer of the channel (fan-out), a buffer is popped only once all of them acked it.
+    // Queue holds buffers until the slowest subscriber acks, so a lagging subscriber keeps up to max_buffers_per_channel
+    // buffers in memory and eventually blocks pushes for all of them
+    subscribers: usize,
+    // buffer_id -> subscribers that acked it, while not acked by every subscriber
+    subscriber_acks: HashMap<u64, HashSet<usize>>,
+
+    // buffers are popped once scheduled, without waiting for acks, see at_most_once in DataWriterConfig
+    at_most_once: bool,
+
+    // subscribers that sent backpressure signal, nothing is scheduled while any of them is paused
+    paused: HashSet<usize>,
+    // subscribers whose reader shut down for good, see ShutdownMessage. Their acks are no longer waited for, once all of
+    // them did nothing is scheduled or pushed anymore. Any later message from a subscriber reopens it, see set_open
+    closed: HashSet<usize>,
+
+    rate_limiter: Option<RateLimiter>,
+    // set while next buffer is held back by rate limiter
+    throttled_since: Option<Instant>,
+    // not yet reported, see take_throttled_micros
+    throttled_micros: u64,
+    // not yet reported, see take_num_expired
+    num_expired: u64,
+    // already sent buffers rewound by replay_from, count as retransmits once scheduled again
+    resend_ids: HashSet<u64>,
+    // not yet reported, see take_num_retransmits
+    num_retransmits: u64,
+
+    // key <-> id of buffer pushed with try_push_compacted and not scheduled yet
+    compaction_keys: HashMap<Vec<u8>, u64>,
+    compaction_ids: HashMap<u64, Vec<u8>>,
+
+    clock: C
 }
 
 impl BufferQueue {
+    pub fn new(config: &DataWriterConfig, channel_id: &str) -> Self {
+        Self::with_clock(config, channel_id, SystemClock)
+    }
+}
 
-    pub fn new(max_buffers_per_channel: usize) -> Self {
-        BufferQueue{v: VecDeque::with_capacity(max_buffers_per_channel), index: 0, buffer_id_seq: 0, pop_requests: HashSet::new(), max_buffers_per_channel: max_buffers_per_channel}
+impl<C: Clock> BufferQueue<C> {
+
+    // queue size, retention and channel's rate limit come from config
+    pub fn with_clock(config: &DataWriterConfig, channel_id: &str, clock: C) -> Self {
+        let max_buffers_per_channel = config.max_buffers_per_channel;
+        let retention = config.retention;
+        BufferQueue{
+            v: VecDeque::with_capacity(max_buffers_per_channel),
+            index: 0,
+            buffer_id_seq: 0,
+            writer_epoch: next_writer_epoch(clock.unix_micros()),
+            pop_requests: HashSet::new(),
+            max_buffers_per_channel: max_buffers_per_channel,
+            priority: VecDeque::new(),
+            scheduled_ahead: HashSet::new(),
+            retained: VecDeque::with_capacity(retention),
+            retention,
+            retained_index: None,
+            subscribers: 1,
+            subscriber_acks: HashMap::new(),
+            at_most_once: config.is_at_most_once(channel_id),
+            paused: HashSet::new(),
+            closed: HashSet::new(),
+            rate_limiter: config.rate_limits.get(channel_id).map(|rate_limit| RateLimiter::new(rate_limit, clock.now())),
+            throttled_since: None,
+            throttled_micros: 0,
+            num_expired: 0,
+            resend_ids: HashSet::new(),
+            num_retransmits: 0,
+            compaction_keys: HashMap::new(),
+            compaction_ids: HashMap::new(),
+            clock
+        }
     }
 
     pub fn try_push(&mut self, channel_id: String, b: Box<Bytes>) -> bool {
+        self.try_push_with_meta(channel_id, b, None, None, 0)
+    }
+
+    // buffer is not delivered after expire_ts_micros, see schedule_next
+    pub fn try_push_with_meta(&mut self, channel_id: String, b: Box<Bytes>, expire_ts_micros: Option<u64>, event_time_wm: Option<u64>, flags: u8) -> bool {
         if self.v.len() == self.max_buffers_per_channel {
             return false;
         }
         let buffer_id = self.buffer_id_seq;
-        let new_b = new_buffer_with_meta(b, channel_id.clone(), buffer_id);
+        let _span = buffer_span!("pushed", channel_id, buffer_id);
+        let send_ts = self.clock.unix_micros();
+        let new_b = new_buffer_with_meta_and_flags(b, channel_id.clone(), buffer_id, send_ts, expire_ts_micros, event_time_wm, flags, Some(self.writer_epoch));
         self.v.push_back(new_b);
+        if flags & BUFFER_FLAG_PRIORITY != 0 {
+            self.priority.push_back(buffer_id);
+        }
         self.buffer_id_seq = buffer_id + 1;
         return true
     }
 
-    // returns value from queue at schedule index without popping
+    // all fragments (see split_fragments) are queued under consecutive buffer ids, or none if they do not fit
+    pub fn try_push_fragments(&mut self, channel_id: String, fragments: Vec<Box<Bytes>>, expire_ts_micros: Option<u64>, event_time_wm: Option<u64>) -> bool {
+        if self.v.len() + fragments.len() > self.max_buffers_per_channel {
+            return false;
+        }
+        for fragment in fragments {
+            self.try_push_with_meta(channel_id.clone(), fragment, expire_ts_micros, event_time_wm, BUFFER_FLAG_FRAGMENT);
+        }
+        true
+    }
+
+    // Keeps only the newest buffer per key among buffers not scheduled yet: a queued buffer with the same key is replaced
+    // in place, keeping its buffer id and position, so it needs no room. Buffers already scheduled (sent) are never
+    // replaced, in-flight and resent buffers stay as they were and a later push of the key is queued behind them
+    pub fn try_push_compacted(&mut self, channel_id: String, key: &[u8], b: Box<Bytes>, expire_ts_micros: Option<u64>, event_time_wm: Option<u64>) -> CompactedPush {
+        if let Some(&buffer_id) = self.compaction_keys.get(key) {
+            let _span = buffer_span!("pushed", channel_id, buffer_id);
+            let front_buffer_id = get_buffer_id(self.v.front().unwrap().clone());
+            let send_ts = self.clock.unix_micros();
+            self.v[(buffer_id - front_buffer_id) as usize] = new_buffer_with_meta_and_flags(b, channel_id, buffer_id, send_ts, expire_ts_micros, event_time_wm, 0, Some(self.writer_epoch));
+            return CompactedPush::Replaced;
+        }
+        let buffer_id = self.buffer_id_seq;
+        if !self.try_push_with_meta(channel_id, b, expire_ts_micros, event_time_wm, 0) {
+            return CompactedPush::Full;
+        }
+        self.compaction_keys.insert(key.to_vec(), buffer_id);
+        self.compaction_ids.insert(buffer_id, key.to_vec());
+        CompactedPush::Queued
+    }
+
+    // returns value from queue at schedule index without popping.
+    // Priority buffers go first: order is kept among priority buffers and among the rest, but not across the two.
+    // Expired buffer is replaced with its payload-less copy, which is still sent (and acked and popped as usual),
+    // so reader does not wait for a missing buffer_id
     pub fn schedule_next(&mut self) -> Option<Box<Bytes>> {
-        let len = self.v.len();
-        if len == 0 {
+        if !self.paused.is_empty() || self.is_closed() {
             return None;
         }
+        let from = self.next_schedule_from()?;
+        let b = match from {
+            ScheduleFrom::Retained(i) => self.retained.get_mut(i).unwrap(),
+            ScheduleFrom::Priority(i) | ScheduleFrom::Queue(i) => self.v.get_mut(i).unwrap()
+        };
+        let now_ts = self.clock.unix_micros();
+        if get_buffer_flags(b) & BUFFER_FLAG_EXPIRED == 0 && is_buffer_expired(b, now_ts) {
+            *b = new_expired_buffer(b);
+            self.num_expired += 1;
+        }
+        let res = b.clone();
+        if let Some(rate_limiter) = &mut self.rate_limiter {
+            let now = self.clock.now();
+            if !rate_limiter.try_acquire(res.len(), now) {
+                self.throttled_since.get_or_insert(now);
+                return None;
+            }
+            if let Some(throttled_since) = self.throttled_since.take() {
+                self.throttled_micros += now.duration_since(throttled_since).as_micros() as u64;
+            }
+        }
+        match from {
+            ScheduleFrom::Retained(i) => self.retained_index = Some(i + 1),
+            ScheduleFrom::Priority(_) => {
+                let buffer_id = self.priority.pop_front().unwrap();
+                self.scheduled_ahead.insert(buffer_id);
+            },
+            ScheduleFrom::Queue(_) => {
+                self.index += 1;
+                self.forget_compaction_key(&res);
+            }
+        }
+        if !self.resend_ids.is_empty() && self.resend_ids.remove(&get_buffer_id(res.clone())) {
+            self.num_retransmits += 1;
+        }
+        let _span = buffer_span!("scheduled", super::buffer_utils::get_channeld_id(res.clone()), get_buffer_id(res.clone()));
+        if self.at_most_once {
+            // as if every subscriber acked it, priority buffer is popped once the front reaches it
+            self.request_pop(get_buffer_id(res.clone()));
+        }
+        Some(res)
+    }
 
-        let index = self.index;
-        if index >= (len as u32) {
-            return None;
+    fn next_schedule_from(&mut self) -> Option<ScheduleFrom> {
+        if let Some(retained_index) = self.retained_index {
+            if retained_index < self.retained.len() {
+                return Some(ScheduleFrom::Retained(retained_index));
+            }
+            self.retained_index = None;
+        }
+
+        // skip buffers already scheduled as priority
+        while let Some(b) = self.v.get(self.index as usize) {
+            if !self.scheduled_ahead.remove(&get_buffer_id(b.clone())) {
+                break;
+            }
+            self.index += 1;
         }
-        let res = self.v.get(index as usize).unwrap();
-        self.index += 1;
-        Some(res.clone())
+        let front_buffer_id = self.v.front().map(|b| get_buffer_id(b.clone()))?;
+        // priority buffers index has reached were scheduled in order
+        while let Some(&buffer_id) = self.priority.front() {
+            if buffer_id >= front_buffer_id && (buffer_id - front_buffer_id) as usize > self.index as usize {
+                return Some(ScheduleFrom::Priority((buffer_id - front_buffer_id) as usize));
+            }
+            self.priority.pop_front();
+        }
+        if (self.index as usize) < self.v.len() {
+            return Some(ScheduleFrom::Queue(self.index as usize));
+        }
+        None
+    }
+
+    // buffer can not be replaced once sent
+    fn forget_compaction_key(&mut self, b: &Bytes) {
+        if self.compaction_ids.is_empty() {
+            return;
+        }
+        if let Some(key) = self.compaction_ids.remove(&get_buffer_id(Box::new(b.clone()))) {
+            self.compaction_keys.remove(&key);
+        }
+    }
+
+    // rewinds schedule index so buffers starting from buffer_id are scheduled (re-sent) again.
+    // Only buffers still held in queue (not acked yet) or in retention window can be replayed, returns false otherwise
+    pub fn replay_from(&mut self, buffer_id: u64) -> bool {
+        // buffers scheduled ahead are re-sent in queue order
+        if let Some(pos) = self.v.iter().position(|b| get_buffer_id(b.clone()) == buffer_id) {
+            self.mark_resend(buffer_id);
+            self.scheduled_ahead.clear();
+            self.retained_index = None;
+            self.index = pos as u32;
+            return true;
+        }
+        let retained_pos = self.retained.iter().position(|b| get_buffer_id(b.clone()) == buffer_id);
+        if retained_pos.is_some() {
+            // replay retained part first, then everything in queue
+            self.mark_resend(buffer_id);
+            self.scheduled_ahead.clear();
+            self.retained_index = retained_pos;
+            self.index = 0;
+            return true;
+        }
+        false
+    }
+
+    // rewinds schedule index to the front, so every buffer not acked yet is scheduled (re-sent) again,
+    // e.g. after reader reconnected and lost what was in flight. Unlike replay_from retained buffers are not replayed
+    pub fn reset_schedule(&mut self) {
+        let front_buffer_id = self.v.front().map(|b| get_buffer_id(b.clone())).unwrap_or(self.buffer_id_seq);
+        self.mark_resend(front_buffer_id);
+        self.scheduled_ahead.clear();
+        self.retained_index = None;
+        self.index = 0;
+    }
+
+    // remembers buffers sent so far starting from from_buffer_id, before replay_from rewinds
+    fn mark_resend(&mut self, from_buffer_id: u64) {
+        let front_buffer_id = self.v.front().map(|b| get_buffer_id(b.clone())).unwrap_or(self.buffer_id_seq);
+        let sent_retained: Vec<u64> = self.retained.iter().map(|b| get_buffer_id(b.clone())).collect();
+        self.resend_ids.extend(sent_retained);
+        self.resend_ids.extend(front_buffer_id..front_buffer_id + self.index as u64);
+        self.resend_ids.extend(self.scheduled_ahead.iter().copied());
+        self.resend_ids.retain(|buffer_id| *buffer_id >= from_buffer_id);
     }
 
     // submits pop request, performs pop only for in-order requests
-    pub fn request_pop(&mut self, buffer_id: u32) {
+    pub fn request_pop(&mut self, buffer_id: u64) {
+        let front_buffer_id = self.v.front().map(|b| get_buffer_id(b.clone())).unwrap_or(self.buffer_id_seq);
+        if buffer_id < front_buffer_id {
+            // already popped, e.g. ack for a replayed retained buffer
+            return;
+        }
         self.pop_requests.insert(buffer_id);
         while self.v.len() != 0 {
             let peek_buffer = self.v.get(0).unwrap();
             let peek_buffer_id = get_buffer_id(peek_buffer.clone());
             if self.pop_requests.contains(&peek_buffer_id) {
-                self.v.pop_front();
+                let popped = self.v.pop_front().unwrap();
+                // acked without being scheduled, e.g. skipped by reader
+                self.forget_compaction_key(&popped);
+                self.retain(popped);
                 self.pop_requests.remove(&peek_buffer_id);
-                self.index -= 1;
+                self.scheduled_ahead.remove(&peek_buffer_id);
+                // index can already be at front after replay_from
+                self.index = self.index.saturating_sub(1);
             } else {
                 break;
             }
         }
     }
+
+    // ack of one subscriber, pops (see request_pop) once every subscriber acked buffer_id.
+    // Returns true if buffer is acked by all subscribers
+    pub fn ack(&mut self, subscriber: usize, buffer_id: u64) -> bool {
+        let front_buffer_id = self.v.front().map(|b| get_buffer_id(b.clone())).unwrap_or(self.buffer_id_seq);
+        if buffer_id < front_buffer_id {
+            // already popped, e.g. re-ack of a resent duplicate
+            return true;
+        }
+        if self.subscribers > 1 {
+            let acked = self.subscriber_acks.entry(buffer_id).or_default();
+            acked.insert(subscriber);
+            if !acked_by_open(acked, &self.closed, self.subscribers) {
+                return false;
+            }
+            self.subscriber_acks.remove(&buffer_id);
+        }
+        self.request_pop(buffer_id);
+        true
+    }
+
+    pub fn set_subscribers(&mut self, subscribers: usize) {
+        self.subscribers = subscribers.max(1);
+    }
+
+    pub fn set_paused(&mut self, subscriber: usize, paused: bool) {
+        if paused {
+            self.paused.insert(subscriber);
+        } else {
+            self.paused.remove(&subscriber);
+        }
+    }
+
+    // Buffers only waiting for this subscriber's ack are popped now, returns their ids
+    pub fn set_closed(&mut self, subscriber: usize) -> Vec<u64> {
+        self.closed.insert(subscriber);
+        if self.is_closed() {
+            return Vec::new();
+        }
+        let mut acked: Vec<u64> = self.subscriber_acks.iter()
+            .filter(|(_, acked)| acked_by_open(acked, &self.closed, self.subscribers))
+            .map(|(buffer_id, _)| *buffer_id)
+            .collect();
+        acked.sort_unstable();
+        for buffer_id in &acked {
+            self.subscriber_acks.remove(buffer_id);
+            self.request_pop(*buffer_id);
+        }
+        acked
+    }
+
+    // subscriber's reader is back, e.g. restarted after shutdown. Buffers popped while it was closed are not re-sent
+    pub fn set_open(&mut self, subscriber: usize) {
+        self.closed.remove(&subscriber);
+    }
+
+    // every subscriber shut down
+    pub fn is_closed(&self) -> bool {
+        self.closed.len() >= self.subscribers
+    }
+
+    // time spent held back by rate limiter since last call
+    pub fn take_throttled_micros(&mut self) -> u64 {
+        if let Some(throttled_since) = self.throttled_since.as_mut() {
+            let now = self.clock.now();
+            self.throttled_micros += now.duration_since(*throttled_since).as_micros() as u64;
+            *throttled_since = now;
+        }
+        std::mem::take(&mut self.throttled_micros)
+    }
+
+    // buffers expired before being scheduled since last call
+    pub fn take_num_expired(&mut self) -> u64 {
+        std::mem::take(&mut self.num_expired)
+    }
+
+    // buffers sent again after replay_from since last call
+    pub fn take_num_retransmits(&mut self) -> u64 {
+        std::mem::take(&mut self.num_retransmits)
+    }
+
+    // scheduled (sent) but not acked yet, popping moves index back along with the front
+    pub fn in_flight(&self) -> usize {
+        self.index as usize + self.scheduled_ahead.len()
+    }
+
+    // not acked yet, both in flight and waiting to be scheduled
+    pub fn queue_depth(&self) -> usize {
+        self.v.len()
+    }
+
+    fn retain(&mut self, b: Box<Bytes>) {
+        if self.retention == 0 {
+            return;
+        }
+        if self.retained.len() == self.retention {
+            self.retained.pop_front();
+            self.retained_index = self.retained_index.map(|i| i.saturating_sub(1));
+        }
+        self.retained.push_back(b);
+    }
+}
+
+// space_freed is notified whenever request_pop removes buffers, see push_timeout
+struct SharedQueue<C: Clock> {
+    queue: Mutex<BufferQueue<C>>,
+    space_freed: Condvar
 }
 
-pub struct BufferQueues {
-    in_queues: Arc<RwLock<HashMap<String, Arc<Mutex<BufferQueue>>>>>,
+impl<C: Clock> SharedQueue<C> {
+    fn new(queue: BufferQueue<C>) -> Arc<Self> {
+        Arc::new(SharedQueue{queue: Mutex::new(queue), space_freed: Condvar::new()})
+    }
+}
+
+type InQueues<C> = RwLock<HashMap<String, Arc<SharedQueue<C>>>>;
+
+pub struct BufferQueues<C: Clock = SystemClock> {
+    in_queues: Arc<InQueues<C>>,
+    // stamped on every queued buffer, 0 - not set
+    event_time_watermark: AtomicU64,
+    config: Arc<DataWriterConfig>,
+    // push rejections and queue depth per channel
+    metrics_recorder: Arc<MetricsRecorder>,
+    clock: C
 }
 
 impl BufferQueues {
-    pub fn new(channels: Vec<Channel>, max_buffers_per_channel: usize) -> BufferQueues {
+    pub fn new(channels: Vec<Channel>, config: Arc<DataWriterConfig>, metrics_recorder: Arc<MetricsRecorder>) -> BufferQueues {
+        Self::with_clock(channels, config, metrics_recorder, SystemClock)
+    }
+}
+
+impl<C: Clock> BufferQueues<C> {
+    pub fn with_clock(channels: Vec<Channel>, config: Arc<DataWriterConfig>, metrics_recorder: Arc<MetricsRecorder>, clock: C) -> Self {
         let n_channels = channels.len();
         let mut in_queues = HashMap::with_capacity(n_channels);
+        // channel listed more than once is fanned out to each entry's reader
+        let mut subscribers: HashMap<String, usize> = HashMap::with_capacity(n_channels);
         for ch in channels {
-            in_queues.insert(ch.get_channel_id().clone(), Arc::new(Mutex::new(BufferQueue::new(max_buffers_per_channel))));
+            *subscribers.entry(ch.get_channel_id().clone()).or_default() += 1;
+        }
+        for (channel_id, n) in subscribers {
+            let mut queue = BufferQueue::with_clock(&config, &channel_id, clock.clone());
+            queue.set_subscribers(n);
+            in_queues.insert(channel_id, SharedQueue::new(queue));
         }
 
-        BufferQueues{in_queues: Arc::new(RwLock::new(in_queues))}
+        BufferQueues{in_queues: Arc::new(RwLock::new(in_queues)), event_time_watermark: AtomicU64::new(0), config, metrics_recorder, clock}
     }
 
-    pub fn try_push(&self, channel_id: &String, b: Box<Bytes>) -> bool {
-        let locked_queues = self.in_queues.read().unwrap();
-        let mut locked_queue = locked_queues.get(channel_id).unwrap().lock().unwrap();
-        locked_queue.try_push(channel_id.clone(), b)
+    pub fn add_channel(&self, channel_id: &String) -> NetworkResult<()> {
+        let mut locked_queues = self.in_queues.write().map_err(poisoned("in_queues"))?;
+        if locked_queues.contains_key(channel_id) {
+            return Err(NetworkError::ChannelExists(channel_id.clone()));
+        }
+        locked_queues.insert(channel_id.clone(), SharedQueue::new(BufferQueue::with_clock(&self.config, channel_id, self.clock.clone())));
+        Ok(())
+    }
+
+    // drops all queued buffers
+    pub fn remove_channel(&self, channel_id: &str) -> NetworkResult<()> {
+        self.in_queues.write().map_err(poisoned("in_queues"))?.remove(channel_id);
+        self.metrics_recorder.set(QUEUE_DEPTH, channel_id, 0);
+        Ok(())
+    }
+
+    pub fn try_push(&self, channel_id: &String, b: Box<Bytes>) -> NetworkResult<bool> {
+        self.try_push_with_meta(channel_id, b, None, 0)
+    }
+
+    pub fn try_push_with_meta(&self, channel_id: &String, b: Box<Bytes>, expire_ts_micros: Option<u64>, flags: u8) -> NetworkResult<bool> {
+        let event_time_wm = Some(self.event_time_watermark.load(Ordering::Relaxed)).filter(|wm| *wm != 0);
+        let (pushed, depth) = self.with_open_queue(channel_id, |queue| (queue.try_push_with_meta(channel_id.clone(), b, expire_ts_micros, event_time_wm, flags), queue.queue_depth()))?;
+        self.record_push(channel_id, pushed, depth);
+        Ok(pushed)
     }
 
-    pub fn schedule_next(&self, channel_id: &String) -> Option<Box<Bytes>> {
-        let locked_queues = self.in_queues.read().unwrap();
-        let mut locked_queue = locked_queues.get(channel_id).unwrap().lock().unwrap();
-        locked_queue.schedule_next()
+    pub fn try_push_fragments(&self, channel_id: &String, fragments: Vec<Box<Bytes>>, expire_ts_micros: Option<u64>) -> NetworkResult<bool> {
+        let event_time_wm = Some(self.event_time_watermark.load(Ordering::Relaxed)).filter(|wm| *wm != 0);
+        let (pushed, depth) = self.with_open_queue(channel_id, |queue| (queue.try_push_fragments(channel_id.clone(), fragments, expire_ts_micros, event_time_wm), queue.queue_depth()))?;
+        self.record_push(channel_id, pushed, depth);
+        Ok(pushed)
     }
-    pub fn request_pop(&self, channel_id: &String, buffer_id: u32) {
-        let locked_queues = self.in_queues.read().unwrap();
-        let mut locked_queue = locked_queues.get(channel_id).unwrap().lock().unwrap();
-        locked_queue.request_pop(buffer_id)
+
+    // see BufferQueue::try_push_compacted, false if queue is full
+    pub fn try_push_compacted(&self, channel_id: &String, key: &[u8], b: Box<Bytes>, expire_ts_micros: Option<u64>) -> NetworkResult<bool> {
+        let event_time_wm = Some(self.event_time_watermark.load(Ordering::Relaxed)).filter(|wm| *wm != 0);
+        let (res, depth) = self.with_open_queue(channel_id, |queue| (queue.try_push_compacted(channel_id.clone(), key, b, expire_ts_micros, event_time_wm), queue.queue_depth()))?;
+        if res == CompactedPush::Replaced {
+            self.metrics_recorder.inc(NUM_COMPACTED, channel_id, 1);
+        }
+        self.record_push(channel_id, res != CompactedPush::Full, depth);
+        Ok(res != CompactedPush::Full)
+    }
+
+    // Waits up to timeout_ms for room instead of failing right away, woken up by request_pop freeing space.
+    // Channel lock is not held while waiting, so a channel removed meanwhile just times out.
+    // Deadline is on self.clock, re-checked at least every PUSH_TIMEOUT_POLL as moving a mock clock wakes no one
+    pub fn push_timeout(&self, channel_id: &String, b: Box<Bytes>, timeout_ms: u64) -> NetworkResult<bool> {
+        let deadline = self.clock.now() + Duration::from_millis(timeout_ms);
+        let queue = self.get_queue(channel_id)?;
+        let mut locked_queue = queue.queue.lock().map_err(poisoned("buffer_queue"))?;
+        loop {
+            if locked_queue.is_closed() {
+                return Err(NetworkError::ChannelClosed(format!("channel {channel_id}")));
+            }
+            let event_time_wm = Some(self.event_time_watermark.load(Ordering::Relaxed)).filter(|wm| *wm != 0);
+            // checked first, as try_push_with_meta takes b even if full
+            if locked_queue.queue_depth() < locked_queue.max_buffers_per_channel {
+                locked_queue.try_push_with_meta(channel_id.clone(), b, None, event_time_wm, 0);
+                self.record_push(channel_id, true, locked_queue.queue_depth());
+                return Ok(true);
+            }
+            let now = self.clock.now();
+            if now >= deadline {
+                self.record_push(channel_id, false, locked_queue.queue_depth());
+                return Ok(false);
+            }
+            locked_queue = queue.space_freed.wait_timeout(locked_queue, (deadline - now).min(PUSH_TIMEOUT_POLL)).map_err(poisoned("buffer_queue"))?.0;
+        }
+    }
+
+    fn record_push(&self, channel_id: &str, pushed: bool, depth: usize) {
+        if !pushed {
+            self.metrics_recorder.inc(NUM_PUSH_REJECTED, channel_id, 1);
+        }
+        self.metrics_recorder.set(QUEUE_DEPTH, channel_id, depth as u64);
+    }
+
+    // never moves back
+    pub fn advance_event_time_watermark(&self, event_time_wm: u64) {
+        self.event_time_watermark.fetch_max(event_time_wm, Ordering::Relaxed);
+    }
+
+    // at_most_once queues pop what they schedule, which frees room like an ack does
+    pub fn schedule_next(&self, channel_id: &String) -> NetworkResult<Option<Box<Bytes>>> {
+        self.with_shared_queue_pop(channel_id, |queue| queue.schedule_next())
+    }
+
+    pub fn replay_from(&self, channel_id: &String, buffer_id: u64) -> NetworkResult<bool> {
+        self.with_queue(channel_id, |queue| queue.replay_from(buffer_id))
+    }
+
+    // under queue lock, so it can not interleave with request_pop moving the front
+    pub fn reset_schedule(&self, channel_id: &String) -> NetworkResult<()> {
+        self.with_queue(channel_id, |queue| queue.reset_schedule())
+    }
+
+    pub fn take_throttled_micros(&self, channel_id: &String) -> NetworkResult<u64> {
+        self.with_queue(channel_id, |queue| queue.take_throttled_micros())
+    }
+
+    pub fn take_num_expired(&self, channel_id: &String) -> NetworkResult<u64> {
+        self.with_queue(channel_id, |queue| queue.take_num_expired())
+    }
+
+    pub fn take_num_retransmits(&self, channel_id: &String) -> NetworkResult<u64> {
+        self.with_queue(channel_id, |queue| queue.take_num_retransmits())
+    }
+
+    // ignored for unknown (e.g. removed) channels
+    pub fn set_paused(&self, channel_id: &String, subscriber: usize, paused: bool) -> NetworkResult<()> {
+        match self.with_queue(channel_id, |queue| queue.set_paused(subscriber, paused)) {
+            Err(NetworkError::UnknownChannel(_)) => Ok(()),
+            res => res
+        }
+    }
+
+    // see BufferQueue::set_closed, ignored for unknown (e.g. removed) channels. Wakes up push_timeout waiters,
+    // so they fail right away if the whole channel closed, or retry if popped buffers made room
+    pub fn set_closed(&self, channel_id: &String, subscriber: usize) -> NetworkResult<Vec<u64>> {
+        let queue = match self.get_queue(channel_id) {
+            Err(NetworkError::UnknownChannel(_)) => return Ok(Vec::new()),
+            res => res?
+        };
+        let popped = queue.queue.lock().map_err(poisoned("buffer_queue"))?.set_closed(subscriber);
+        queue.space_freed.notify_all();
+        Ok(popped)
+    }
+
+    // ignored for unknown (e.g. removed) channels
+    pub fn set_open(&self, channel_id: &String, subscriber: usize) -> NetworkResult<()> {
+        match self.with_queue(channel_id, |queue| queue.set_open(subscriber)) {
+            Err(NetworkError::UnknownChannel(_)) => Ok(()),
+            res => res
+        }
+    }
+
+    pub fn closed_channels(&self) -> NetworkResult<Vec<String>> {
+        Ok(self.map_queues(|queue| queue.is_closed())?.into_iter().filter_map(|(channel_id, closed)| closed.then_some(channel_id)).collect())
+    }
+
+    pub fn request_pop(&self, channel_id: &String, buffer_id: u64) -> NetworkResult<()> {
+        self.with_shared_queue_pop(channel_id, |queue| queue.request_pop(buffer_id))
+    }
+
+    // see BufferQueue::ack
+    pub fn ack(&self, channel_id: &String, subscriber: usize, buffer_id: u64) -> NetworkResult<bool> {
+        self.with_shared_queue_pop(channel_id, |queue| queue.ack(subscriber, buffer_id))
+    }
+
+    // runs f which may pop buffers, wakes up push_timeout waiters if it did
+    fn with_shared_queue_pop<T>(&self, channel_id: &String, f: impl FnOnce(&mut BufferQueue<C>) -> T) -> NetworkResult<T> {
+        let queue = self.get_queue(channel_id)?;
+        let mut locked_queue = queue.queue.lock().map_err(poisoned("buffer_queue"))?;
+        let prev_depth = locked_queue.queue_depth();
+        let res = f(&mut locked_queue);
+        let depth = locked_queue.queue_depth();
+        drop(locked_queue);
+        if depth < prev_depth {
+            queue.space_freed.notify_all();
+        }
+        self.metrics_recorder.set(QUEUE_DEPTH, channel_id, depth as u64);
+        Ok(res)
+    }
+
+    // channel_id -> BufferQueue::in_flight
+    pub fn in_flight(&self) -> NetworkResult<HashMap<String, usize>> {
+        self.map_queues(|queue| queue.in_flight())
+    }
+
+    // channel_id -> BufferQueue::queue_depth
+    pub fn queue_depths(&self) -> NetworkResult<HashMap<String, usize>> {
+        self.map_queues(|queue| queue.queue_depth())
+    }
+
+    fn map_queues<T>(&self, f: impl Fn(&BufferQueue<C>) -> T) -> NetworkResult<HashMap<String, T>> {
+        let locked_queues = self.in_queues.read().map_err(poisoned("in_queues"))?;
+        let mut res = HashMap::with_capacity(locked_queues.len());
+        for (channel_id, queue) in locked_queues.iter() {
+            res.insert(channel_id.clone(), f(&*queue.queue.lock().map_err(poisoned("buffer_queue"))?));
+        }
+        Ok(res)
+    }
+
+    // queue stays usable after in_queues lock is released, e.g. to wait on it
+    fn get_queue(&self, channel_id: &String) -> NetworkResult<Arc<SharedQueue<C>>> {
+        self.in_queues.read().map_err(poisoned("in_queues"))?.get(channel_id).cloned().ok_or_else(|| NetworkError::UnknownChannel(channel_id.clone()))
+    }
+
+    fn with_queue<T>(&self, channel_id: &String, f: impl FnOnce(&mut BufferQueue<C>) -> T) -> NetworkResult<T> {
+        let locked_queues = self.in_queues.read().map_err(poisoned("in_queues"))?;
+        let queue = locked_queues.get(channel_id).ok_or_else(|| NetworkError::UnknownChannel(channel_id.clone()))?;
+        let mut locked_queue = queue.queue.lock().map_err(poisoned("buffer_queue"))?;
+        Ok(f(&mut locked_queue))
+    }
+
+    // for pushes, fails with ChannelClosed once channel's reader shut down
+    fn with_open_queue<T>(&self, channel_id: &String, f: impl FnOnce(&mut BufferQueue<C>) -> T) -> NetworkResult<T> {
+        self.with_queue(channel_id, |queue| (!queue.is_closed()).then(|| f(queue)))?
+            .ok_or_else(|| NetworkError::ChannelClosed(format!("channel {channel_id}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::network::{buffer_utils::{get_buffer_event_time_watermark, get_buffer_writer_epoch, new_buffer_drop_meta}, clock::MockClock, rate_limiter::RateLimit};
+
+    use super::*;
+
+    fn test_metrics_recorder() -> Arc<MetricsRecorder> {
+        Arc::new(MetricsRecorder::new(String::from("dummy_handler"), String::from("dummy_job"), 1000))
+    }
+
+    fn test_config(max_buffers_per_channel: usize, retention: usize) -> DataWriterConfig {
+        DataWriterConfig{metrics_enabled: false, retention, ..DataWriterConfig::new(1, max_buffers_per_channel)}
+    }
+
+    #[test]
+    fn test_add_remove_channel() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let bqs = BufferQueues::new(vec![ch_0], Arc::new(test_config(2, 0)), test_metrics_recorder());
+        let ch_1 = String::from("ch_1");
+        assert_eq!(bqs.add_channel(&ch_1), Ok(()));
+        assert_eq!(bqs.add_channel(&ch_1), Err(NetworkError::ChannelExists(ch_1.clone())));
+        assert_eq!(bqs.try_push(&ch_1, Box::new(vec![1])), Ok(true));
+        assert_eq!(bqs.try_push(&ch_1, Box::new(vec![2])), Ok(true));
+        assert_eq!(bqs.try_push(&ch_1, Box::new(vec![3])), Ok(false));
+        assert_eq!(get_buffer_id(bqs.schedule_next(&ch_1).unwrap().unwrap()), 0);
+
+        bqs.remove_channel(&ch_1).unwrap();
+        assert!(!bqs.in_queues.read().unwrap().contains_key(&ch_1));
+        assert_eq!(bqs.try_push(&ch_1, Box::new(vec![1])), Err(NetworkError::UnknownChannel(ch_1.clone())));
+
+        // re-added channel starts from scratch
+        bqs.add_channel(&ch_1).unwrap();
+        assert_eq!(bqs.try_push(&ch_1, Box::new(vec![1])), Ok(true));
+        assert_eq!(get_buffer_id(bqs.schedule_next(&ch_1).unwrap().unwrap()), 0);
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_id = ch_0.get_channel_id().clone();
+        let bqs = BufferQueues::new(vec![ch_0], Arc::new(test_config(10, 0)), test_metrics_recorder());
+        for i in 0..4 {
+            bqs.try_push(&ch_id, Box::new(vec![i])).unwrap();
+        }
+        assert_eq!(bqs.in_flight().unwrap(), HashMap::from([(ch_id.clone(), 0)]));
+        for _ in 0..3 {
+            bqs.schedule_next(&ch_id).unwrap();
+        }
+        assert_eq!(bqs.in_flight().unwrap(), HashMap::from([(ch_id.clone(), 3)]));
+
+        // out of order ack is not popped yet
+        bqs.request_pop(&ch_id, 1).unwrap();
+        assert_eq!(bqs.in_flight().unwrap()[&ch_id], 3);
+        bqs.request_pop(&ch_id, 0).unwrap();
+        assert_eq!(bqs.in_flight().unwrap()[&ch_id], 1);
+        assert_eq!(bqs.queue_depths().unwrap()[&ch_id], 2);
+
+        // replay re-schedules from scratch
+        bqs.replay_from(&ch_id, 2).unwrap();
+        assert_eq!(bqs.in_flight().unwrap()[&ch_id], 0);
+    }
+
+    #[test]
+    fn test_push_metrics() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_id = ch_0.get_channel_id().clone();
+        let metrics_recorder = test_metrics_recorder();
+        let bqs = BufferQueues::new(vec![ch_0], Arc::new(test_config(2, 0)), metrics_recorder.clone());
+        for i in 0..3 {
+            bqs.try_push(&ch_id, Box::new(vec![i])).unwrap();
+        }
+        assert!(!bqs.try_push_fragments(&ch_id, vec![Box::new(vec![3])], None).unwrap());
+        let stats = metrics_recorder.snapshot()[&ch_id].clone();
+        assert_eq!((stats.num_push_rejected, stats.queue_depth), (2, 2));
+
+        bqs.schedule_next(&ch_id).unwrap();
+        bqs.request_pop(&ch_id, 0).unwrap();
+        assert_eq!(metrics_recorder.snapshot()[&ch_id].queue_depth, 1);
+    }
+
+    #[test]
+    fn test_push_timeout() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_id = ch_0.get_channel_id().clone();
+        let bqs = Arc::new(BufferQueues::new(vec![ch_0], Arc::new(test_config(1, 0)), test_metrics_recorder()));
+        assert_eq!(bqs.push_timeout(&ch_id, Box::new(vec![0]), 0), Ok(true));
+        let start = Instant::now();
+        assert_eq!(bqs.push_timeout(&ch_id, Box::new(vec![1]), 20), Ok(false));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // woken up by ack freeing space, well before timeout
+        bqs.schedule_next(&ch_id).unwrap();
+        let this_bqs = bqs.clone();
+        let this_ch_id = ch_id.clone();
+        let popper = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            this_bqs.request_pop(&this_ch_id, 0).unwrap();
+        });
+        let start = Instant::now();
+        assert_eq!(bqs.push_timeout(&ch_id, Box::new(vec![1]), 10_000), Ok(true));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        popper.join().unwrap();
+        assert_eq!(get_buffer_id(bqs.schedule_next(&ch_id).unwrap().unwrap()), 1);
+        assert_eq!(bqs.push_timeout(&String::from("ch_1"), Box::new(vec![1]), 0), Err(NetworkError::UnknownChannel(String::from("ch_1"))));
+
+        // deadline follows the injected clock, not wall time
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let clock = MockClock::new();
+        let bqs = Arc::new(BufferQueues::with_clock(vec![ch_0], Arc::new(test_config(1, 0)), test_metrics_recorder(), clock.clone()));
+        assert_eq!(bqs.push_timeout(&ch_id, Box::new(vec![0]), 0), Ok(true));
+        let this_bqs = bqs.clone();
+        let this_ch_id = ch_id.clone();
+        let pusher = std::thread::spawn(move || this_bqs.push_timeout(&this_ch_id, Box::new(vec![1]), 3_600_000));
+        let start = Instant::now();
+        while !pusher.is_finished() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            clock.advance(Duration::from_secs(600));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(pusher.join().unwrap(), Ok(false));
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut bq = BufferQueue::new(&test_config(10, 0), "ch_0");
+        let ch_id = String::from("ch_0");
+        for i in 0..6 {
+            let flags = if i == 2 || i == 4 { BUFFER_FLAG_PRIORITY } else { 0 };
+            assert!(bq.try_push_with_meta(ch_id.clone(), Box::new(vec![i]), None, None, flags));
+        }
+        let schedule = |bq: &mut BufferQueue| bq.schedule_next().map(get_buffer_id);
+
+        // priority buffers jump ahead in push order, the rest keep their order and skip already sent ones
+        assert_eq!(schedule(&mut bq), Some(2));
+        assert_eq!(bq.in_flight(), 1);
+        assert_eq!(schedule(&mut bq), Some(4));
+        assert_eq!((0..4).map(|_| schedule(&mut bq).unwrap()).collect::<Vec<u64>>(), vec![0, 1, 3, 5]);
+        assert_eq!(schedule(&mut bq), None);
+        assert_eq!(bq.in_flight(), 6);
+
+        // popped in buffer id order regardless of ack order
+        bq.request_pop(2);
+        bq.request_pop(0);
+        assert_eq!(bq.queue_depth(), 5);
+        bq.request_pop(1);
+        assert_eq!(bq.queue_depth(), 3);
+        assert_eq!(bq.in_flight(), 3);
+
+        // priority buffer pushed after backlog is sent before it
+        let mut bq = BufferQueue::new(&test_config(10, 0), "ch_0");
+        for i in 0..3 {
+            bq.try_push(ch_id.clone(), Box::new(vec![i]));
+        }
+        bq.try_push_with_meta(ch_id.clone(), Box::new(vec![3]), None, None, BUFFER_FLAG_PRIORITY);
+        assert_eq!(schedule(&mut bq), Some(3));
+        // replay re-sends in queue order
+        assert!(bq.replay_from(0));
+        assert_eq!((0..4).map(|_| schedule(&mut bq).unwrap()).collect::<Vec<u64>>(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_event_time_watermark() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_id = ch_0.get_channel_id().clone();
+        let bqs = BufferQueues::new(vec![ch_0], Arc::new(test_config(10, 0)), test_metrics_recorder());
+        bqs.try_push(&ch_id, Box::new(vec![0])).unwrap();
+        bqs.advance_event_time_watermark(100);
+        bqs.try_push(&ch_id, Box::new(vec![1])).unwrap();
+        // does not move back
+        bqs.advance_event_time_watermark(50);
+        bqs.try_push(&ch_id, Box::new(vec![2])).unwrap();
+        let wms: Vec<Option<u64>> = (0..3).map(|_| get_buffer_event_time_watermark(&bqs.schedule_next(&ch_id).unwrap().unwrap())).collect();
+        assert_eq!(wms, vec![None, Some(100), Some(100)]);
+    }
+
+    #[test]
+    fn test_writer_epoch() {
+        // same clock tick, restarted queue still gets a newer epoch
+        let clock = MockClock::new();
+        let mut bq = BufferQueue::with_clock(&test_config(10, 0), "ch_0", clock.clone());
+        let mut restarted = BufferQueue::with_clock(&test_config(10, 0), "ch_0", clock.clone());
+        bq.try_push(String::from("ch_0"), Box::new(vec![0]));
+        bq.try_push(String::from("ch_0"), Box::new(vec![1]));
+        restarted.try_push(String::from("ch_0"), Box::new(vec![0]));
+        let epoch = get_buffer_writer_epoch(&bq.schedule_next().unwrap()).unwrap();
+        assert!(epoch >= clock.unix_micros());
+        assert_eq!(get_buffer_writer_epoch(&bq.schedule_next().unwrap()), Some(epoch));
+        assert!(get_buffer_writer_epoch(&restarted.schedule_next().unwrap()).unwrap() > epoch);
+    }
+
+    #[test]
+    fn test_poisoned_lock() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let bqs = Arc::new(BufferQueues::new(vec![ch_0], Arc::new(test_config(2, 0)), test_metrics_recorder()));
+        let ch_id = String::from("ch_0");
+        let this_bqs = bqs.clone();
+        let this_ch_id = ch_id.clone();
+        let res = std::thread::spawn(move || {
+            this_bqs.with_queue(&this_ch_id, |_| panic!("failed while holding queue lock")).unwrap();
+        }).join();
+        assert!(res.is_err());
+
+        // error instead of a panic, other channels are not affected
+        assert_eq!(bqs.try_push(&ch_id, Box::new(vec![1])), Err(NetworkError::LockPoisoned(String::from("buffer_queue"))));
+        bqs.add_channel(&String::from("ch_1")).unwrap();
+        assert_eq!(bqs.try_push(&String::from("ch_1"), Box::new(vec![1])), Ok(true));
+    }
+
+    #[test]
+    fn test_replay_from() {
+        let mut bq = BufferQueue::new(&test_config(10, 0), "ch_0");
+        let ch_id = String::from("ch_0");
+        for i in 0..3 {
+            bq.try_push(ch_id.clone(), Box::new(vec![i]));
+        }
+        for i in 0..3 {
+            assert_eq!(get_buffer_id(bq.schedule_next().unwrap()), i);
+        }
+        assert!(bq.schedule_next().is_none());
+
+        assert!(bq.replay_from(1));
+        assert_eq!(get_buffer_id(bq.schedule_next().unwrap()), 1);
+
+        // acked buffers are gone and can not be replayed
+        bq.request_pop(0);
+        assert!(!bq.replay_from(0));
+
+        assert!(bq.replay_from(1));
+        // stale ack for replayed buffer
+        bq.request_pop(1);
+        assert_eq!(get_buffer_id(bq.schedule_next().unwrap()), 2);
+        assert!(bq.schedule_next().is_none());
+    }
+
+    #[test]
+    fn test_reset_schedule() {
+        let bqs = BufferQueues::new(vec![], Arc::new(test_config(10, 1)), test_metrics_recorder());
+        let ch_id = String::from("ch_0");
+        bqs.add_channel(&ch_id).unwrap();
+        for i in 0..4 {
+            bqs.try_push(&ch_id, Box::new(vec![i])).unwrap();
+        }
+        for i in 0..3 {
+            assert_eq!(get_buffer_id(bqs.schedule_next(&ch_id).unwrap().unwrap()), i);
+        }
+        bqs.request_pop(&ch_id, 0).unwrap();
+
+        // acked (retained) buffer 0 is not re-sent, sent ones are counted as retransmits
+        bqs.reset_schedule(&ch_id).unwrap();
+        for i in 1..4 {
+            assert_eq!(get_buffer_id(bqs.schedule_next(&ch_id).unwrap().unwrap()), i);
+        }
+        assert!(bqs.schedule_next(&ch_id).unwrap().is_none());
+        assert_eq!(bqs.take_num_retransmits(&ch_id), Ok(2));
+        assert_eq!(bqs.reset_schedule(&String::from("ch_1")), Err(NetworkError::UnknownChannel(String::from("ch_1"))));
+    }
+
+    #[test]
+    fn test_paused() {
+        let mut bq = BufferQueue::new(&test_config(10, 0), "ch_0");
+        let ch_id = String::from("ch_0");
+        for i in 0..2 {
+            bq.try_push(ch_id.clone(), Box::new(vec![i]));
+        }
+        assert_eq!(get_buffer_id(bq.schedule_next().unwrap()), 0);
+        bq.set_paused(0, true);
+        assert!(bq.schedule_next().is_none());
+        // pushing still works, so writer's caller sees backpressure only once queue is full
+        assert!(bq.try_push(ch_id.clone(), Box::new(vec![2])));
+        bq.set_paused(0, false);
+        assert_eq!(get_buffer_id(bq.schedule_next().unwrap()), 1);
+        assert_eq!(get_buffer_id(bq.schedule_next().unwrap()), 2);
+    }
+
+    #[test]
+    fn test_closed() {
+        let bqs = BufferQueues::new(vec![Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")}], Arc::new(test_config(10, 0)), test_metrics_recorder());
+        let ch_id = String::from("ch_0");
+        for i in 0..2 {
+            assert_eq!(bqs.try_push(&ch_id, Box::new(vec![i])), Ok(true));
+        }
+        assert_eq!(get_buffer_id(bqs.schedule_next(&ch_id).unwrap().unwrap()), 0);
+        bqs.set_closed(&ch_id, 0).unwrap();
+        assert_eq!(bqs.schedule_next(&ch_id), Ok(None));
+        assert_eq!(bqs.try_push(&ch_id, Box::new(vec![2])), Err(NetworkError::ChannelClosed(String::from("channel ch_0"))));
+        assert_eq!(bqs.push_timeout(&ch_id, Box::new(vec![2]), 0), Err(NetworkError::ChannelClosed(String::from("channel ch_0"))));
+        assert_eq!(bqs.closed_channels(), Ok(vec![ch_id.clone()]));
+        // still undelivered
+        assert_eq!(bqs.queue_depths().unwrap()[&ch_id], 2);
+        assert_eq!(bqs.set_closed(&String::from("ch_1"), 0), Ok(vec![]));
+
+        // reopened by its reader coming back
+        bqs.set_open(&ch_id, 0).unwrap();
+        assert_eq!(bqs.closed_channels(), Ok(vec![]));
+        assert_eq!(bqs.try_push(&ch_id, Box::new(vec![2])), Ok(true));
+        assert_eq!(get_buffer_id(bqs.schedule_next(&ch_id).unwrap().unwrap()), 1);
+    }
+
+    #[test]
+    fn test_at_most_once() {
+        let at_most_once = HashMap::from([(String::from("ch_0"), true)]);
+        let config = DataWriterConfig{metrics_enabled: false, at_most_once, ..DataWriterConfig::new(1, 2)};
+        let bqs = BufferQueues::new(vec![Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")}], Arc::new(config), test_metrics_recorder());
+        let ch_id = String::from("ch_0");
+        for i in 0..2 {
+            assert_eq!(bqs.try_push(&ch_id, Box::new(vec![i])), Ok(true));
+        }
+        assert_eq!(bqs.try_push(&ch_id, Box::new(vec![2])), Ok(false));
+
+        // released once sent, without acks, so there is nothing to re-send
+        assert_eq!(get_buffer_id(bqs.schedule_next(&ch_id).unwrap().unwrap()), 0);
+        assert_eq!(bqs.queue_depths().unwrap()[&ch_id], 1);
+        assert_eq!(bqs.try_push(&ch_id, Box::new(vec![2])), Ok(true));
+        assert_eq!(get_buffer_id(bqs.schedule_next(&ch_id).unwrap().unwrap()), 1);
+        assert_eq!(get_buffer_id(bqs.schedule_next(&ch_id).unwrap().unwrap()), 2);
+        assert_eq!(bqs.schedule_next(&ch_id), Ok(None));
+        assert_eq!(bqs.queue_depths().unwrap()[&ch_id], 0);
+        assert_eq!(bqs.in_flight().unwrap()[&ch_id], 0);
+    }
+
+    #[test]
+    fn test_subscribers() {
+        let mut bq = BufferQueue::new(&test_config(10, 0), "ch_0");
+        bq.set_subscribers(2);
+        let ch_id = String::from("ch_0");
+        for i in 0..2 {
+            bq.try_push(ch_id.clone(), Box::new(vec![i]));
+        }
+        assert_eq!(get_buffer_id(bq.schedule_next().unwrap()), 0);
+        assert_eq!(get_buffer_id(bq.schedule_next().unwrap()), 1);
+
+        // held until slowest subscriber acks, repeated ack of same subscriber does not count
+        assert!(!bq.ack(0, 0));
+        assert!(!bq.ack(0, 0));
+        assert!(!bq.ack(1, 1));
+        assert_eq!(bq.queue_depth(), 2);
+        assert!(bq.ack(1, 0));
+        assert_eq!(bq.queue_depth(), 1);
+        assert!(bq.ack(0, 1));
+        assert_eq!(bq.queue_depth(), 0);
+        // re-ack of popped buffer
+        assert!(bq.ack(1, 0));
+
+        // paused while any subscriber is paused
+        bq.try_push(ch_id.clone(), Box::new(vec![2]));
+        bq.set_paused(0, true);
+        bq.set_paused(1, true);
+        bq.set_paused(0, false);
+        assert!(bq.schedule_next().is_none());
+        bq.set_paused(1, false);
+        assert_eq!(get_buffer_id(bq.schedule_next().unwrap()), 2);
+
+        // shut down subscriber is not waited for, the other one keeps the channel open
+        bq.try_push(ch_id.clone(), Box::new(vec![3]));
+        assert_eq!(get_buffer_id(bq.schedule_next().unwrap()), 3);
+        assert!(!bq.ack(0, 2));
+        assert_eq!(bq.set_closed(1), vec![2]);
+        assert!(!bq.is_closed());
+        assert_eq!(bq.queue_depth(), 1);
+        assert!(bq.ack(0, 3));
+        assert_eq!(bq.queue_depth(), 0);
+        assert_eq!(bq.set_closed(0), Vec::<u64>::new());
+        assert!(bq.is_closed());
+        bq.set_open(1);
+        assert!(!bq.is_closed());
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let clock = MockClock::new();
+        let mut bq = BufferQueue::with_clock(&DataWriterConfig{metrics_enabled: false, rate_limits: HashMap::from([(String::from("ch_0"), RateLimit::new(None, Some(1)))]), ..DataWriterConfig::new(1, 10)}, "ch_0", clock.clone());
+        let ch_id = String::from("ch_0");
+        for i in 0..2 {
+            bq.try_push(ch_id.clone(), Box::new(vec![i]));
+        }
+        assert_eq!(get_buffer_id(bq.schedule_next().unwrap()), 0);
+        // bucket is empty until refill
+        assert!(bq.schedule_next().is_none());
+        clock.advance(Duration::from_millis(20));
+        assert_eq!(bq.take_throttled_micros(), 20000);
+        assert_eq!(bq.take_throttled_micros(), 0);
+        assert!(bq.schedule_next().is_none());
+        clock.advance(Duration::from_millis(980));
+        assert_eq!(get_buffer_id(bq.schedule_next().unwrap()), 1);
+        assert_eq!(bq.take_throttled_micros(), 980000);
+    }
+
+    #[test]
+    fn test_expired() {
+        let clock = MockClock::new();
+        let mut bq = BufferQueue::with_clock(&test_config(10, 0), "ch_0", clock.clone());
+        let ch_id = String::from("ch_0");
+        let now_ts = clock.unix_micros();
+        bq.try_push_with_meta(ch_id.clone(), Box::new(vec![0]), Some(now_ts + 1000), None, 0);
+        bq.try_push_with_meta(ch_id.clone(), Box::new(vec![1]), Some(now_ts + 2000), None, 0);
+        bq.try_push(ch_id.clone(), Box::new(vec![2]));
+        clock.advance(Duration::from_micros(1500));
+
+        // expired buffer keeps its id, but loses payload
+        let b = bq.schedule_next().unwrap();
+        assert_eq!(get_buffer_id(b.clone()), 0);
+        assert_eq!(get_buffer_flags(&b), BUFFER_FLAG_EXPIRED);
+        assert_eq!(bq.take_num_expired(), 1);
+        for i in 1..3 {
+            let b = bq.schedule_next().unwrap();
+            assert_eq!(get_buffer_id(b.clone()), i);
+            assert_eq!(get_buffer_flags(&b), 0);
+        }
+        assert_eq!(bq.take_num_expired(), 0);
+
+        // acked and popped as usual
+        bq.request_pop(0);
+        assert_eq!(get_buffer_id(bq.v.front().unwrap().clone()), 1);
+    }
+
+    #[test]
+    fn test_retention() {
+        let mut bq = BufferQueue::new(&test_config(10, 2), "ch_0");
+        let ch_id = String::from("ch_0");
+        for i in 0..4 {
+            bq.try_push(ch_id.clone(), Box::new(vec![i]));
+        }
+        for i in 0..4 {
+            assert_eq!(get_buffer_id(bq.schedule_next().unwrap()), i);
+        }
+        for i in 0..3 {
+            bq.request_pop(i);
+        }
+        // only last 2 acked buffers are retained
+        assert!(!bq.replay_from(0));
+        assert!(bq.replay_from(1));
+        let replayed: Vec<u64> = std::iter::from_fn(|| bq.schedule_next()).map(get_buffer_id).collect();
+        assert_eq!(replayed, vec![1, 2, 3]);
+
+        // acks for replayed retained buffers are ignored
+        bq.request_pop(1);
+        bq.request_pop(2);
+        assert!(bq.pop_requests.is_empty());
+        bq.request_pop(3);
+        assert!(bq.v.is_empty());
+        assert!(bq.replay_from(2));
+        assert_eq!(get_buffer_id(bq.schedule_next().unwrap()), 2);
+    }
+
+    #[test]
+    fn test_compacted() {
+        let bqs = BufferQueues::new(vec![Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")}], Arc::new(test_config(3, 0)), test_metrics_recorder());
+        let ch_id = String::from("ch_0");
+        let push = |key: &[u8], v: u8| bqs.try_push_compacted(&ch_id, key, Box::new(vec![v]), None).unwrap();
+        let schedule = || bqs.schedule_next(&ch_id).unwrap().map(|b| (get_buffer_id(b.clone()), *new_buffer_drop_meta(b)));
+        assert!(push(b"a", 0));
+        assert!(push(b"b", 1));
+        assert!(bqs.try_push(&ch_id, Box::new(vec![2])).unwrap());
+        // full, but replacing needs no room
+        assert!(push(b"a", 3));
+        assert!(!push(b"c", 4));
+        assert_eq!(bqs.queue_depths().unwrap()[&ch_id], 3);
+
+        // newest value of a takes its first position and id
+        assert_eq!(schedule(), Some((0, vec![3])));
+        // sent buffer is not replaced, new value of a is queued behind
+        bqs.request_pop(&ch_id, 0).unwrap();
+        assert!(push(b"a", 5));
+        assert!(push(b"b", 6));
+        assert_eq!(schedule(), Some((1, vec![6])));
+        assert_eq!(schedule(), Some((2, vec![2])));
+        assert_eq!(schedule(), Some((3, vec![5])));
+        assert_eq!(bqs.metrics_recorder.snapshot()["ch_0"].num_compacted, 2);
+    }
+
+    #[test]
+    fn test_retransmits() {
+        let mut bq = BufferQueue::new(&test_config(10, 2), "ch_0");
+        let ch_id = String::from("ch_0");
+        for i in 0..5 {
+            let flags = if i == 4 { BUFFER_FLAG_PRIORITY } else { 0 };
+            bq.try_push_with_meta(ch_id.clone(), Box::new(vec![i]), None, None, flags);
+        }
+        // 4 (priority), 0, 1 sent, 0 acked
+        for _ in 0..3 {
+            bq.schedule_next().unwrap();
+        }
+        bq.request_pop(0);
+        assert_eq!(bq.take_num_retransmits(), 0);
+
+        // 1 and 4 were sent before, 2 and 3 were not
+        assert!(bq.replay_from(1));
+        let replayed: Vec<u64> = std::iter::from_fn(|| bq.schedule_next()).map(get_buffer_id).collect();
+        assert_eq!(replayed, vec![1, 2, 3, 4]);
+        assert_eq!(bq.take_num_retransmits(), 2);
+
+        // retained 0 and everything in queue
+        assert!(bq.replay_from(0));
+        assert_eq!(std::iter::from_fn(|| bq.schedule_next()).count(), 5);
+        assert_eq!(bq.take_num_retransmits(), 5);
+        assert_eq!(bq.take_num_retransmits(), 0);
     }
 }
\ No newline at end of file
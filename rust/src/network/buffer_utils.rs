@@ -5,61 +5,328 @@ use varint::{ VarintRead, VarintWrite };
 
 use std::io::Cursor;
 
-use super::io_loop::Bytes;
+use super::{error::{NetworkError, NetworkResult}, io_loop::Bytes};
 
+pub const META_VERSION_BYTES_LENGTH: usize = 1;
 pub const CHANNEL_ID_META_BYTES_LENGTH: usize = 16 * 4; // 16 chars
+pub const SEND_TS_META_BYTES_LENGTH: usize = 8;
+pub const EXPIRE_TS_META_BYTES_LENGTH: usize = 8;
+pub const EVENT_TIME_WM_META_BYTES_LENGTH: usize = 8;
+pub const FLAGS_META_BYTES_LENGTH: usize = 1;
+pub const WRITER_EPOCH_META_BYTES_LENGTH: usize = 8;
 
-pub fn new_buffer_with_meta(b: Box<Bytes>, channel_id: String, buffer_id: u32) -> Box<Bytes>{
-    // let channel_id_bytes = vec![0; CHANNEL_ID_META_BYTES_LENGTH];
-    let channel_id_bytes = channel_id.as_bytes().to_vec();
-    if channel_id_bytes.len() > CHANNEL_ID_META_BYTES_LENGTH {
-        panic!("channel_id is too long")
+// first byte of every buffer and reader message, layout of everything after it depends on it.
+// Any meta layout change gets a new version, readers must be able to parse it before writers start sending it
+pub const META_VERSION_V1: u8 = 1;
+// v1 + writer epoch after flags
+pub const META_VERSION_V2: u8 = 2;
+// version this build writes
+pub const META_VERSION: u8 = META_VERSION_V2;
+
+// payload is a batch of buffers, see pack_batch
+pub const BUFFER_FLAG_BATCH: u8 = 1;
+// payload was dropped by writer after TTL passed, buffer only keeps its id so reader's sequence has no gaps
+pub const BUFFER_FLAG_EXPIRED: u8 = 2;
+// control buffer (e.g. flush signal) scheduled ahead of queued data on the same channel, see BufferQueue::schedule_next
+pub const BUFFER_FLAG_PRIORITY: u8 = 4;
+// payload is one part of a message larger than writer's max_buffer_size, see split_fragments
+pub const BUFFER_FLAG_FRAGMENT: u8 = 8;
+// no payload, writer sends nothing more on the channel, see DataWriter::write_eof
+pub const BUFFER_FLAG_EOF: u8 = 16;
+
+// Largest message writer splits into fragments, and smallest max_buffer_size it splits by. Reader allocates a slot
+// per fragment as soon as the first one of a message arrives, so num_fragments of received buffers is capped by them
+pub const MAX_FRAGMENTED_MESSAGE_SIZE: u64 = 1 << 32;
+pub const MIN_FRAGMENT_SIZE: u64 = 1 << 16;
+pub const MAX_FRAGMENTS: u32 = (MAX_FRAGMENTED_MESSAGE_SIZE / MIN_FRAGMENT_SIZE) as u32;
+
+// checked on everything received from peers, so getters below can assume a known version
+pub fn get_meta_version(b: &Bytes) -> NetworkResult<u8> {
+    match b.first() {
+        Some(&META_VERSION_V1) => Ok(META_VERSION_V1),
+        Some(&META_VERSION_V2) => Ok(META_VERSION_V2),
+        Some(version) => Err(NetworkError::Decode(format!("unsupported meta version {version}, this build reads up to {META_VERSION}"))),
+        None => Err(NetworkError::Decode(String::from("empty buffer, no meta version")))
     }
+}
 
-    // let mut res = b.to_vec();
-    let mut res = Vec::new(); 
-    for _ in 0..(CHANNEL_ID_META_BYTES_LENGTH - channel_id_bytes.len()) {
-        res.push(0x00 as u8);
+// v1, v2 header: [version u8][channel_id (padded)]
+fn channel_id_offset(b: &Bytes) -> usize {
+    match get_meta_version(b) {
+        Ok(META_VERSION_V1 | META_VERSION_V2) => META_VERSION_BYTES_LENGTH,
+        res => panic!("{res:?}")
     }
+}
 
-    for v in channel_id_bytes {
-        res.push(v);   
+// end of [version][channel_id] header, shared by buffers and reader messages
+pub fn channel_id_header_len(b: &Bytes) -> usize {
+    channel_id_offset(b) + CHANNEL_ID_META_BYTES_LENGTH
+}
+
+// checks [version][channel_id] header of bytes from a peer (buffer or reader message), returns its length
+pub fn check_channel_id_header(b: &Bytes) -> NetworkResult<usize> {
+    get_meta_version(b)?;
+    let header_len = channel_id_header_len(b);
+    let channel_id_bytes = b.get(channel_id_offset(b)..header_len).ok_or_else(|| malformed("truncated channel id"))?;
+    str::from_utf8(channel_id_bytes).map_err(|_| malformed("channel id is not utf8"))?;
+    Ok(header_len)
+}
+
+// Checks meta and payload layout of a buffer from a peer, so getters, unpack_batch and parse_fragment below can not
+// panic on it. Err(Decode) for corrupt, truncated or unsupported version buffers
+pub fn check_buffer(b: &Bytes) -> NetworkResult<()> {
+    let header_len = check_channel_id_header(b)?;
+    let (_, buffer_id_len) = read_varint_64(b, header_len).ok_or_else(|| malformed("truncated buffer id"))?;
+    let flags_pos = header_len + buffer_id_len + SEND_TS_META_BYTES_LENGTH + EXPIRE_TS_META_BYTES_LENGTH + EVENT_TIME_WM_META_BYTES_LENGTH;
+    if b.len() <= flags_pos || b.len() < payload_offset(b) {
+        return Err(malformed("truncated meta"));
+    }
+    let flags = b[flags_pos];
+    let payload = &b[payload_offset(b)..];
+    if flags & BUFFER_FLAG_BATCH != 0 {
+        let mut pos = 0;
+        while pos < payload.len() {
+            let (size, len) = read_varint_32(payload, pos).ok_or_else(|| malformed("truncated batch entry size"))?;
+            pos += len + size as usize;
+        }
+        if pos > payload.len() {
+            return Err(malformed("truncated batch entry"));
+        }
+    }
+    // expired buffers carry no payload
+    if flags & BUFFER_FLAG_FRAGMENT != 0 && flags & BUFFER_FLAG_EXPIRED == 0 {
+        let (fragment_index, num_fragments, _) = parse_fragment(payload)?;
+        if num_fragments > MAX_FRAGMENTS {
+            return Err(malformed(&format!("{num_fragments} fragments, at most {MAX_FRAGMENTS} allowed")));
+        }
+        if fragment_index >= num_fragments {
+            return Err(malformed("fragment index out of range"));
+        }
     }
+    Ok(())
+}
+
+fn malformed(what: &str) -> NetworkError {
+    NetworkError::Decode(format!("malformed buffer: {what}"))
+}
 
-    let buffer_id_bytes = Vec::new();
-    let mut c = Cursor::new(buffer_id_bytes);
-    VarintWrite::write_unsigned_varint_32(&mut c, buffer_id).expect("ok");
+// (value, length) of unsigned varint at pos, None if truncated or too long for u32
+fn read_varint_32(b: &[u8], pos: usize) -> Option<(u32, usize)> {
+    read_varint_64(b, pos).and_then(|(value, len)| u32::try_from(value).ok().map(|value| (value, len)))
+}
 
-    for v in c.get_ref() {
-        res.push(*v);   
+// same for u64, up to 10 bytes
+fn read_varint_64(b: &[u8], pos: usize) -> Option<(u64, usize)> {
+    let mut value: u64 = 0;
+    for (i, byte) in b.get(pos..)?.iter().take(10).enumerate() {
+        let bits = (byte & 0x7f) as u64;
+        // 10th byte only has room for the top bit
+        if i == 9 && bits > 1 {
+            return None;
+        }
+        value |= bits << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
     }
+    None
+}
 
-    res.append(&mut b.to_vec());
+// LEB128, same encoding as write_unsigned_varint_32 for values that fit u32
+fn write_varint_64(res: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        res.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    res.push(value as u8);
+}
+
+pub fn new_channel_id_header(channel_id: &str) -> Vec<u8> {
+    let channel_id_bytes = channel_id.as_bytes();
+    if channel_id_bytes.len() > CHANNEL_ID_META_BYTES_LENGTH {
+        panic!("channel_id is too long")
+    }
+    let mut res = Vec::with_capacity(META_VERSION_BYTES_LENGTH + CHANNEL_ID_META_BYTES_LENGTH);
+    res.push(META_VERSION);
+    res.resize(META_VERSION_BYTES_LENGTH + CHANNEL_ID_META_BYTES_LENGTH - channel_id_bytes.len(), 0x00);
+    res.extend_from_slice(channel_id_bytes);
+    res
+}
+
+// buffer layout (v2): [version u8][channel_id (padded)][buffer_id u64 varint][send_ts_micros u64 le][expire_ts_micros u64 le, 0 - no TTL]
+// [event_time_wm u64 le, 0 - none][flags u8][writer_epoch u64 le, 0 - unknown][payload]. v1 is the same without writer_epoch.
+// Payload may be empty, it is a regular data buffer delivered as empty - only flags mark control or expired buffers.
+// Buffer ids were u32 before, varint encoding is the same for those, so older readers only fail on ids past u32::MAX
+pub fn new_buffer_with_meta(b: Box<Bytes>, channel_id: String, buffer_id: u64, send_ts_micros: u64) -> Box<Bytes>{
+    new_buffer_with_meta_and_flags(b, channel_id, buffer_id, send_ts_micros, None, None, 0, None)
+}
+
+// event_time_wm - writer's event-time watermark when buffer was queued: data written after this buffer is newer.
+// writer_epoch - changes every time writer's queue for the channel is created, buffer ids restart from 0 in a new epoch
+#[allow(clippy::too_many_arguments)]
+pub fn new_buffer_with_meta_and_flags(b: Box<Bytes>, channel_id: String, buffer_id: u64, send_ts_micros: u64, expire_ts_micros: Option<u64>, event_time_wm: Option<u64>, flags: u8, writer_epoch: Option<u64>) -> Box<Bytes>{
+    let mut res = new_channel_id_header(&channel_id);
+    match META_VERSION {
+        META_VERSION_V2 => {
+            write_varint_64(&mut res, buffer_id);
+            res.extend_from_slice(&send_ts_micros.to_le_bytes());
+            res.extend_from_slice(&expire_ts_micros.unwrap_or(0).to_le_bytes());
+            res.extend_from_slice(&event_time_wm.unwrap_or(0).to_le_bytes());
+            res.push(flags);
+            res.extend_from_slice(&writer_epoch.unwrap_or(0).to_le_bytes());
+        }
+        version => panic!("no layout for meta version {version}")
+    }
+    res.extend_from_slice(&b);
 
     Box::new(res)
 }
 
 pub fn new_buffer_drop_meta(b: Box<Bytes>) -> Box<Bytes> {
-    let local_b = b.clone();
-    let mut c = Cursor::new(*b);
-    c.set_position(CHANNEL_ID_META_BYTES_LENGTH as u64);
-    VarintRead::read_unsigned_varint_32(&mut c).expect("ok");
-    let pos = c.position();
-    let res = local_b[pos as usize..].to_vec();
-    Box::new(res)
+    let pos = payload_offset(&b);
+    Box::new(b[pos..].to_vec())
+}
+
+// same as new_buffer_drop_meta, but into dst's storage, replacing what it held
+pub fn copy_buffer_payload(b: &Bytes, dst: &mut Bytes) {
+    dst.clear();
+    dst.extend_from_slice(&b[payload_offset(b)..]);
 }
 
 pub fn get_channeld_id(b: Box<Bytes>) -> String {
-    let ch_id_bytes = &b[0..CHANNEL_ID_META_BYTES_LENGTH];
+    let pos = channel_id_offset(&b);
+    let ch_id_bytes = &b[pos..pos + CHANNEL_ID_META_BYTES_LENGTH];
 
     str::from_utf8(ch_id_bytes).unwrap().trim_matches(char::from(0)).to_string()
 }
 
-pub fn get_buffer_id(b: Box<Bytes>) -> u32 {
-    let mut c = Cursor::new(*b);
-    c.set_position(CHANNEL_ID_META_BYTES_LENGTH as u64);
-    let buff_id = VarintRead::read_unsigned_varint_32(&mut c).expect("ok");
-    buff_id
+pub fn get_buffer_id(b: Box<Bytes>) -> u64 {
+    let pos = buffer_id_offset(&b);
+    read_varint_64(&b, pos).expect("ok").0
+}
+
+pub fn get_buffer_send_ts(b: Box<Bytes>) -> u64 {
+    let pos = send_ts_offset(&b);
+    let ts_bytes: [u8; SEND_TS_META_BYTES_LENGTH] = b[pos..pos + SEND_TS_META_BYTES_LENGTH].try_into().unwrap();
+    u64::from_le_bytes(ts_bytes)
+}
+
+fn buffer_id_offset(b: &Bytes) -> usize {
+    match get_meta_version(b) {
+        Ok(META_VERSION_V1 | META_VERSION_V2) => channel_id_header_len(b),
+        res => panic!("{res:?}")
+    }
+}
+
+// position of send_ts, buffer_id varint ends with first byte without continuation bit
+fn send_ts_offset(b: &Bytes) -> usize {
+    let pos = buffer_id_offset(b);
+    let buffer_id_len = b[pos..].iter().position(|v| v & 0x80 == 0).unwrap() + 1;
+    pos + buffer_id_len
+}
+
+fn flags_offset(b: &Bytes) -> usize {
+    send_ts_offset(b) + SEND_TS_META_BYTES_LENGTH + EXPIRE_TS_META_BYTES_LENGTH + EVENT_TIME_WM_META_BYTES_LENGTH
+}
+
+fn payload_offset(b: &Bytes) -> usize {
+    let pos = flags_offset(b) + FLAGS_META_BYTES_LENGTH;
+    match get_meta_version(b) {
+        Ok(META_VERSION_V1) => pos,
+        Ok(META_VERSION_V2) => pos + WRITER_EPOCH_META_BYTES_LENGTH,
+        res => panic!("{res:?}")
+    }
+}
+
+pub fn get_buffer_payload_len(b: &Bytes) -> usize {
+    b.len() - payload_offset(b)
+}
+
+pub fn get_buffer_flags(b: &Bytes) -> u8 {
+    b[flags_offset(b)]
+}
+
+// None for v1 buffers and writers that do not track epochs
+pub fn get_buffer_writer_epoch(b: &Bytes) -> Option<u64> {
+    match get_meta_version(b) {
+        Ok(META_VERSION_V2) => {
+            let pos = flags_offset(b) + FLAGS_META_BYTES_LENGTH;
+            let epoch_bytes: [u8; WRITER_EPOCH_META_BYTES_LENGTH] = b[pos..pos + WRITER_EPOCH_META_BYTES_LENGTH].try_into().unwrap();
+            Some(u64::from_le_bytes(epoch_bytes)).filter(|epoch| *epoch != 0)
+        }
+        _ => None
+    }
+}
+
+pub fn get_buffer_expire_ts(b: &Bytes) -> Option<u64> {
+    let pos = send_ts_offset(b) + SEND_TS_META_BYTES_LENGTH;
+    let ts_bytes: [u8; EXPIRE_TS_META_BYTES_LENGTH] = b[pos..pos + EXPIRE_TS_META_BYTES_LENGTH].try_into().unwrap();
+    Some(u64::from_le_bytes(ts_bytes)).filter(|ts| *ts != 0)
+}
+
+pub fn get_buffer_event_time_watermark(b: &Bytes) -> Option<u64> {
+    let pos = send_ts_offset(b) + SEND_TS_META_BYTES_LENGTH + EXPIRE_TS_META_BYTES_LENGTH;
+    let wm_bytes: [u8; EVENT_TIME_WM_META_BYTES_LENGTH] = b[pos..pos + EVENT_TIME_WM_META_BYTES_LENGTH].try_into().unwrap();
+    Some(u64::from_le_bytes(wm_bytes)).filter(|wm| *wm != 0)
+}
+
+// expire ts is compared against local clock, so clocks of writer and reader hosts are assumed to be in sync
+pub fn is_buffer_expired(b: &Bytes, now_micros: u64) -> bool {
+    get_buffer_flags(b) & BUFFER_FLAG_EXPIRED != 0 || get_buffer_expire_ts(b).is_some_and(|ts| ts <= now_micros)
+}
+
+// same meta with BUFFER_FLAG_EXPIRED set and no payload
+pub fn new_expired_buffer(b: &Bytes) -> Box<Bytes> {
+    let flags_pos = flags_offset(b);
+    let mut res = b[..payload_offset(b)].to_vec();
+    res[flags_pos] |= BUFFER_FLAG_EXPIRED;
+    Box::new(res)
+}
+
+// batch payload layout: [len varint][bytes] for each buffer
+pub fn pack_batch(bs: &[Bytes]) -> Box<Bytes> {
+    let mut c = Cursor::new(Vec::with_capacity(bs.iter().map(|b| b.len() + 4).sum()));
+    for b in bs {
+        VarintWrite::write_unsigned_varint_32(&mut c, b.len() as u32).expect("ok");
+        c.get_mut().extend_from_slice(b);
+        c.set_position(c.get_ref().len() as u64);
+    }
+    Box::new(c.into_inner())
+}
+
+pub fn unpack_batch(b: Bytes) -> Vec<Box<Bytes>> {
+    let len = b.len();
+    let mut c = Cursor::new(b);
+    let mut res = Vec::new();
+    while (c.position() as usize) < len {
+        let size = VarintRead::read_unsigned_varint_32(&mut c).expect("ok") as usize;
+        let pos = c.position() as usize;
+        res.push(Box::new(c.get_ref()[pos..pos + size].to_vec()));
+        c.set_position((pos + size) as u64);
+    }
+    res
+}
+
+// fragment payload layout: [fragment_index varint][num_fragments varint][part].
+// Fragments of a message are queued under consecutive buffer ids, so first one's id is buffer_id - fragment_index
+pub fn split_fragments(b: &Bytes, max_part_size: usize) -> Vec<Box<Bytes>> {
+    // empty message is still one (empty) fragment
+    let parts: Vec<&[u8]> = if b.is_empty() { vec![&[]] } else { b.chunks(max_part_size).collect() };
+    let num_fragments = parts.len() as u32;
+    parts.into_iter().enumerate().map(|(fragment_index, part)| {
+        let mut c = Cursor::new(Vec::with_capacity(part.len() + 10));
+        VarintWrite::write_unsigned_varint_32(&mut c, fragment_index as u32).expect("ok");
+        VarintWrite::write_unsigned_varint_32(&mut c, num_fragments).expect("ok");
+        c.get_mut().extend_from_slice(part);
+        Box::new(c.into_inner())
+    }).collect()
+}
+
+// (fragment_index, num_fragments, part), Err(Decode) if the varints are truncated
+pub fn parse_fragment(b: &[u8]) -> NetworkResult<(u32, u32, &[u8])> {
+    let (fragment_index, index_len) = read_varint_32(b, 0).ok_or_else(|| malformed("truncated fragment index"))?;
+    let (num_fragments, num_len) = read_varint_32(b, index_len).ok_or_else(|| malformed("truncated number of fragments"))?;
+    Ok((fragment_index, num_fragments, &b[index_len + num_len..]))
 }
 
 #[cfg(test)]
@@ -75,15 +342,173 @@ mod tests {
         // let l = b.len();
         let ch_id = String::from("ch_0");
         let buffer_id = 12345;
-        let _b = new_buffer_with_meta(b.clone(), ch_id.clone(), buffer_id);
+        let send_ts = 1718000000123456;
+        let _b = new_buffer_with_meta(b.clone(), ch_id.clone(), buffer_id, send_ts);
 
         let _ch_id = get_channeld_id(_b.clone());
         let _buffer_id = get_buffer_id(_b.clone());
+        let _send_ts = get_buffer_send_ts(_b.clone());
 
         let b_ = new_buffer_drop_meta(_b);
         let s_: String = bincode::deserialize(&b_).unwrap();
         assert_eq!(ch_id, _ch_id);
         assert_eq!(buffer_id, _buffer_id);
+        assert_eq!(send_ts, _send_ts);
         assert_eq!(s_, s);
+        assert_eq!(get_buffer_flags(&new_buffer_with_meta(b.clone(), ch_id.clone(), buffer_id, send_ts)), 0);
+    }
+
+    #[test]
+    fn test_meta_version() {
+        let b = new_buffer_with_meta(Box::new(vec![1, 2]), String::from("ch_0"), 7, 100);
+        assert_eq!(b[0], META_VERSION);
+        assert_eq!(get_meta_version(&b), Ok(META_VERSION_V2));
+        assert_eq!(get_channeld_id(b.clone()), "ch_0");
+        assert_eq!(channel_id_header_len(&b), META_VERSION_BYTES_LENGTH + CHANNEL_ID_META_BYTES_LENGTH);
+
+        let mut unknown = b.clone();
+        unknown[0] = 200;
+        assert!(matches!(get_meta_version(&unknown), Err(NetworkError::Decode(msg)) if msg.contains("unsupported meta version 200")));
+        assert!(get_meta_version(&Vec::new()).is_err());
+        assert!(std::panic::catch_unwind(|| get_buffer_id(unknown)).is_err());
+    }
+
+    #[test]
+    fn test_buffer_id_u32_boundary() {
+        for buffer_id in [u32::MAX as u64 - 1, u32::MAX as u64, u32::MAX as u64 + 1, u64::MAX] {
+            let b = new_buffer_with_meta(Box::new(vec![1]), String::from("ch_0"), buffer_id, 100);
+            assert_eq!(check_buffer(&b), Ok(()));
+            assert_eq!((get_buffer_id(b.clone()), get_buffer_send_ts(b.clone()), *new_buffer_drop_meta(b)), (buffer_id, 100, vec![1]));
+        }
+        // ids that fit u32 are encoded as before
+        let mut c = Cursor::new(Vec::new());
+        VarintWrite::write_unsigned_varint_32(&mut c, u32::MAX).unwrap();
+        let mut res = Vec::new();
+        write_varint_64(&mut res, u32::MAX as u64);
+        assert_eq!(&res, c.get_ref());
+        assert_eq!(read_varint_32(&res, 0), Some((u32::MAX, 5)));
+        res.clear();
+        write_varint_64(&mut res, u32::MAX as u64 + 1);
+        assert_eq!((read_varint_32(&res, 0), read_varint_64(&res, 0)), (None, Some((u32::MAX as u64 + 1, 5))));
+        // longer than u64
+        assert_eq!(read_varint_64(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02], 0), None);
+    }
+
+    #[test]
+    fn test_writer_epoch() {
+        let b = new_buffer_with_meta_and_flags(Box::new(vec![1, 2]), String::from("ch_0"), 7, 100, Some(200), None, BUFFER_FLAG_BATCH, Some(5));
+        assert_eq!(get_buffer_writer_epoch(&b), Some(5));
+        assert_eq!(get_buffer_writer_epoch(&new_expired_buffer(&b)), Some(5));
+        assert_eq!(get_buffer_writer_epoch(&new_buffer_with_meta(Box::new(vec![1, 2]), String::from("ch_0"), 7, 100)), None);
+
+        // v1 buffer, as sent by writers not upgraded yet
+        let flags_pos = flags_offset(&b);
+        let mut v1 = b[..flags_pos + FLAGS_META_BYTES_LENGTH].to_vec();
+        v1[0] = META_VERSION_V1;
+        v1.extend_from_slice(&[1, 2]);
+        assert_eq!(get_meta_version(&v1), Ok(META_VERSION_V1));
+        assert_eq!((get_channeld_id(Box::new(v1.clone())), get_buffer_id(Box::new(v1.clone()))), (String::from("ch_0"), 7));
+        assert_eq!((get_buffer_expire_ts(&v1), get_buffer_flags(&v1)), (Some(200), BUFFER_FLAG_BATCH));
+        assert_eq!(get_buffer_writer_epoch(&v1), None);
+        assert_eq!(*new_buffer_drop_meta(Box::new(v1.clone())), vec![1, 2]);
+        assert!(new_buffer_drop_meta(new_expired_buffer(&v1)).is_empty());
+    }
+
+    #[test]
+    fn test_batch() {
+        let bs = vec![vec![1, 2], vec![], vec![7; 300]];
+        let b = new_buffer_with_meta_and_flags(pack_batch(&bs), String::from("ch_0"), 3, 0, None, None, BUFFER_FLAG_BATCH, None);
+        assert_eq!(get_buffer_id(b.clone()), 3);
+        assert_eq!(get_buffer_flags(&b), BUFFER_FLAG_BATCH);
+        assert_eq!(unpack_batch(*new_buffer_drop_meta(b)), bs.into_iter().map(Box::new).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_fragments() {
+        let b: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let fragments = split_fragments(&b, 300);
+        assert_eq!(fragments.len(), 4);
+        let mut joined = Vec::new();
+        for (i, fragment) in fragments.iter().enumerate() {
+            let (fragment_index, num_fragments, part) = parse_fragment(fragment).unwrap();
+            assert_eq!((fragment_index, num_fragments), (i as u32, 4));
+            joined.extend_from_slice(part);
+        }
+        assert_eq!(joined, b);
+        assert_eq!(parse_fragment(&fragments[3]).unwrap().2.len(), 100);
+        assert!(matches!(parse_fragment(&[0x80]), Err(NetworkError::Decode(_))));
+    }
+
+    #[test]
+    fn test_empty_payload() {
+        let b = new_buffer_with_meta(Box::default(), String::from("ch_0"), 1, 100);
+        assert_eq!((get_buffer_id(b.clone()), get_buffer_flags(&b)), (1, 0));
+        assert!(!is_buffer_expired(&b, u64::MAX));
+        assert!(new_buffer_drop_meta(b).is_empty());
+
+        // empty entries keep their place in a batch
+        let bs = vec![vec![], vec![1], vec![]];
+        assert_eq!(unpack_batch(*pack_batch(&bs)), bs.into_iter().map(Box::new).collect::<Vec<_>>());
+        assert!(unpack_batch(*pack_batch(&[])).is_empty());
+
+        let fragments = split_fragments(&Vec::new(), 10);
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(parse_fragment(&fragments[0]), Ok((0, 1, &[][..])));
+    }
+
+    #[test]
+    fn test_check_buffer() {
+        let b = new_buffer_with_meta(Box::new(vec![1, 2, 3]), String::from("ch_0"), 300, 100);
+        assert_eq!(check_buffer(&b), Ok(()));
+        assert_eq!(read_varint_32(&b, channel_id_header_len(&b)).map(|(v, _)| v), Some(300));
+        let batch = new_buffer_with_meta_and_flags(pack_batch(&[vec![1], vec![2, 3]]), String::from("ch_0"), 0, 0, None, None, BUFFER_FLAG_BATCH, None);
+        assert_eq!(check_buffer(&batch), Ok(()));
+        let fragment = new_buffer_with_meta_and_flags(split_fragments(&vec![1, 2, 3], 2)[1].clone(), String::from("ch_0"), 0, 0, None, None, BUFFER_FLAG_FRAGMENT, None);
+        assert_eq!(check_buffer(&fragment), Ok(()));
+        assert_eq!(check_buffer(&new_expired_buffer(&fragment)), Ok(()));
+
+        let is_malformed = |b: &Bytes| matches!(check_buffer(b), Err(NetworkError::Decode(_)));
+        assert!(is_malformed(&Vec::new()));
+        assert!(is_malformed(&vec![META_VERSION + 1; 100]));
+        // truncated in channel id, in buffer id and in fixed meta
+        assert!(is_malformed(&b[..10].to_vec()));
+        assert!(is_malformed(&b[..channel_id_header_len(&b) + 1].to_vec()));
+        assert!(is_malformed(&b[..b.len() - 4].to_vec()));
+        let mut bad_channel_id = b.clone();
+        bad_channel_id[1] = 0xff;
+        assert!(is_malformed(&bad_channel_id));
+        // batch entry longer than payload
+        assert!(is_malformed(&new_buffer_with_meta_and_flags(Box::new(vec![5, 1]), String::from("ch_0"), 0, 0, None, None, BUFFER_FLAG_BATCH, None)));
+        assert!(is_malformed(&new_buffer_with_meta_and_flags(Box::new(vec![2, 1]), String::from("ch_0"), 0, 0, None, None, BUFFER_FLAG_FRAGMENT, None)));
+        assert!(is_malformed(&new_buffer_with_meta_and_flags(Box::default(), String::from("ch_0"), 0, 0, None, None, BUFFER_FLAG_FRAGMENT, None)));
+        // would make reader allocate a slot per fragment
+        let mut too_many = Vec::new();
+        write_varint_64(&mut too_many, 0);
+        write_varint_64(&mut too_many, MAX_FRAGMENTS as u64 + 1);
+        assert!(is_malformed(&new_buffer_with_meta_and_flags(Box::new(too_many), String::from("ch_0"), 0, 0, None, None, BUFFER_FLAG_FRAGMENT, None)));
+    }
+
+    #[test]
+    fn test_expire() {
+        let b = new_buffer_with_meta_and_flags(Box::new(vec![1, 2]), String::from("ch_0"), 300, 100, Some(200), Some(50), BUFFER_FLAG_BATCH, None);
+        assert_eq!(get_buffer_send_ts(b.clone()), 100);
+        assert_eq!(get_buffer_expire_ts(&b), Some(200));
+        assert_eq!(get_buffer_event_time_watermark(&b), Some(50));
+        assert_eq!(get_buffer_flags(&b), BUFFER_FLAG_BATCH);
+        assert_eq!(*new_buffer_drop_meta(b.clone()), vec![1, 2]);
+        assert_eq!(get_buffer_payload_len(&b), 2);
+        assert!(!is_buffer_expired(&b, 199));
+        assert!(is_buffer_expired(&b, 200));
+
+        let no_ttl = new_buffer_with_meta(Box::new(vec![1, 2]), String::from("ch_0"), 300, 100);
+        assert_eq!(get_buffer_expire_ts(&no_ttl), None);
+        assert_eq!(get_buffer_event_time_watermark(&no_ttl), None);
+        assert!(!is_buffer_expired(&no_ttl, u64::MAX));
+
+        let expired = new_expired_buffer(&no_ttl);
+        assert_eq!(get_buffer_id(expired.clone()), 300);
+        assert_eq!(get_buffer_flags(&expired), BUFFER_FLAG_EXPIRED);
+        assert!(is_buffer_expired(&expired, 0));
+        assert!(new_buffer_drop_meta(expired).is_empty());
     }
 }
\ No newline at end of file
@@ -1,8 +1,11 @@
 use serde::{Deserialize, Serialize};
 
-use super::{buffer_utils::CHANNEL_ID_META_BYTES_LENGTH, io_loop::Bytes};
+use super::{buffer_utils::{check_channel_id_header, new_channel_id_header}, error::{NetworkError, NetworkResult}, io_loop::Bytes};
 
-#[derive(Clone)]
+// Persisted topology format, e.g. {"type": "local", "channel_id": "ch_0", "ipc_addr": "ipc:///tmp/ipc_0"}.
+// Tag values and field names are part of it and must not be renamed, unknown fields are ignored
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum Channel {
     Local {
         channel_id: String,
@@ -17,6 +20,10 @@ pub enum Channel {
         target_node_ip: String,
         target_node_id: String,
         port: i32,
+    },
+    // writer and reader in the same process, linked by InMemoryTransport instead of io loop sockets
+    InMemory {
+        channel_id: String
     }
 }
 
@@ -28,54 +35,248 @@ impl Channel {
             },
             Channel::Remote { channel_id, ..} => {
                 channel_id
+            },
+            Channel::InMemory { channel_id } => {
+                channel_id
+            }
+        }
+    }
+
+    pub fn is_remote(&self) -> bool {
+        matches!(self, Channel::Remote{..})
+    }
+
+    // catches misconfiguration at setup instead of as sockets failing to bind or connect later
+    pub fn validate(&self) -> NetworkResult<()> {
+        let channel_id = self.get_channel_id();
+        let invalid = |msg: &str| Err(NetworkError::InvalidChannel(format!("{channel_id}: {msg}")));
+        if channel_id.is_empty() {
+            return Err(NetworkError::InvalidChannel(String::from("with empty channel_id")));
+        }
+        match self {
+            Channel::Local { ipc_addr, ..} => {
+                if !is_valid_ipc_addr(ipc_addr) {
+                    return invalid(&format!("malformed ipc_addr {ipc_addr:?}"));
+                }
+            },
+            Channel::Remote { source_local_ipc_addr, source_node_ip, source_node_id, target_local_ipc_addr, target_node_ip, target_node_id, port, ..} => {
+                for ipc_addr in [source_local_ipc_addr, target_local_ipc_addr] {
+                    if !is_valid_ipc_addr(ipc_addr) {
+                        return invalid(&format!("malformed ipc addr {ipc_addr:?}"));
+                    }
+                }
+                for node_ip in [source_node_ip, target_node_ip] {
+                    if node_ip.is_empty() || node_ip.contains(|c: char| c.is_whitespace() || c == '/') {
+                        return invalid(&format!("malformed node ip {node_ip:?}"));
+                    }
+                }
+                if source_node_id.is_empty() || target_node_id.is_empty() {
+                    return invalid("empty node id");
+                }
+                if !(1..=65535).contains(port) {
+                    return invalid(&format!("port {port} out of range"));
+                }
+            },
+            Channel::InMemory { .. } => {}
+        }
+        Ok(())
+    }
+
+    // ipc_addr for Local, source_node_ip:port -> target_node_ip:port for Remote, inmem://channel_id for InMemory
+    pub fn address_summary(&self) -> String {
+        match self {
+            Channel::Local { ipc_addr, ..} => ipc_addr.clone(),
+            Channel::InMemory { channel_id } => in_memory_addr(channel_id),
+            Channel::Remote { source_node_ip, target_node_ip, port, ..} => {
+                format!("{source_node_ip}:{port} -> {target_node_ip}:{port}")
             }
         }
     }
 }
 
+// ipc://path or bare /path, see normalize_ipc_addr
+fn is_valid_ipc_addr(ipc_addr: &str) -> bool {
+    let path = ipc_addr.strip_prefix("ipc://").unwrap_or(ipc_addr);
+    path.starts_with('/') && path.len() > 1
+}
+
+// Channel list of one handler, see Channel::validate. Reader keys its state by channel_id, so every id must be unique.
+// Writer may list a channel once per subscriber (fan-out), entries only have to differ in addressing
+pub fn validate_channels(channels: &[Channel], fan_out: bool) -> NetworkResult<()> {
+    for (i, ch) in channels.iter().enumerate() {
+        ch.validate()?;
+        let duplicate = channels[..i].iter().any(|prev| if fan_out { prev == ch } else { prev.get_channel_id() == ch.get_channel_id() });
+        if duplicate {
+            return Err(NetworkError::InvalidChannel(format!("{}: listed more than once", ch.get_channel_id())));
+        }
+    }
+    Ok(())
+}
+
+// stands in for socket address of InMemory channels, e.g. in SocketMetadata
+pub fn in_memory_addr(channel_id: &str) -> String {
+    format!("inmem://{channel_id}")
+}
+
+// list of channels in Channel's persisted format
+pub fn channels_from_json(s: &str) -> NetworkResult<Vec<Channel>> {
+    serde_json::from_str(s).map_err(|err| NetworkError::Decode(format!("channels json: {err}")))
+}
+
+pub fn channels_from_yaml(s: &str) -> NetworkResult<Vec<Channel>> {
+    serde_yaml::from_str(s).map_err(|err| NetworkError::Decode(format!("channels yaml: {err}")))
+}
+
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct AckMessage {
     pub channel_id: String,
-    pub buffer_id: u32
+    pub buffer_id: u64
 }
 
-impl AckMessage {
+// acks of several buffers of one channel in one message, see AckStrategy::Batched
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct AckBatchMessage {
+    pub channel_id: String,
+    pub buffer_ids: Vec<u64>
+}
 
-    pub fn ser(&self) -> Box<Bytes>{
-    
-        let mut b = bincode::serialize(&self).unwrap();
-       
-        // append channel_id header
-        let channel_id_bytes = self.channel_id.as_bytes().to_vec();
-        if channel_id_bytes.len() > CHANNEL_ID_META_BYTES_LENGTH {
-            panic!("channel_id is too long")
+// reader asks writer to resend a buffer it is missing now instead of after in-flight timeout, see max_ooo_wait_ms in DataReaderConfig
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct NackMessage {
+    pub channel_id: String,
+    pub buffer_id: u64
+}
+
+// reader is shutting down for good (DataReader::shutdown, not a plain close before restart), writer stops waiting for
+// its acks. Once every subscriber of the channel did, writer stops scheduling to it and rejects further writes instead
+// of waiting for in-flight timeouts. Any later message from the reader reopens it. Best-effort, a writer that misses
+// it finds out the usual way
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ShutdownMessage {
+    pub channel_id: String
+}
+
+// reader asks writer to stop (paused = true) or resume scheduling new buffers for the channel
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct BackpressureMessage {
+    pub channel_id: String,
+    pub paused: bool
+}
+
+// everything reader sends upstream to writer, see ReaderMessageWire for the encoding
+#[derive(PartialEq, Debug, Clone)]
+pub enum ReaderMessage {
+    Ack(AckMessage),
+    Backpressure(BackpressureMessage),
+    AckBatch(AckBatchMessage),
+    Nack(NackMessage),
+    Shutdown(ShutdownMessage)
+}
+
+// acks as sent by readers from before buffer ids were widened to u64, still decoded so they work with newer writers
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+struct AckMessageU32 {
+    channel_id: String,
+    buffer_id: u32
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+struct AckBatchMessageU32 {
+    channel_id: String,
+    buffer_ids: Vec<u32>
+}
+
+// bincode encoding of ReaderMessage. Variants are only appended, so bincode indices of older ones are kept
+#[derive(Serialize, Deserialize)]
+enum ReaderMessageWire {
+    AckU32(AckMessageU32),
+    Backpressure(BackpressureMessage),
+    AckBatchU32(AckBatchMessageU32),
+    Nack(NackMessage),
+    Shutdown(ShutdownMessage),
+    Ack(AckMessage),
+    AckBatch(AckBatchMessage)
+}
+
+impl From<ReaderMessage> for ReaderMessageWire {
+    fn from(msg: ReaderMessage) -> Self {
+        match msg {
+            ReaderMessage::Ack(ack) => ReaderMessageWire::Ack(ack),
+            ReaderMessage::Backpressure(bp) => ReaderMessageWire::Backpressure(bp),
+            ReaderMessage::AckBatch(acks) => ReaderMessageWire::AckBatch(acks),
+            ReaderMessage::Nack(nack) => ReaderMessageWire::Nack(nack),
+            ReaderMessage::Shutdown(shutdown) => ReaderMessageWire::Shutdown(shutdown)
         }
+    }
+}
+
+impl From<ReaderMessageWire> for ReaderMessage {
+    fn from(msg: ReaderMessageWire) -> Self {
+        match msg {
+            ReaderMessageWire::AckU32(ack) => ReaderMessage::Ack(AckMessage{channel_id: ack.channel_id, buffer_id: ack.buffer_id as u64}),
+            ReaderMessageWire::AckBatchU32(acks) => ReaderMessage::AckBatch(AckBatchMessage{
+                channel_id: acks.channel_id,
+                buffer_ids: acks.buffer_ids.into_iter().map(u64::from).collect()
+            }),
+            ReaderMessageWire::Ack(ack) => ReaderMessage::Ack(ack),
+            ReaderMessageWire::Backpressure(bp) => ReaderMessage::Backpressure(bp),
+            ReaderMessageWire::AckBatch(acks) => ReaderMessage::AckBatch(acks),
+            ReaderMessageWire::Nack(nack) => ReaderMessage::Nack(nack),
+            ReaderMessageWire::Shutdown(shutdown) => ReaderMessage::Shutdown(shutdown)
+        }
+    }
+}
+
+impl AckMessage {
 
-        let mut res = Vec::new(); 
-        for _ in 0..(CHANNEL_ID_META_BYTES_LENGTH - channel_id_bytes.len()) {
-            res.push(0x00 as u8);
+    pub fn ser(&self) -> Box<Bytes> {
+        ReaderMessage::Ack(self.clone()).ser()
+    }
+
+    pub fn de(b: Box<Bytes>) -> Self {
+        match ReaderMessage::de(b) {
+            ReaderMessage::Ack(ack) => ack,
+            msg => panic!("Expected ack, got {:?}", msg)
         }
+    }
+}
+
+impl ReaderMessage {
 
-        for v in channel_id_bytes {
-            res.push(v);   
+    pub fn get_channel_id(&self) -> &String {
+        match self {
+            ReaderMessage::Ack(ack) => &ack.channel_id,
+            ReaderMessage::Backpressure(bp) => &bp.channel_id,
+            ReaderMessage::AckBatch(acks) => &acks.channel_id,
+            ReaderMessage::Nack(nack) => &nack.channel_id,
+            ReaderMessage::Shutdown(shutdown) => &shutdown.channel_id
         }
+    }
 
-        res.append(&mut b);
+    // same [version][channel_id] header as data buffers, so routing by channel id works for both
+    pub fn ser(&self) -> Box<Bytes>{
+        let mut res = new_channel_id_header(self.get_channel_id());
+        res.append(&mut bincode::serialize(&ReaderMessageWire::from(self.clone())).unwrap());
         Box::new(res)
     }
 
     pub fn de(b: Box<Bytes>) -> Self {
-        let mut _b = b.clone();
-        _b.drain(0..CHANNEL_ID_META_BYTES_LENGTH);
-        let ack: AckMessage = bincode::deserialize(&_b).unwrap();
-        ack
+        Self::try_de(&b).unwrap()
+    }
+
+    // de for bytes from a peer, Err(Decode) instead of a panic on malformed ones
+    pub fn try_de(b: &Bytes) -> NetworkResult<Self> {
+        let header_len = check_channel_id_header(b)?;
+        bincode::deserialize::<ReaderMessageWire>(&b[header_len..]).map(ReaderMessage::from).map_err(|err| NetworkError::Decode(format!("reader message: {err}")))
     }
 }
 
 
 #[cfg(test)]
 mod tests {
+    use crate::network::buffer_utils::get_channeld_id;
+
     use super::*;
 
     #[test]
@@ -85,5 +286,119 @@ mod tests {
         let _ack = AckMessage::de(b);
 
         assert_eq!(ack, _ack);
+
+        let bp = ReaderMessage::Backpressure(BackpressureMessage{channel_id: String::from("ch_0"), paused: true});
+        let b = bp.ser();
+        assert_eq!(get_channeld_id(b.clone()), "ch_0");
+        assert_eq!(ReaderMessage::de(b), bp);
+
+        let acks = ReaderMessage::AckBatch(AckBatchMessage{channel_id: String::from("ch_0"), buffer_ids: vec![3, 1, 2]});
+        let b = acks.ser();
+        assert_eq!(get_channeld_id(b.clone()), "ch_0");
+        assert_eq!(ReaderMessage::de(b), acks);
+
+        let nack = ReaderMessage::Nack(NackMessage{channel_id: String::from("ch_0"), buffer_id: 7});
+        let b = nack.ser();
+        assert_eq!(get_channeld_id(b.clone()), "ch_0");
+        assert_eq!(ReaderMessage::de(b), nack);
+
+        let shutdown = ReaderMessage::Shutdown(ShutdownMessage{channel_id: String::from("ch_0")});
+        let b = shutdown.ser();
+        assert_eq!(get_channeld_id(b.clone()), "ch_0");
+        assert_eq!(ReaderMessage::de(b), shutdown);
+
+        // u32 acks of older readers
+        let legacy = |msg: &ReaderMessageWire| {
+            let mut b = new_channel_id_header("ch_0");
+            b.append(&mut bincode::serialize(msg).unwrap());
+            Box::new(b)
+        };
+        let b = legacy(&ReaderMessageWire::AckU32(AckMessageU32{channel_id: String::from("ch_0"), buffer_id: 5}));
+        // same bytes older readers sent: variant index 0, then channel_id and a u32 id
+        let header_len = check_channel_id_header(&b).unwrap();
+        assert_eq!(b[header_len..header_len + 4], [0, 0, 0, 0]);
+        assert_eq!(b[b.len() - 4..], [5, 0, 0, 0]);
+        assert_eq!(ReaderMessage::de(b), ReaderMessage::Ack(AckMessage{channel_id: String::from("ch_0"), buffer_id: 5}));
+        let b = legacy(&ReaderMessageWire::AckBatchU32(AckBatchMessageU32{channel_id: String::from("ch_0"), buffer_ids: vec![u32::MAX, 1]}));
+        assert_eq!(ReaderMessage::de(b), ReaderMessage::AckBatch(AckBatchMessage{channel_id: String::from("ch_0"), buffer_ids: vec![u32::MAX as u64, 1]}));
+    }
+
+    #[test]
+    fn test_address_summary() {
+        let local = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        assert!(!local.is_remote());
+        assert_eq!(local.address_summary(), "ipc:///tmp/ipc_0");
+
+        let remote = Channel::Remote{
+            channel_id: String::from("ch_1"),
+            source_local_ipc_addr: String::from("ipc:///tmp/source_ipc"),
+            source_node_ip: String::from("10.0.0.1"),
+            source_node_id: String::from("node_1"),
+            target_local_ipc_addr: String::from("ipc:///tmp/target_ipc"),
+            target_node_ip: String::from("10.0.0.2"),
+            target_node_id: String::from("node_2"),
+            port: 1234
+        };
+        assert!(remote.is_remote());
+        assert_eq!(remote.address_summary(), "10.0.0.1:1234 -> 10.0.0.2:1234");
+
+        let in_memory = Channel::InMemory{channel_id: String::from("ch_2")};
+        assert!(!in_memory.is_remote());
+        assert_eq!(in_memory.address_summary(), "inmem://ch_2");
+    }
+
+    #[test]
+    fn test_validate_channels() {
+        let local = |channel_id: &str, ipc_addr: &str| Channel::Local{channel_id: String::from(channel_id), ipc_addr: String::from(ipc_addr)};
+        let remote = |source_node_ip: &str, port: i32| Channel::Remote{
+            channel_id: String::from("ch_1"),
+            source_local_ipc_addr: String::from("/tmp/source_ipc"),
+            source_node_ip: String::from(source_node_ip),
+            source_node_id: String::from("node_1"),
+            target_local_ipc_addr: String::from("ipc:///tmp/target_ipc"),
+            target_node_ip: String::from("10.0.0.2"),
+            target_node_id: String::from("node_2"),
+            port
+        };
+        let in_memory = Channel::InMemory{channel_id: String::from("ch_2")};
+        assert_eq!(validate_channels(&[local("ch_0", "ipc:///tmp/ipc_0"), remote("10.0.0.1", 1234), in_memory.clone()], false), Ok(()));
+
+        let invalid = |channels: &[Channel], fan_out: bool| match validate_channels(channels, fan_out) {
+            Err(NetworkError::InvalidChannel(msg)) => msg,
+            res => panic!("expected InvalidChannel, got {res:?}")
+        };
+        assert_eq!(invalid(&[local("", "ipc:///tmp/ipc_0")], false), "with empty channel_id");
+        assert_eq!(invalid(&[local("ch_0", "tmp/ipc_0")], false), "ch_0: malformed ipc_addr \"tmp/ipc_0\"");
+        assert_eq!(invalid(&[local("ch_0", "ipc://")], false), "ch_0: malformed ipc_addr \"ipc://\"");
+        assert_eq!(invalid(&[remote("", 1234)], false), "ch_1: malformed node ip \"\"");
+        assert_eq!(invalid(&[remote("10.0.0.1", 0)], false), "ch_1: port 0 out of range");
+
+        // reader needs unique ids, writer only unique entries
+        let fan_out = [local("ch_0", "ipc:///tmp/ipc_0"), local("ch_0", "ipc:///tmp/ipc_1")];
+        assert_eq!(invalid(&fan_out, false), "ch_0: listed more than once");
+        assert_eq!(validate_channels(&fan_out, true), Ok(()));
+        assert_eq!(invalid(&[in_memory.clone(), in_memory], true), "ch_2: listed more than once");
+    }
+
+    #[test]
+    fn test_channels_serde() {
+        let json = r#"[
+            {"type": "local", "channel_id": "ch_0", "ipc_addr": "ipc:///tmp/ipc_0", "unknown_field": 1},
+            {"type": "remote", "channel_id": "ch_1", "source_local_ipc_addr": "ipc:///tmp/source_ipc", "source_node_ip": "10.0.0.1",
+             "source_node_id": "node_1", "target_local_ipc_addr": "ipc:///tmp/target_ipc", "target_node_ip": "10.0.0.2",
+             "target_node_id": "node_2", "port": 1234},
+            {"type": "in_memory", "channel_id": "ch_2"}
+        ]"#;
+        let channels = channels_from_json(json).unwrap();
+        assert_eq!(channels[0], Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")});
+        assert_eq!(channels[1].address_summary(), "10.0.0.1:1234 -> 10.0.0.2:1234");
+        assert_eq!(channels[2], Channel::InMemory{channel_id: String::from("ch_2")});
+
+        let yaml = serde_yaml::to_string(&channels).unwrap();
+        assert!(yaml.contains("type: local"));
+        assert_eq!(channels_from_yaml(&yaml).unwrap(), channels);
+
+        assert!(matches!(channels_from_json(r#"[{"type": "tcp", "channel_id": "ch_0"}]"#), Err(NetworkError::Decode(_))));
+        assert!(matches!(channels_from_yaml("- channel_id: ch_0"), Err(NetworkError::Decode(_))));
     }
 }
\ No newline at end of file
@@ -0,0 +1,75 @@
+use std::{fs::{self, File}, io::{self, Write}, path::Path};
+
+// Where DataReader persists its checkpoint. Each save replaces the previous checkpoint as a whole and has to be atomic:
+// a reader restored after a crash in the middle of a save sees either the old checkpoint or the new one.
+// Called from reader's own threads, so implementations for remote targets (e.g. object stores) should bound their latency
+pub trait CheckpointStore: Send + Sync {
+    fn save(&self, b: &[u8]) -> io::Result<()>;
+
+    // None if nothing was saved yet
+    fn load(&self) -> io::Result<Option<Vec<u8>>>;
+
+    // for logs
+    fn describe(&self) -> String;
+}
+
+// writes and fsyncs a temp file next to path, then renames it over path and fsyncs the directory, so a save survives
+// power loss as well as a process crash
+pub struct FileCheckpointStore {
+    path: String
+}
+
+impl FileCheckpointStore {
+    pub fn new(path: String) -> Self {
+        FileCheckpointStore{path}
+    }
+}
+
+impl CheckpointStore for FileCheckpointStore {
+    fn save(&self, b: &[u8]) -> io::Result<()> {
+        let parent = match Path::new(&self.path).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new(".")
+        };
+        fs::create_dir_all(parent)?;
+        let tmp_path = format!("{}.tmp", self.path);
+        // synced before rename, otherwise after a power loss the renamed file may be empty or truncated
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(b)?;
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        // makes the rename itself durable
+        #[cfg(unix)]
+        File::open(parent)?.sync_all()?;
+        Ok(())
+    }
+
+    fn load(&self) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(&self.path) {
+            Ok(b) => Ok(Some(b)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    fn describe(&self) -> String {
+        self.path.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    #[test]
+    fn test_file_store() {
+        let now_ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+        let store = FileCheckpointStore::new(format!("/tmp/volga/rust/checkpoints/store-{now_ts}/test.checkpoint"));
+        assert_eq!(store.load().unwrap(), None);
+        store.save(&[1, 2]).unwrap();
+        store.save(&[3]).unwrap();
+        assert_eq!(store.load().unwrap(), Some(vec![3]));
+    }
+}
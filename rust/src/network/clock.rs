@@ -0,0 +1,81 @@
+use std::{sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, Instant, SystemTime}};
+
+// Time source for timeout-based logic (expiry, rate limiting, liveness), so tests can move time instead of sleeping.
+// Users are generic over it, so SystemClock compiles down to plain Instant::now()/SystemTime::now() calls
+pub trait Clock: Clone + Send + Sync + 'static {
+    fn now(&self) -> Instant;
+
+    // wall clock, for timestamps stamped into buffers and compared on the other end
+    fn unix_micros(&self) -> u64;
+
+    fn unix_millis(&self) -> u64 {
+        self.unix_micros() / 1000
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    #[inline]
+    fn unix_micros(&self) -> u64 {
+        SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_micros() as u64
+    }
+}
+
+// starts at wall time of creation and only moves on advance(), clones share the same time
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    start: Instant,
+    start_unix_micros: u64,
+    elapsed_micros: Arc<AtomicU64>
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock{start: Instant::now(), start_unix_micros: SystemClock.unix_micros(), elapsed_micros: Arc::new(AtomicU64::new(0))}
+    }
+
+    pub fn advance(&self, d: Duration) {
+        self.elapsed_micros.fetch_add(d.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + Duration::from_micros(self.elapsed_micros.load(Ordering::Relaxed))
+    }
+
+    fn unix_micros(&self) -> u64 {
+        self.start_unix_micros + self.elapsed_micros.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new();
+        let (now, unix_micros) = (clock.now(), clock.unix_micros());
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!((clock.now(), clock.unix_micros()), (now, unix_micros));
+
+        // clones move together
+        clock.clone().advance(Duration::from_millis(1500));
+        assert_eq!(clock.now() - now, Duration::from_millis(1500));
+        assert_eq!(clock.unix_millis(), unix_micros / 1000 + 1500);
+    }
+}
@@ -1,229 +1,4765 @@
-use std::{collections::{HashMap, VecDeque}, sync::{atomic::{AtomicBool, AtomicI32, Ordering}, Arc, Mutex, RwLock}, thread::JoinHandle};
+use std::{any::Any, collections::{BTreeMap, HashMap, HashSet, VecDeque}, fmt, fs, io, panic::{self, AssertUnwindSafe}, sync::{atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering}, Arc, Condvar, Mutex, PoisonError, RwLock}, thread::{self, JoinHandle}, time::{Duration, Instant}};
 
-use super::{buffer_utils::{get_buffer_id, new_buffer_drop_meta}, channel::{AckMessage, Channel}, io_loop::{Bytes, IOHandler, IOHandlerType}, metrics::{MetricsRecorder, NUM_BUFFERS_RECVD, NUM_BYTES_RECVD, NUM_BYTES_SENT}, sockets::SocketMetadata};
-use crossbeam::{channel::{bounded, unbounded, Receiver, Sender}, queue::ArrayQueue};
-use pyo3::{pyclass, pymethods};
+use super::{checkpoint_store::{CheckpointStore, FileCheckpointStore}, buffer_utils::{check_buffer, copy_buffer_payload, get_buffer_event_time_watermark, get_buffer_flags, get_buffer_id, get_buffer_payload_len, get_buffer_send_ts, get_buffer_writer_epoch, is_buffer_expired, parse_fragment, unpack_batch, BUFFER_FLAG_BATCH, BUFFER_FLAG_EOF, BUFFER_FLAG_FRAGMENT, BUFFER_FLAG_PRIORITY}, channel::{validate_channels, AckBatchMessage, AckMessage, BackpressureMessage, Channel, NackMessage, ReaderMessage, ShutdownMessage}, clock::{Clock, SystemClock}, io_loop::{Bytes, BytesChan, IOHandler, IOHandlerType}, lock_order::{LockRank, RankedMutex, RankedRwLock}, partitioner::hash_key, error::{poisoned, try_locked, DecodeErrorPolicy, NetworkError, NetworkResult}, metrics::{default_metrics_enabled, default_metrics_flush_interval_ms, ChannelStats, JobStats, LatencyPercentiles, MetricsRecorder, DELIVERY_LATENCY_MICROS, IN_QUEUE_DEPTH, NUM_ACKS_DROPPED, OUT_QUEUE_DWELL_MICROS, NUM_BUFFERS_RECVD, NUM_BYTES_RECVD, NUM_BYTES_SENT, NUM_DECODE_ERRORS, NUM_DROPPED_FULL, NUM_DROPPED_MEM, NUM_DUP_BELOW_WM, NUM_DUP_OOO, NUM_EMPTY_READ_BATCHES, NUM_EVICTED, NUM_EXPIRED, NUM_FORCE_SKIPPED, NUM_GAP_WAITS, NUM_NACKS_SENT, NUM_OVERFLOWED, NUM_SKIPPED, NUM_WRITER_RESTARTS, OUT_OF_ORDER_BYTES, OUT_QUEUE_DEPTH, Sampler}, sockets::SocketMetadata, threads::ThreadConfig, trace::buffer_span};
+use crossbeam::{channel::{bounded, unbounded, Receiver, Select, Sender, TrySendError}, queue::ArrayQueue};
+use pyo3::{exceptions::{PyTypeError, PyValueError}, pyclass, pymethods, types::PyDict, PyAny, PyResult};
 use serde::{Deserialize, Serialize};
 
 // const DEFAULT_OUTPUT_QUEUE_SIZE: usize = 10;
 
-#[derive(Serialize, Deserialize, Clone)]
-#[pyclass(name="RustDataReaderConfig")]
-pub struct DataReaderConfig {
-    output_queue_size: usize
+// safeguard, writer should never have more than max_buffers_per_channel un-acked buffers in flight
+const MAX_OUT_OF_ORDER_BUFFERS_PER_CHANNEL: usize = 1000;
+const MAX_RECV_BATCH_PER_CHANNEL: usize = 64;
+const MIN_IDLE_BACKOFF_MICROS: u64 = 1;
+const MAX_PARTIAL_MESSAGES: usize = 1024;
+const CHECKPOINT_SLEEP_STEP_MS: u64 = 100; // so close() does not wait for full checkpoint interval
+const FULL_QUEUE_BLOCK_STEP_MS: u64 = 5; // so a blocked dispatcher still flushes acks and sees close()
+const MAX_RETIRED_WRITER_EPOCHS: usize = 16;
+const MAX_POOLED_PAYLOADS: usize = 64;
+
+// per channel map of buffer_id -> buffer
+type ChannelsOutOfOrderBuffers = HashMap<String, Arc<RwLock<OutOfOrder>>>;
+type OutOfOrderBuffers = RwLock<ChannelsOutOfOrderBuffers>;
+
+// (channel_id, buffer_id, payload, event_time_wm, enqueued at, kind)
+type OutQueueEntry = (String, u64, Box<Bytes>, Option<u64>, Instant, EntryKind);
+type OutQueue = Mutex<VecDeque<OutQueueEntry>>;
+
+// Markers are consumed in order with the rest of their channel but never returned to consumer
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum EntryKind {
+    Data,
+    // completes its channel
+    Eof,
+    // place of an expired buffer, so in ExactlyOnce it is acked once consumed watermark passes it
+    Expired
+}
+// entries handed off to consumer, see out_queue_ring in DataReaderConfig
+type Ring = ArrayQueue<OutQueueEntry>;
+
+type Watermarks = RwLock<HashMap<String, Arc<AtomicI64>>>;
+
+type DedupWindows = RwLock<HashMap<String, Arc<Mutex<DedupWindow>>>>;
+
+// per channel timestamp (millis) of last received buffer, 0 - nothing received yet
+type LastRecvTimestamps = RwLock<HashMap<String, Arc<AtomicU64>>>;
+// per channel micros since reader's created_at of its last delivery to out_queue, 0 - nothing delivered yet
+type LastActivity = RwLock<HashMap<String, Arc<AtomicU64>>>;
+// per channel event-time watermark of consumed buffers, 0 - none yet
+type EventTimeWatermarks = RwLock<HashMap<String, Arc<AtomicU64>>>;
+// per channel buffer_id -> payloads read but not committed, see manual_commit in DataReaderConfig
+type Uncommitted = HashMap<String, BTreeMap<u64, Vec<Box<Bytes>>>>;
+
+// Gets channel and buffer as received, meta included (see buffer_utils getters). Runs on dispatcher thread holding
+// its locks, so it should be quick and must not call back into the reader. A panic fails the dispatcher
+pub type InspectHook = Arc<dyn Fn(&Channel, &Bytes) + Send + Sync>;
+
+// Gets buffers received while out_queue is full, as received with meta (fragments and batches included), and owns
+// them, see DataReader::set_overflow_handler. Runs on dispatcher thread, so it must be fast and must not call back
+// into the reader. A panic fails the dispatcher
+pub type OverflowHandler = Arc<dyn Fn(Box<Bytes>) + Send + Sync>;
+
+pub const DEFAULT_HEALTH_RECV_WINDOW_MS: u64 = 5000;
+
+pub const DEFAULT_BACKPRESSURE_LOW_WATERMARK: f64 = 0.5;
+
+pub const DEFAULT_OUTPUT_QUEUE_FULL_THRESHOLD: f64 = 1.0;
+
+pub const DEFAULT_MAX_IDLE_BACKOFF_MICROS: u64 = 1000;
+
+pub const DEFAULT_ACK_BATCH_SIZE: usize = 64;
+
+pub const DEFAULT_ACK_BATCH_DELAY_MS: u64 = 5;
+
+pub const DEFAULT_PREFETCH_MAX_BYTES: usize = 4 * 1024 * 1024;
+
+pub const DEFAULT_IDLE_CHANNEL_POLL_EVERY: u32 = 8;
+
+fn default_dispatcher_threads() -> usize {
+    1
+}
+
+fn default_backpressure_low_watermark() -> f64 {
+    DEFAULT_BACKPRESSURE_LOW_WATERMARK
+}
+
+fn default_output_queue_full_threshold() -> f64 {
+    DEFAULT_OUTPUT_QUEUE_FULL_THRESHOLD
+}
+
+fn default_max_idle_backoff_micros() -> u64 {
+    DEFAULT_MAX_IDLE_BACKOFF_MICROS
+}
+
+fn default_ack_batch_size() -> usize {
+    DEFAULT_ACK_BATCH_SIZE
+}
+
+fn default_ack_batch_delay_ms() -> u64 {
+    DEFAULT_ACK_BATCH_DELAY_MS
+}
+
+fn default_prefetch_max_bytes() -> usize {
+    DEFAULT_PREFETCH_MAX_BYTES
+}
+
+fn default_idle_channel_poll_every() -> u32 {
+    DEFAULT_IDLE_CHANNEL_POLL_EVERY
+}
+
+#[derive(Clone, Debug)]
+#[pyclass(name="RustHealthStatus")]
+pub struct HealthStatus {
+    #[pyo3(get)]
+    pub running: bool,
+    // false if any dispatcher thread exited, including on panic
+    #[pyo3(get)]
+    pub dispatcher_alive: bool,
+    // per channel - received anything within recv window. Idle channels are not unhealthy by themselves
+    #[pyo3(get)]
+    pub channels_receiving: HashMap<String, bool>,
+    // per channel - writer is currently asked to pause
+    #[pyo3(get)]
+    pub channels_backpressured: HashMap<String, bool>,
+    // panic message if dispatcher thread failed
+    #[pyo3(get)]
+    pub dispatcher_error: Option<String>,
+    // channel_id -> panic message, for channels dispatcher stopped handling while others go on. Not counted
+    // by is_healthy, so one bad channel does not get the whole reader restarted
+    #[pyo3(get)]
+    pub channels_failed: HashMap<String, String>
 }
 
 #[pymethods]
-impl DataReaderConfig { 
-    #[new]
-    pub fn new(output_queue_size: usize) -> Self {
-        DataReaderConfig{
-            output_queue_size
-        }
+impl HealthStatus {
+    // reader is started and dispatcher did not die
+    pub fn is_healthy(&self) -> bool {
+        self.running && self.dispatcher_alive
     }
 }
 
-pub struct DataReader {
-    name: String,
-    job_name: String,
-    channels: Vec<Channel>,
+fn panic_message(err: &(dyn Any + Send)) -> String {
+    if let Some(msg) = err.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = err.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        String::from("unknown panic")
+    }
+}
 
-    send_chans: Arc<RwLock<HashMap<String, (Sender<Box<Bytes>>, Receiver<Box<Bytes>>)>>>,
-    recv_chans: Arc<RwLock<HashMap<String, (Sender<Box<Bytes>>, Receiver<Box<Bytes>>)>>>,
-    out_queue: Arc<Mutex<VecDeque<Box<Bytes>>>>,
+// entries moved out of out_queue but not read yet, to ring or output chan
+fn handed_off_len(ring: &Option<Arc<Ring>>, output_chan: &Option<OutputChan>) -> usize {
+    ring.as_ref().map_or(0, |ring| ring.len()) + output_chan.as_ref().map_or(0, |chan| chan.0.len())
+}
 
-    // TODO only one thread actually modifies this, can we simplify?
-    watermarks: Arc<RwLock<HashMap<String, Arc<AtomicI32>>>>,
-    out_of_order_buffers: Arc<RwLock<HashMap<String, Arc<RwLock<HashMap<i32, Box<Bytes>>>>>>>,
+// moves staged entries into ring in order, as many as fit
+fn hand_off(out_queue: &mut VecDeque<OutQueueEntry>, ring: &Ring) {
+    while let Some(entry) = out_queue.pop_front() {
+        if let Err(entry) = ring.push(entry) {
+            out_queue.push_front(entry);
+            break;
+        }
+    }
+}
 
-    metrics_recorder: Arc<MetricsRecorder>,
+// Item of output_chan. EOF markers follow their channel's payloads, the channel is completed once consumer took
+// the marker off output_chan and dropped it, so everything before it was taken too
+pub enum Output {
+    Payload(Box<Bytes>),
+    Eof(EofMarker)
+}
 
-    running: Arc<AtomicBool>,
-    dispatcher_thread_handle: Arc<ArrayQueue<JoinHandle<()>>>, // array queue so we do not mutate DataReader and kepp ownership
+pub struct EofMarker {
+    channel_id: String,
+    completed: Arc<Mutex<HashSet<String>>>
+}
 
-    config: Arc<DataReaderConfig>
+impl EofMarker {
+    pub fn channel_id(&self) -> &str {
+        &self.channel_id
+    }
 }
 
-impl DataReader {
+impl Drop for EofMarker {
+    fn drop(&mut self) {
+        let mut locked_completed = self.completed.lock_ranked(LockRank::Completed).unwrap_or_else(PoisonError::into_inner);
+        locked_completed.insert(std::mem::take(&mut self.channel_id));
+    }
+}
 
-    pub fn new(name: String, job_name: String, data_reader_config: DataReaderConfig, channels: Vec<Channel>) -> DataReader {
-        let n_channels = channels.len();
-        let mut send_chans = HashMap::with_capacity(n_channels);
-        let mut recv_chans = HashMap::with_capacity(n_channels);
-        let mut watermarks = HashMap::with_capacity(n_channels);
-        let mut out_of_order_buffers = HashMap::with_capacity(n_channels);
+type OutputChan = (Sender<Output>, Receiver<Output>);
 
-        for ch in &channels {
-            // TODO making recv_chans bounded drops throughput 10x, why?
-            send_chans.insert(ch.get_channel_id().clone(), unbounded());
-            recv_chans.insert(ch.get_channel_id().clone(), unbounded()); 
-            watermarks.insert(ch.get_channel_id().clone(), Arc::new(AtomicI32::new(-1)));
-            out_of_order_buffers.insert(ch.get_channel_id().clone(), Arc::new(RwLock::new(HashMap::new())));   
-        }
+// next payload on output_chan, EOF markers on the way complete their channels as they are dropped
+fn recv_output(chan: &OutputChan) -> Option<Box<Bytes>> {
+    chan.1.try_iter().find_map(|output| match output {
+        Output::Payload(b) => Some(b),
+        Output::Eof(_) => None
+    })
+}
 
-        // parse config
+// hand_off for output_chan. Does what consuming an entry from out_queue would, except completing channels which
+// is left to EOF markers: event-time watermarks are consumed and dwell is observed
+fn hand_off_output(
+    out_queue: &mut VecDeque<OutQueueEntry>,
+    output: &Sender<Output>,
+    completed: &Arc<Mutex<HashSet<String>>>,
+    event_time_watermarks: &EventTimeWatermarks,
+    metrics_recorder: &MetricsRecorder,
+    now: Instant
+) -> NetworkResult<()> {
+    while let Some((channel_id, buffer_id, b, event_time_wm, enqueued_at, kind)) = out_queue.pop_front() {
+        // hand-offs are serialized by out_queue lock and consumers only take, so a free slot stays free. Checked
+        // upfront as an EOF marker that failed to send would complete its channel on drop
+        if output.is_full() {
+            out_queue.push_front((channel_id, buffer_id, b, event_time_wm, enqueued_at, kind));
+            break;
+        }
+        let item = match kind {
+            EntryKind::Data => Some(Output::Payload(b)),
+            EntryKind::Eof => Some(Output::Eof(EofMarker{channel_id: channel_id.clone(), completed: completed.clone()})),
+            EntryKind::Expired => None
+        };
+        // reader keeps a receiver, so it is never disconnected either
+        if let Some(item) = item {
+            let _ = output.try_send(item);
+        }
+        if kind == EntryKind::Data {
+            metrics_recorder.observe(OUT_QUEUE_DWELL_MICROS, &channel_id, now.saturating_duration_since(enqueued_at).as_micros() as u64);
+        }
+        if let (Some(event_time_wm), Some(wm)) = (event_time_wm, event_time_watermarks.read_ranked(LockRank::EventTimeWatermarks).map_err(poisoned("event_time_watermarks"))?.get(&channel_id)) {
+            wm.fetch_max(event_time_wm, Ordering::Relaxed);
+        }
+    }
+    Ok(())
+}
 
-        DataReader{
-            name: name.clone(),
-            job_name: job_name.clone(),
-            channels,
-            send_chans: Arc::new(RwLock::new(send_chans)),
-            recv_chans: Arc::new(RwLock::new(recv_chans)),
-            out_queue: Arc::new(Mutex::new(VecDeque::with_capacity(data_reader_config.output_queue_size))),
-            watermarks: Arc::new(RwLock::new(watermarks)),
-            out_of_order_buffers: Arc::new(RwLock::new(out_of_order_buffers)),
-            metrics_recorder: Arc::new(MetricsRecorder::new(name.clone(), job_name.clone())),
-            running: Arc::new(AtomicBool::new(false)),
-            dispatcher_thread_handle: Arc::new(ArrayQueue::new(1)),
-            config: Arc::new(data_reader_config),
+// Evicts up to n entries from the front of a full out_queue, see FullQueuePolicy::DropOldest. EOF markers are
+// not data, their channels are completed as if read. Event-time watermarks of evicted entries are consumed, nothing
+// older is coming for them
+fn evict_oldest(
+    out_queue: &mut VecDeque<OutQueueEntry>,
+    n: usize,
+    completed: &Mutex<HashSet<String>>,
+    event_time_watermarks: &EventTimeWatermarks,
+    metrics_recorder: &MetricsRecorder
+) -> NetworkResult<()> {
+    for (channel_id, _, _, event_time_wm, _, kind) in out_queue.drain(..n.min(out_queue.len())) {
+        match kind {
+            EntryKind::Data => metrics_recorder.inc(NUM_EVICTED, &channel_id, 1),
+            EntryKind::Eof => {
+                completed.lock_ranked(LockRank::Completed).map_err(poisoned("completed"))?.insert(channel_id.clone());
+            },
+            EntryKind::Expired => {}
+        }
+        if let (Some(event_time_wm), Some(wm)) = (event_time_wm, event_time_watermarks.read_ranked(LockRank::EventTimeWatermarks).map_err(poisoned("event_time_watermarks"))?.get(&channel_id)) {
+            wm.fetch_max(event_time_wm, Ordering::Relaxed);
         }
     }
+    Ok(())
+}
 
-    pub fn read_bytes(&self) -> Option<Box<Bytes>> {
-        // TODO set limit for backpressure
-        let mut locked_out_queue = self.out_queue.lock().unwrap();
-        let b = locked_out_queue.pop_front();
-        if !b.is_none() {
-            let b = b.unwrap();
-            Some(b)
-        } else {
-            None
+// What became of a buffer received while out_queue is full, see DataReader::set_overflow_handler
+#[derive(PartialEq, Debug)]
+enum Overflow {
+    // handed to overflow handler and acked
+    Taken,
+    // already delivered or held, re-acked and dropped
+    Duplicate,
+    // dropped unacked, writer resends it and it is delivered once there is room
+    Left
+}
+
+// For ordered channels, a taken buffer leaves an empty marker in out_of_order as a priority buffer does, so watermark
+// moves past it without delivering it. An id below watermark which is not a duplicate means a channel reset, that is
+// left to dispatcher's regular path, as is anything out_of_order has no room for
+fn take_overflowed(buffer_id: u64, wm: i64, dedup_window: &DedupWindow, out_of_order: &mut OutOfOrder) -> Overflow {
+    let below_wm = buffer_id as i64 <= wm;
+    let is_dup = match dedup_window.is_enabled() {
+        true => dedup_window.contains(buffer_id),
+        false => below_wm
+    };
+    if is_dup || out_of_order.contains_key(&(buffer_id as i64)) {
+        return Overflow::Duplicate;
+    }
+    if below_wm || out_of_order.len() >= MAX_OUT_OF_ORDER_BUFFERS_PER_CHANNEL || !out_of_order.fits(buffer_id as i64, wm) {
+        return Overflow::Left;
+    }
+    out_of_order.insert(buffer_id as i64, Box::default());
+    Overflow::Taken
+}
+
+// Wakes a dispatcher blocked on a full out_queue once a read took an entry out, see FullQueuePolicy::Block.
+// Reads bump num_popped under out_queue lock, so a dispatcher which saw the queue full and then waits for num_popped
+// to move can not miss one. Its lock is a leaf taken without rank, as Condvar needs the plain guard
+#[derive(Default)]
+struct OutQueueSpace {
+    num_popped: AtomicU64,
+    lock: Mutex<()>,
+    freed: Condvar
+}
+
+impl OutQueueSpace {
+    fn num_popped(&self) -> u64 {
+        self.num_popped.load(Ordering::SeqCst)
+    }
+
+    fn notify(&self) {
+        self.num_popped.fetch_add(1, Ordering::SeqCst);
+        let _guard = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        self.freed.notify_all();
+    }
+
+    // until something is popped after num_popped returned since, or timeout
+    fn wait(&self, since: u64, timeout: Duration) {
+        let guard = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let _ = self.freed.wait_timeout_while(guard, timeout, |_| self.num_popped() == since);
+    }
+}
+
+// marks dispatcher shard as dead when thread exits, even by panic
+struct AliveGuard(Arc<AtomicBool>);
+
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+// Sliding window of last `capacity` delivered buffer ids, capacity 0 disables it.
+// When enabled, a buffer is a duplicate only if its id is in the window. An id below watermark which is not in the window
+// means the channel was reset (writer restarted its id sequence), so the watermark is rewound to it instead of dropping it.
+// Ids reused while still in the window are still dropped, so window should be smaller than the id distance of any reset.
+// Not needed for resets of writers stamping their epoch into buffers, those are detected by dispatcher directly
+pub struct DedupWindow {
+    ids: HashSet<u64>,
+    order: VecDeque<u64>,
+    capacity: usize
+}
+
+impl DedupWindow {
+    pub fn new(capacity: usize) -> Self {
+        DedupWindow{ids: HashSet::with_capacity(capacity), order: VecDeque::with_capacity(capacity), capacity}
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn contains(&self, buffer_id: u64) -> bool {
+        self.ids.contains(&buffer_id)
+    }
+
+    pub fn insert(&mut self, buffer_id: u64) {
+        if !self.is_enabled() || !self.ids.insert(buffer_id) {
+            return;
+        }
+        self.order.push_back(buffer_id);
+        if self.order.len() > self.capacity {
+            let evicted = self.order.pop_front().unwrap();
+            self.ids.remove(&evicted);
         }
     }
 
-    fn send_ack(channel_id: &String, buffer_id: u32, sender: Sender<Box<Bytes>>, metrics_recorder: Arc<MetricsRecorder>) {
-        // we assume ack channels are unbounded
-        let ack = AckMessage{channel_id: channel_id.clone(), buffer_id};
-        let b = ack.ser();
-        let size = b.len();
-        sender.send(b).unwrap();
-        metrics_recorder.inc(NUM_BYTES_SENT, channel_id, size as u64);
+    // refills window as if everything up to watermark was delivered in order
+    pub fn reset_to(&mut self, watermark: i64) {
+        self.ids.clear();
+        self.order.clear();
+        let from = (watermark + 1 - self.capacity as i64).max(0);
+        for buffer_id in from..=watermark {
+            self.insert(buffer_id as u64);
+        }
     }
 }
 
-impl IOHandler for DataReader {
-    
-    fn get_name(&self) -> String {
-        self.name.clone()
+// where OutOfOrder keeps its buffers, see out_of_order_slots in DataReaderConfig
+enum HeldBuffers {
+    Map(HashMap<i64, Box<Bytes>>),
+    // buffer_id -> slot buffer_id % len, held ids are all within len after watermark so they never share a slot
+    Ring(Vec<Option<(i64, Box<Bytes>)>>)
+}
+
+// Buffers held back by a missing predecessor, keyed by buffer id. Keeps total payload bytes held,
+// so memory can be capped regardless of buffer sizes (see max_out_of_order_bytes)
+struct OutOfOrder {
+    buffers: HeldBuffers,
+    len: usize,
+    num_bytes: usize
+}
+
+impl OutOfOrder {
+    // 0 slots - unbounded map
+    fn new(slots: usize) -> Self {
+        let buffers = match slots {
+            0 => HeldBuffers::Map(HashMap::new()),
+            slots => HeldBuffers::Ring((0..slots).map(|_| None).collect())
+        };
+        OutOfOrder{buffers, len: 0, num_bytes: 0}
     }
 
-    fn get_handler_type(&self) -> IOHandlerType {
-        IOHandlerType::DataReader
+    fn len(&self) -> usize {
+        self.len
     }
 
-    fn get_channels(&self) -> &Vec<Channel> {
-        &self.channels
+    fn num_bytes(&self) -> usize {
+        self.num_bytes
     }
 
-    fn get_send_chan(&self, sm: &SocketMetadata) -> (Sender<Box<Bytes>>, Receiver<Box<Bytes>>) {
-        let hm = &self.send_chans.read().unwrap();
-        let v = hm.get(&sm.channel_id).unwrap();
-        v.clone()
+    fn slot(ring: &[Option<(i64, Box<Bytes>)>], buffer_id: i64) -> usize {
+        buffer_id.rem_euclid(ring.len() as i64) as usize
     }
 
-    fn get_recv_chan(&self, sm: &SocketMetadata) -> (Sender<Box<Bytes>>, Receiver<Box<Bytes>>) {
-        let hm = &self.recv_chans.read().unwrap();
-        let v = hm.get(&sm.channel_id).unwrap();
-        v.clone()
+    // false if buffer_id is too far ahead of watermark wm to have a slot
+    fn fits(&self, buffer_id: i64, wm: i64) -> bool {
+        match &self.buffers {
+            HeldBuffers::Map(_) => true,
+            HeldBuffers::Ring(ring) => buffer_id - wm <= ring.len() as i64
+        }
     }
 
-    fn start(&self) {
-        // start dispatcher thread: takes message from channels, in shared out_queue
-        self.running.store(true, Ordering::Relaxed);
-        self.metrics_recorder.start();
+    fn contains_key(&self, buffer_id: &i64) -> bool {
+        self.get(buffer_id).is_some()
+    }
 
-        let this_runnning = self.running.clone();
-        let this_recv_chans = self.recv_chans.clone();
-        let this_send_chans = self.send_chans.clone();
-        let this_out_queue = self.out_queue.clone();
-        let this_watermarks = self.watermarks.clone();
-        let this_out_of_order_buffers = self.out_of_order_buffers.clone();
-        let this_metrics_recorder = self.metrics_recorder.clone();
-        let this_config = self.config.clone();
+    fn get(&self, buffer_id: &i64) -> Option<&Bytes> {
+        match &self.buffers {
+            HeldBuffers::Map(map) => map.get(buffer_id).map(|b| b.as_ref()),
+            HeldBuffers::Ring(ring) => match &ring[Self::slot(ring, *buffer_id)] {
+                Some((held_id, b)) if held_id == buffer_id => Some(b.as_ref()),
+                _ => None
+            }
+        }
+    }
 
-        let f = move || {
+    // in no particular order
+    fn keys(&self) -> Box<dyn Iterator<Item = i64> + '_> {
+        match &self.buffers {
+            HeldBuffers::Map(map) => Box::new(map.keys().copied()),
+            HeldBuffers::Ring(ring) => Box::new(ring.iter().flatten().map(|(buffer_id, _)| *buffer_id))
+        }
+    }
 
-            while this_runnning.load(Ordering::Relaxed) {
-                
-                let locked_recv_chans = this_recv_chans.read().unwrap();
-                let locked_send_chans = this_send_chans.read().unwrap();
-                let locked_watermarks = this_watermarks.read().unwrap();
-                let locked_out_of_order_buffers = this_out_of_order_buffers.read().unwrap();
-                for channel_id in locked_recv_chans.keys() {
-                    let mut locked_out_queue = this_out_queue.lock().unwrap();
-                    if locked_out_queue.len() == this_config.output_queue_size {
-                        // full
-                        drop(locked_out_queue);
-                        continue
-                    }
-                    let recv_chan = locked_recv_chans.get(channel_id).unwrap();
-                    let receiver = recv_chan.1.clone();
+    // caller checks fits first, a ring slot taken by another id is overwritten
+    fn insert(&mut self, buffer_id: i64, b: Box<Bytes>) {
+        self.num_bytes += b.len();
+        self.len += 1;
+        let replaced = match &mut self.buffers {
+            HeldBuffers::Map(map) => map.insert(buffer_id, b),
+            HeldBuffers::Ring(ring) => {
+                let slot = Self::slot(ring, buffer_id);
+                ring[slot].replace((buffer_id, b)).map(|(_, replaced)| replaced)
+            }
+        };
+        if let Some(replaced) = replaced {
+            self.num_bytes -= replaced.len();
+            self.len -= 1;
+        }
+    }
 
-                    let b = receiver.try_recv();
-                    if b.is_ok() {
-                        let b = b.unwrap();
-                        let size = b.len();
-                        this_metrics_recorder.inc(NUM_BUFFERS_RECVD, channel_id, 1);
-                        this_metrics_recorder.inc(NUM_BYTES_RECVD, channel_id, size as u64);
-                        let buffer_id = get_buffer_id(b.clone());
-
-                        let wm = locked_watermarks.get(channel_id).unwrap().load(Ordering::Relaxed);
-                        if buffer_id as i32 <= wm {
-                            // drop and resend ack
-                            let send_chan = locked_send_chans.get(channel_id).unwrap();
-                            let sender = send_chan.0.clone();
-                            Self::send_ack(channel_id, buffer_id, sender, this_metrics_recorder.clone());
-                        } else {
-                            // We don't want out_of_order to grow infinitely and should put a limit on it,
-                            // however in theory it should not happen - sender will ony send maximum of it's buffer queue size
-                            // before receiving ack and sending more (which happens only after all _out_of_order is processed)
-                            let locked_out_of_orders = locked_out_of_order_buffers.get(channel_id).unwrap();
-                            let mut locked_out_of_order = locked_out_of_orders.write().unwrap(); 
-                            
-                            if locked_out_of_order.contains_key(&(buffer_id as i32)) {
-                                // duplocate
-                                let send_chan = locked_send_chans.get(channel_id).unwrap();
-                                let sender = send_chan.0.clone();
-                                Self::send_ack(channel_id, buffer_id, sender, this_metrics_recorder.clone());
-                            } else {
-                                locked_out_of_order.insert(buffer_id as i32, b.clone());
-                                let mut next_wm = wm + 1;
-                                while locked_out_of_order.contains_key(&next_wm) {
-                                    if locked_out_queue.len() == this_config.output_queue_size {
-                                        // full
-                                        break;
-                                    }
+    fn remove(&mut self, buffer_id: &i64) -> Option<Box<Bytes>> {
+        let removed = match &mut self.buffers {
+            HeldBuffers::Map(map) => map.remove(buffer_id)?,
+            HeldBuffers::Ring(ring) => {
+                let slot = Self::slot(ring, *buffer_id);
+                let slot = &mut ring[slot];
+                if slot.as_ref().is_none_or(|(held_id, _)| held_id != buffer_id) {
+                    return None;
+                }
+                slot.take().unwrap().1
+            }
+        };
+        self.num_bytes -= removed.len();
+        self.len -= 1;
+        Some(removed)
+    }
 
-                                    let stored_b = locked_out_of_order.get(&next_wm).unwrap();
-                                    let stored_buffer_id = get_buffer_id(stored_b.clone());
-                                    let payload = new_buffer_drop_meta(stored_b.clone());
+    fn clear(&mut self) {
+        match &mut self.buffers {
+            HeldBuffers::Map(map) => map.clear(),
+            HeldBuffers::Ring(ring) => ring.iter_mut().for_each(|slot| *slot = None)
+        }
+        self.len = 0;
+        self.num_bytes = 0;
+    }
+}
 
-                                    locked_out_queue.push_back(payload); 
+// dispatcher's wait on a missing buffer of a channel with max_ooo_wait_ms: since when watermark is stuck at watermark,
+// or since the nack if one was sent
+struct GapWait {
+    watermark: i64,
+    since: Instant,
+    nacked: bool
+}
 
-                                    // send ack
-                                    let send_chan = locked_send_chans.get(channel_id).unwrap();
-                                    let sender = send_chan.0.clone();
-                                    Self::send_ack(channel_id, stored_buffer_id, sender, this_metrics_recorder.clone());
-                                    locked_out_of_order.remove(&next_wm);
-                                    next_wm += 1;
-                                }
-                                locked_watermarks.get(channel_id).unwrap().store(next_wm - 1, Ordering::Relaxed);
-                            }
-                        }
-                    }
+// Writer epochs seen on a channel, see get_buffer_writer_epoch. Epochs are wall clock based and a restarted writer's
+// clock may be behind, so any epoch not seen before is a restart, lower or not. Replaced epochs are remembered, so
+// late buffers of a previous writer are not taken for yet another restart
+#[derive(Default)]
+struct WriterEpochs {
+    current: Option<u64>,
+    // newest last, up to MAX_RETIRED_WRITER_EPOCHS
+    retired: VecDeque<u64>
+}
+
+#[derive(PartialEq, Debug)]
+enum EpochChange {
+    Same,
+    Restarted,
+    // buffer of a replaced writer still in flight, nobody waits for its ack
+    Stale
+}
+
+impl WriterEpochs {
+    fn observe(&mut self, epoch: u64) -> EpochChange {
+        match self.current {
+            Some(current) if current == epoch => EpochChange::Same,
+            _ if self.retired.contains(&epoch) => EpochChange::Stale,
+            None => {
+                self.current = Some(epoch);
+                EpochChange::Same
+            }
+            Some(current) => {
+                if self.retired.len() == MAX_RETIRED_WRITER_EPOCHS {
+                    self.retired.pop_front();
                 }
+                self.retired.push_back(current);
+                self.current = Some(epoch);
+                EpochChange::Restarted
             }
+        }
+    }
+}
+
+// see DataReader::set_inspect_hook
+struct Inspector {
+    hook: InspectHook,
+    sample_every: u64,
+    num_seen: AtomicU64
+}
+
+// Collects fragments of messages split by writer (see split_fragments), keyed by channel and first fragment's buffer id.
+// Fragments may come in any order (e.g. on unordered channels). A message whose fragments never all arrive
+// (expired or channel reset) is evicted once MAX_PARTIAL_MESSAGES others are pending
+#[derive(Default)]
+struct FragmentAssembler {
+    partial: BTreeMap<(String, u64), Vec<Option<Bytes>>>
+}
+
+impl FragmentAssembler {
+    // whole message once its last missing fragment is added
+    fn add(&mut self, channel_id: &str, buffer_id: u64, fragment: &Bytes) -> Option<Box<Bytes>> {
+        // checked by check_buffer on receive
+        let Ok((fragment_index, num_fragments, part)) = parse_fragment(fragment) else {
+            return None
         };
+        let key = (channel_id.to_string(), buffer_id.wrapping_sub(fragment_index as u64));
+        if !self.partial.contains_key(&key) && self.partial.len() >= MAX_PARTIAL_MESSAGES {
+            self.partial.pop_first();
+        }
+        let parts = self.partial.entry(key.clone()).or_insert_with(|| vec![None; num_fragments as usize]);
+        if let Some(slot) = parts.get_mut(fragment_index as usize) {
+            *slot = Some(part.to_vec());
+        }
+        if parts.iter().any(|part| part.is_none()) {
+            return None;
+        }
+        let parts = self.partial.remove(&key).unwrap();
+        Some(Box::new(parts.into_iter().flatten().flatten().collect()))
+    }
 
-        let name = &self.name;
-        let thread_name = format!("volga_{name}_dispatcher_thread");
-        self.dispatcher_thread_handle.push(std::thread::Builder::new().name(thread_name).spawn(f).unwrap()).unwrap();
+    fn clear_channel(&mut self, channel_id: &str) {
+        self.partial.retain(|(partial_channel_id, _), _| partial_channel_id != channel_id);
     }
+}
 
-    fn close (&self) {
-        self.running.store(false, Ordering::Relaxed);
-        let handle = self.dispatcher_thread_handle.pop();
-        handle.unwrap().join().unwrap();
-        self.metrics_recorder.close();
+// AtLeastOnce: buffer is acked as soon as dispatcher puts it in out_queue. No duplicates are delivered
+//   within reader's lifetime. Across restarts (with checkpoint_path) buffers delivered after last checkpoint
+//   are delivered again if writer still has them, while buffers sitting in out_queue at crash time are lost
+//   (they were already acked) unless writer retention allows replaying them. No extra cost.
+// ExactlyOnce: requires checkpoint_path (or a checkpoint store). Each read_bytes persists a per-channel consumed watermark
+//   before returning the buffer and only then acks it, so writer keeps un-consumed buffers and re-sends
+//   them after restart, while everything at or below consumed watermark is dropped. A buffer is never
+//   returned from read_bytes twice, but if consumer crashes after read_bytes returned, that buffer is not re-delivered.
+//   Costs a synchronous checkpoint write per read_bytes, during which dispatcher can not push to out_queue,
+//   and acks are delayed until consumption, so writer's in-flight window stays full longer.
+//   Only the last fragment of a fragmented message is acked on consumption, the others are re-acked as duplicates
+//   when writer re-sends them after in-flight timeout.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[pyclass(name="RustDeliveryGuarantee")]
+pub enum DeliveryGuarantee {
+    #[default]
+    AtLeastOnce,
+    ExactlyOnce
+}
+
+// How acks are sent to writer, applies to every ack regardless of DeliveryGuarantee.
+// Immediate: one message per buffer as soon as it is acked, lowest latency for writer releasing its queue.
+// Batched: acks are held per channel and sent in one message once ack_batch_size of them are held or the oldest
+//   one waited ack_batch_delay_ms. Fewer messages, but writer keeps buffers longer, so its in-flight timeout should
+//   stay well above the delay to avoid resends. Needs writers that understand batched acks
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[pyclass(name="RustAckStrategy")]
+pub enum AckStrategy {
+    #[default]
+    Immediate,
+    Batched
+}
+
+// What an ordered channel with max_ooo_wait_ms does once a missing buffer held others back that long.
+// Nack: asks writer to re-send it, again every max_ooo_wait_ms, and keeps waiting - nothing is ever given up on.
+// NackThenSkip: asks once, and if it is still missing after another max_ooo_wait_ms gives up on it and whatever else is
+//   missing before the first held buffer, same as force_advance (data loss, counted as num_force_skipped)
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[pyclass(name="RustGapPolicy")]
+pub enum GapPolicy {
+    Nack,
+    #[default]
+    NackThenSkip
+}
+
+// What dispatcher does while out_queue is full, unless an overflow handler is set (see DataReader::set_overflow_handler).
+// Spin: keeps polling channels with the idle backoff, receiving nothing until there is room.
+// Block: sleeps until a read takes an entry out of out_queue, so a stalled consumer costs no CPU.
+// DropOldest: evicts entries from the front of out_queue to make room for what was received, so consumers lagging
+//   behind get recent data (lossy, counted as num_evicted - evicted buffers were acked, writer does not re-send them).
+// Block and DropOldest are not supported with out_queue_ring or output_chan, DropOldest is AtLeastOnce only
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[pyclass(name="RustFullQueuePolicy")]
+pub enum FullQueuePolicy {
+    #[default]
+    Spin,
+    Block,
+    DropOldest
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CloseError {
+    // dispatcher did not exit in time, close can be retried
+    Timeout,
+    ThreadPanicked(String),
+    Checkpoint(String),
+    Drain(String)
+}
+
+impl fmt::Display for CloseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseError::Timeout => write!(f, "dispatcher thread did not exit in time"),
+            CloseError::ThreadPanicked(msg) => write!(f, "dispatcher thread panicked: {msg}"),
+            CloseError::Checkpoint(msg) => write!(f, "failed to checkpoint on close: {msg}"),
+            CloseError::Drain(msg) => write!(f, "failed to drain on close: {msg}")
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[pyclass(name="RustDataReaderConfig")]
+pub struct DataReaderConfig {
+    pub output_queue_size: usize,
+    #[serde(default = "default_metrics_enabled")]
+    pub metrics_enabled: bool,
+    #[serde(default = "default_metrics_flush_interval_ms")]
+    pub metrics_flush_interval_ms: u64,
+    // if set, watermarks are restored from this file on construction and checkpointed to it on close
+    #[serde(default)]
+    pub checkpoint_path: Option<String>,
+    // if set (together with checkpoint_path), a background thread also checkpoints periodically, skipping unchanged snapshots.
+    // Dispatcher only updates watermarks in memory, so save latency stays off the hot path
+    #[serde(default)]
+    pub checkpoint_interval_ms: Option<u64>,
+    #[serde(default)]
+    pub delivery_guarantee: DeliveryGuarantee,
+    // size of per channel duplicate detection window (see DedupWindow), 0 - detect duplicates by watermark only
+    #[serde(default)]
+    pub dedup_window: usize,
+    // fractions of output_queue_size: writers are asked to pause once out_queue reaches high watermark
+    // and to resume only once it drains to low watermark. None - no backpressure
+    #[serde(default)]
+    pub backpressure_high_watermark: Option<f64>,
+    #[serde(default = "default_backpressure_low_watermark")]
+    pub backpressure_low_watermark: f64,
+    // bound on buffers received from io loop but not yet processed by dispatcher, per channel.
+    // None - unbounded, memory grows if dispatcher falls behind. An idle dispatcher wakes as soon as a buffer arrives
+    // and io loop yields while a chan is full, so even small capacities keep throughput close to unbounded - as long
+    // as consumers polling read_bytes yield on None instead of spinning, see benches/recv_chan_bench.rs
+    #[serde(default)]
+    pub recv_chan_capacity: Option<usize>,
+    // channels are sharded across this many dispatcher threads by channel id hash, all writing to the shared out_queue,
+    // so delivery order is kept per channel only. Checkpointing and backpressure are done by the first one
+    #[serde(default = "default_dispatcher_threads")]
+    pub dispatcher_threads: usize,
+    // channel_id -> ordered, channels not listed are ordered. Unordered channel buffers are delivered and acked
+    // as soon as they arrive, skipping watermark and out-of-order tracking, so they may be delivered more than once
+    // when writer re-sends a buffer whose ack was lost or late
+    #[serde(default)]
+    pub ordered: HashMap<String, bool>,
+    // fraction of output_queue_size at which dispatcher treats out_queue as full and stops moving buffers into it,
+    // leaving headroom e.g. for batches unpacked over the limit. See DataReader::available_capacity
+    #[serde(default = "default_output_queue_full_threshold")]
+    pub output_queue_full_threshold: f64,
+    // after a pass over channels receives nothing, dispatcher sleeps with exponentially growing delay up to this,
+    // reset by any receive. Bounds added latency for first buffer after idle period, 0 - only yield, keeps a core busy
+    #[serde(default = "default_max_idle_backoff_micros")]
+    pub max_idle_backoff_micros: u64,
+    // None - unbounded. Acks that do not fit are dropped and counted as num_acks_dropped, writer resends
+    // unacked buffers after in-flight timeout and duplicate is re-acked, so a slow io loop never blocks dispatcher
+    #[serde(default)]
+    pub ack_chan_capacity: Option<usize>,
+    // name prefix, priority and cpu pinning of dispatcher threads, dispatcher i is pinned to cpu_affinity[i % len]
+    #[serde(default)]
+    pub dispatcher_thread_config: ThreadConfig,
+    // per channel cap on payload bytes held out-of-order, applied along with the buffer count cap, whichever is hit first.
+    // Buffers over it are dropped without ack (num_dropped_mem) and re-sent by writer. None - count cap only
+    #[serde(default)]
+    pub max_out_of_order_bytes: Option<usize>,
+    #[serde(default)]
+    pub ack_strategy: AckStrategy,
+    // AckStrategy::Batched only
+    #[serde(default = "default_ack_batch_size")]
+    pub ack_batch_size: usize,
+    #[serde(default = "default_ack_batch_delay_ms")]
+    pub ack_batch_delay_ms: u64,
+    // out_queue may take up to this many entries over its limit while dispatcher drains buffers already held
+    // out-of-order, instead of leaving them until the channel's next receive. Keeps batch reads full (fewer empty
+    // read_batch calls), entries over the limit are capped by prefetch_max_bytes of payload. 0 - strict limit
+    #[serde(default)]
+    pub prefetch: usize,
+    #[serde(default = "default_prefetch_max_bytes")]
+    pub prefetch_max_bytes: usize,
+    // Hands delivered entries to the consumer through a lock-free ring instead of sharing out_queue's mutex with it,
+    // out_queue then only stages what does not fit the ring. Single dispatcher and AtLeastOnce only. read_bytes_from
+    // is not supported, skip_to and writer restarts can not take back entries already in the ring
+    #[serde(default)]
+    pub out_queue_ring: bool,
+    // malformed buffers are dropped without ack (writer resends them after in-flight timeout, so a persistently bad
+    // one shows up as a gap, see force_advance) or fail the channel
+    #[serde(default)]
+    pub decode_error_policy: DecodeErrorPolicy,
+    // after this many passes in a row with nothing received, a channel is polled only every idle_channel_poll_every-th
+    // pass until it receives again, so with many idle channels dispatcher spends its time on active ones. Adds up to
+    // that many passes (and idle backoffs) of latency to the first buffer on an idle channel. 0 - poll every pass
+    #[serde(default)]
+    pub idle_channel_misses: u32,
+    #[serde(default = "default_idle_channel_poll_every")]
+    pub idle_channel_poll_every: u32,
+    // Hands delivered payloads to a bounded crossbeam channel instead of keeping them in out_queue until read, so Rust
+    // consumers can select over it with their other channels, see output_receiver. read_bytes reads from it too,
+    // read_message and read_bytes_from are not supported as ids are not kept. EOF markers are handed off too and
+    // complete their channel once consumer drops them (see Output), event-time watermarks are consumed once handed
+    // off rather than once read, out_queue_dwell covers time staged in out_queue only. AtLeastOnce only, not with
+    // out_queue_ring. Same as with the ring, skip_to and writer restarts can not take back payloads already handed off
+    #[serde(default)]
+    pub output_chan: bool,
+    // channel_id -> how long a gap may hold back buffers received after it before gap_policy kicks in, measured from
+    // when watermark last moved. Channels not listed (and unordered ones) wait for writer's in-flight timeout only
+    #[serde(default)]
+    pub max_ooo_wait_ms: HashMap<String, u64>,
+    #[serde(default)]
+    pub gap_policy: GapPolicy,
+    // Consumer acks: dispatcher does not ack delivered buffers, the consumer does it with commit once it is done with a
+    // buffer returned by read_message, so a consumer crashing mid-processing gets it again after restart (writer re-sends
+    // what was not acked). read_bytes and friends are not supported, there would be no id to commit. Until committed,
+    // buffers count against writer's in-flight window. See DataReader::commit. AtLeastOnce only, not with
+    // out_queue_ring or output_chan
+    #[serde(default)]
+    pub manual_commit: bool,
+    // Per channel reassembly window: held buffers go to a ring of this many slots indexed by buffer_id % slots, so
+    // holding and draining them in order takes no hashing. Buffers further than this ahead of watermark are dropped
+    // without ack like on a full window (num_dropped_full) and re-sent by writer, so it should cover writer's
+    // max_buffers_per_channel. 0 - unbounded map, capped by buffer count only
+    #[serde(default)]
+    pub out_of_order_slots: usize,
+    // channel_id -> at most once, channels not listed are not. Fire-and-forget: buffers are delivered as they arrive,
+    // nothing is acked, no watermark, dedup or reassembly window is kept. Writer releases buffers once sent and never
+    // re-sends (needs at_most_once on writer side too), so a buffer lost on the way or dropped here on a full
+    // out_queue is gone for good, and duplicates are not filtered. Such channels are never ordered, AtLeastOnce only,
+    // not with manual_commit
+    #[serde(default)]
+    pub at_most_once: HashMap<String, bool>,
+    #[serde(default)]
+    pub full_queue_policy: FullQueuePolicy
+}
+
+#[pymethods]
+impl DataReaderConfig { 
+    // keyword arguments are the fields below, see set_py
+    #[new]
+    #[pyo3(signature = (output_queue_size, **kwargs))]
+    pub fn py_new(output_queue_size: usize, kwargs: Option<&PyDict>) -> PyResult<Self> {
+        let mut config = Self::new(output_queue_size);
+        for (name, value) in kwargs.into_iter().flatten() {
+            config.set_py(name.extract()?, value)?;
+        }
+        config.validate().map_err(PyValueError::new_err)?;
+        Ok(config)
+    }
+}
+
+impl DataReaderConfig {
+    // Everything else at the same defaults as fields left out of a persisted config. Set options with struct update,
+    // e.g. DataReaderConfig{ordered, ..DataReaderConfig::new(100)}, DataReader::new validates the result
+    pub fn new(output_queue_size: usize) -> Self {
+        DataReaderConfig{
+            output_queue_size,
+            metrics_enabled: default_metrics_enabled(),
+            metrics_flush_interval_ms: default_metrics_flush_interval_ms(),
+            checkpoint_path: None,
+            checkpoint_interval_ms: None,
+            delivery_guarantee: DeliveryGuarantee::default(),
+            dedup_window: 0,
+            backpressure_high_watermark: None,
+            backpressure_low_watermark: default_backpressure_low_watermark(),
+            recv_chan_capacity: None,
+            dispatcher_threads: default_dispatcher_threads(),
+            ordered: HashMap::new(),
+            output_queue_full_threshold: default_output_queue_full_threshold(),
+            max_idle_backoff_micros: default_max_idle_backoff_micros(),
+            ack_chan_capacity: None,
+            dispatcher_thread_config: ThreadConfig::default(),
+            max_out_of_order_bytes: None,
+            ack_strategy: AckStrategy::default(),
+            ack_batch_size: default_ack_batch_size(),
+            ack_batch_delay_ms: default_ack_batch_delay_ms(),
+            prefetch: 0,
+            prefetch_max_bytes: default_prefetch_max_bytes(),
+            out_queue_ring: false,
+            decode_error_policy: DecodeErrorPolicy::default(),
+            idle_channel_misses: 0,
+            idle_channel_poll_every: default_idle_channel_poll_every(),
+            output_chan: false,
+            max_ooo_wait_ms: HashMap::new(),
+            gap_policy: GapPolicy::default(),
+            manual_commit: false,
+            out_of_order_slots: 0,
+            at_most_once: HashMap::new(),
+            full_queue_policy: FullQueuePolicy::default()
+        }
+    }
+
+    // one keyword argument of the Python constructor
+    fn set_py(&mut self, name: &str, value: &PyAny) -> PyResult<()> {
+        match name {
+            "metrics_enabled" => self.metrics_enabled = value.extract()?,
+            "metrics_flush_interval_ms" => self.metrics_flush_interval_ms = value.extract()?,
+            "checkpoint_path" => self.checkpoint_path = value.extract()?,
+            "checkpoint_interval_ms" => self.checkpoint_interval_ms = value.extract()?,
+            "delivery_guarantee" => self.delivery_guarantee = value.extract()?,
+            "dedup_window" => self.dedup_window = value.extract()?,
+            "backpressure_high_watermark" => self.backpressure_high_watermark = value.extract()?,
+            "backpressure_low_watermark" => self.backpressure_low_watermark = value.extract()?,
+            "recv_chan_capacity" => self.recv_chan_capacity = value.extract()?,
+            "dispatcher_threads" => self.dispatcher_threads = value.extract()?,
+            "ordered" => self.ordered = value.extract()?,
+            "output_queue_full_threshold" => self.output_queue_full_threshold = value.extract()?,
+            "max_idle_backoff_micros" => self.max_idle_backoff_micros = value.extract()?,
+            "ack_chan_capacity" => self.ack_chan_capacity = value.extract()?,
+            "dispatcher_thread_config" => self.dispatcher_thread_config = value.extract::<Option<ThreadConfig>>()?.unwrap_or_default(),
+            "max_out_of_order_bytes" => self.max_out_of_order_bytes = value.extract()?,
+            "ack_strategy" => self.ack_strategy = value.extract()?,
+            "ack_batch_size" => self.ack_batch_size = value.extract()?,
+            "ack_batch_delay_ms" => self.ack_batch_delay_ms = value.extract()?,
+            "prefetch" => self.prefetch = value.extract()?,
+            "prefetch_max_bytes" => self.prefetch_max_bytes = value.extract()?,
+            "out_queue_ring" => self.out_queue_ring = value.extract()?,
+            "decode_error_policy" => self.decode_error_policy = value.extract()?,
+            "idle_channel_misses" => self.idle_channel_misses = value.extract()?,
+            "idle_channel_poll_every" => self.idle_channel_poll_every = value.extract()?,
+            "output_chan" => self.output_chan = value.extract()?,
+            "max_ooo_wait_ms" => self.max_ooo_wait_ms = value.extract()?,
+            "gap_policy" => self.gap_policy = value.extract()?,
+            "manual_commit" => self.manual_commit = value.extract()?,
+            "out_of_order_slots" => self.out_of_order_slots = value.extract()?,
+            "at_most_once" => self.at_most_once = value.extract()?,
+            "full_queue_policy" => self.full_queue_policy = value.extract()?,
+            _ => return Err(PyTypeError::new_err(format!("RustDataReaderConfig got an unexpected keyword argument {name:?}")))
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.validate_with_store(self.checkpoint_path.is_some())
     }
-}
\ No newline at end of file
+
+    // has_store - reader checkpoints, to checkpoint_path or to a store given to DataReader::with_checkpoint_store
+    fn validate_with_store(&self, has_store: bool) -> Result<(), String> {
+        if self.output_queue_size == 0 {
+            // dispatcher would treat out_queue as always full and never deliver
+            return Err(String::from("output_queue_size must be greater than 0"));
+        }
+        if self.metrics_enabled && self.metrics_flush_interval_ms == 0 {
+            return Err(String::from("metrics_flush_interval_ms must be greater than 0 when metrics are enabled"));
+        }
+        self.dispatcher_thread_config.validate()?;
+        if self.checkpoint_interval_ms.is_some() && !has_store {
+            return Err(String::from("checkpoint_interval_ms requires checkpoint_path or a checkpoint store"));
+        }
+        if self.checkpoint_interval_ms == Some(0) {
+            return Err(String::from("checkpoint_interval_ms must be greater than 0"));
+        }
+        if self.delivery_guarantee == DeliveryGuarantee::ExactlyOnce && !has_store {
+            return Err(String::from("ExactlyOnce delivery requires checkpoint_path or a checkpoint store"));
+        }
+        if self.delivery_guarantee == DeliveryGuarantee::ExactlyOnce && self.ordered.values().any(|ordered| !ordered) {
+            // consumed watermark can not be tracked without ordering
+            return Err(String::from("ExactlyOnce delivery requires all channels to be ordered"));
+        }
+        if self.recv_chan_capacity == Some(0) {
+            return Err(String::from("recv_chan_capacity must be greater than 0"));
+        }
+        if self.ack_chan_capacity == Some(0) {
+            return Err(String::from("ack_chan_capacity must be greater than 0"));
+        }
+        if self.max_out_of_order_bytes == Some(0) {
+            return Err(String::from("max_out_of_order_bytes must be greater than 0"));
+        }
+        if self.ack_strategy == AckStrategy::Batched && self.ack_batch_size == 0 {
+            return Err(String::from("ack_batch_size must be greater than 0"));
+        }
+        if self.prefetch > 0 && self.prefetch_max_bytes == 0 {
+            return Err(String::from("prefetch_max_bytes must be greater than 0 when prefetch is enabled"));
+        }
+        if self.idle_channel_misses > 0 && self.idle_channel_poll_every == 0 {
+            return Err(String::from("idle_channel_poll_every must be greater than 0 when idle_channel_misses is set"));
+        }
+        if self.manual_commit && self.delivery_guarantee == DeliveryGuarantee::ExactlyOnce {
+            return Err(String::from("manual_commit requires AtLeastOnce delivery"));
+        }
+        if self.manual_commit && (self.out_queue_ring || self.output_chan) {
+            // ids are not kept past hand-off
+            return Err(String::from("manual_commit can not be used with out_queue_ring or output_chan"));
+        }
+        if self.at_most_once.values().any(|at_most_once| *at_most_once) {
+            if self.delivery_guarantee == DeliveryGuarantee::ExactlyOnce || self.manual_commit {
+                // both are about acking on consumption, at_most_once channels never ack
+                return Err(String::from("at_most_once channels can not be used with ExactlyOnce delivery or manual_commit"));
+            }
+            if self.at_most_once.iter().any(|(channel_id, at_most_once)| *at_most_once && self.ordered.get(channel_id) == Some(&true)) {
+                return Err(String::from("at_most_once channels can not be ordered"));
+            }
+        }
+        if self.full_queue_policy != FullQueuePolicy::Spin && (self.out_queue_ring || self.output_chan) {
+            // handed off entries are taken out by consumer without out_queue lock
+            return Err(String::from("Block and DropOldest full_queue_policy can not be used with out_queue_ring or output_chan"));
+        }
+        if self.full_queue_policy == FullQueuePolicy::DropOldest && (self.delivery_guarantee == DeliveryGuarantee::ExactlyOnce || self.manual_commit) {
+            // evicted entries would never be acked
+            return Err(String::from("DropOldest full_queue_policy requires AtLeastOnce delivery without manual_commit"));
+        }
+        if self.max_ooo_wait_ms.values().any(|max_wait_ms| *max_wait_ms == 0) {
+            return Err(String::from("max_ooo_wait_ms must be greater than 0"));
+        }
+        if self.dispatcher_threads == 0 {
+            return Err(String::from("dispatcher_threads must be greater than 0"));
+        }
+        if self.out_queue_ring && self.dispatcher_threads > 1 {
+            return Err(String::from("out_queue_ring requires a single dispatcher thread"));
+        }
+        if self.out_queue_ring && self.delivery_guarantee == DeliveryGuarantee::ExactlyOnce {
+            // consumption is checkpointed under out_queue lock
+            return Err(String::from("out_queue_ring requires AtLeastOnce delivery"));
+        }
+        if self.output_chan && self.out_queue_ring {
+            return Err(String::from("output_chan and out_queue_ring can not be used together"));
+        }
+        if self.output_chan && self.delivery_guarantee == DeliveryGuarantee::ExactlyOnce {
+            return Err(String::from("output_chan requires AtLeastOnce delivery"));
+        }
+        if !(self.output_queue_full_threshold > 0.0 && self.output_queue_full_threshold <= 1.0) {
+            return Err(String::from("output_queue_full_threshold must be in (0, 1]"));
+        }
+        if let Some(high) = self.backpressure_high_watermark {
+            if !(high > 0.0 && high <= 1.0) {
+                return Err(String::from("backpressure_high_watermark must be in (0, 1]"));
+            }
+            if high > self.output_queue_full_threshold {
+                // out_queue would never grow enough to trigger it
+                return Err(String::from("backpressure_high_watermark must not be above output_queue_full_threshold"));
+            }
+            if !(self.backpressure_low_watermark >= 0.0 && self.backpressure_low_watermark < high) {
+                return Err(String::from("backpressure_low_watermark must be in [0, backpressure_high_watermark)"));
+            }
+        }
+        Ok(())
+    }
+
+    fn new_recv_chan(&self) -> BytesChan {
+        match self.recv_chan_capacity {
+            Some(capacity) => bounded(capacity),
+            None => unbounded()
+        }
+    }
+
+    // reader to writer, carries acks and backpressure signals
+    fn new_send_chan(&self) -> BytesChan {
+        match self.ack_chan_capacity {
+            Some(capacity) => bounded(capacity),
+            None => unbounded()
+        }
+    }
+
+    // dispatcher leaves acks of delivered buffers to consumption (ExactlyOnce) or to commit (manual_commit)
+    fn acks_deferred(&self) -> bool {
+        self.delivery_guarantee == DeliveryGuarantee::ExactlyOnce || self.manual_commit
+    }
+
+    fn is_ordered(&self, channel_id: &str) -> bool {
+        !self.is_at_most_once(channel_id) && self.ordered.get(channel_id).copied().unwrap_or(true)
+    }
+
+    fn is_at_most_once(&self, channel_id: &str) -> bool {
+        self.at_most_once.get(channel_id).copied().unwrap_or(false)
+    }
+
+    // index of dispatcher thread handling this channel
+    fn dispatcher_shard(&self, channel_id: &str) -> usize {
+        (hash_key(channel_id.as_bytes()) % self.dispatcher_threads as u64) as usize
+    }
+
+    fn next_idle_backoff_micros(&self, cur_micros: u64) -> u64 {
+        if cur_micros == 0 {
+            MIN_IDLE_BACKOFF_MICROS.min(self.max_idle_backoff_micros)
+        } else {
+            cur_micros.saturating_mul(2).min(self.max_idle_backoff_micros)
+        }
+    }
+
+    // whether a channel that got nothing in its last misses polls is left out of this pass
+    fn skip_idle_channel(&self, misses: u32, pass: u64) -> bool {
+        self.idle_channel_misses > 0 && misses >= self.idle_channel_misses && !pass.is_multiple_of(self.idle_channel_poll_every as u64)
+    }
+
+    // out_queue size dispatcher stops at
+    fn output_queue_limit(&self) -> usize {
+        ((self.output_queue_full_threshold * self.output_queue_size as f64).ceil() as usize).clamp(1, self.output_queue_size)
+    }
+
+    // whether a held out-of-order buffer with given payload size can still be drained into out_queue, see prefetch.
+    // handed_off_len entries are already handed off (see out_queue_ring and output_chan), they come first and their bytes are not counted
+    fn can_drain(&self, out_queue: &VecDeque<OutQueueEntry>, handed_off_len: usize, out_queue_limit: usize, size: usize) -> bool {
+        let len = out_queue.len() + handed_off_len;
+        if len < out_queue_limit {
+            return true;
+        }
+        if len >= out_queue_limit + self.prefetch {
+            return false;
+        }
+        let prefetched_bytes: usize = out_queue.iter().skip(out_queue_limit.saturating_sub(handed_off_len)).map(|entry| entry.2.len()).sum();
+        prefetched_bytes + size <= self.prefetch_max_bytes
+    }
+
+    // (pause at, resume at) out_queue sizes, None if backpressure is disabled
+    fn backpressure_thresholds(&self) -> Option<(usize, usize)> {
+        self.backpressure_high_watermark.map(|high| {
+            let size = self.output_queue_size as f64;
+            let high_size = ((high * size).ceil() as usize).clamp(1, self.output_queue_size);
+            let low_size = (self.backpressure_low_watermark * size).floor() as usize;
+            (high_size, low_size)
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ReaderCheckpoint {
+    pub watermarks: HashMap<String, i64>,
+    // informational only - payloads are not checkpointed, so these are re-received after restore
+    pub out_of_order_buffer_ids: HashMap<String, Vec<i64>>
+}
+
+// Sends acks according to AckStrategy, shared by dispatchers and consuming reads
+struct AckSender<C: Clock> {
+    strategy: AckStrategy,
+    batch_size: usize,
+    batch_delay: Duration,
+    // Batched only, channel_id -> (held buffer ids, when the first of them was held)
+    pending: Mutex<HashMap<String, (Vec<u64>, Instant)>>,
+    // ExactlyOnce only, channel_id -> ids given up on by deliver_held_locked, acked once a buffer past them is consumed
+    // and checkpointed, see ack_skipped
+    skipped: Mutex<HashMap<String, Vec<u64>>>,
+    exactly_once: bool,
+    metrics_recorder: Arc<MetricsRecorder>,
+    clock: C
+}
+
+impl<C: Clock> AckSender<C> {
+    fn new(config: &DataReaderConfig, metrics_recorder: Arc<MetricsRecorder>, clock: C) -> Self {
+        AckSender{
+            strategy: config.ack_strategy,
+            batch_size: config.ack_batch_size,
+            batch_delay: Duration::from_millis(config.ack_batch_delay_ms),
+            pending: Mutex::new(HashMap::new()),
+            skipped: Mutex::new(HashMap::new()),
+            exactly_once: config.delivery_guarantee == DeliveryGuarantee::ExactlyOnce,
+            metrics_recorder,
+            clock
+        }
+    }
+
+    fn ack(&self, channel_id: &String, buffer_id: u64, sender: &Sender<Box<Bytes>>) -> NetworkResult<()> {
+        if self.strategy == AckStrategy::Immediate {
+            DataReader::<C>::send_ack(channel_id, buffer_id, sender.clone(), self.metrics_recorder.clone());
+            return Ok(());
+        }
+        let mut locked_pending = self.pending.lock_ranked(LockRank::PendingAcks).map_err(poisoned("pending_acks"))?;
+        let (buffer_ids, _) = locked_pending.entry(channel_id.clone()).or_insert_with(|| (Vec::with_capacity(self.batch_size), self.clock.now()));
+        buffer_ids.push(buffer_id);
+        if buffer_ids.len() >= self.batch_size {
+            let (buffer_ids, _) = locked_pending.remove(channel_id).unwrap();
+            DataReader::<C>::send_ack_batch(channel_id, buffer_ids, sender, &self.metrics_recorder);
+        }
+        Ok(())
+    }
+
+    // Missing ids given up on. In ExactlyOnce they are held until release_skipped: acked before the consumed watermark
+    // past them is persisted, a restarted reader would wait for ids the writer already released
+    fn ack_skipped(&self, channel_id: &String, skipped_ids: Vec<u64>, sender: &Sender<Box<Bytes>>) -> NetworkResult<()> {
+        if self.exactly_once {
+            self.skipped.lock_ranked(LockRank::PendingAcks).map_err(poisoned("skipped_acks"))?.entry(channel_id.clone()).or_default().extend(skipped_ids);
+            return Ok(());
+        }
+        for skipped_id in skipped_ids {
+            self.ack(channel_id, skipped_id, sender)?;
+        }
+        Ok(())
+    }
+
+    // acks skipped ids up to consumed_wm, call once it is persisted
+    fn release_skipped(&self, channel_id: &String, consumed_wm: u64, sender: &Sender<Box<Bytes>>) -> NetworkResult<()> {
+        let released: Vec<u64> = {
+            let mut locked_skipped = self.skipped.lock_ranked(LockRank::PendingAcks).map_err(poisoned("skipped_acks"))?;
+            let Some(skipped_ids) = locked_skipped.get_mut(channel_id) else {
+                return Ok(());
+            };
+            let (released, held) = skipped_ids.iter().partition(|skipped_id| **skipped_id <= consumed_wm);
+            *skipped_ids = held;
+            if skipped_ids.is_empty() {
+                locked_skipped.remove(channel_id);
+            }
+            released
+        };
+        for skipped_id in released {
+            self.ack(channel_id, skipped_id, sender)?;
+        }
+        Ok(())
+    }
+
+    // held acks are for buffer ids of writer's previous epoch, a restarted writer would release its own buffers on them
+    fn discard(&self, channel_id: &str) -> NetworkResult<()> {
+        self.pending.lock_ranked(LockRank::PendingAcks).map_err(poisoned("pending_acks"))?.remove(channel_id);
+        self.skipped.lock_ranked(LockRank::PendingAcks).map_err(poisoned("skipped_acks"))?.remove(channel_id);
+        Ok(())
+    }
+
+    // sends batches whose oldest ack waited batch_delay, all of them if force.
+    // Acks of channels without send chan (removed) are dropped
+    fn flush(&self, send_chans: &HashMap<String, BytesChan>, force: bool) -> NetworkResult<()> {
+        let mut locked_pending = self.pending.lock_ranked(LockRank::PendingAcks).map_err(poisoned("pending_acks"))?;
+        if locked_pending.is_empty() {
+            return Ok(());
+        }
+        let now = self.clock.now();
+        let due: Vec<String> = locked_pending.iter()
+            .filter(|(_, (_, since))| force || now.duration_since(*since) >= self.batch_delay)
+            .map(|(channel_id, _)| channel_id.clone())
+            .collect();
+        for channel_id in due {
+            let (buffer_ids, _) = locked_pending.remove(&channel_id).unwrap();
+            if let Some(send_chan) = send_chans.get(&channel_id) {
+                DataReader::<C>::send_ack_batch(&channel_id, buffer_ids, &send_chan.0, &self.metrics_recorder);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Locks below are taken in LockRank order, see lock_order
+pub struct DataReader<C: Clock = SystemClock> {
+    name: String,
+    job_name: String,
+    channels: Arc<RwLock<Vec<Channel>>>,
+
+    send_chans: Arc<RwLock<HashMap<String, (Sender<Box<Bytes>>, Receiver<Box<Bytes>>)>>>,
+    recv_chans: Arc<RwLock<HashMap<String, (Sender<Box<Bytes>>, Receiver<Box<Bytes>>)>>>,
+    out_queue: Arc<OutQueue>,
+
+    // TODO only one thread actually modifies this, can we simplify?
+    watermarks: Arc<Watermarks>,
+    // last buffer returned by read_bytes per channel, persisted in ExactlyOnce mode
+    consumed_watermarks: Arc<Watermarks>,
+    out_of_order_buffers: Arc<OutOfOrderBuffers>,
+    dedup_windows: Arc<DedupWindows>,
+    last_recv_ts: Arc<LastRecvTimestamps>,
+    // see last_activity
+    last_activity: Arc<LastActivity>,
+    created_at: Instant,
+    // Event time, unlike sequence watermarks above (buffer ids used for reliable in-order delivery): writers stamp
+    // buffers with their event-time watermark, promising that nothing written later is older. Updated only when an
+    // entry leaves out_queue (read by consumer, handed off to output_chan or evicted by FullQueuePolicy), so it never
+    // runs ahead of what consumer has actually seen. See current_event_time_watermark
+    event_time_watermarks: Arc<EventTimeWatermarks>,
+    // channels whose writers were asked to pause, kept here so restarted dispatcher still resumes them
+    backpressured: Arc<Mutex<HashSet<String>>>,
+    // channels whose EOF was reached by consumer, see completed_channels
+    completed: Arc<Mutex<HashSet<String>>>,
+    // see out_queue_ring in DataReaderConfig
+    ring: Option<Arc<Ring>>,
+    // see output_chan in DataReaderConfig
+    output_chan: Option<OutputChan>,
+    // storage read_into and recycle gave back, dispatcher copies payloads into it instead of allocating
+    payload_pool: Arc<ArrayQueue<Bytes>>,
+    // manual_commit only: returned by read_message but not committed yet, channel_id -> buffer_id -> payloads
+    // (several for a batched buffer). Updated under out_queue lock, so dispatcher sees a buffer either queued or here
+    uncommitted: Arc<Mutex<Uncommitted>>,
+    // per dispatcher shard, outside of dispatcher so force_advance delivers into the same partial messages
+    fragments: Arc<Vec<Mutex<FragmentAssembler>>>,
+    inspector: Arc<RwLock<Option<Arc<Inspector>>>>,
+    overflow_handler: Arc<RwLock<Option<OverflowHandler>>>,
+    out_queue_space: Arc<OutQueueSpace>,
+
+    metrics_recorder: Arc<MetricsRecorder>,
+    acks: Arc<AckSender<C>>,
+
+    running: Arc<AtomicBool>,
+    // set by first start and never cleared, so a second start (also after close) does not spawn dispatchers again
+    started: AtomicBool,
+    // per dispatcher shard
+    dispatchers_alive: Arc<Vec<Arc<AtomicBool>>>,
+    dispatcher_error: Arc<Mutex<Option<String>>>,
+    // channel_id -> panic message, see HealthStatus::channels_failed
+    failed_channels: Arc<Mutex<HashMap<String, String>>>,
+    dispatcher_thread_handles: Arc<ArrayQueue<(usize, JoinHandle<()>)>>, // (shard, handle), array queue so we do not mutate DataReader and kepp ownership
+    // target of checkpoint_path, or one given to with_checkpoint_store. None - no checkpointing
+    checkpoint_store: Option<Arc<dyn CheckpointStore>>,
+    checkpoint_thread_handle: Mutex<Option<JoinHandle<()>>>,
+
+    config: Arc<DataReaderConfig>,
+
+    // expiry, delivery latency and liveness timestamps
+    clock: C
+}
+
+impl DataReader {
+    // Err(InvalidConfig) or Err(InvalidChannel) if config or channels do not validate
+    pub fn new(name: String, job_name: String, data_reader_config: DataReaderConfig, channels: Vec<Channel>) -> NetworkResult<DataReader> {
+        Self::with_clock(name, job_name, data_reader_config, channels, SystemClock)
+    }
+}
+
+impl<C: Clock> DataReader<C> {
+
+    pub fn with_clock(name: String, job_name: String, data_reader_config: DataReaderConfig, channels: Vec<Channel>, clock: C) -> NetworkResult<Self> {
+        let checkpoint_store = data_reader_config.checkpoint_path.clone().map(|path| Arc::new(FileCheckpointStore::new(path)) as Arc<dyn CheckpointStore>);
+        Self::with_store(name, job_name, data_reader_config, channels, clock, checkpoint_store)
+    }
+
+    // Checkpoints go to (and are restored from) given store instead of the file at checkpoint_path, e.g. an object store.
+    // checkpoint_path is not used and may be left unset
+    pub fn with_checkpoint_store(name: String, job_name: String, data_reader_config: DataReaderConfig, channels: Vec<Channel>, clock: C, checkpoint_store: Arc<dyn CheckpointStore>) -> NetworkResult<Self> {
+        Self::with_store(name, job_name, data_reader_config, channels, clock, Some(checkpoint_store))
+    }
+
+    fn with_store(name: String, job_name: String, data_reader_config: DataReaderConfig, channels: Vec<Channel>, clock: C, checkpoint_store: Option<Arc<dyn CheckpointStore>>) -> NetworkResult<Self> {
+        // config may come deserialized or built with struct update, without any validation
+        data_reader_config.validate_with_store(checkpoint_store.is_some()).map_err(NetworkError::InvalidConfig)?;
+        validate_channels(&channels, false)?;
+
+        let n_channels = channels.len();
+        let mut send_chans = HashMap::with_capacity(n_channels);
+        let mut recv_chans = HashMap::with_capacity(n_channels);
+        let mut watermarks = HashMap::with_capacity(n_channels);
+        let mut consumed_watermarks = HashMap::with_capacity(n_channels);
+        let mut out_of_order_buffers = HashMap::with_capacity(n_channels);
+        let mut dedup_windows = HashMap::with_capacity(n_channels);
+        let mut last_recv_ts = HashMap::with_capacity(n_channels);
+        let mut last_activity = HashMap::with_capacity(n_channels);
+        let mut event_time_watermarks = HashMap::with_capacity(n_channels);
+
+        for ch in &channels {
+            send_chans.insert(ch.get_channel_id().clone(), data_reader_config.new_send_chan());
+            recv_chans.insert(ch.get_channel_id().clone(), data_reader_config.new_recv_chan());
+            watermarks.insert(ch.get_channel_id().clone(), Arc::new(AtomicI64::new(-1)));
+            consumed_watermarks.insert(ch.get_channel_id().clone(), Arc::new(AtomicI64::new(-1)));
+            out_of_order_buffers.insert(ch.get_channel_id().clone(), Arc::new(RwLock::new(OutOfOrder::new(data_reader_config.out_of_order_slots))));   
+            dedup_windows.insert(ch.get_channel_id().clone(), Arc::new(Mutex::new(DedupWindow::new(data_reader_config.dedup_window))));
+            last_recv_ts.insert(ch.get_channel_id().clone(), Arc::new(AtomicU64::new(0)));
+            last_activity.insert(ch.get_channel_id().clone(), Arc::new(AtomicU64::new(0)));
+            event_time_watermarks.insert(ch.get_channel_id().clone(), Arc::new(AtomicU64::new(0)));
+        }
+
+        let metrics_recorder = Arc::new(if data_reader_config.metrics_enabled {
+            MetricsRecorder::new(name.clone(), job_name.clone(), data_reader_config.metrics_flush_interval_ms)
+        } else {
+            MetricsRecorder::new_disabled(name.clone(), job_name.clone())
+        });
+        let acks = Arc::new(AckSender::new(&data_reader_config, metrics_recorder.clone(), clock.clone()));
+
+        let data_reader = DataReader{
+            name: name.clone(),
+            job_name: job_name.clone(),
+            channels: Arc::new(RwLock::new(channels)),
+            send_chans: Arc::new(RwLock::new(send_chans)),
+            recv_chans: Arc::new(RwLock::new(recv_chans)),
+            out_queue: Arc::new(Mutex::new(VecDeque::with_capacity(data_reader_config.output_queue_size))),
+            watermarks: Arc::new(RwLock::new(watermarks)),
+            consumed_watermarks: Arc::new(RwLock::new(consumed_watermarks)),
+            out_of_order_buffers: Arc::new(RwLock::new(out_of_order_buffers)),
+            dedup_windows: Arc::new(RwLock::new(dedup_windows)),
+            last_recv_ts: Arc::new(RwLock::new(last_recv_ts)),
+            last_activity: Arc::new(RwLock::new(last_activity)),
+            created_at: clock.now(),
+            event_time_watermarks: Arc::new(RwLock::new(event_time_watermarks)),
+            backpressured: Arc::new(Mutex::new(HashSet::new())),
+            completed: Arc::new(Mutex::new(HashSet::new())),
+            uncommitted: Arc::new(Mutex::new(HashMap::new())),
+            fragments: Arc::new((0..data_reader_config.dispatcher_threads).map(|_| Mutex::new(FragmentAssembler::default())).collect()),
+            inspector: Arc::new(RwLock::new(None)),
+            overflow_handler: Arc::new(RwLock::new(None)),
+            out_queue_space: Arc::new(OutQueueSpace::default()),
+            // prefetched entries fit too
+            ring: data_reader_config.out_queue_ring.then(|| Arc::new(ArrayQueue::new(data_reader_config.output_queue_size + data_reader_config.prefetch))),
+            output_chan: data_reader_config.output_chan.then(|| bounded(data_reader_config.output_queue_size + data_reader_config.prefetch)),
+            payload_pool: Arc::new(ArrayQueue::new(MAX_POOLED_PAYLOADS)),
+            metrics_recorder,
+            acks,
+            running: Arc::new(AtomicBool::new(false)),
+            started: AtomicBool::new(false),
+            dispatchers_alive: Arc::new((0..data_reader_config.dispatcher_threads).map(|_| Arc::new(AtomicBool::new(false))).collect()),
+            dispatcher_error: Arc::new(Mutex::new(None)),
+            failed_channels: Arc::new(Mutex::new(HashMap::new())),
+            dispatcher_thread_handles: Arc::new(ArrayQueue::new(data_reader_config.dispatcher_threads)),
+            checkpoint_store,
+            checkpoint_thread_handle: Mutex::new(None),
+            config: Arc::new(data_reader_config),
+            clock
+        };
+
+        data_reader.metrics_recorder.add_sampler(data_reader.queue_depth_sampler());
+
+        // an unreadable or corrupt checkpoint fails construction rather than starting over, which would deliver
+        // everything since the last checkpoint again and skip nothing under ExactlyOnce
+        if let Some(store) = &data_reader.checkpoint_store {
+            let checkpoint = store.load().map_err(|err| NetworkError::Io(format!("checkpoint {}: {err}", store.describe())))?;
+            if let Some(b) = checkpoint {
+                data_reader.restore(&b)?;
+            }
+        }
+        Ok(data_reader)
+    }
+
+    // sampled by metrics flush thread, only reads queue lengths so dispatchers and consumer pay nothing for it
+    fn queue_depth_sampler(&self) -> Sampler {
+        let this_name = self.name.clone();
+        let this_out_queue = self.out_queue.clone();
+        let this_ring = self.ring.clone();
+        let this_output_chan = self.output_chan.clone();
+        let this_recv_chans = self.recv_chans.clone();
+        Box::new(move || {
+            let mut samples = Vec::new();
+            if let Ok(locked_out_queue) = this_out_queue.lock_ranked(LockRank::OutQueue) {
+                samples.push((OUT_QUEUE_DEPTH, this_name.clone(), (locked_out_queue.len() + handed_off_len(&this_ring, &this_output_chan)) as u64));
+            }
+            if let Ok(locked_recv_chans) = this_recv_chans.read_ranked(LockRank::RecvChans) {
+                for (channel_id, recv_chan) in locked_recv_chans.iter() {
+                    samples.push((IN_QUEUE_DEPTH, channel_id.clone(), recv_chan.1.len() as u64));
+                }
+            }
+            samples
+        })
+    }
+
+    // Writes watermarks atomically (write to temp file, then rename).
+    // In AtLeastOnce mode buffers already put in out_queue count as delivered, even if not yet read by consumer,
+    // in ExactlyOnce mode only buffers returned by read_bytes do
+    pub fn checkpoint(&self, path: &str) -> NetworkResult<()> {
+        self.checkpoint_to(&FileCheckpointStore::new(path.to_string()))
+    }
+
+    fn checkpoint_to(&self, store: &dyn CheckpointStore) -> NetworkResult<()> {
+        let checkpoint = if self.config.delivery_guarantee == DeliveryGuarantee::ExactlyOnce {
+            // out of order buffers are not acked and will be re-sent
+            let locked_consumed_watermarks = self.consumed_watermarks.read_ranked(LockRank::ConsumedWatermarks).map_err(poisoned("consumed_watermarks"))?;
+            Self::build_checkpoint(&locked_consumed_watermarks, &HashMap::new())?
+        } else {
+            Self::snapshot_checkpoint(&self.watermarks, &self.out_of_order_buffers)?
+        };
+        Ok(Self::persist_checkpoint(&checkpoint, store)?)
+    }
+
+    // Restores watermarks for known channels. Writer re-sends buffers it did not get acks for,
+    // the ones below restored watermark are treated as duplicates and re-acked, so writer skips them
+    pub fn restore_from(&self, path: &str) -> NetworkResult<()> {
+        self.restore(&fs::read(path)?)
+    }
+
+    fn restore(&self, b: &[u8]) -> NetworkResult<()> {
+        let checkpoint: ReaderCheckpoint = rmp_serde::from_slice(b).map_err(|err| NetworkError::Decode(format!("checkpoint: {err}")))?;
+        let locked_watermarks = self.watermarks.read_ranked(LockRank::Watermarks).map_err(poisoned("watermarks"))?;
+        let locked_consumed_watermarks = self.consumed_watermarks.read_ranked(LockRank::ConsumedWatermarks).map_err(poisoned("consumed_watermarks"))?;
+        let locked_dedup_windows = self.dedup_windows.read_ranked(LockRank::DedupWindows).map_err(poisoned("dedup_windows"))?;
+        for (channel_id, wm) in checkpoint.watermarks.iter() {
+            if let Some(watermark) = locked_watermarks.get(channel_id) {
+                watermark.store(*wm, Ordering::Relaxed);
+            }
+            if let Some(consumed_watermark) = locked_consumed_watermarks.get(channel_id) {
+                consumed_watermark.store(*wm, Ordering::Relaxed);
+            }
+            if let Some(dedup_window) = locked_dedup_windows.get(channel_id) {
+                dedup_window.lock_ranked(LockRank::DedupWindow).map_err(poisoned("dedup_window"))?.reset_to(*wm);
+            }
+        }
+        Ok(())
+    }
+
+    // AtLeastOnce checkpoint of current (delivered) watermarks
+    fn snapshot_checkpoint(watermarks: &Watermarks, out_of_order_buffers: &OutOfOrderBuffers) -> NetworkResult<ReaderCheckpoint> {
+        let locked_watermarks = watermarks.read_ranked(LockRank::Watermarks).map_err(poisoned("watermarks"))?;
+        let locked_out_of_order_buffers = out_of_order_buffers.read_ranked(LockRank::OutOfOrderBuffers).map_err(poisoned("out_of_order_buffers"))?;
+        Self::build_checkpoint(&locked_watermarks, &locked_out_of_order_buffers)
+    }
+
+    fn build_checkpoint(
+        watermarks: &HashMap<String, Arc<AtomicI64>>,
+        out_of_order_buffers: &ChannelsOutOfOrderBuffers
+    ) -> NetworkResult<ReaderCheckpoint> {
+        let mut checkpoint = ReaderCheckpoint{watermarks: HashMap::new(), out_of_order_buffer_ids: HashMap::new()};
+        for (channel_id, wm) in watermarks.iter() {
+            checkpoint.watermarks.insert(channel_id.clone(), wm.load(Ordering::Relaxed));
+        }
+        for (channel_id, out_of_order) in out_of_order_buffers.iter() {
+            let ids = out_of_order.read_ranked(LockRank::OutOfOrder).map_err(poisoned("out_of_order"))?.keys().collect();
+            checkpoint.out_of_order_buffer_ids.insert(channel_id.clone(), ids);
+        }
+        Ok(checkpoint)
+    }
+
+    fn persist_checkpoint(checkpoint: &ReaderCheckpoint, store: &dyn CheckpointStore) -> io::Result<()> {
+        let b = rmp_serde::to_vec(checkpoint).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        store.save(&b)
+    }
+
+    // periodic AtLeastOnce checkpoints, see checkpoint_interval_ms. Exits on close, which then does the final one itself
+    fn spawn_checkpoint_thread(&self) {
+        let (Some(store), Some(interval_ms), DeliveryGuarantee::AtLeastOnce) = (&self.checkpoint_store, self.config.checkpoint_interval_ms, self.config.delivery_guarantee) else {
+            return
+        };
+        let this_running = self.running.clone();
+        let this_watermarks = self.watermarks.clone();
+        let this_out_of_order_buffers = self.out_of_order_buffers.clone();
+        let this_store = store.clone();
+        let this_name = self.name.clone();
+        let f = move || {
+            let mut last_saved: Option<ReaderCheckpoint> = None;
+            while this_running.load(Ordering::Relaxed) {
+                let mut slept_ms = 0;
+                while slept_ms < interval_ms && this_running.load(Ordering::Relaxed) {
+                    let step_ms = CHECKPOINT_SLEEP_STEP_MS.min(interval_ms - slept_ms);
+                    thread::sleep(Duration::from_millis(step_ms));
+                    slept_ms += step_ms;
+                }
+                if !this_running.load(Ordering::Relaxed) {
+                    break;
+                }
+                let res = Self::snapshot_checkpoint(&this_watermarks, &this_out_of_order_buffers).and_then(|checkpoint| {
+                    if last_saved.as_ref() != Some(&checkpoint) {
+                        Self::persist_checkpoint(&checkpoint, this_store.as_ref())?;
+                        last_saved = Some(checkpoint);
+                    }
+                    Ok(())
+                });
+                if let Err(err) = res {
+                    println!("[Reader {this_name}] Failed to checkpoint to {}: {err}", this_store.describe());
+                }
+            }
+        };
+        let handle = thread::Builder::new().name(format!("{}_checkpoint_thread", self.name)).spawn(f).unwrap();
+        *self.checkpoint_thread_handle.lock_ranked(LockRank::CheckpointThread).unwrap_or_else(PoisonError::into_inner) = Some(handle);
+    }
+
+    pub fn read_bytes(&self) -> NetworkResult<Option<Box<Bytes>>> {
+        self.check_no_manual_commit()?;
+        self.next_bytes()
+    }
+
+    // payload-only reads give consumer no id to commit
+    fn check_no_manual_commit(&self) -> NetworkResult<()> {
+        if self.config.manual_commit {
+            return Err(NetworkError::Unsupported(String::from("reading without buffer ids with manual_commit, use read_message")));
+        }
+        Ok(())
+    }
+
+    fn next_bytes(&self) -> NetworkResult<Option<Box<Bytes>>> {
+        if let Some(chan) = &self.output_chan {
+            return Ok(recv_output(chan));
+        }
+        Ok(self.read_message()?.map(|(_, _, b)| b))
+    }
+
+    // Receiving end of output_chan (see DataReaderConfig), for consumers selecting over it together with their own
+    // channels instead of polling read_bytes. Clones share the channel, each item goes to one of them
+    pub fn output_receiver(&self) -> NetworkResult<Receiver<Output>> {
+        match &self.output_chan {
+            Some(chan) => Ok(chan.1.clone()),
+            None => Err(NetworkError::Unsupported(String::from("output receiver without output_chan")))
+        }
+    }
+
+    // (channel_id, buffer_id, payload), for consumers doing their own dedup or ordering checks.
+    // Entries unpacked from one batched buffer share buffer_id
+    pub fn read_message(&self) -> NetworkResult<Option<(String, u64, Box<Bytes>)>> {
+        self.read_message_filtered(None)
+    }
+
+    // Next buffer of given channel only, in the channel's delivery order. Picks it out of the shared out_queue, so entries
+    // of other channels left behind still count towards its limit - they have to be drained too or dispatching stalls.
+    // Not supported with out_queue_ring
+    pub fn read_bytes_from(&self, channel_id: &str) -> NetworkResult<Option<Box<Bytes>>> {
+        if !self.channels.read_ranked(LockRank::Channels).map_err(poisoned("channels"))?.iter().any(|ch| ch.get_channel_id() == channel_id) {
+            return Err(NetworkError::UnknownChannel(channel_id.to_string()));
+        }
+        self.check_no_manual_commit()?;
+        Ok(self.read_message_filtered(Some(channel_id))?.map(|(_, _, b)| b))
+    }
+
+    // Up to max_size buffers already delivered, does not wait for more. Empty results are counted as
+    // num_empty_read_batches in job totals, see prefetch in DataReaderConfig for keeping them rare
+    pub fn read_batch(&self, max_size: usize) -> NetworkResult<Vec<Box<Bytes>>> {
+        let mut res = Vec::new();
+        while res.len() < max_size {
+            let Some(b) = self.read_bytes()? else {
+                break
+            };
+            res.push(b);
+        }
+        if res.is_empty() {
+            self.metrics_recorder.inc(NUM_EMPTY_READ_BATCHES, &self.name, 1);
+        }
+        Ok(res)
+    }
+
+    // Same as read_bytes, but puts the payload into dst (replacing what it held) and returns its length. dst takes over
+    // the payload's storage and its own goes back to the pool dispatcher copies later payloads into, so a consumer
+    // reusing one dst makes neither side allocate per message once payloads fit the storage going round
+    pub fn read_into(&self, dst: &mut Vec<u8>) -> NetworkResult<Option<usize>> {
+        let Some(mut b) = self.read_bytes()? else {
+            return Ok(None)
+        };
+        std::mem::swap(dst, &mut *b);
+        self.recycle(*b);
+        Ok(Some(dst.len()))
+    }
+
+    // Gives a payload's storage back for dispatcher to copy a later payload into, for consumers done with it
+    pub fn recycle(&self, b: Bytes) {
+        // pool full - dropped
+        if b.capacity() > 0 {
+            let _ = self.payload_pool.push(b);
+        }
+    }
+
+    // Same as read_bytes, but returns Ok(None) right away instead of waiting whenever a lock it needs is held, e.g. by
+    // dispatcher moving buffers into out_queue, so a real-time consumer never blocks behind it. The price is spurious
+    // None under contention while buffers are available - callers should just poll again. ExactlyOnce still writes
+    // the checkpoint to disk before returning
+    pub fn try_read_bytes(&self) -> NetworkResult<Option<Box<Bytes>>> {
+        self.check_no_manual_commit()?;
+        if self.config.delivery_guarantee == DeliveryGuarantee::ExactlyOnce {
+            let Some(locked_send_chans) = try_locked(self.send_chans.try_read_ranked(LockRank::SendChans), "send_chans")? else {
+                return Ok(None);
+            };
+            let Some(locked_consumed_watermarks) = try_locked(self.consumed_watermarks.try_read_ranked(LockRank::ConsumedWatermarks), "consumed_watermarks")? else {
+                return Ok(None);
+            };
+            let Some(mut locked_out_queue) = try_locked(self.out_queue.try_lock_ranked(LockRank::OutQueue), "out_queue")? else {
+                return Ok(None);
+            };
+            return Ok(self.consume_exactly_once(&locked_send_chans, &locked_consumed_watermarks, &mut locked_out_queue, None)?.map(|(_, _, b)| b));
+        }
+        if let Some(chan) = &self.output_chan {
+            return Ok(recv_output(chan));
+        }
+        let entry = match &self.ring {
+            Some(ring) => self.pop_ring_entry(ring, None)?,
+            None => {
+                let Some(mut locked_out_queue) = try_locked(self.out_queue.try_lock_ranked(LockRank::OutQueue), "out_queue")? else {
+                    return Ok(None);
+                };
+                self.pop_data_entry(&mut locked_out_queue, None)?
+            }
+        };
+        let Some((channel_id, _, b, event_time_wm, enqueued_at, _)) = entry else {
+            return Ok(None);
+        };
+        self.observe_dwell(&channel_id, enqueued_at);
+        self.consume_event_time_watermark(&channel_id, event_time_wm)?;
+        Ok(Some(b))
+    }
+
+    fn read_message_filtered(&self, channel_id: Option<&str>) -> NetworkResult<Option<(String, u64, Box<Bytes>)>> {
+        // TODO set limit for backpressure
+        if self.config.delivery_guarantee == DeliveryGuarantee::ExactlyOnce {
+            return self.read_message_exactly_once(channel_id);
+        }
+        if self.output_chan.is_some() {
+            return Err(NetworkError::Unsupported(String::from("reading messages with output_chan")));
+        }
+        let entry = match &self.ring {
+            Some(ring) => self.pop_ring_entry(ring, channel_id)?,
+            None => {
+                let mut locked_out_queue = self.out_queue.lock_ranked(LockRank::OutQueue).map_err(poisoned("out_queue"))?;
+                let entry = self.pop_data_entry(&mut locked_out_queue, channel_id)?;
+                if let (true, Some((channel_id, buffer_id, b, ..))) = (self.config.manual_commit, &entry) {
+                    self.uncommitted.lock_ranked(LockRank::Uncommitted).map_err(poisoned("uncommitted"))?.entry(channel_id.clone()).or_default().entry(*buffer_id).or_default().push(b.clone());
+                }
+                entry
+            }
+        };
+        let Some((channel_id, buffer_id, b, event_time_wm, enqueued_at, _)) = entry else {
+            return Ok(None);
+        };
+        self.observe_dwell(&channel_id, enqueued_at);
+        self.consume_event_time_watermark(&channel_id, event_time_wm)?;
+        Ok(Some((channel_id, buffer_id, b)))
+    }
+
+    // time entry waited in out_queue for the consumer, high values with low delivery latency point at a slow consumer
+    fn observe_dwell(&self, channel_id: &str, enqueued_at: Instant) {
+        let dwell = self.clock.now().saturating_duration_since(enqueued_at);
+        self.metrics_recorder.observe(OUT_QUEUE_DWELL_MICROS, channel_id, dwell.as_micros() as u64);
+    }
+
+    fn consume_event_time_watermark(&self, channel_id: &str, event_time_wm: Option<u64>) -> NetworkResult<()> {
+        let Some(event_time_wm) = event_time_wm else {
+            return Ok(());
+        };
+        // channel may be removed by now
+        if let Some(wm) = self.event_time_watermarks.read_ranked(LockRank::EventTimeWatermarks).map_err(poisoned("event_time_watermarks"))?.get(channel_id) {
+            wm.fetch_max(event_time_wm, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    // Minimum event-time watermark across channels: everything with event time up to it has been returned by
+    // read_message, so windows ending at or before it can fire. None until every channel has reported one, so
+    // an idle channel holds it back. Buffers of unordered channels may arrive after a later buffer's watermark
+    // was consumed, and watermark of an expired buffer is skipped
+    pub fn current_event_time_watermark(&self) -> NetworkResult<Option<u64>> {
+        let locked_event_time_watermarks = self.event_time_watermarks.read_ranked(LockRank::EventTimeWatermarks).map_err(poisoned("event_time_watermarks"))?;
+        let min_wm = locked_event_time_watermarks.values().map(|wm| wm.load(Ordering::Relaxed)).min();
+        Ok(min_wm.filter(|wm| *wm != 0))
+    }
+
+    // first entry, or first entry of given channel
+    fn pop_entry(out_queue: &mut VecDeque<OutQueueEntry>, channel_id: Option<&str>) -> Option<OutQueueEntry> {
+        match channel_id {
+            None => out_queue.pop_front(),
+            Some(channel_id) => {
+                let index = out_queue.iter().position(|entry| entry.0 == channel_id)?;
+                out_queue.remove(index)
+            }
+        }
+    }
+
+    // pop_entry passing over markers, channels of EOF markers are completed
+    fn pop_data_entry(&self, out_queue: &mut VecDeque<OutQueueEntry>, channel_id: Option<&str>) -> NetworkResult<Option<OutQueueEntry>> {
+        while let Some(entry) = Self::pop_entry(out_queue, channel_id) {
+            self.notify_popped();
+            if entry.5 == EntryKind::Data {
+                return Ok(Some(entry));
+            }
+            self.consume_marker(&entry)?;
+        }
+        Ok(None)
+    }
+
+    // pop_data_entry for out_queue_ring, lock-free
+    fn pop_ring_entry(&self, ring: &Ring, channel_id: Option<&str>) -> NetworkResult<Option<OutQueueEntry>> {
+        if channel_id.is_some() {
+            return Err(NetworkError::Unsupported(String::from("reading a single channel with out_queue_ring")));
+        }
+        while let Some(entry) = ring.pop() {
+            if entry.5 == EntryKind::Data {
+                return Ok(Some(entry));
+            }
+            self.consume_marker(&entry)?;
+        }
+        Ok(None)
+    }
+
+    // outside ExactlyOnce, where markers need no ack
+    fn consume_marker(&self, entry: &OutQueueEntry) -> NetworkResult<()> {
+        self.consume_event_time_watermark(&entry.0, entry.3)?;
+        if entry.5 == EntryKind::Eof {
+            self.complete_channel(&entry.0)?;
+        }
+        Ok(())
+    }
+
+    // under out_queue lock, see OutQueueSpace
+    fn notify_popped(&self) {
+        if self.config.full_queue_policy == FullQueuePolicy::Block {
+            self.out_queue_space.notify();
+        }
+    }
+
+    fn complete_channel(&self, channel_id: &str) -> NetworkResult<()> {
+        self.completed.lock_ranked(LockRank::Completed).map_err(poisoned("completed"))?.insert(channel_id.to_string());
+        Ok(())
+    }
+
+    // Channels whose writers sent EOF (see DataWriter::write_eof) and everything before it was read, so downstream
+    // can finalize their state. On unordered channels EOF may overtake buffers still in flight.
+    // Not checkpointed - a restarted reader does not know about channels completed before restart
+    pub fn completed_channels(&self) -> NetworkResult<Vec<String>> {
+        let mut res: Vec<String> = self.completed.lock_ranked(LockRank::Completed).map_err(poisoned("completed"))?.iter().cloned().collect();
+        res.sort();
+        Ok(res)
+    }
+
+    fn read_message_exactly_once(&self, channel_id: Option<&str>) -> NetworkResult<Option<(String, u64, Box<Bytes>)>> {
+        // same lock order as dispatcher
+        let locked_send_chans = self.send_chans.read_ranked(LockRank::SendChans).map_err(poisoned("send_chans"))?;
+        let locked_consumed_watermarks = self.consumed_watermarks.read_ranked(LockRank::ConsumedWatermarks).map_err(poisoned("consumed_watermarks"))?;
+        // out_queue stays locked until consumed watermark is persisted, so checkpoints follow consumption order
+        let mut locked_out_queue = self.out_queue.lock_ranked(LockRank::OutQueue).map_err(poisoned("out_queue"))?;
+        self.consume_exactly_once(&locked_send_chans, &locked_consumed_watermarks, &mut locked_out_queue, channel_id)
+    }
+
+    fn consume_exactly_once(
+        &self,
+        locked_send_chans: &HashMap<String, BytesChan>,
+        locked_consumed_watermarks: &HashMap<String, Arc<AtomicI64>>,
+        locked_out_queue: &mut VecDeque<OutQueueEntry>,
+        channel_id: Option<&str>
+    ) -> NetworkResult<Option<(String, u64, Box<Bytes>)>> {
+        // markers are consumed like a regular entry, then the next one is returned
+        loop {
+            let Some((channel_id, buffer_id, b, event_time_wm, enqueued_at, kind)) = Self::pop_entry(locked_out_queue, channel_id) else {
+                return Ok(None);
+            };
+            self.notify_popped();
+            if kind == EntryKind::Data {
+                self.observe_dwell(&channel_id, enqueued_at);
+            }
+            self.consume_event_time_watermark(&channel_id, event_time_wm)?;
+            // entries unpacked from one batched buffer are consumed (and re-delivered after restart) as a whole
+            if locked_out_queue.iter().find(|entry| entry.0 == channel_id).is_some_and(|entry| entry.1 == buffer_id) {
+                return Ok(Some((channel_id, buffer_id, b)));
+            }
+            if let Some(consumed_watermark) = locked_consumed_watermarks.get(&channel_id) {
+                consumed_watermark.store(buffer_id as i64, Ordering::Relaxed);
+            }
+            let checkpoint = Self::build_checkpoint(locked_consumed_watermarks, &HashMap::new())?;
+            Self::persist_checkpoint(&checkpoint, self.checkpoint_store.as_deref().unwrap())?;
+
+            // ack only once persisted, so writer keeps un-consumed buffers and re-sends them after restart
+            if let Some(send_chan) = locked_send_chans.get(&channel_id) {
+                self.acks.ack(&channel_id, buffer_id, &send_chan.0)?;
+                self.acks.release_skipped(&channel_id, buffer_id, &send_chan.0)?;
+            }
+            match kind {
+                EntryKind::Data => return Ok(Some((channel_id, buffer_id, b))),
+                EntryKind::Eof => self.complete_channel(&channel_id)?,
+                EntryKind::Expired => {}
+            }
+        }
+    }
+
+    // Safe to call while dispatcher is running. Write locks are taken in the same order dispatcher takes read locks.
+    // Note that IOLoop creates sockets only on connect, so channels added after connect have no transport until reconnect.
+    pub fn add_channel(&self, channel: Channel) -> NetworkResult<()> {
+        channel.validate()?;
+        let channel_id = channel.get_channel_id().clone();
+        let mut locked_channels = self.channels.write_ranked(LockRank::Channels).map_err(poisoned("channels"))?;
+        if locked_channels.iter().any(|ch| *ch.get_channel_id() == channel_id) {
+            return Err(NetworkError::ChannelExists(channel_id));
+        }
+        let mut locked_recv_chans = self.recv_chans.write_ranked(LockRank::RecvChans).map_err(poisoned("recv_chans"))?;
+        let mut locked_send_chans = self.send_chans.write_ranked(LockRank::SendChans).map_err(poisoned("send_chans"))?;
+        let mut locked_watermarks = self.watermarks.write_ranked(LockRank::Watermarks).map_err(poisoned("watermarks"))?;
+        let mut locked_consumed_watermarks = self.consumed_watermarks.write_ranked(LockRank::ConsumedWatermarks).map_err(poisoned("consumed_watermarks"))?;
+        let mut locked_out_of_order_buffers = self.out_of_order_buffers.write_ranked(LockRank::OutOfOrderBuffers).map_err(poisoned("out_of_order_buffers"))?;
+        let mut locked_dedup_windows = self.dedup_windows.write_ranked(LockRank::DedupWindows).map_err(poisoned("dedup_windows"))?;
+        let mut locked_last_recv_ts = self.last_recv_ts.write_ranked(LockRank::LastRecvTs).map_err(poisoned("last_recv_ts"))?;
+        let mut locked_last_activity = self.last_activity.write_ranked(LockRank::LastActivity).map_err(poisoned("last_activity"))?;
+        let mut locked_event_time_watermarks = self.event_time_watermarks.write_ranked(LockRank::EventTimeWatermarks).map_err(poisoned("event_time_watermarks"))?;
+        locked_recv_chans.insert(channel_id.clone(), self.config.new_recv_chan());
+        locked_send_chans.insert(channel_id.clone(), self.config.new_send_chan());
+        locked_watermarks.insert(channel_id.clone(), Arc::new(AtomicI64::new(-1)));
+        locked_consumed_watermarks.insert(channel_id.clone(), Arc::new(AtomicI64::new(-1)));
+        locked_out_of_order_buffers.insert(channel_id.clone(), Arc::new(RwLock::new(OutOfOrder::new(self.config.out_of_order_slots))));
+        locked_dedup_windows.insert(channel_id.clone(), Arc::new(Mutex::new(DedupWindow::new(self.config.dedup_window))));
+        locked_last_recv_ts.insert(channel_id.clone(), Arc::new(AtomicU64::new(0)));
+        locked_last_activity.insert(channel_id.clone(), Arc::new(AtomicU64::new(0)));
+        locked_event_time_watermarks.insert(channel_id.clone(), Arc::new(AtomicU64::new(0)));
+        locked_channels.push(channel);
+        Ok(())
+    }
+
+    // Discards all buffers received but not yet delivered for this channel (un-acked, so writer would resend them).
+    // Buffers already put in out_queue are still returned by read_bytes.
+    pub fn remove_channel(&self, channel_id: &str) -> NetworkResult<()> {
+        let mut locked_channels = self.channels.write_ranked(LockRank::Channels).map_err(poisoned("channels"))?;
+        let mut locked_recv_chans = self.recv_chans.write_ranked(LockRank::RecvChans).map_err(poisoned("recv_chans"))?;
+        let mut locked_send_chans = self.send_chans.write_ranked(LockRank::SendChans).map_err(poisoned("send_chans"))?;
+        let mut locked_watermarks = self.watermarks.write_ranked(LockRank::Watermarks).map_err(poisoned("watermarks"))?;
+        let mut locked_consumed_watermarks = self.consumed_watermarks.write_ranked(LockRank::ConsumedWatermarks).map_err(poisoned("consumed_watermarks"))?;
+        let mut locked_out_of_order_buffers = self.out_of_order_buffers.write_ranked(LockRank::OutOfOrderBuffers).map_err(poisoned("out_of_order_buffers"))?;
+        let mut locked_dedup_windows = self.dedup_windows.write_ranked(LockRank::DedupWindows).map_err(poisoned("dedup_windows"))?;
+        let mut locked_last_recv_ts = self.last_recv_ts.write_ranked(LockRank::LastRecvTs).map_err(poisoned("last_recv_ts"))?;
+        let mut locked_last_activity = self.last_activity.write_ranked(LockRank::LastActivity).map_err(poisoned("last_activity"))?;
+        let mut locked_event_time_watermarks = self.event_time_watermarks.write_ranked(LockRank::EventTimeWatermarks).map_err(poisoned("event_time_watermarks"))?;
+        locked_recv_chans.remove(channel_id);
+        locked_send_chans.remove(channel_id);
+        locked_watermarks.remove(channel_id);
+        locked_consumed_watermarks.remove(channel_id);
+        locked_out_of_order_buffers.remove(channel_id);
+        locked_dedup_windows.remove(channel_id);
+        locked_last_recv_ts.remove(channel_id);
+        locked_last_activity.remove(channel_id);
+        locked_event_time_watermarks.remove(channel_id);
+        locked_channels.retain(|ch| ch.get_channel_id() != channel_id);
+        self.completed.lock_ranked(LockRank::Completed).map_err(poisoned("completed"))?.remove(channel_id);
+        self.failed_channels.lock_ranked(LockRank::FailedChannels).map_err(poisoned("failed_channels"))?.remove(channel_id);
+        self.metrics_recorder.set(OUT_OF_ORDER_BYTES, channel_id, 0);
+        Ok(())
+    }
+
+    // Rewinds channel so buffers after given watermark are delivered again, buffered out-of-order data is discarded.
+    // Writer has to re-send them (see DataWriter::replay), which only works if it still retains those buffers,
+    // otherwise the channel stalls waiting for watermark + 1.
+    pub fn seek(&self, channel_id: &str, watermark: i64) -> NetworkResult<()> {
+        let locked_watermarks = self.watermarks.read_ranked(LockRank::Watermarks).map_err(poisoned("watermarks"))?;
+        let locked_consumed_watermarks = self.consumed_watermarks.read_ranked(LockRank::ConsumedWatermarks).map_err(poisoned("consumed_watermarks"))?;
+        let locked_out_of_order_buffers = self.out_of_order_buffers.read_ranked(LockRank::OutOfOrderBuffers).map_err(poisoned("out_of_order_buffers"))?;
+        let locked_dedup_windows = self.dedup_windows.read_ranked(LockRank::DedupWindows).map_err(poisoned("dedup_windows"))?;
+        let Some(out_of_order) = locked_out_of_order_buffers.get(channel_id) else {
+            return Err(NetworkError::UnknownChannel(channel_id.to_string()));
+        };
+        out_of_order.write_ranked(LockRank::OutOfOrder).map_err(poisoned("out_of_order"))?.clear();
+        self.metrics_recorder.set(OUT_OF_ORDER_BYTES, channel_id, 0);
+        locked_watermarks.get(channel_id).unwrap().store(watermark, Ordering::Relaxed);
+        locked_consumed_watermarks.get(channel_id).unwrap().store(watermark, Ordering::Relaxed);
+        locked_dedup_windows.get(channel_id).unwrap().lock_ranked(LockRank::DedupWindow).map_err(poisoned("dedup_window"))?.reset_to(watermark);
+        // EOF is delivered again
+        self.completed.lock_ranked(LockRank::Completed).map_err(poisoned("completed"))?.remove(channel_id);
+        Ok(())
+    }
+
+    // Drops channel's backlog below buffer_id - buffered out-of-order, not yet consumed from out_queue or not received at all -
+    // and acks all of it so writer releases its queue, e.g. to catch up after a long stall instead of processing stale data.
+    // Delivery resumes from buffer_id, buffers already held at or above it go out once the next buffer of the channel arrives.
+    // buffer_id should have been written already, acks for ids writer has not assigned yet would release future buffers unsent.
+    // Returns number of skipped buffer ids, 0 for unordered channels or if buffer_id is not past watermark
+    pub fn skip_to(&self, channel_id: &str, buffer_id: u64) -> NetworkResult<usize> {
+        // same lock order as dispatcher
+        let locked_send_chans = self.send_chans.read_ranked(LockRank::SendChans).map_err(poisoned("send_chans"))?;
+        let locked_watermarks = self.watermarks.read_ranked(LockRank::Watermarks).map_err(poisoned("watermarks"))?;
+        let locked_consumed_watermarks = self.consumed_watermarks.read_ranked(LockRank::ConsumedWatermarks).map_err(poisoned("consumed_watermarks"))?;
+        let locked_out_of_order_buffers = self.out_of_order_buffers.read_ranked(LockRank::OutOfOrderBuffers).map_err(poisoned("out_of_order_buffers"))?;
+        let locked_dedup_windows = self.dedup_windows.read_ranked(LockRank::DedupWindows).map_err(poisoned("dedup_windows"))?;
+        let (Some(send_chan), Some(out_of_order)) = (locked_send_chans.get(channel_id), locked_out_of_order_buffers.get(channel_id)) else {
+            return Err(NetworkError::UnknownChannel(channel_id.to_string()));
+        };
+        let wm = locked_watermarks.get(channel_id).unwrap().load(Ordering::Relaxed);
+        if !self.config.is_ordered(channel_id) || buffer_id as i64 <= wm + 1 {
+            return Ok(0);
+        }
+        let exactly_once = self.config.delivery_guarantee == DeliveryGuarantee::ExactlyOnce;
+        let mut locked_out_queue = self.out_queue.lock_ranked(LockRank::OutQueue).map_err(poisoned("out_queue"))?;
+        let mut locked_dedup_window = locked_dedup_windows.get(channel_id).unwrap().lock_ranked(LockRank::DedupWindow).map_err(poisoned("dedup_window"))?;
+        let mut locked_out_of_order = out_of_order.write_ranked(LockRank::OutOfOrder).map_err(poisoned("out_of_order"))?;
+
+        // delivered but not consumed, acked already unless ExactlyOnce or manual_commit. Entries of one buffer are adjacent
+        let mut dropped_ids: Vec<u64> = Vec::new();
+        locked_out_queue.retain(|(entry_channel_id, entry_buffer_id, _, _, _, _)| {
+            let skipped = entry_channel_id == channel_id && *entry_buffer_id < buffer_id;
+            if skipped && dropped_ids.last() != Some(entry_buffer_id) {
+                dropped_ids.push(*entry_buffer_id);
+            }
+            !skipped
+        });
+        drop(locked_out_queue);
+        let mut num_skipped = dropped_ids.len();
+        let mut to_ack = if self.config.acks_deferred() { dropped_ids } else { Vec::new() };
+        for skipped_id in wm + 1..buffer_id as i64 {
+            // empty marker is a priority buffer, already delivered and acked
+            if locked_out_of_order.remove(&skipped_id).is_some_and(|b| b.is_empty()) {
+                continue;
+            }
+            to_ack.push(skipped_id as u64);
+            num_skipped += 1;
+        }
+
+        // priority buffers right after target were delivered already
+        let mut next_wm = buffer_id as i64;
+        while locked_out_of_order.get(&next_wm).is_some_and(|b| b.is_empty()) {
+            locked_out_of_order.remove(&next_wm);
+            next_wm += 1;
+        }
+        locked_watermarks.get(channel_id).unwrap().store(next_wm - 1, Ordering::Relaxed);
+        locked_consumed_watermarks.get(channel_id).unwrap().store(next_wm - 1, Ordering::Relaxed);
+        locked_dedup_window.reset_to(next_wm - 1);
+        self.metrics_recorder.set(OUT_OF_ORDER_BYTES, channel_id, locked_out_of_order.num_bytes() as u64);
+        if exactly_once {
+            // persisted before acking, so skipped buffers are not expected again after restart
+            let checkpoint = Self::build_checkpoint(&locked_consumed_watermarks, &HashMap::new())?;
+            Self::persist_checkpoint(&checkpoint, self.checkpoint_store.as_deref().unwrap())?;
+        }
+        for skipped_id in to_ack {
+            self.acks.ack(&channel_id.to_string(), skipped_id, &send_chan.0)?;
+        }
+        if exactly_once {
+            self.acks.release_skipped(&channel_id.to_string(), (next_wm - 1) as u64, &send_chan.0)?;
+        }
+        self.metrics_recorder.inc(NUM_SKIPPED, channel_id, num_skipped as u64);
+        Ok(num_skipped)
+    }
+
+    // manual_commit only: consumer is done with buffer_id of channel_id, as returned by read_message, so it is acked to
+    // writer and will not be re-delivered. Entries unpacked from one batched buffer share buffer_id and are committed
+    // together, so commit once all of them were read. Commits may come in any order.
+    // Returns false if the buffer was not read or was committed already
+    pub fn commit(&self, channel_id: &str, buffer_id: u64) -> NetworkResult<bool> {
+        if !self.config.manual_commit {
+            return Err(NetworkError::Unsupported(String::from("commit without manual_commit")));
+        }
+        let locked_send_chans = self.send_chans.read_ranked(LockRank::SendChans).map_err(poisoned("send_chans"))?;
+        let Some(send_chan) = locked_send_chans.get(channel_id) else {
+            return Err(NetworkError::UnknownChannel(channel_id.to_string()));
+        };
+        let committed = self.uncommitted.lock_ranked(LockRank::Uncommitted).map_err(poisoned("uncommitted"))?.get_mut(channel_id).and_then(|ids| ids.remove(&buffer_id));
+        if committed.is_none() {
+            return Ok(false);
+        }
+        self.acks.ack(&channel_id.to_string(), buffer_id, &send_chan.0)?;
+        Ok(true)
+    }
+
+    // channel_id -> sorted ids of buffers read but not committed yet, see commit
+    pub fn uncommitted(&self) -> HashMap<String, Vec<u64>> {
+        self.uncommitted.lock_ranked(LockRank::Uncommitted).unwrap_or_else(PoisonError::into_inner).iter()
+            .map(|(channel_id, ids)| (channel_id.clone(), ids.keys().copied().collect()))
+            .collect()
+    }
+
+    // With manual_commit a duplicate of a delivered buffer is re-acked only once everything up to it was committed,
+    // i.e. it is below the first id still queued or read but not committed
+    fn is_committed(channel_id: &str, buffer_id: u64, out_queue: &VecDeque<OutQueueEntry>, uncommitted: &Uncommitted) -> bool {
+        let first_queued = out_queue.iter().find(|entry| entry.0 == channel_id).map(|entry| entry.1);
+        let first_uncommitted = uncommitted.get(channel_id).and_then(|ids| ids.keys().next().copied());
+        [first_queued, first_uncommitted].into_iter().flatten().min().is_none_or(|first_pending| buffer_id < first_pending)
+    }
+
+    // Operator-initiated recovery from a gap that will never be filled, e.g. writer lost the buffer: unlike skip_to nothing
+    // received is dropped - buffers held out-of-order up to up_to are delivered in order, missing ids are given up on
+    // (data loss) and acked, so writer stops resending them. Buffers held right after up_to follow if contiguous.
+    // up_to past the highest held buffer is lowered to it, ids after that may not have been written yet.
+    // In ExactlyOnce missing ids are acked once the buffer after them is consumed, as that persists the checkpoint.
+    // Held buffers go out even if out_queue is full, same as a batch does.
+    // Returns number of skipped (missing) ids, 0 for unordered channels or if up_to is not past watermark
+    pub fn force_advance(&self, channel_id: &str, up_to: i64) -> NetworkResult<usize> {
+        let num_skipped = self.deliver_held(channel_id, Some(up_to))?;
+        self.metrics_recorder.inc(NUM_FORCE_SKIPPED, channel_id, num_skipped as u64);
+        Ok(num_skipped)
+    }
+
+    // Moves held out-of-order buffers of an ordered channel to out_queue in order, advancing watermark. With up_to
+    // missing ids up to it (at most up to the highest held buffer) are skipped and acked, see AckSender::ack_skipped.
+    // Without it only buffers contiguous with watermark go out. Returns number of skipped ids
+    fn deliver_held(&self, channel_id: &str, up_to: Option<i64>) -> NetworkResult<usize> {
+        // same lock order as dispatcher
+        let locked_send_chans = self.send_chans.read_ranked(LockRank::SendChans).map_err(poisoned("send_chans"))?;
+        let locked_watermarks = self.watermarks.read_ranked(LockRank::Watermarks).map_err(poisoned("watermarks"))?;
+        let locked_out_of_order_buffers = self.out_of_order_buffers.read_ranked(LockRank::OutOfOrderBuffers).map_err(poisoned("out_of_order_buffers"))?;
+        let locked_dedup_windows = self.dedup_windows.read_ranked(LockRank::DedupWindows).map_err(poisoned("dedup_windows"))?;
+        let (Some(send_chan), Some(out_of_order)) = (locked_send_chans.get(channel_id), locked_out_of_order_buffers.get(channel_id)) else {
+            return Err(NetworkError::UnknownChannel(channel_id.to_string()));
+        };
+        let wm = locked_watermarks.get(channel_id).unwrap().load(Ordering::Relaxed);
+        if !self.config.is_ordered(channel_id) || up_to.is_some_and(|up_to| up_to <= wm) {
+            return Ok(0);
+        }
+        let mut fragments = self.fragments[self.config.dispatcher_shard(channel_id)].lock_ranked(LockRank::Fragments).map_err(poisoned("fragments"))?;
+        let mut locked_out_queue = self.out_queue.lock_ranked(LockRank::OutQueue).map_err(poisoned("out_queue"))?;
+        let mut locked_dedup_window = locked_dedup_windows.get(channel_id).unwrap().lock_ranked(LockRank::DedupWindow).map_err(poisoned("dedup_window"))?;
+        let mut locked_out_of_order = out_of_order.write_ranked(LockRank::OutOfOrder).map_err(poisoned("out_of_order"))?;
+        Self::deliver_held_locked(
+            channel_id, up_to, locked_watermarks.get(channel_id).unwrap(), &mut locked_out_of_order, &mut locked_dedup_window,
+            &mut locked_out_queue, &mut fragments, &self.payload_pool, &send_chan.0, &self.acks, &self.metrics_recorder, &self.clock, self.config.acks_deferred()
+        )
+    }
+
+    // deliver_held with the channel's locks already taken, e.g. by dispatcher giving up on a gap (see GapPolicy)
+    #[allow(clippy::too_many_arguments)]
+    fn deliver_held_locked(
+        channel_id: &str, up_to: Option<i64>, watermark: &AtomicI64, locked_out_of_order: &mut OutOfOrder, locked_dedup_window: &mut DedupWindow,
+        locked_out_queue: &mut VecDeque<OutQueueEntry>, fragments: &mut FragmentAssembler, payload_pool: &ArrayQueue<Bytes>, send_chan: &Sender<Box<Bytes>>,
+        acks: &AckSender<C>, metrics_recorder: &MetricsRecorder, clock: &C, acks_deferred: bool
+    ) -> NetworkResult<usize> {
+        let wm = watermark.load(Ordering::Relaxed);
+        // acking ids the writer has not assigned yet would release its future buffers unsent
+        let up_to = up_to.map(|up_to| up_to.min(locked_out_of_order.keys().max().unwrap_or(wm)));
+        let mut skipped_ids: Vec<u64> = Vec::new();
+        let mut next_wm = wm + 1;
+        while up_to.is_some_and(|up_to| next_wm <= up_to) || locked_out_of_order.contains_key(&next_wm) {
+            match locked_out_of_order.remove(&next_wm) {
+                None => skipped_ids.push(next_wm as u64),
+                // priority buffer, already delivered and acked
+                Some(b) if b.is_empty() => {},
+                Some(b) => {
+                    // in ExactlyOnce acked once consumed, with manual_commit once committed, as in dispatcher
+                    Self::deliver(channel_id, &b, locked_out_queue, fragments, payload_pool, metrics_recorder, clock);
+                    if !acks_deferred {
+                        acks.ack(&channel_id.to_string(), next_wm as u64, send_chan)?;
+                    }
+                }
+            }
+            locked_dedup_window.insert(next_wm as u64);
+            next_wm += 1;
+        }
+        watermark.store(next_wm - 1, Ordering::Relaxed);
+        metrics_recorder.set(OUT_OF_ORDER_BYTES, channel_id, locked_out_of_order.num_bytes() as u64);
+        let num_skipped = skipped_ids.len();
+        acks.ack_skipped(&channel_id.to_string(), skipped_ids, send_chan)?;
+        Ok(num_skipped)
+    }
+
+    // Debug tap without a side channel: hook gets every n-th buffer received over all channels, n = 1 / sample_rate,
+    // e.g. 0.001 to log 0.1% of traffic. Sampled before dedup and expiry, so dropped buffers show up too.
+    // None removes the hook, taking effect from the dispatcher's next pass. Err(InvalidConfig) if sample_rate is not in (0, 1]
+    pub fn set_inspect_hook(&self, hook: Option<InspectHook>, sample_rate: f64) -> NetworkResult<()> {
+        if !(sample_rate > 0.0 && sample_rate <= 1.0) {
+            return Err(NetworkError::InvalidConfig(format!("sample_rate must be in (0, 1], got {sample_rate}")));
+        }
+        let inspector = hook.map(|hook| Arc::new(Inspector{hook, sample_every: (1.0 / sample_rate).round() as u64, num_seen: AtomicU64::new(0)}));
+        *self.inspector.write_ranked(LockRank::Inspector).unwrap_or_else(PoisonError::into_inner) = inspector;
+        Ok(())
+    }
+
+    // By default a channel is not received from while out_queue is full, so its recv chan fills up and the writer stalls
+    // on unacked buffers. With a handler set, dispatcher keeps receiving and passes what does not fit to the handler
+    // (counted as num_overflowed), e.g. to spill it to storage of its own. Handed buffers are acked and never read from
+    // the reader: the handler owns them from then on, and if it loses one it is lost. On ordered channels the
+    // watermark moves past them as if they were delivered, so what follows is not held back. Duplicates are re-acked
+    // and dropped. Buffers which can not be taken over - EOF markers, buffers of an unseen writer epoch, resets and
+    // ones out_of_order has no room for - are dropped unacked and go the regular way once the writer resends them.
+    // Only with AtLeastOnce delivery without manual_commit, as acks can not wait for reads the reader never sees.
+    // None restores stalling, taking effect from the dispatcher's next pass
+    pub fn set_overflow_handler(&self, handler: Option<OverflowHandler>) -> NetworkResult<()> {
+        if handler.is_some() && self.config.acks_deferred() {
+            return Err(NetworkError::Unsupported(String::from("overflow handler with ExactlyOnce delivery or manual_commit")));
+        }
+        *self.overflow_handler.write_ranked(LockRank::OverflowHandler).unwrap_or_else(PoisonError::into_inner) = handler;
+        Ok(())
+    }
+
+    pub fn get_metrics_snapshot(&self) -> HashMap<String, ChannelStats> {
+        self.metrics_recorder.snapshot()
+    }
+
+    pub fn get_job_totals(&self) -> JobStats {
+        self.metrics_recorder.job_totals()
+    }
+
+    // how many more entries dispatcher can move into out_queue before treating it as full, 0 means consumer
+    // is falling behind and writers are (or soon will be) held back
+    pub fn available_capacity(&self) -> NetworkResult<usize> {
+        let len = self.out_queue.lock_ranked(LockRank::OutQueue).map_err(poisoned("out_queue"))?.len() + handed_off_len(&self.ring, &self.output_chan);
+        Ok(self.config.output_queue_limit().saturating_sub(len))
+    }
+
+    // For stall detection: channel_id -> when dispatcher last put a buffer of it in out_queue, channels that have not
+    // delivered anything yet are left out. Unlike health, which is about threads being alive and buffers arriving,
+    // this is about data moving on: a channel stuck behind a gap keeps receiving but delivers nothing
+    pub fn last_activity(&self) -> HashMap<String, Instant> {
+        let locked_last_activity = self.last_activity.read_ranked(LockRank::LastActivity).unwrap_or_else(PoisonError::into_inner);
+        locked_last_activity.iter().filter_map(|(channel_id, micros)| match micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some((channel_id.clone(), self.created_at + Duration::from_micros(micros)))
+        }).collect()
+    }
+
+    // between start and close
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    // for liveness/readiness probes: tells idle reader from one whose dispatcher died
+    pub fn health(&self, recv_window_ms: u64) -> HealthStatus {
+        let now_ts = self.clock.unix_millis();
+        // diagnostics, so readable even if a failed thread poisoned the locks
+        let locked_last_recv_ts = self.last_recv_ts.read_ranked(LockRank::LastRecvTs).unwrap_or_else(PoisonError::into_inner);
+        let channels_receiving = locked_last_recv_ts.iter().map(|(channel_id, ts)| {
+            let ts = ts.load(Ordering::Relaxed);
+            (channel_id.clone(), ts != 0 && now_ts.saturating_sub(ts) <= recv_window_ms)
+        }).collect();
+        let locked_backpressured = self.backpressured.lock_ranked(LockRank::Backpressured).unwrap_or_else(PoisonError::into_inner);
+        let channels_backpressured = locked_last_recv_ts.keys().map(|channel_id| {
+            (channel_id.clone(), locked_backpressured.contains(channel_id))
+        }).collect();
+        HealthStatus{
+            running: self.running.load(Ordering::Relaxed),
+            dispatcher_alive: self.dispatchers_alive.iter().all(|alive| alive.load(Ordering::Relaxed)),
+            channels_receiving,
+            channels_backpressured,
+            dispatcher_error: self.get_dispatcher_error(),
+            channels_failed: self.failed_channels.lock_ranked(LockRank::FailedChannels).unwrap_or_else(PoisonError::into_inner).clone()
+        }
+    }
+
+    // channel_id -> sorted ids of received buffers held back by a missing predecessor, channel is stuck
+    // waiting for watermark + 1 if not empty. Takes the dispatcher's locks, meant for diagnostic polling
+    pub fn gaps(&self) -> HashMap<String, Vec<u64>> {
+        // same lock order as dispatcher, readable even if a failed thread poisoned the locks
+        let locked_watermarks = self.watermarks.read_ranked(LockRank::Watermarks).unwrap_or_else(PoisonError::into_inner);
+        let locked_out_of_order_buffers = self.out_of_order_buffers.read_ranked(LockRank::OutOfOrderBuffers).unwrap_or_else(PoisonError::into_inner);
+        locked_out_of_order_buffers.iter().map(|(channel_id, out_of_order)| {
+            let wm = locked_watermarks.get(channel_id).map_or(-1, |wm| wm.load(Ordering::Relaxed));
+            let mut ids: Vec<u64> = out_of_order.read_ranked(LockRank::OutOfOrder).unwrap_or_else(PoisonError::into_inner).keys()
+                .filter(|buffer_id| *buffer_id > wm)
+                .map(|buffer_id| buffer_id as u64)
+                .collect();
+            ids.sort_unstable();
+            (channel_id.clone(), ids)
+        }).collect()
+    }
+
+    // Some(panic message) if a dispatcher thread died, its channels deliver nothing until restart_dispatcher()
+    pub fn get_dispatcher_error(&self) -> Option<String> {
+        self.dispatcher_error.lock_ranked(LockRank::DispatcherError).unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    // Re-spawns failed dispatcher threads and resumes failed channels, returns false if reader is not running or
+    // there is nothing to restart. State touched by the panicked iteration is kept as is (buffer that caused it is lost
+    // and will be re-sent by writer only if it was not acked), locks poisoned by it are cleared.
+    pub fn restart_dispatcher(&self) -> bool {
+        if !self.running.load(Ordering::Relaxed) {
+            return false;
+        }
+        let mut dead_shards = Vec::new();
+        let mut alive_handles = Vec::new();
+        while let Some((shard, handle)) = self.dispatcher_thread_handles.pop() {
+            if self.dispatchers_alive[shard].load(Ordering::Relaxed) {
+                alive_handles.push((shard, handle));
+            } else {
+                // panic is caught inside, so join itself does not fail
+                handle.join().unwrap();
+                dead_shards.push(shard);
+            }
+        }
+        for handle in alive_handles {
+            self.dispatcher_thread_handles.push(handle).unwrap();
+        }
+        if dead_shards.is_empty() && self.failed_channels.lock_ranked(LockRank::FailedChannels).unwrap_or_else(PoisonError::into_inner).is_empty() {
+            return false;
+        }
+        // Poison is cleared before failed channels are picked up again, not under failed_channels lock as it ranks after
+        // the maps clear_poison reads. A channel failing in between fails its shard on the next pass, so it is not lost
+        self.clear_poison();
+        self.failed_channels.lock_ranked(LockRank::FailedChannels).unwrap_or_else(PoisonError::into_inner).clear();
+        *self.dispatcher_error.lock_ranked(LockRank::DispatcherError).unwrap_or_else(PoisonError::into_inner) = None;
+        for shard in dead_shards {
+            self.spawn_dispatcher(shard);
+        }
+        true
+    }
+
+    // Signals stop and waits up to timeout_ms for all dispatcher threads to exit instead of blocking forever.
+    // On timeout nothing is checkpointed and handles of threads still running are kept, so close can be retried
+    pub fn close_timeout(&self, timeout_ms: u64) -> Result<(), CloseError> {
+        self.close_with(timeout_ms, None, false).map(|_| ())
+    }
+
+    // Final close, for a reader that is not coming back: closes as close_timeout does, then tells writers to stop
+    // waiting for its acks (see ShutdownMessage). A plain close does not, so writers keep re-sending unacked buffers
+    // to a reader restarted from checkpoint. Also works after close
+    pub fn shutdown(&self, timeout_ms: u64) -> Result<(), CloseError> {
+        self.close_with(timeout_ms, None, true).map(|_| ())
+    }
+
+    // Blocking close handing back everything already received instead of leaving it in out_queue: stops dispatchers,
+    // then returns remaining buffers in read order, as read_message would (EOF markers complete their channels, in
+    // ExactlyOnce each is consumed and checkpointed). With drain_out_of_order, held buffers contiguous with watermark,
+    // e.g. left behind by a failed channel, go out too. Necessarily excluded:
+    // - buffers held behind a true gap (a missing id), delivering them would break order - see force_advance
+    // - buffers still in recv channels, not yet dispatched - these were not acked, so writer resends them
+    // Works after close as well, draining what close left behind
+    pub fn close_and_drain(&self, drain_out_of_order: bool) -> Result<Vec<Box<Bytes>>, CloseError> {
+        self.close_with(u64::MAX, Some(drain_out_of_order), false)
+    }
+
+    // drain_out_of_order: None to leave out_queue as is. Boxed buffers, same as read_batch returns
+    #[allow(clippy::vec_box)]
+    fn close_with(&self, timeout_ms: u64, drain_out_of_order: Option<bool>, shutdown: bool) -> Result<Vec<Box<Bytes>>, CloseError> {
+        self.running.store(false, Ordering::Relaxed);
+        if self.dispatcher_thread_handles.is_empty() {
+            // already closed
+            if shutdown {
+                Self::send_shutdown(&self.send_chans.read_ranked(LockRank::SendChans).unwrap_or_else(PoisonError::into_inner), &self.metrics_recorder);
+            }
+            return match drain_out_of_order {
+                Some(drain_out_of_order) => self.drain(drain_out_of_order).map_err(|err| CloseError::Drain(err.to_string())),
+                None => Ok(Vec::new())
+            };
+        }
+        let deadline = Instant::now().checked_add(Duration::from_millis(timeout_ms));
+        let mut res = Ok(());
+        while let Some((shard, handle)) = self.dispatcher_thread_handles.pop() {
+            while !handle.is_finished() {
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    self.dispatcher_thread_handles.push((shard, handle)).unwrap();
+                    return Err(CloseError::Timeout);
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            // panics are caught inside dispatcher, so this is not expected
+            if handle.join().is_err() {
+                res = Err(CloseError::ThreadPanicked(String::from("unknown panic")));
+            }
+        }
+        // in case dispatcher failed
+        self.clear_poison();
+        // before acks are flushed and consumed watermarks checkpointed, so ExactlyOnce acks drained buffers
+        let drained = match drain_out_of_order {
+            Some(drain_out_of_order) => self.drain(drain_out_of_order).map_err(|err| CloseError::Drain(err.to_string())),
+            None => Ok(Vec::new())
+        };
+        if let Err(err) = self.acks.flush(&self.send_chans.read_ranked(LockRank::SendChans).unwrap_or_else(PoisonError::into_inner), true) {
+            println!("[Reader {}] Failed to send held acks on close: {err}", self.name);
+        }
+        // after held acks, so writers pop what was delivered before they stop sending
+        if shutdown {
+            Self::send_shutdown(&self.send_chans.read_ranked(LockRank::SendChans).unwrap_or_else(PoisonError::into_inner), &self.metrics_recorder);
+        }
+        // stopped first, so a late periodic save does not overwrite the final one
+        let checkpoint_thread_handle = self.checkpoint_thread_handle.lock_ranked(LockRank::CheckpointThread).unwrap_or_else(PoisonError::into_inner).take();
+        if let Some(handle) = checkpoint_thread_handle {
+            if handle.join().is_err() {
+                res = Err(CloseError::ThreadPanicked(String::from("checkpoint thread")));
+            }
+        }
+        if let Some(store) = &self.checkpoint_store {
+            self.checkpoint_to(store.as_ref()).map_err(|err| CloseError::Checkpoint(err.to_string()))?;
+        }
+        self.metrics_recorder.close();
+        res?;
+        drained
+    }
+
+    // reads out_queue (and ring or output chan) until empty, dispatchers must be stopped
+    #[allow(clippy::vec_box)]
+    fn drain(&self, drain_out_of_order: bool) -> NetworkResult<Vec<Box<Bytes>>> {
+        if drain_out_of_order {
+            let channel_ids: Vec<String> = self.channels.read_ranked(LockRank::Channels).map_err(poisoned("channels"))?.iter().map(|ch| ch.get_channel_id().clone()).collect();
+            for channel_id in &channel_ids {
+                self.deliver_held(channel_id, None)?;
+            }
+        }
+        let mut res = Vec::new();
+        loop {
+            // with ring or output chan, entries may still be staged in out_queue
+            if let Some(ring) = &self.ring {
+                hand_off(&mut *self.out_queue.lock_ranked(LockRank::OutQueue).map_err(poisoned("out_queue"))?, ring);
+            }
+            if let Some(chan) = &self.output_chan {
+                hand_off_output(&mut *self.out_queue.lock_ranked(LockRank::OutQueue).map_err(poisoned("out_queue"))?, &chan.0, &self.completed, &self.event_time_watermarks, &self.metrics_recorder, self.clock.now())?;
+            }
+            let b = match self.config.manual_commit {
+                true => self.read_message_filtered(None)?.map(|(_, _, b)| b),
+                false => self.next_bytes()?
+            };
+            match b {
+                Some(b) => res.push(b),
+                None if (self.ring.is_some() || self.output_chan.is_some()) && !self.out_queue.lock_ranked(LockRank::OutQueue).map_err(poisoned("out_queue"))?.is_empty() => continue,
+                None => break
+            }
+        }
+        if self.config.manual_commit {
+            // everything not committed, including what was read before - none of it is acked, so writer
+            // re-delivers it to a restarted reader too
+            let mut locked_uncommitted = self.uncommitted.lock_ranked(LockRank::Uncommitted).map_err(poisoned("uncommitted"))?;
+            let mut channel_ids: Vec<String> = locked_uncommitted.keys().cloned().collect();
+            channel_ids.sort();
+            res = channel_ids.iter().flat_map(|channel_id| locked_uncommitted.remove(channel_id).unwrap().into_values().flatten()).collect();
+        }
+        Ok(res)
+    }
+
+    fn clear_poison(&self) {
+        self.out_queue.clear_poison();
+        for out_of_order in self.out_of_order_buffers.read_ranked(LockRank::OutOfOrderBuffers).unwrap().values() {
+            out_of_order.clear_poison();
+        }
+        for dedup_window in self.dedup_windows.read_ranked(LockRank::DedupWindows).unwrap().values() {
+            dedup_window.clear_poison();
+        }
+        for fragments in self.fragments.iter() {
+            fragments.clear_poison();
+        }
+    }
+
+    pub fn reset_metrics(&self) {
+        self.metrics_recorder.reset()
+    }
+
+    // end-to-end latency (from writer push to in-order delivery) percentiles in micros
+    pub fn get_delivery_latency(&self, channel_id: &str) -> Option<LatencyPercentiles> {
+        self.metrics_recorder.get_percentiles(DELIVERY_LATENCY_MICROS, channel_id)
+    }
+
+    // A lost ack is not fatal - writer resends and the duplicate is re-acked - so an ack that does not fit
+    // a bounded ack channel, or a disconnected one (e.g. io loop already closed during shutdown), is counted and skipped
+    fn send_ack(channel_id: &String, buffer_id: u64, sender: Sender<Box<Bytes>>, metrics_recorder: Arc<MetricsRecorder>) {
+        let ack = AckMessage{channel_id: channel_id.clone(), buffer_id};
+        let b = ack.ser();
+        let size = b.len();
+        match sender.try_send(b) {
+            Ok(()) => metrics_recorder.inc(NUM_BYTES_SENT, channel_id, size as u64),
+            Err(TrySendError::Full(_)) => metrics_recorder.inc(NUM_ACKS_DROPPED, channel_id, 1),
+            Err(TrySendError::Disconnected(_)) => {
+                println!("[Reader] Dropped ack for buffer {buffer_id} on channel {channel_id}: send chan is closed");
+                metrics_recorder.inc(NUM_ACKS_DROPPED, channel_id, 1);
+            }
+        }
+    }
+
+    // Lossy as acks are: a nack that does not fit is not retried here, with GapPolicy::Nack the next one follows
+    // max_ooo_wait_ms later, with NackThenSkip the gap is skipped then
+    fn send_nack(channel_id: &str, buffer_id: u64, sender: &Sender<Box<Bytes>>, metrics_recorder: &MetricsRecorder) {
+        let b = ReaderMessage::Nack(NackMessage{channel_id: channel_id.to_string(), buffer_id}).ser();
+        let size = b.len();
+        if sender.try_send(b).is_ok() {
+            metrics_recorder.inc(NUM_NACKS_SENT, channel_id, 1);
+            metrics_recorder.inc(NUM_BYTES_SENT, channel_id, size as u64);
+        }
+    }
+
+    // Idle backoff of dispatcher shard. Woken early by a buffer arriving on any of its recv chans, so io loop facing
+    // a full bounded recv chan (see recv_chan_capacity) does not wait out the whole backoff. Plain sleep if one of
+    // them already has buffers - last pass left them there as out_queue is full, waiting on them would spin
+    fn idle_wait(
+        shard: usize,
+        config: &DataReaderConfig,
+        recv_chans: &RwLock<HashMap<String, BytesChan>>,
+        failed_channels: &Mutex<HashMap<String, String>>,
+        backoff: Duration
+    ) -> NetworkResult<()> {
+        let receivers: Vec<Receiver<Box<Bytes>>> = {
+            let locked_recv_chans = recv_chans.read_ranked(LockRank::RecvChans).map_err(poisoned("recv_chans"))?;
+            let locked_failed_channels = failed_channels.lock_ranked(LockRank::FailedChannels).map_err(poisoned("failed_channels"))?;
+            locked_recv_chans.iter()
+                .filter(|(channel_id, _)| config.dispatcher_shard(channel_id) == shard && !locked_failed_channels.contains_key(*channel_id))
+                .map(|(_, chan)| chan.1.clone())
+                .collect()
+        };
+        if receivers.is_empty() || receivers.iter().any(|receiver| !receiver.is_empty()) {
+            thread::sleep(backoff);
+            return Ok(());
+        }
+        let mut select = Select::new();
+        for receiver in &receivers {
+            select.recv(receiver);
+        }
+        let _ = select.ready_timeout(backoff);
+        Ok(())
+    }
+
+    // tells writers this reader is going away, see ShutdownMessage. Best-effort: dropped if send chan is full or
+    // closed, so close never waits on it
+    fn send_shutdown(send_chans: &HashMap<String, BytesChan>, metrics_recorder: &MetricsRecorder) {
+        for (channel_id, send_chan) in send_chans {
+            let b = ReaderMessage::Shutdown(ShutdownMessage{channel_id: channel_id.clone()}).ser();
+            let size = b.len();
+            if send_chan.0.try_send(b).is_ok() {
+                metrics_recorder.inc(NUM_BYTES_SENT, channel_id, size as u64);
+            }
+        }
+    }
+
+    // same as send_ack for several buffers of one channel
+    fn send_ack_batch(channel_id: &String, buffer_ids: Vec<u64>, sender: &Sender<Box<Bytes>>, metrics_recorder: &MetricsRecorder) {
+        let num_acks = buffer_ids.len() as u64;
+        let b = ReaderMessage::AckBatch(AckBatchMessage{channel_id: channel_id.clone(), buffer_ids}).ser();
+        let size = b.len();
+        match sender.try_send(b) {
+            Ok(()) => metrics_recorder.inc(NUM_BYTES_SENT, channel_id, size as u64),
+            Err(TrySendError::Full(_)) => metrics_recorder.inc(NUM_ACKS_DROPPED, channel_id, num_acks),
+            Err(TrySendError::Disconnected(_)) => {
+                println!("[Reader] Dropped {num_acks} acks on channel {channel_id}: send chan is closed");
+                metrics_recorder.inc(NUM_ACKS_DROPPED, channel_id, num_acks);
+            }
+        }
+    }
+
+    // asks writers of given channels to pause (or resume) scheduling, unknown (removed) channels are skipped.
+    // Unlike acks these are not retried, so this waits for room in a bounded ack channel
+    fn send_backpressure<'a>(channel_ids: impl Iterator<Item = &'a String>, send_chans: &HashMap<String, BytesChan>, paused: bool, metrics_recorder: &MetricsRecorder) -> NetworkResult<()> {
+        for channel_id in channel_ids {
+            let Some(send_chan) = send_chans.get(channel_id) else {
+                continue
+            };
+            let msg = ReaderMessage::Backpressure(BackpressureMessage{channel_id: channel_id.clone(), paused});
+            let b = msg.ser();
+            let size = b.len();
+            send_chan.0.send(b).map_err(|_| NetworkError::ChannelClosed(format!("send chan {channel_id}")))?;
+            metrics_recorder.inc(NUM_BYTES_SENT, channel_id, size as u64);
+        }
+        Ok(())
+    }
+
+    fn spawn_dispatchers(&self) {
+        for shard in 0..self.config.dispatcher_threads {
+            self.spawn_dispatcher(shard);
+        }
+    }
+
+    // pushes buffer payload to out_queue, expired buffers leave a marker in its place (see EntryKind).
+    // Batched buffers are unpacked into separate entries, so out_queue may go over limit by batch size.
+    // Event-time watermark goes with the last entry, as it only holds once the whole buffer is consumed
+    fn deliver(channel_id: &str, b: &Bytes, out_queue: &mut VecDeque<OutQueueEntry>, fragments: &mut FragmentAssembler, payload_pool: &ArrayQueue<Bytes>, metrics_recorder: &MetricsRecorder, clock: &C) {
+        let buffer_id = get_buffer_id(Box::new(b.clone()));
+        let send_ts = get_buffer_send_ts(Box::new(b.clone()));
+        let now_ts = clock.unix_micros();
+        let event_time_wm = get_buffer_event_time_watermark(b);
+        let enqueued_at = clock.now();
+        if is_buffer_expired(b, now_ts) {
+            metrics_recorder.inc(NUM_EXPIRED, channel_id, 1);
+            // its event-time watermark would cover buffers still in flight
+            out_queue.push_back((channel_id.to_string(), buffer_id, Box::default(), None, enqueued_at, EntryKind::Expired));
+            return;
+        }
+        if get_buffer_flags(b) & BUFFER_FLAG_EOF != 0 {
+            // kept in out_queue, so channel completes only once consumer read everything queued before it
+            out_queue.push_back((channel_id.to_string(), buffer_id, Box::default(), event_time_wm, enqueued_at, EntryKind::Eof));
+            return;
+        }
+        // storage given back by read_into if there is some, see payload_pool
+        let mut payload = Box::new(payload_pool.pop().unwrap_or_default());
+        copy_buffer_payload(b, &mut payload);
+        if get_buffer_flags(b) & BUFFER_FLAG_FRAGMENT != 0 {
+            // message goes out with the buffer id of its last arrived fragment
+            let Some(message) = fragments.add(channel_id, buffer_id, &payload) else {
+                return
+            };
+            let _span = buffer_span!("reassembled", channel_id, buffer_id);
+            payload = message;
+        }
+        let _span = buffer_span!("delivered", channel_id, buffer_id);
+        if get_buffer_flags(b) & BUFFER_FLAG_BATCH != 0 {
+            out_queue.extend(unpack_batch(*payload).into_iter().map(|b| (channel_id.to_string(), buffer_id, b, None, enqueued_at, EntryKind::Data)));
+            if let Some(last) = out_queue.back_mut().filter(|last| last.0 == channel_id && last.1 == buffer_id) {
+                last.3 = event_time_wm;
+            }
+        } else {
+            out_queue.push_back((channel_id.to_string(), buffer_id, payload, event_time_wm, enqueued_at, EntryKind::Data));
+        }
+        metrics_recorder.observe(DELIVERY_LATENCY_MICROS, channel_id, now_ts.saturating_sub(send_ts));
+    }
+
+    // handles channels of given shard only, panics are caught and reported via get_dispatcher_error()/health()
+    fn spawn_dispatcher(&self, shard: usize) {
+        let this_runnning = self.running.clone();
+        let this_recv_chans = self.recv_chans.clone();
+        let this_send_chans = self.send_chans.clone();
+        let this_out_queue = self.out_queue.clone();
+        let this_watermarks = self.watermarks.clone();
+        let this_consumed_watermarks = self.consumed_watermarks.clone();
+        let this_out_of_order_buffers = self.out_of_order_buffers.clone();
+        let this_dedup_windows = self.dedup_windows.clone();
+        let this_payload_pool = self.payload_pool.clone();
+        let this_last_recv_ts = self.last_recv_ts.clone();
+        let this_last_activity = self.last_activity.clone();
+        let this_created_at = self.created_at;
+        let this_fragments = self.fragments.clone();
+        let this_channels = self.channels.clone();
+        let this_inspector = self.inspector.clone();
+        let this_overflow_handler = self.overflow_handler.clone();
+        let this_out_queue_space = self.out_queue_space.clone();
+        let this_failed_channels = self.failed_channels.clone();
+        let this_ring = self.ring.clone();
+        let this_output_chan = self.output_chan.clone();
+        let this_completed = self.completed.clone();
+        let this_event_time_watermarks = self.event_time_watermarks.clone();
+        let this_uncommitted = self.uncommitted.clone();
+        let this_backpressured = self.backpressured.clone();
+        let backpressure_thresholds = self.config.backpressure_thresholds();
+        let out_queue_limit = self.config.output_queue_limit();
+        let this_dispatcher_alive = self.dispatchers_alive[shard].clone();
+        let this_metrics_recorder = self.metrics_recorder.clone();
+        let this_acks = self.acks.clone();
+        let this_config = self.config.clone();
+        let this_clock = self.clock.clone();
+        let this_name = self.name.clone();
+        // in ExactlyOnce mode buffers are acked by read_bytes once consumed and checkpointed, with manual_commit by commit
+        let exactly_once = self.config.delivery_guarantee == DeliveryGuarantee::ExactlyOnce;
+        let acks_deferred = self.config.acks_deferred();
+
+        // set before spawning so health() right after start() does not report dead dispatcher
+        this_dispatcher_alive.store(true, Ordering::Relaxed);
+        let f = move || -> NetworkResult<()> {
+            // Not checkpointed, first buffer after (reader or dispatcher) restart is taken as is
+            let mut writer_epochs: HashMap<String, WriterEpochs> = HashMap::new();
+            let mut last_pass_idle = false;
+            let mut idle_backoff_micros = 0;
+            // num_popped of out_queue_space when last pass found out_queue full, FullQueuePolicy::Block only
+            let mut blocked_at: Option<u64> = None;
+            // channel_id -> polls in a row that received nothing, see idle_channel_misses
+            let mut channel_misses: HashMap<String, u32> = HashMap::new();
+            // channel_id -> gap currently waited on, see max_ooo_wait_ms
+            let mut gap_waits: HashMap<String, GapWait> = HashMap::new();
+            let mut pass: u64 = 0;
+            while this_runnning.load(Ordering::Relaxed) {
+                pass = pass.wrapping_add(1);
+
+                // back off while idle, before taking any locks
+                if let Some(num_popped) = blocked_at.take() {
+                    this_out_queue_space.wait(num_popped, Duration::from_millis(FULL_QUEUE_BLOCK_STEP_MS));
+                    idle_backoff_micros = 0;
+                } else if last_pass_idle {
+                    idle_backoff_micros = this_config.next_idle_backoff_micros(idle_backoff_micros);
+                    if idle_backoff_micros == 0 {
+                        thread::yield_now();
+                    } else {
+                        Self::idle_wait(shard, &this_config, &this_recv_chans, &this_failed_channels, Duration::from_micros(idle_backoff_micros))?;
+                    }
+                } else {
+                    idle_backoff_micros = 0;
+                }
+
+                // channels are looked up for the hook only, locked first as add_channel does
+                let inspector = this_inspector.read_ranked(LockRank::Inspector).map_err(poisoned("inspector"))?.clone();
+                let locked_channels = match inspector {
+                    Some(_) => Some(this_channels.read_ranked(LockRank::Channels).map_err(poisoned("channels"))?),
+                    None => None
+                };
+                let overflow_handler = this_overflow_handler.read_ranked(LockRank::OverflowHandler).map_err(poisoned("overflow_handler"))?.clone();
+                let locked_recv_chans = this_recv_chans.read_ranked(LockRank::RecvChans).map_err(poisoned("recv_chans"))?;
+                let locked_send_chans = this_send_chans.read_ranked(LockRank::SendChans).map_err(poisoned("send_chans"))?;
+                let locked_watermarks = this_watermarks.read_ranked(LockRank::Watermarks).map_err(poisoned("watermarks"))?;
+                let locked_consumed_watermarks = this_consumed_watermarks.read_ranked(LockRank::ConsumedWatermarks).map_err(poisoned("consumed_watermarks"))?;
+                let locked_out_of_order_buffers = this_out_of_order_buffers.read_ranked(LockRank::OutOfOrderBuffers).map_err(poisoned("out_of_order_buffers"))?;
+                let locked_dedup_windows = this_dedup_windows.read_ranked(LockRank::DedupWindows).map_err(poisoned("dedup_windows"))?;
+                let locked_last_recv_ts = this_last_recv_ts.read_ranked(LockRank::LastRecvTs).map_err(poisoned("last_recv_ts"))?;
+                let locked_last_activity = this_last_activity.read_ranked(LockRank::LastActivity).map_err(poisoned("last_activity"))?;
+                let mut fragments = this_fragments[shard].lock_ranked(LockRank::Fragments).map_err(poisoned("fragments"))?;
+
+                if shard == 0 {
+                    this_acks.flush(&locked_send_chans, false)?;
+                }
+
+                if let (0, Some((high_size, low_size))) = (shard, backpressure_thresholds) {
+                    // hysteresis - pause at high watermark, resume only at low one, so queue hovering near full does not thrash writers
+                    let out_queue_len = this_out_queue.lock_ranked(LockRank::OutQueue).map_err(poisoned("out_queue"))?.len() + handed_off_len(&this_ring, &this_output_chan);
+                    let mut locked_backpressured = this_backpressured.lock_ranked(LockRank::Backpressured).map_err(poisoned("backpressured"))?;
+                    if locked_backpressured.is_empty() && out_queue_len >= high_size {
+                        Self::send_backpressure(locked_send_chans.keys(), &locked_send_chans, true, &this_metrics_recorder)?;
+                        locked_backpressured.extend(locked_send_chans.keys().cloned());
+                    } else if !locked_backpressured.is_empty() && out_queue_len <= low_size {
+                        Self::send_backpressure(locked_backpressured.iter(), &locked_send_chans, false, &this_metrics_recorder)?;
+                        locked_backpressured.clear();
+                    }
+                }
+
+                let mut num_recvd_in_pass = 0;
+                let failed_channels: HashSet<String> = this_failed_channels.lock_ranked(LockRank::FailedChannels).map_err(poisoned("failed_channels"))?.keys().cloned().collect();
+                for channel_id in locked_recv_chans.keys().filter(|channel_id| this_config.dispatcher_shard(channel_id) == shard && !failed_channels.contains(*channel_id)) {
+                    let misses = channel_misses.get(channel_id).copied().unwrap_or(0);
+                    if this_config.skip_idle_channel(misses, pass) {
+                        continue
+                    }
+                    let mut locked_out_queue = this_out_queue.lock_ranked(LockRank::OutQueue).map_err(poisoned("out_queue"))?;
+                    // consumer may have made room since last pass
+                    if let Some(ring) = &this_ring {
+                        hand_off(&mut locked_out_queue, ring);
+                    }
+                    if let Some(chan) = &this_output_chan {
+                        hand_off_output(&mut locked_out_queue, &chan.0, &this_completed, &this_event_time_watermarks, &this_metrics_recorder, this_clock.now())?;
+                    }
+                    if locked_out_queue.len() + handed_off_len(&this_ring, &this_output_chan) >= out_queue_limit {
+                        // full
+                        let receiver = &locked_recv_chans.get(channel_id).unwrap().1;
+                        let policy = this_config.full_queue_policy;
+                        if overflow_handler.is_some() || policy != FullQueuePolicy::DropOldest || receiver.is_empty() {
+                            if overflow_handler.is_none() && policy == FullQueuePolicy::Block {
+                                // read before out_queue is released, so a read right after still wakes the wait
+                                blocked_at = Some(this_out_queue_space.num_popped());
+                            }
+                            drop(locked_out_queue);
+                            if let Some(handler) = &overflow_handler {
+                                let send_chan = &locked_send_chans.get(channel_id).unwrap().0;
+                                let ordered = this_config.is_ordered(channel_id);
+                                let at_most_once = this_config.is_at_most_once(channel_id);
+                                let current_epoch = writer_epochs.get(channel_id).and_then(|epochs| epochs.current);
+                                for b in receiver.try_iter().take(MAX_RECV_BATCH_PER_CHANNEL) {
+                                    num_recvd_in_pass += 1;
+                                    this_metrics_recorder.inc(NUM_BUFFERS_RECVD, channel_id, 1);
+                                    this_metrics_recorder.inc(NUM_BYTES_RECVD, channel_id, b.len() as u64);
+                                    locked_last_recv_ts.get(channel_id).unwrap().store(this_clock.unix_millis(), Ordering::Relaxed);
+                                    // EOF completes its channel and a new epoch resets it, regular path does both once there is room
+                                    if check_buffer(&b).is_err() || get_buffer_flags(&b) & BUFFER_FLAG_EOF != 0 || get_buffer_writer_epoch(&b).is_some_and(|epoch| Some(epoch) != current_epoch) {
+                                        continue;
+                                    }
+                                    let buffer_id = get_buffer_id(b.clone());
+                                    let overflow = match ordered {
+                                        true => {
+                                            let wm = locked_watermarks.get(channel_id).unwrap().load(Ordering::Relaxed);
+                                            let locked_dedup_window = locked_dedup_windows.get(channel_id).unwrap().lock_ranked(LockRank::DedupWindow).map_err(poisoned("dedup_window"))?;
+                                            let mut locked_out_of_order = locked_out_of_order_buffers.get(channel_id).unwrap().write_ranked(LockRank::OutOfOrder).map_err(poisoned("out_of_order"))?;
+                                            take_overflowed(buffer_id, wm, &locked_dedup_window, &mut locked_out_of_order)
+                                        }
+                                        false => Overflow::Taken
+                                    };
+                                    if overflow != Overflow::Left && !at_most_once {
+                                        this_acks.ack(channel_id, buffer_id, send_chan)?;
+                                    }
+                                    if overflow == Overflow::Taken {
+                                        this_metrics_recorder.inc(NUM_OVERFLOWED, channel_id, 1);
+                                        handler(b);
+                                    }
+                                }
+                            }
+                            continue
+                        }
+                        // room for what is waiting, oldest entries go first whatever their channel
+                        evict_oldest(&mut locked_out_queue, receiver.len().min(MAX_RECV_BATCH_PER_CHANNEL), &this_completed, &this_event_time_watermarks, &this_metrics_recorder)?;
+                    }
+                    let recv_chan = locked_recv_chans.get(channel_id).unwrap();
+                    let receiver = recv_chan.1.clone();
+                    let ordered = this_config.is_ordered(channel_id);
+                    let at_most_once = this_config.is_at_most_once(channel_id);
+                    let num_recvd_before = num_recvd_in_pass;
+                    let out_queue_len_before = locked_out_queue.len();
+
+                    // A panic while handling one channel's buffers stops that channel only, other channels
+                    // keep going. Shared guards are only borrowed in here, so just the failed channel's own locks get poisoned
+                    let res = panic::catch_unwind(AssertUnwindSafe(|| -> NetworkResult<()> {
+                        // drain up to a batch per pass, so per-pass locking is amortized when recv_chan has backlog
+                        let mut num_recvd = 0;
+                        while num_recvd < MAX_RECV_BATCH_PER_CHANNEL && locked_out_queue.len() + handed_off_len(&this_ring, &this_output_chan) < out_queue_limit {
+                            let Ok(b) = receiver.try_recv() else {
+                                break
+                            };
+                            num_recvd += 1;
+                            num_recvd_in_pass += 1;
+                            let size = b.len();
+                            this_metrics_recorder.inc(NUM_BUFFERS_RECVD, channel_id, 1);
+                            this_metrics_recorder.inc(NUM_BYTES_RECVD, channel_id, size as u64);
+                            let now_ts = this_clock.unix_millis();
+                            locked_last_recv_ts.get(channel_id).unwrap().store(now_ts, Ordering::Relaxed);
+                            // corrupt, or writer is on a newer meta layout, e.g. readers were not upgraded first
+                            if let Err(err) = check_buffer(&b) {
+                                this_config.decode_error_policy.handle(err)?;
+                                this_metrics_recorder.inc(NUM_DECODE_ERRORS, channel_id, 1);
+                                continue;
+                            }
+                            let buffer_id = get_buffer_id(b.clone());
+                            let _span = buffer_span!("received", channel_id, buffer_id);
+                            if let (Some(inspector), Some(locked_channels)) = (&inspector, &locked_channels) {
+                                if inspector.num_seen.fetch_add(1, Ordering::Relaxed) % inspector.sample_every == 0 {
+                                    if let Some(channel) = locked_channels.iter().find(|ch| ch.get_channel_id() == channel_id) {
+                                        (inspector.hook)(channel, &b);
+                                    }
+                                }
+                            }
+
+                            if let Some(epoch) = get_buffer_writer_epoch(&b) {
+                                match writer_epochs.entry(channel_id.clone()).or_default().observe(epoch) {
+                                    EpochChange::Stale => continue,
+                                    EpochChange::Restarted => {
+                                        // writer restarted and its buffer ids start over, so the old sequence state would
+                                        // take them for duplicates. Whatever was not delivered from the previous epoch is dropped
+                                        locked_watermarks.get(channel_id).unwrap().store(-1, Ordering::Relaxed);
+                                        locked_consumed_watermarks.get(channel_id).unwrap().store(-1, Ordering::Relaxed);
+                                        locked_out_of_order_buffers.get(channel_id).unwrap().write_ranked(LockRank::OutOfOrder).map_err(poisoned("out_of_order"))?.clear();
+                                        locked_dedup_windows.get(channel_id).unwrap().lock_ranked(LockRank::DedupWindow).map_err(poisoned("dedup_window"))?.reset_to(-1);
+                                        fragments.clear_channel(channel_id);
+                                        this_acks.discard(channel_id)?;
+                                        if acks_deferred {
+                                            // not acked yet, consuming or committing them would ack ids of the new epoch
+                                            locked_out_queue.retain(|entry| entry.0 != *channel_id);
+                                            this_uncommitted.lock_ranked(LockRank::Uncommitted).map_err(poisoned("uncommitted"))?.remove(channel_id);
+                                        }
+                                        this_metrics_recorder.set(OUT_OF_ORDER_BYTES, channel_id, 0);
+                                        this_metrics_recorder.inc(NUM_WRITER_RESTARTS, channel_id, 1);
+                                    }
+                                    EpochChange::Same => {}
+                                }
+                            }
+
+                            if !ordered {
+                                Self::deliver(channel_id, &b, &mut locked_out_queue, &mut fragments, &this_payload_pool, &this_metrics_recorder, &this_clock);
+                                if !acks_deferred && !at_most_once {
+                                    this_acks.ack(channel_id, buffer_id, &locked_send_chans.get(channel_id).unwrap().0)?;
+                                }
+                                continue;
+                            }
+
+                            let mut wm = locked_watermarks.get(channel_id).unwrap().load(Ordering::Relaxed);
+                            let mut locked_dedup_window = locked_dedup_windows.get(channel_id).unwrap().lock_ranked(LockRank::DedupWindow).map_err(poisoned("dedup_window"))?;
+                            let mut is_dup = buffer_id as i64 <= wm;
+                            if locked_dedup_window.is_enabled() {
+                                is_dup = locked_dedup_window.contains(buffer_id);
+                                if !is_dup && buffer_id as i64 <= wm {
+                                    // not seen recently - channel was reset, restart from this buffer
+                                    wm = buffer_id as i64 - 1;
+                                    locked_watermarks.get(channel_id).unwrap().store(wm, Ordering::Relaxed);
+                                    locked_consumed_watermarks.get(channel_id).unwrap().store(wm, Ordering::Relaxed);
+                                    locked_out_of_order_buffers.get(channel_id).unwrap().write_ranked(LockRank::OutOfOrder).map_err(poisoned("out_of_order"))?.clear();
+                                }
+                            }
+                            if is_dup {
+                                // drop and resend ack (unless it is still waiting in out_queue to be consumed)
+                                this_metrics_recorder.inc(NUM_DUP_BELOW_WM, channel_id, 1);
+                                let consumed_wm = locked_consumed_watermarks.get(channel_id).unwrap().load(Ordering::Relaxed);
+                                let reack = match (exactly_once, this_config.manual_commit) {
+                                    (true, _) => buffer_id as i64 <= consumed_wm,
+                                    (_, true) => Self::is_committed(channel_id, buffer_id, &locked_out_queue, &*this_uncommitted.lock_ranked(LockRank::Uncommitted).map_err(poisoned("uncommitted"))?),
+                                    _ => true
+                                };
+                                if reack {
+                                    this_acks.ack(channel_id, buffer_id, &locked_send_chans.get(channel_id).unwrap().0)?;
+                                }
+                            } else {
+                                // In theory out_of_order should not grow infinitely - sender will ony send maximum of it's buffer queue size
+                                // before receiving ack and sending more (which happens only after all _out_of_order is processed),
+                                // but we still put a limit on it
+                                let locked_out_of_orders = locked_out_of_order_buffers.get(channel_id).unwrap();
+                                let mut locked_out_of_order = locked_out_of_orders.write_ranked(LockRank::OutOfOrder).map_err(poisoned("out_of_order"))?; 
+                            
+                                if locked_out_of_order.contains_key(&(buffer_id as i64)) {
+                                    // duplocate
+                                    this_metrics_recorder.inc(NUM_DUP_OOO, channel_id, 1);
+                                    if !acks_deferred {
+                                        this_acks.ack(channel_id, buffer_id, &locked_send_chans.get(channel_id).unwrap().0)?;
+                                    }
+                                } else if (locked_out_of_order.len() >= MAX_OUT_OF_ORDER_BUFFERS_PER_CHANNEL || !locked_out_of_order.fits(buffer_id as i64, wm)) && buffer_id as i64 != wm + 1 {
+                                    // full - drop without ack, writer will resend after in-flight timeout.
+                                    // Next expected buffer is always accepted, otherwise channel would stall
+                                    this_metrics_recorder.inc(NUM_DROPPED_FULL, channel_id, 1);
+                                } else if this_config.max_out_of_order_bytes.is_some_and(|max_bytes| locked_out_of_order.num_bytes() + size > max_bytes) && buffer_id as i64 != wm + 1 {
+                                    // same as above, by bytes
+                                    this_metrics_recorder.inc(NUM_DROPPED_MEM, channel_id, 1);
+                                } else if !acks_deferred && buffer_id as i64 != wm + 1 && get_buffer_flags(&b) & BUFFER_FLAG_PRIORITY != 0 {
+                                    // priority buffer skips the gap, an empty marker keeps its place so watermark moves past it
+                                    // without delivering it again. Its event-time watermark would cover buffers still missing, so it is dropped
+                                    Self::deliver(channel_id, &b, &mut locked_out_queue, &mut fragments, &this_payload_pool, &this_metrics_recorder, &this_clock);
+                                    if let Some(last) = locked_out_queue.back_mut().filter(|last| last.0 == *channel_id && last.1 == buffer_id) {
+                                        last.3 = None;
+                                    }
+                                    this_acks.ack(channel_id, buffer_id, &locked_send_chans.get(channel_id).unwrap().0)?;
+                                    locked_out_of_order.insert(buffer_id as i64, Box::new(Vec::new()));
+                                } else {
+                                    locked_out_of_order.insert(buffer_id as i64, b.clone());
+                                    let mut next_wm = wm + 1;
+                                    while locked_out_of_order.contains_key(&next_wm) {
+                                        let stored_b = locked_out_of_order.get(&next_wm).unwrap();
+                                        if stored_b.is_empty() {
+                                            // priority buffer, already delivered and acked. Received buffers always carry meta,
+                                            // so an empty payload is never mistaken for this marker
+                                            locked_out_of_order.remove(&next_wm);
+                                            locked_dedup_window.insert(next_wm as u64);
+                                            next_wm += 1;
+                                            continue;
+                                        }
+                                        if !this_config.can_drain(&locked_out_queue, handed_off_len(&this_ring, &this_output_chan), out_queue_limit, get_buffer_payload_len(stored_b)) {
+                                            // full
+                                            break;
+                                        }
+                                        let stored_buffer_id = get_buffer_id(Box::new(stored_b.clone()));
+                                        // In ExactlyOnce expired buffer is acked once its marker is consumed, like any other
+                                        Self::deliver(channel_id, stored_b, &mut locked_out_queue, &mut fragments, &this_payload_pool, &this_metrics_recorder, &this_clock);
+
+                                        // send ack
+                                        if !acks_deferred {
+                                            this_acks.ack(channel_id, stored_buffer_id, &locked_send_chans.get(channel_id).unwrap().0)?;
+                                        }
+                                        locked_out_of_order.remove(&next_wm);
+                                        locked_dedup_window.insert(stored_buffer_id);
+                                        next_wm += 1;
+                                    }
+                                    locked_watermarks.get(channel_id).unwrap().store(next_wm - 1, Ordering::Relaxed);
+                                }
+                                this_metrics_recorder.set(OUT_OF_ORDER_BYTES, channel_id, locked_out_of_order.num_bytes() as u64);
+                            }
+                        }
+                        Ok(())
+                    }));
+                    let failure = match res {
+                        // only DecodeErrorPolicy::Fail gets a Decode error out of here, it stops the channel like a panic
+                        Ok(Err(err @ NetworkError::Decode(_))) => Some(err.to_string()),
+                        Ok(res) => {
+                            res?;
+                            None
+                        },
+                        Err(err) => Some(panic_message(err.as_ref()))
+                    };
+                    if let (None, true, Some(&max_ooo_wait_ms)) = (&failure, ordered, this_config.max_ooo_wait_ms.get(channel_id)) {
+                        let watermark = locked_watermarks.get(channel_id).unwrap();
+                        let wm = watermark.load(Ordering::Relaxed);
+                        // same lock order as above
+                        let mut locked_dedup_window = locked_dedup_windows.get(channel_id).unwrap().lock_ranked(LockRank::DedupWindow).map_err(poisoned("dedup_window"))?;
+                        let mut locked_out_of_order = locked_out_of_order_buffers.get(channel_id).unwrap().write_ranked(LockRank::OutOfOrder).map_err(poisoned("out_of_order"))?;
+                        // held next expected buffer means out_queue is full, not a gap
+                        match locked_out_of_order.keys().min().filter(|first_held| *first_held > wm + 1) {
+                            None => {
+                                gap_waits.remove(channel_id);
+                            },
+                            Some(first_held) => {
+                                let now = this_clock.now();
+                                let gap_wait = gap_waits.entry(channel_id.clone()).or_insert_with(|| {
+                                    this_metrics_recorder.inc(NUM_GAP_WAITS, channel_id, 1);
+                                    GapWait{watermark: wm, since: now, nacked: false}
+                                });
+                                if gap_wait.watermark != wm {
+                                    // gap moved on, wait starts over
+                                    *gap_wait = GapWait{watermark: wm, since: now, nacked: false};
+                                    this_metrics_recorder.inc(NUM_GAP_WAITS, channel_id, 1);
+                                } else if now.saturating_duration_since(gap_wait.since) >= Duration::from_millis(max_ooo_wait_ms) {
+                                    let send_chan = &locked_send_chans.get(channel_id).unwrap().0;
+                                    if !gap_wait.nacked || this_config.gap_policy == GapPolicy::Nack {
+                                        Self::send_nack(channel_id, (wm + 1) as u64, send_chan, &this_metrics_recorder);
+                                        gap_wait.nacked = true;
+                                        gap_wait.since = now;
+                                    } else {
+                                        let num_skipped = Self::deliver_held_locked(
+                                            channel_id, Some(first_held - 1), watermark, &mut locked_out_of_order, &mut locked_dedup_window,
+                                            &mut locked_out_queue, &mut fragments, &this_payload_pool, send_chan, &this_acks, &this_metrics_recorder, &this_clock, acks_deferred
+                                        )?;
+                                        this_metrics_recorder.inc(NUM_FORCE_SKIPPED, channel_id, num_skipped as u64);
+                                        gap_waits.remove(channel_id);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    if let Some(msg) = failure {
+                        println!("[Reader {this_name}] Channel {channel_id} failed, not dispatched until restart_dispatcher(): {msg}");
+                        this_failed_channels.lock_ranked(LockRank::FailedChannels).map_err(poisoned("failed_channels"))?.insert(channel_id.clone(), msg);
+                    }
+                    if locked_out_queue.len() > out_queue_len_before {
+                        // at least 1, 0 means no activity
+                        let micros = (this_clock.now().saturating_duration_since(this_created_at).as_micros() as u64).max(1);
+                        locked_last_activity.get(channel_id).unwrap().store(micros, Ordering::Relaxed);
+                    }
+                    if this_config.idle_channel_misses > 0 {
+                        if num_recvd_in_pass == num_recvd_before {
+                            channel_misses.insert(channel_id.clone(), misses.saturating_add(1));
+                        } else {
+                            channel_misses.remove(channel_id);
+                        }
+                    }
+                    if let Some(ring) = &this_ring {
+                        hand_off(&mut locked_out_queue, ring);
+                    }
+                    if let Some(chan) = &this_output_chan {
+                        hand_off_output(&mut locked_out_queue, &chan.0, &this_completed, &this_event_time_watermarks, &this_metrics_recorder, this_clock.now())?;
+                    }
+                }
+                last_pass_idle = num_recvd_in_pass == 0;
+            }
+            Ok(())
+        };
+
+        let this_dispatcher_error = self.dispatcher_error.clone();
+        let name = self.name.clone();
+        let thread_config = self.config.dispatcher_thread_config.clone();
+        let g = move || {
+            let _alive_guard = AliveGuard(this_dispatcher_alive);
+            thread_config.apply_to_current_thread(shard);
+            let msg = match panic::catch_unwind(AssertUnwindSafe(f)) {
+                Ok(Ok(())) => return,
+                Ok(Err(err)) => err.to_string(),
+                Err(err) => panic_message(err.as_ref())
+            };
+            println!("[Reader {name}] Dispatcher thread failed: {msg}");
+            *this_dispatcher_error.lock_ranked(LockRank::DispatcherError).unwrap_or_else(PoisonError::into_inner) = Some(msg);
+        };
+
+        let name = &self.name;
+        let thread_name = self.config.dispatcher_thread_config.thread_name(&format!("{name}_dispatcher_thread_{shard}"));
+        self.dispatcher_thread_handles.push((shard, std::thread::Builder::new().name(thread_name).spawn(g).unwrap())).unwrap();
+    }
+}
+
+
+impl<C: Clock> IOHandler for DataReader<C> {
+    
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_handler_type(&self) -> IOHandlerType {
+        IOHandlerType::DataReader
+    }
+
+    fn get_channels(&self) -> Vec<Channel> {
+        self.channels.read_ranked(LockRank::Channels).unwrap().clone()
+    }
+
+    fn get_send_chan(&self, sm: &SocketMetadata) -> Option<BytesChan> {
+        let hm = &self.send_chans.read_ranked(LockRank::SendChans).unwrap();
+        hm.get(&sm.channel_id).cloned()
+    }
+
+    fn get_recv_chan(&self, sm: &SocketMetadata) -> Option<BytesChan> {
+        let hm = &self.recv_chans.read_ranked(LockRank::RecvChans).unwrap();
+        hm.get(&sm.channel_id).cloned()
+    }
+
+    // No-op if already started, a closed reader is not started again. Close of a reader never started
+    // is a no-op as well, there are no threads to stop and nothing to checkpoint
+    fn start(&self) {
+        if self.started.swap(true, Ordering::SeqCst) {
+            println!("[Reader {}] Already started, ignoring start", self.name);
+            return;
+        }
+        // start dispatcher thread: takes message from channels, in shared out_queue
+        self.running.store(true, Ordering::Relaxed);
+        self.metrics_recorder.start();
+        self.spawn_dispatchers();
+        self.spawn_checkpoint_thread();
+    }
+
+    fn close (&self) {
+        if let Err(err) = self.close_timeout(u64::MAX) {
+            panic!("[Reader {}] Failed to close: {err}", self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use crate::network::{buffer_utils::{new_buffer_with_meta, new_buffer_with_meta_and_flags, BUFFER_FLAG_EOF, new_expired_buffer, pack_batch, split_fragments}, clock::MockClock, metrics::DEFAULT_FLUSH_INTERVAL_MS, sockets::{SocketKind, SocketOwner}, threads::MAX_CPUS, utils::{wait_for, wait_until, TEST_WAIT_TIMEOUT}};
+
+    use super::*;
+
+    fn socket_meta(channel_id: &str) -> SocketMetadata {
+        SocketMetadata{owner: SocketOwner::Client, kind: SocketKind::Connect, channel_id: channel_id.to_string(), addr: String::from("ipc:///tmp/ipc_test")}
+    }
+
+    #[test]
+    fn test_add_remove_channel() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_1 = Channel::Local{channel_id: String::from("ch_1"), ipc_addr: String::from("ipc:///tmp/ipc_1")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        data_reader.start();
+
+        assert!(data_reader.get_recv_chan(&socket_meta("ch_1")).is_none());
+        let malformed = Channel::Local{channel_id: String::from("ch_1"), ipc_addr: String::from("tmp/ipc_1")};
+        assert!(matches!(data_reader.add_channel(malformed), Err(NetworkError::InvalidChannel(_))));
+        data_reader.add_channel(ch_1).unwrap();
+        assert_eq!(data_reader.get_channels().len(), 2);
+
+        // buffers on added channel are dispatched
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_1")).unwrap();
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![1, 2, 3]), String::from("ch_1"), 0, 0)).unwrap();
+        let b = wait_for(|| data_reader.read_bytes().unwrap());
+        assert_eq!(*b, vec![1, 2, 3]);
+
+        data_reader.remove_channel("ch_1").unwrap();
+        assert!(data_reader.get_recv_chan(&socket_meta("ch_1")).is_none());
+        assert!(data_reader.get_send_chan(&socket_meta("ch_1")).is_none());
+        assert_eq!(data_reader.get_channels().len(), 1);
+        data_reader.close();
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_seek() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let read = || {
+            let b = wait_for(|| data_reader.read_bytes().unwrap());
+            *b
+        };
+
+        for i in 0..2 {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![i]), String::from("ch_0"), i as u64, 0)).unwrap();
+            assert_eq!(read(), vec![i]);
+        }
+
+        // buffer 1 would be a duplicate without seek
+        data_reader.seek("ch_0", 0).unwrap();
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![1]), String::from("ch_0"), 1, 0)).unwrap();
+        assert_eq!(read(), vec![1]);
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_skip_to() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig::new(10), vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        let send = |buffer_id: u64| recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        let recv_ack = || match ReaderMessage::de(send_chan.1.recv().unwrap()) {
+            ReaderMessage::Ack(ack) => ack.buffer_id,
+            _ => panic!("expected ack")
+        };
+
+        // 0 is delivered but not consumed, 3 and 5 wait for missing 1, 2 and 4
+        for buffer_id in [0, 3, 5] {
+            send(buffer_id);
+        }
+        assert_eq!(recv_ack(), 0);
+        wait_until(|| data_reader.gaps()["ch_0"] == vec![3, 5]);
+
+        assert_eq!(data_reader.skip_to("ch_0", 4), Ok(4));
+        assert_eq!((0..3).map(|_| recv_ack()).collect::<Vec<u64>>(), vec![1, 2, 3]);
+        assert_eq!(data_reader.gaps()["ch_0"], vec![5]);
+        assert_eq!(data_reader.read_bytes().unwrap(), None);
+        assert_eq!(data_reader.get_metrics_snapshot()["ch_0"].num_skipped, 4);
+        assert_eq!(data_reader.skip_to("ch_0", 2), Ok(0));
+        assert_eq!(data_reader.skip_to("ch_1", 2), Err(NetworkError::UnknownChannel(String::from("ch_1"))));
+
+        // late skipped buffer is a duplicate, delivery resumes from target and drains what was held after it
+        send(2);
+        assert_eq!(recv_ack(), 2);
+        send(4);
+        let mut read = Vec::new();
+        while read.len() < 2 {
+            let b = wait_for(|| data_reader.read_bytes().unwrap());
+            read.push(*b);
+        }
+        assert_eq!(read, vec![vec![4], vec![5]]);
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_u32_boundary_buffer_ids() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        let send = |buffer_id: u64| recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+
+        let first = u32::MAX as u64 - 1;
+        data_reader.seek("ch_0", first as i64 - 1).unwrap();
+        // past u32::MAX arrives first and waits for the ids below it
+        for buffer_id in [first + 3, first + 2, first, first + 1] {
+            send(buffer_id);
+        }
+        let mut read = Vec::new();
+        while read.len() < 4 {
+            let (_, buffer_id, _) = wait_for(|| data_reader.read_message().unwrap());
+            read.push(buffer_id);
+        }
+        assert_eq!(read, (first..first + 4).collect::<Vec<u64>>());
+        let mut acks: Vec<u64> = (0..4).map(|_| AckMessage::de(send_chan.1.recv().unwrap()).buffer_id).collect();
+        acks.sort();
+        assert_eq!(acks, read);
+        assert!(data_reader.gaps()["ch_0"].is_empty());
+
+        // duplicate past the boundary is acked again, not delivered
+        send(first + 3);
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, first + 3);
+        assert_eq!(data_reader.read_message().unwrap(), None);
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_force_advance() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig::new(10), vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        let send = |buffer_id: u64| recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        let recv_ack = || match ReaderMessage::de(send_chan.1.recv().unwrap()) {
+            ReaderMessage::Ack(ack) => ack.buffer_id,
+            _ => panic!("expected ack")
+        };
+        let read = |n: usize| {
+            let mut read = Vec::new();
+            while read.len() < n {
+                let b = wait_for(|| data_reader.read_bytes().unwrap());
+                read.push(*b);
+            }
+            read
+        };
+
+        // 2, 3 and 6 wait for missing 1, 4 and 5
+        for buffer_id in [0, 2, 3, 6] {
+            send(buffer_id);
+        }
+        assert_eq!(recv_ack(), 0);
+        wait_until(|| data_reader.gaps()["ch_0"] == vec![2, 3, 6]);
+
+        // held buffers up to target are delivered, 5 is still missing so 6 keeps waiting
+        assert_eq!(data_reader.force_advance("ch_0", 4), Ok(2));
+        assert_eq!((0..4).map(|_| recv_ack()).collect::<Vec<u64>>(), vec![2, 3, 1, 4]);
+        assert_eq!(read(3), vec![vec![0], vec![2], vec![3]]);
+        assert_eq!(data_reader.gaps()["ch_0"], vec![6]);
+        assert_eq!(data_reader.get_metrics_snapshot()["ch_0"].num_force_skipped, 2);
+        assert_eq!(data_reader.force_advance("ch_0", 3), Ok(0));
+        assert_eq!(data_reader.force_advance("ch_1", 5), Err(NetworkError::UnknownChannel(String::from("ch_1"))));
+
+        // late skipped buffer is a duplicate, next one drains what was held after target
+        send(4);
+        assert_eq!(recv_ack(), 4);
+        send(5);
+        assert_eq!(read(2), vec![vec![5], vec![6]]);
+
+        assert_eq!((0..2).map(|_| recv_ack()).collect::<Vec<u64>>(), vec![5, 6]);
+
+        // nothing held, so nothing past watermark is given up on
+        assert_eq!(data_reader.force_advance("ch_0", i64::MAX), Ok(0));
+        send(8);
+        wait_until(|| data_reader.gaps()["ch_0"] == vec![8]);
+        assert_eq!(data_reader.force_advance("ch_0", i64::MAX), Ok(1));
+        assert_eq!((0..2).map(|_| recv_ack()).collect::<Vec<u64>>(), vec![8, 7]);
+        assert_eq!(read(1), vec![vec![8]]);
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_force_advance_exactly_once() {
+        let now_ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        let path = format!("/tmp/volga/rust/checkpoints/job-{now_ts}/test_force_advance.checkpoint");
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let config = DataReaderConfig{metrics_enabled: false, checkpoint_path: Some(path.clone()), delivery_guarantee: DeliveryGuarantee::ExactlyOnce, ..DataReaderConfig::new(10)};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), config, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        for buffer_id in [0, 2] {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        }
+        wait_until(|| data_reader.gaps()["ch_0"] == vec![2]);
+
+        // skipped 1 is acked only after 2 is consumed and checkpointed past it
+        assert_eq!(data_reader.force_advance("ch_0", 2), Ok(1));
+        assert!(send_chan.1.try_recv().is_err());
+        assert_eq!(data_reader.read_bytes().unwrap(), Some(Box::new(vec![0])));
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 0);
+        assert!(send_chan.1.try_recv().is_err());
+        assert_eq!(data_reader.read_bytes().unwrap(), Some(Box::new(vec![2])));
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 2);
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 1);
+        data_reader.close();
+        let checkpoint: ReaderCheckpoint = rmp_serde::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(checkpoint.watermarks["ch_0"], 2);
+    }
+
+    #[test]
+    fn test_max_ooo_wait() {
+        for gap_policy in [GapPolicy::NackThenSkip, GapPolicy::Nack] {
+            let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+            let clock = MockClock::new();
+            let max_ooo_wait_ms = HashMap::from([(String::from("ch_0"), 100)]);
+            let data_reader = DataReader::with_clock(String::from("test_reader"), String::from("test_job"), DataReaderConfig{max_ooo_wait_ms, gap_policy, ..DataReaderConfig::new(10)}, vec![ch_0], clock.clone()).unwrap();
+            data_reader.start();
+            let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+            let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+            let send = |buffer_id: u64| recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+
+            send(0);
+            send(2);
+            assert_eq!(ReaderMessage::de(send_chan.1.recv().unwrap()), ReaderMessage::Ack(AckMessage{channel_id: String::from("ch_0"), buffer_id: 0}));
+            // wait starts at current mock time, clock moves only once it did
+            wait_until(|| data_reader.get_metrics_snapshot().get("ch_0").map_or(0, |stats| stats.num_gap_waits) != 0);
+            assert_eq!(data_reader.gaps()["ch_0"], vec![2]);
+            assert!(send_chan.1.try_recv().is_err());
+
+            // missing buffer is nacked once the wait is over
+            clock.advance(Duration::from_millis(100));
+            let nack = ReaderMessage::Nack(NackMessage{channel_id: String::from("ch_0"), buffer_id: 1});
+            assert_eq!(ReaderMessage::de(send_chan.1.recv().unwrap()), nack);
+            assert_eq!(data_reader.get_metrics_snapshot()["ch_0"].num_nacks_sent, 1);
+
+            clock.advance(Duration::from_millis(100));
+            if gap_policy == GapPolicy::Nack {
+                // keeps asking, until it arrives
+                assert_eq!(ReaderMessage::de(send_chan.1.recv().unwrap()), nack);
+                send(1);
+            }
+            // NackThenSkip gives up on 1 and delivers 2, acking both
+            let acks: Vec<u64> = (0..2).map(|_| match ReaderMessage::de(send_chan.1.recv().unwrap()) {
+                ReaderMessage::Ack(ack) => ack.buffer_id,
+                _ => panic!("expected ack")
+            }).collect();
+            let mut read = Vec::new();
+            while read.len() < 3 - (gap_policy == GapPolicy::NackThenSkip) as usize {
+                let b = wait_for(|| data_reader.read_bytes().unwrap());
+                read.push(*b);
+            }
+            let metrics = data_reader.get_metrics_snapshot();
+            if gap_policy == GapPolicy::Nack {
+                assert_eq!(acks, vec![1, 2]);
+                assert_eq!(read, vec![vec![0], vec![1], vec![2]]);
+                assert_eq!(metrics["ch_0"].num_force_skipped, 0);
+            } else {
+                assert_eq!(acks, vec![2, 1]);
+                assert_eq!(read, vec![vec![0], vec![2]]);
+                assert_eq!(metrics["ch_0"].num_force_skipped, 1);
+            }
+            data_reader.close();
+        }
+    }
+
+    #[test]
+    fn test_manual_commit() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, manual_commit: true, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        let send = |buffer_id: u64| recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        let no_ack = || {
+            std::thread::sleep(Duration::from_millis(20));
+            send_chan.1.try_recv().is_err()
+        };
+        for buffer_id in 0..3 {
+            send(buffer_id);
+        }
+        wait_until(|| data_reader.out_queue.lock().unwrap().len() >= 3);
+        // delivered is not acked
+        assert!(no_ack());
+        assert!(matches!(data_reader.read_bytes(), Err(NetworkError::Unsupported(_))));
+        assert_eq!(data_reader.read_message().unwrap(), Some((String::from("ch_0"), 0, Box::new(vec![0]))));
+        assert_eq!(data_reader.read_message().unwrap(), Some((String::from("ch_0"), 1, Box::new(vec![1]))));
+        assert_eq!(data_reader.uncommitted(), HashMap::from([(String::from("ch_0"), vec![0, 1])]));
+
+        // out of order commit is acked, but a duplicate of it is re-acked only once everything before it is committed too
+        assert_eq!(data_reader.commit("ch_0", 1), Ok(true));
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 1);
+        assert_eq!(data_reader.commit("ch_0", 1), Ok(false));
+        assert_eq!(data_reader.commit("ch_0", 2), Ok(false));
+        assert_eq!(data_reader.commit("ch_1", 0), Err(NetworkError::UnknownChannel(String::from("ch_1"))));
+        send(1);
+        assert!(no_ack());
+        assert_eq!(data_reader.commit("ch_0", 0), Ok(true));
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 0);
+        send(1);
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 1);
+
+        // read but not committed and not read at all are handed back, none of them acked
+        assert_eq!(data_reader.read_message().unwrap(), Some((String::from("ch_0"), 2, Box::new(vec![2]))));
+        send(3);
+        wait_until(|| !data_reader.out_queue.lock().unwrap().is_empty());
+        assert_eq!(data_reader.close_and_drain(false), Ok(vec![Box::new(vec![2]), Box::new(vec![3])]));
+        // no acks and no shutdown, so writer re-delivers to a restarted reader
+        assert!(send_chan.1.try_recv().is_err());
+        assert!(data_reader.uncommitted().is_empty());
+    }
+
+    #[test]
+    fn test_close_and_drain() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+
+        // 0..3 are delivered but not read, 4 waits for missing 3
+        for buffer_id in [0, 1, 2, 4] {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        }
+        for _ in 0..3 {
+            send_chan.1.recv().unwrap();
+        }
+        wait_until(|| data_reader.gaps()["ch_0"] == vec![4]);
+
+        let drained: Vec<Vec<u8>> = data_reader.close_and_drain(true).unwrap().into_iter().map(|b| *b).collect();
+        assert_eq!(drained, vec![vec![0], vec![1], vec![2]]);
+        // already closed, nothing left
+        assert_eq!(data_reader.close_and_drain(true).unwrap().len(), 0);
+        assert_eq!(data_reader.gaps()["ch_0"], vec![4]);
+        // writer is told only on final shutdown
+        assert!(send_chan.1.try_recv().is_err());
+        assert_eq!(data_reader.shutdown(1000), Ok(()));
+        assert_eq!(ReaderMessage::de(send_chan.1.try_recv().unwrap()), ReaderMessage::Shutdown(ShutdownMessage{channel_id: String::from("ch_0")}));
+        assert!(send_chan.1.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_checkpoint_restore() {
+        let now_ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        let path = format!("/tmp/volga/rust/checkpoints/job-{now_ts}/test_reader.checkpoint");
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let config = DataReaderConfig{metrics_enabled: false, checkpoint_path: Some(path.clone()), ..DataReaderConfig::new(10)};
+
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), config.clone(), vec![ch_0.clone()]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        for i in 0..3 {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![i]), String::from("ch_0"), i as u64, 0)).unwrap();
+            wait_until(|| data_reader.read_bytes().unwrap().is_some());
+        }
+        data_reader.close(); // checkpoints on close
+
+        let b = fs::read(&path).unwrap();
+        let checkpoint: ReaderCheckpoint = rmp_serde::from_slice(&b).unwrap();
+        assert_eq!(*checkpoint.watermarks.get("ch_0").unwrap(), 2);
+
+        // restarted reader re-acks and drops already delivered buffers
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), config, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![1]), String::from("ch_0"), 1, 0)).unwrap();
+        let ack = send_chan.1.recv().unwrap();
+        assert_eq!(AckMessage::de(ack).buffer_id, 1);
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![3]), String::from("ch_0"), 3, 0)).unwrap();
+        let b = wait_for(|| data_reader.read_bytes().unwrap());
+        assert_eq!(*b, vec![3]);
+        data_reader.close();
+        fs::remove_file(path).unwrap();
+    }
+
+    // keeps every save, so tests can see how often it was written
+    #[derive(Default)]
+    struct MemCheckpointStore {
+        saved: Mutex<Vec<Vec<u8>>>
+    }
+
+    impl CheckpointStore for MemCheckpointStore {
+        fn save(&self, b: &[u8]) -> io::Result<()> {
+            self.saved.lock().unwrap().push(b.to_vec());
+            Ok(())
+        }
+
+        fn load(&self) -> io::Result<Option<Vec<u8>>> {
+            Ok(self.saved.lock().unwrap().last().cloned())
+        }
+
+        fn describe(&self) -> String {
+            String::from("memory")
+        }
+    }
+
+    #[test]
+    fn test_periodic_checkpoint() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let config = DataReaderConfig{metrics_enabled: false, checkpoint_interval_ms: Some(20), ..DataReaderConfig::new(10)};
+        assert!(matches!(DataReader::new(String::from("test_reader"), String::from("test_job"), config.clone(), vec![ch_0.clone()]), Err(NetworkError::InvalidConfig(_))));
+        let store = Arc::new(MemCheckpointStore::default());
+        let last_watermark = |store: &MemCheckpointStore| {
+            store.load().unwrap().map(|b| rmp_serde::from_slice::<ReaderCheckpoint>(&b).unwrap().watermarks["ch_0"])
+        };
+
+        let data_reader = DataReader::with_checkpoint_store(String::from("test_reader"), String::from("test_job"), config.clone(), vec![ch_0.clone()], SystemClock, store.clone()).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        for i in 0..3 {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![i]), String::from("ch_0"), i as u64, 0)).unwrap();
+            wait_until(|| data_reader.read_bytes().unwrap().is_some());
+        }
+        // saved in background while running
+        wait_until(|| last_watermark(&store) == Some(2));
+        // unchanged snapshots are not saved again
+        let num_saved = store.saved.lock().unwrap().len();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(store.saved.lock().unwrap().len(), num_saved);
+
+        // final save on close, even if unchanged
+        data_reader.close();
+        assert_eq!(store.saved.lock().unwrap().len(), num_saved + 1);
+        assert_eq!(last_watermark(&store), Some(2));
+
+        // restored from the store
+        let data_reader = DataReader::with_checkpoint_store(String::from("test_reader"), String::from("test_job"), config.clone(), vec![ch_0.clone()], SystemClock, store.clone()).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![2]), String::from("ch_0"), 2, 0)).unwrap();
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![3]), String::from("ch_0"), 3, 0)).unwrap();
+        let b = wait_for(|| data_reader.read_bytes().unwrap());
+        assert_eq!(*b, vec![3]);
+        data_reader.close();
+
+        // corrupt checkpoint fails construction
+        store.save(&[0xc1]).unwrap();
+        let res = DataReader::with_checkpoint_store(String::from("test_reader"), String::from("test_job"), config, vec![ch_0], SystemClock, store);
+        assert!(matches!(res, Err(NetworkError::Decode(_))));
+    }
+
+    #[test]
+    fn test_exactly_once_restart() {
+        let now_ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        let path = format!("/tmp/volga/rust/checkpoints/job-{now_ts}/test_reader_exactly_once.checkpoint");
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let config = DataReaderConfig{metrics_enabled: false, checkpoint_path: Some(path.clone()), delivery_guarantee: DeliveryGuarantee::ExactlyOnce, ..DataReaderConfig::new(10)};
+        let send_all = |data_reader: &DataReader| {
+            // writer re-sends everything it has no acks for
+            let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+            for i in 0..4 {
+                recv_chan.0.send(new_buffer_with_meta(Box::new(vec![i]), String::from("ch_0"), i as u64, 0)).unwrap();
+            }
+        };
+        let read = |data_reader: &DataReader| {
+            let b = wait_for(|| data_reader.read_bytes().unwrap());
+            b[0]
+        };
+
+        let mut delivered = Vec::new();
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), config.clone(), vec![ch_0.clone()]).unwrap();
+        data_reader.start();
+        send_all(&data_reader);
+        delivered.push(read(&data_reader));
+        delivered.push(read(&data_reader));
+        // wait for all 4 to be dispatched
+        wait_until(|| data_reader.out_queue.lock().unwrap().len() == 2);
+
+        // only consumed buffers are acked
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        let acks: Vec<u64> = send_chan.1.try_iter().map(|b| AckMessage::de(b).buffer_id).collect();
+        assert_eq!(acks, vec![0, 1]);
+
+        // crash - stop dispatcher without closing (close would checkpoint)
+        data_reader.running.store(false, Ordering::Relaxed);
+        data_reader.dispatcher_thread_handles.pop().unwrap().1.join().unwrap();
+        drop(data_reader);
+
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), config, vec![ch_0]).unwrap();
+        data_reader.start();
+        send_all(&data_reader);
+        delivered.push(read(&data_reader));
+        delivered.push(read(&data_reader));
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(data_reader.read_bytes().unwrap().is_none());
+        assert_eq!(delivered, vec![0, 1, 2, 3]);
+
+        // re-sent consumed buffers are re-acked
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        let mut acks: Vec<u64> = send_chan.1.try_iter().map(|b| AckMessage::de(b).buffer_id).collect();
+        acks.sort();
+        assert_eq!(acks, vec![0, 1, 2, 3]);
+        data_reader.close();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_dedup_window() {
+        let mut dedup_window = DedupWindow::new(2);
+        for i in 0..3 {
+            dedup_window.insert(i);
+        }
+        assert!(!dedup_window.contains(0));
+        assert!(dedup_window.contains(1));
+        assert!(dedup_window.contains(2));
+
+        dedup_window.reset_to(5);
+        assert!(!dedup_window.contains(2));
+        assert!(dedup_window.contains(4));
+        assert!(dedup_window.contains(5));
+
+        assert!(!DedupWindow::new(0).is_enabled());
+    }
+
+    #[test]
+    fn test_dedup_window_channel_reset() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_1 = Channel::Local{channel_id: String::from("ch_1"), ipc_addr: String::from("ipc:///tmp/ipc_1")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, dedup_window: 2, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        let send_and_ack = |buffer_id: u64| {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+            AckMessage::de(send_chan.1.recv().unwrap()).buffer_id
+        };
+
+        for i in 0..4 {
+            assert_eq!(send_and_ack(i), i);
+            assert_eq!(*data_reader.read_bytes().unwrap().unwrap(), vec![i as u8]);
+        }
+
+        // duplicate within window
+        assert_eq!(send_and_ack(3), 3);
+        // id sequence restarted - delivered again
+        assert_eq!(send_and_ack(0), 0);
+        assert_eq!(*data_reader.read_bytes().unwrap().unwrap(), vec![0]);
+        assert_eq!(send_and_ack(0), 0);
+        assert_eq!(send_and_ack(1), 1);
+        assert_eq!(*data_reader.read_bytes().unwrap().unwrap(), vec![1]);
+        assert!(data_reader.read_bytes().unwrap().is_none());
+        data_reader.close();
+
+        // without window buffers below watermark are always duplicates
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_1]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_1")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_1")).unwrap();
+        for i in [0, 1, 0] {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![i as u8]), String::from("ch_1"), i, 0)).unwrap();
+            assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, i);
+        }
+        assert_eq!(*data_reader.read_bytes().unwrap().unwrap(), vec![0]);
+        assert_eq!(*data_reader.read_bytes().unwrap().unwrap(), vec![1]);
+        assert!(data_reader.read_bytes().unwrap().is_none());
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_writer_restart() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig::new(10), vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        let send = |epoch: u64, buffer_id: u64| {
+            let b = new_buffer_with_meta_and_flags(Box::new(vec![epoch as u8, buffer_id as u8]), String::from("ch_0"), buffer_id, 0, None, None, 0, Some(epoch));
+            recv_chan.0.send(b).unwrap();
+        };
+        let read = || {
+            let b = wait_for(|| data_reader.read_bytes().unwrap());
+            *b
+        };
+
+        for i in 0..3 {
+            send(10, i);
+            assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, i);
+            assert_eq!(read(), vec![10, i as u8]);
+        }
+        // held out-of-order, never delivered
+        send(10, 4);
+
+        // restarted writer starts over from 0, without dedup window these would be duplicates
+        send(20, 0);
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 0);
+        assert_eq!(read(), vec![20, 0]);
+        // late buffer of the previous epoch is dropped without ack
+        send(10, 3);
+        send(20, 1);
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 1);
+        assert_eq!(read(), vec![20, 1]);
+        assert!(data_reader.read_bytes().unwrap().is_none());
+        assert_eq!(data_reader.get_metrics_snapshot()["ch_0"].num_writer_restarts, 1);
+
+        // restarted writer with a clock behind gets a lower epoch, still a restart
+        send(5, 0);
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 0);
+        assert_eq!(read(), vec![5, 0]);
+        send(20, 2);
+        send(5, 1);
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 1);
+        assert_eq!(read(), vec![5, 1]);
+        assert_eq!(data_reader.get_metrics_snapshot()["ch_0"].num_writer_restarts, 2);
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_writer_epochs() {
+        let mut epochs = WriterEpochs::default();
+        assert_eq!(epochs.observe(10), EpochChange::Same);
+        assert_eq!(epochs.observe(10), EpochChange::Same);
+        assert_eq!(epochs.observe(3), EpochChange::Restarted);
+        assert_eq!(epochs.observe(10), EpochChange::Stale);
+        assert_eq!(epochs.observe(3), EpochChange::Same);
+        for epoch in 100..100 + MAX_RETIRED_WRITER_EPOCHS as u64 {
+            assert_eq!(epochs.observe(epoch), EpochChange::Restarted);
+        }
+        // oldest ones are forgotten
+        assert_eq!(epochs.observe(10), EpochChange::Restarted);
+        assert_eq!(epochs.observe(100), EpochChange::Stale);
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let err = DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(0)}.validate().err();
+        assert_eq!(err.unwrap(), "output_queue_size must be greater than 0");
+        let config = DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)};
+        assert_eq!(DataReaderConfig{checkpoint_interval_ms: Some(100), ..config.clone()}.validate().unwrap_err(), "checkpoint_interval_ms requires checkpoint_path or a checkpoint store");
+        assert_eq!(DataReaderConfig{delivery_guarantee: DeliveryGuarantee::ExactlyOnce, ..config.clone()}.validate().unwrap_err(), "ExactlyOnce delivery requires checkpoint_path or a checkpoint store");
+        assert_eq!(DataReaderConfig{backpressure_high_watermark: Some(1.5), ..config.clone()}.validate().unwrap_err(), "backpressure_high_watermark must be in (0, 1]");
+        assert_eq!(DataReaderConfig{backpressure_high_watermark: Some(0.5), ..config.clone()}.validate().unwrap_err(), "backpressure_low_watermark must be in [0, backpressure_high_watermark)");
+        assert_eq!(DataReaderConfig{backpressure_high_watermark: Some(0.8), backpressure_low_watermark: 0.2, ..config.clone()}.backpressure_thresholds(), Some((8, 2)));
+        assert_eq!(DataReaderConfig{dispatcher_threads: 0, ..config.clone()}.validate().unwrap_err(), "dispatcher_threads must be greater than 0");
+        assert_eq!(DataReaderConfig{max_out_of_order_bytes: Some(0), ..config.clone()}.validate().unwrap_err(), "max_out_of_order_bytes must be greater than 0");
+        assert_eq!(DataReaderConfig{dispatcher_thread_config: ThreadConfig{cpu_affinity: Some(vec![MAX_CPUS]), ..Default::default()}, ..config.clone()}.validate().unwrap_err(), format!("cpu_affinity cpu {MAX_CPUS} out of range, must be below {MAX_CPUS}"));
+        assert_eq!(DataReaderConfig{output_queue_full_threshold: 0.0, ..config.clone()}.validate().unwrap_err(), "output_queue_full_threshold must be in (0, 1]");
+        assert_eq!(DataReaderConfig{output_queue_full_threshold: 0.5, backpressure_high_watermark: Some(0.8), ..config.clone()}.validate().unwrap_err(), "backpressure_high_watermark must not be above output_queue_full_threshold");
+        let unordered = HashMap::from([(String::from("ch_0"), false)]);
+        assert_eq!(DataReaderConfig{delivery_guarantee: DeliveryGuarantee::ExactlyOnce, checkpoint_path: Some(String::from("/tmp/cp")), ordered: unordered, ..config.clone()}.validate().unwrap_err(), "ExactlyOnce delivery requires all channels to be ordered");
+        assert_eq!(DataReaderConfig{ack_strategy: AckStrategy::Batched, ack_batch_size: 0, ..config.clone()}.validate().unwrap_err(), "ack_batch_size must be greater than 0");
+        assert_eq!(DataReaderConfig{prefetch: 4, prefetch_max_bytes: 0, ..config.clone()}.validate().unwrap_err(), "prefetch_max_bytes must be greater than 0 when prefetch is enabled");
+        assert_eq!(DataReaderConfig{out_queue_ring: true, dispatcher_threads: 2, ..config.clone()}.validate().unwrap_err(), "out_queue_ring requires a single dispatcher thread");
+        assert_eq!(DataReaderConfig{out_queue_ring: true, delivery_guarantee: DeliveryGuarantee::ExactlyOnce, checkpoint_path: Some(String::from("/tmp/cp")), ..config.clone()}.validate().unwrap_err(), "out_queue_ring requires AtLeastOnce delivery");
+        assert_eq!(DataReaderConfig{output_chan: true, out_queue_ring: true, ..config.clone()}.validate().unwrap_err(), "output_chan and out_queue_ring can not be used together");
+        assert_eq!(DataReaderConfig{output_chan: true, delivery_guarantee: DeliveryGuarantee::ExactlyOnce, checkpoint_path: Some(String::from("/tmp/cp")), ..config.clone()}.validate().unwrap_err(), "output_chan requires AtLeastOnce delivery");
+        assert_eq!(DataReaderConfig{manual_commit: true, delivery_guarantee: DeliveryGuarantee::ExactlyOnce, checkpoint_path: Some(String::from("/tmp/cp")), ..config.clone()}.validate().unwrap_err(), "manual_commit requires AtLeastOnce delivery");
+        assert_eq!(DataReaderConfig{manual_commit: true, output_chan: true, ..config.clone()}.validate().unwrap_err(), "manual_commit can not be used with out_queue_ring or output_chan");
+        assert_eq!(DataReaderConfig{max_ooo_wait_ms: HashMap::from([(String::from("ch_0"), 0)]), ..config.clone()}.validate().unwrap_err(), "max_ooo_wait_ms must be greater than 0");
+        assert_eq!(DataReaderConfig{idle_channel_misses: 4, idle_channel_poll_every: 0, ..config.clone()}.validate().unwrap_err(), "idle_channel_poll_every must be greater than 0 when idle_channel_misses is set");
+        let at_most_once = HashMap::from([(String::from("ch_0"), true)]);
+        assert_eq!(DataReaderConfig{at_most_once: at_most_once.clone(), manual_commit: true, ..config.clone()}.validate().unwrap_err(), "at_most_once channels can not be used with ExactlyOnce delivery or manual_commit");
+        assert_eq!(DataReaderConfig{at_most_once, ordered: HashMap::from([(String::from("ch_0"), true)]), ..config.clone()}.validate().unwrap_err(), "at_most_once channels can not be ordered");
+        assert_eq!(DataReaderConfig{full_queue_policy: FullQueuePolicy::Block, output_chan: true, ..config.clone()}.validate().unwrap_err(), "Block and DropOldest full_queue_policy can not be used with out_queue_ring or output_chan");
+        assert_eq!(DataReaderConfig{full_queue_policy: FullQueuePolicy::DropOldest, manual_commit: true, ..config.clone()}.validate().unwrap_err(), "DropOldest full_queue_policy requires AtLeastOnce delivery without manual_commit");
+        assert!(DataReaderConfig{metrics_enabled: true, ..config.clone()}.validate().is_ok());
+
+        // reader is not built from invalid config or channels
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let res = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{dispatcher_threads: 0, ..config.clone()}, vec![ch_0.clone()]);
+        assert!(matches!(res, Err(NetworkError::InvalidConfig(msg)) if msg == "dispatcher_threads must be greater than 0"));
+        let res = DataReader::new(String::from("test_reader"), String::from("test_job"), config, vec![ch_0.clone(), ch_0]);
+        assert!(matches!(res, Err(NetworkError::InvalidChannel(_))));
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let config = DataReaderConfig{metrics_enabled: false, backpressure_high_watermark: Some(0.75), backpressure_low_watermark: 0.25, ..DataReaderConfig::new(4)};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), config, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        let recv_backpressure = || {
+            match ReaderMessage::de(send_chan.1.recv().unwrap()) {
+                ReaderMessage::Backpressure(bp) => bp.paused,
+                _ => panic!("expected backpressure message")
+            }
+        };
+        for i in 0..3 {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![i as u8]), String::from("ch_0"), i, 0)).unwrap();
+            assert!(matches!(ReaderMessage::de(send_chan.1.recv().unwrap()), ReaderMessage::Ack(ack) if ack.buffer_id == i));
+        }
+
+        // high watermark reached
+        assert!(recv_backpressure());
+        assert!(data_reader.health(DEFAULT_HEALTH_RECV_WINDOW_MS).channels_backpressured["ch_0"]);
+
+        // between watermarks - still paused
+        assert_eq!(*data_reader.read_bytes().unwrap().unwrap(), vec![0]);
+        assert!(send_chan.1.recv_timeout(std::time::Duration::from_millis(50)).is_err());
+
+        // low watermark reached
+        assert_eq!(*data_reader.read_bytes().unwrap().unwrap(), vec![1]);
+        assert!(!recv_backpressure());
+        assert!(!data_reader.health(DEFAULT_HEALTH_RECV_WINDOW_MS).channels_backpressured["ch_0"]);
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_batched_buffers() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(2)}, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        let batch = pack_batch(&[vec![0], vec![1], vec![2]]);
+        recv_chan.0.send(new_buffer_with_meta_and_flags(batch, String::from("ch_0"), 0, 0, None, None, BUFFER_FLAG_BATCH, None)).unwrap();
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![3]), String::from("ch_0"), 1, 0)).unwrap();
+
+        // one ack per batch
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 0);
+        for i in 0..3 {
+            assert_eq!(*data_reader.read_bytes().unwrap().unwrap(), vec![i]);
+        }
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 1);
+        assert_eq!(data_reader.read_message().unwrap().unwrap(), (String::from("ch_0"), 1, Box::new(vec![3])));
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_empty_payload() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        // out of order first, so it goes through out-of-order buffers
+        recv_chan.0.send(new_buffer_with_meta_and_flags(pack_batch(&[vec![], vec![]]), String::from("ch_0"), 1, 0, None, None, BUFFER_FLAG_BATCH, None)).unwrap();
+        recv_chan.0.send(new_buffer_with_meta(Box::default(), String::from("ch_0"), 0, 0)).unwrap();
+        for i in 0..2 {
+            assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, i);
+        }
+
+        // delivered as empty, not as no data
+        assert_eq!(data_reader.read_message().unwrap().unwrap(), (String::from("ch_0"), 0, Box::default()));
+        for _ in 0..2 {
+            assert_eq!(data_reader.read_message().unwrap().unwrap(), (String::from("ch_0"), 1, Box::default()));
+        }
+        assert_eq!(data_reader.read_bytes(), Ok(None));
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_eof() {
+        let now_ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        for delivery_guarantee in [DeliveryGuarantee::AtLeastOnce, DeliveryGuarantee::ExactlyOnce] {
+            let path = format!("/tmp/volga/rust/checkpoints/job-{now_ts}/test_reader_eof_{delivery_guarantee:?}.checkpoint");
+            let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+            let ch_1 = Channel::Local{channel_id: String::from("ch_1"), ipc_addr: String::from("ipc:///tmp/ipc_1")};
+            let config = DataReaderConfig{metrics_enabled: false, checkpoint_path: Some(path.clone()), delivery_guarantee, ..DataReaderConfig::new(10)};
+            let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), config, vec![ch_0, ch_1]).unwrap();
+            data_reader.start();
+            let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+            let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+            let eof = new_buffer_with_meta_and_flags(Box::default(), String::from("ch_0"), 2, 0, None, None, BUFFER_FLAG_EOF, None);
+
+            // EOF arrives ahead of data, it waits for its turn
+            recv_chan.0.send(eof).unwrap();
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![1]), String::from("ch_0"), 1, 0)).unwrap();
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![0]), String::from("ch_0"), 0, 0)).unwrap();
+            wait_until(|| data_reader.out_queue.lock().unwrap().len() >= 3);
+            assert!(data_reader.completed_channels().unwrap().is_empty());
+
+            // completes once everything before it was read
+            assert_eq!(*data_reader.read_bytes().unwrap().unwrap(), vec![0]);
+            assert_eq!(*data_reader.read_bytes().unwrap().unwrap(), vec![1]);
+            assert!(data_reader.completed_channels().unwrap().is_empty());
+            assert_eq!(data_reader.read_bytes(), Ok(None));
+            assert_eq!(data_reader.completed_channels(), Ok(vec![String::from("ch_0")]));
+
+            // EOF is acked like any buffer, so writer's queue is released
+            let acks: Vec<u64> = (0..3).map(|_| AckMessage::de(send_chan.1.recv().unwrap()).buffer_id).collect();
+            assert_eq!(acks, vec![0, 1, 2]);
+
+            data_reader.seek("ch_0", 1).unwrap();
+            assert!(data_reader.completed_channels().unwrap().is_empty());
+            data_reader.close();
+        }
+    }
+
+    #[test]
+    fn test_read_bytes_from() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_1 = Channel::Local{channel_id: String::from("ch_1"), ipc_addr: String::from("ipc:///tmp/ipc_1")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_0, ch_1]).unwrap();
+        data_reader.start();
+        let recv_chan_0 = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let recv_chan_1 = data_reader.get_recv_chan(&socket_meta("ch_1")).unwrap();
+        for i in 0..2 {
+            recv_chan_0.0.send(new_buffer_with_meta(Box::new(vec![i]), String::from("ch_0"), i as u64, 0)).unwrap();
+        }
+        recv_chan_1.0.send(new_buffer_with_meta(Box::new(vec![10]), String::from("ch_1"), 0, 0)).unwrap();
+        wait_until(|| data_reader.out_queue.lock().unwrap().len() >= 3);
+
+        // picked from behind other channel's entries, which keep their order
+        assert_eq!(*data_reader.read_bytes_from("ch_1").unwrap().unwrap(), vec![10]);
+        assert_eq!(data_reader.read_bytes_from("ch_1").unwrap(), None);
+        assert_eq!(data_reader.read_bytes_from("ch_2"), Err(NetworkError::UnknownChannel(String::from("ch_2"))));
+        assert_eq!(*data_reader.read_bytes_from("ch_0").unwrap().unwrap(), vec![0]);
+        assert_eq!(*data_reader.read_bytes().unwrap().unwrap(), vec![1]);
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_read_into() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![1, 2, 3]), String::from("ch_0"), 0, 0)).unwrap();
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![4]), String::from("ch_0"), 1, 0)).unwrap();
+        let mut dst = Vec::new();
+        let read = |dst: &mut Vec<u8>| wait_for(|| data_reader.read_into(dst).unwrap());
+
+        assert_eq!(read(&mut dst), 3);
+        assert_eq!(dst, vec![1, 2, 3]);
+        // empty dst had no storage to give back
+        assert!(data_reader.payload_pool.is_empty());
+        assert_eq!(read(&mut dst), 1);
+        assert_eq!(dst, vec![4]);
+        // previous payload's storage went to the pool and the next one is copied into it
+        assert_eq!(data_reader.payload_pool.len(), 1);
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![5, 6]), String::from("ch_0"), 2, 0)).unwrap();
+        wait_until(|| data_reader.payload_pool.is_empty());
+        let b = wait_for(|| data_reader.read_bytes().unwrap());
+        assert_eq!(*b, vec![5, 6]);
+        assert!(b.capacity() >= 3);
+        data_reader.recycle(*b);
+        assert_eq!(data_reader.payload_pool.len(), 1);
+        assert_eq!(data_reader.read_into(&mut dst), Ok(None));
+        assert_eq!(dst, vec![4]);
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_try_read_bytes() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![0]), String::from("ch_0"), 0, 0)).unwrap();
+        wait_until(|| !data_reader.out_queue.lock().unwrap().is_empty());
+
+        // does not wait for whoever holds out_queue
+        let locked_out_queue = data_reader.out_queue.lock().unwrap();
+        assert_eq!(data_reader.try_read_bytes(), Ok(None));
+        drop(locked_out_queue);
+        assert_eq!(*data_reader.try_read_bytes().unwrap().unwrap(), vec![0]);
+        assert_eq!(data_reader.try_read_bytes(), Ok(None));
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_expired_buffers() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let clock = MockClock::new();
+        let data_reader = DataReader::with_clock(String::from("test_reader"), String::from("test_job"), DataReaderConfig::new(10), vec![ch_0], clock.clone()).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        let now_ts = clock.unix_micros();
+        clock.advance(Duration::from_millis(1));
+        recv_chan.0.send(new_buffer_with_meta_and_flags(Box::new(vec![0]), String::from("ch_0"), 0, now_ts, Some(now_ts + 1000), None, 0, None)).unwrap();
+        recv_chan.0.send(new_expired_buffer(&new_buffer_with_meta(Box::new(vec![1]), String::from("ch_0"), 1, now_ts))).unwrap();
+        recv_chan.0.send(new_buffer_with_meta_and_flags(Box::new(vec![2]), String::from("ch_0"), 2, now_ts, Some(now_ts + 1001), None, 0, None)).unwrap();
+
+        // expired buffers are acked but not delivered
+        for i in 0..3 {
+            assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, i);
+        }
+        // consumer picks it up 3ms after dispatcher queued it
+        clock.advance(Duration::from_millis(3));
+        assert_eq!(data_reader.read_message().unwrap().unwrap(), (String::from("ch_0"), 2, Box::new(vec![2])));
+        assert!(data_reader.read_bytes().unwrap().is_none());
+        let stats = &data_reader.get_metrics_snapshot()["ch_0"];
+        assert_eq!(stats.num_expired, 2);
+        assert!((3000..3500).contains(&stats.out_queue_dwell_p50_micros));
+        data_reader.close();
+
+        // in ExactlyOnce a trailing expired buffer is acked once consumed watermark passes it, with nothing after it
+        let now_ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        let path = format!("/tmp/volga/rust/checkpoints/job-{now_ts}/test_expired_buffers.checkpoint");
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let config = DataReaderConfig{metrics_enabled: false, checkpoint_path: Some(path), delivery_guarantee: DeliveryGuarantee::ExactlyOnce, ..DataReaderConfig::new(10)};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), config, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![0]), String::from("ch_0"), 0, 0)).unwrap();
+        recv_chan.0.send(new_expired_buffer(&new_buffer_with_meta(Box::new(vec![1]), String::from("ch_0"), 1, 0))).unwrap();
+        wait_until(|| data_reader.out_queue.lock().unwrap().len() >= 2);
+        assert!(send_chan.1.try_recv().is_err());
+        assert_eq!(data_reader.read_bytes().unwrap(), Some(Box::new(vec![0])));
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 0);
+        assert!(send_chan.1.try_recv().is_err());
+        assert_eq!(data_reader.read_bytes().unwrap(), None);
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 1);
+        assert_eq!(data_reader.consumed_watermarks.read().unwrap()["ch_0"].load(Ordering::Relaxed), 1);
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_batched_acks() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let clock = MockClock::new();
+        let data_reader = DataReader::with_clock(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ack_strategy: AckStrategy::Batched, ack_batch_size: 3, ack_batch_delay_ms: 5, ..DataReaderConfig::new(10)}, vec![ch_0], clock.clone()).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        let recv_acks = || match ReaderMessage::de(send_chan.1.recv().unwrap()) {
+            ReaderMessage::AckBatch(acks) => acks.buffer_ids,
+            msg => panic!("Expected ack batch, got {:?}", msg)
+        };
+        let read = || wait_for(|| data_reader.read_bytes().unwrap());
+
+        // held until batch is full
+        for i in 0..4 {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![i as u8]), String::from("ch_0"), i, 0)).unwrap();
+        }
+        assert_eq!(recv_acks(), vec![0, 1, 2]);
+        for i in 0..4 {
+            assert_eq!(read(), Box::new(vec![i as u8]));
+        }
+        // or until the oldest one waited long enough
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(send_chan.1.try_recv().is_err());
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(recv_acks(), vec![3]);
+
+        // rest is sent on close
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![4]), String::from("ch_0"), 4, 0)).unwrap();
+        assert_eq!(read(), Box::new(vec![4]));
+        data_reader.close();
+        assert_eq!(recv_acks(), vec![4]);
+    }
+
+    #[test]
+    fn test_last_activity() {
+        let channels = vec![
+            Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")},
+            Channel::Local{channel_id: String::from("ch_1"), ipc_addr: String::from("ipc:///tmp/ipc_1")}
+        ];
+        let clock = MockClock::new();
+        let start = clock.now();
+        let data_reader = DataReader::with_clock(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, channels, clock.clone()).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        assert!(data_reader.last_activity().is_empty());
+
+        clock.advance(Duration::from_millis(10));
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![0]), String::from("ch_0"), 0, 0)).unwrap();
+        wait_until(|| data_reader.read_bytes().unwrap().is_some());
+        assert_eq!(data_reader.last_activity(), HashMap::from([(String::from("ch_0"), start + Duration::from_millis(10))]));
+
+        // received but stuck behind a gap, not activity
+        clock.advance(Duration::from_millis(10));
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![2]), String::from("ch_0"), 2, 0)).unwrap();
+        wait_until(|| !data_reader.gaps().is_empty());
+        assert_eq!(data_reader.last_activity()["ch_0"], start + Duration::from_millis(10));
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_poisoned_lock() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = Arc::new(DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap());
+        let this_data_reader = data_reader.clone();
+        let res = std::thread::spawn(move || {
+            let _locked_out_queue = this_data_reader.out_queue.lock().unwrap();
+            panic!("failed while holding out_queue lock");
+        }).join();
+        assert!(res.is_err());
+
+        // clean error instead of a panic
+        assert_eq!(data_reader.read_message(), Err(NetworkError::LockPoisoned(String::from("out_queue"))));
+        assert_eq!(data_reader.seek("ch_1", 0), Err(NetworkError::UnknownChannel(String::from("ch_1"))));
+        assert!(data_reader.health(DEFAULT_HEALTH_RECV_WINDOW_MS).dispatcher_error.is_none());
+    }
+
+    #[test]
+    fn test_close_timeout() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        data_reader.start();
+
+        // wedge dispatcher
+        let locked_out_queue = data_reader.out_queue.lock().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert_eq!(data_reader.close_timeout(50), Err(CloseError::Timeout));
+        drop(locked_out_queue);
+        assert_eq!(data_reader.close_timeout(5000), Ok(()));
+        assert!(!data_reader.health(DEFAULT_HEALTH_RECV_WINDOW_MS).dispatcher_alive);
+        assert_eq!(data_reader.close_timeout(0), Ok(()));
+    }
+
+    #[test]
+    fn test_start_close_order() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, dispatcher_threads: 2, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+
+        // never started, nothing to stop and writer is not told to shut down
+        data_reader.close();
+        assert!(!data_reader.is_running());
+        assert!(send_chan.1.is_empty());
+
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), data_reader.config.as_ref().clone(), vec![Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")}]).unwrap();
+        data_reader.start();
+        // does not spawn more dispatchers
+        data_reader.start();
+        assert!(data_reader.is_running());
+        assert_eq!(data_reader.dispatcher_thread_handles.len(), 2);
+        data_reader.close();
+        assert!(!data_reader.is_running());
+        // closed reader stays closed
+        data_reader.start();
+        assert!(!data_reader.is_running());
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_health() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_1 = Channel::Local{channel_id: String::from("ch_1"), ipc_addr: String::from("ipc:///tmp/ipc_1")};
+        let clock = MockClock::new();
+        let data_reader = DataReader::with_clock(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_0, ch_1], clock.clone()).unwrap();
+        assert!(!data_reader.health(DEFAULT_HEALTH_RECV_WINDOW_MS).is_healthy());
+
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![1]), String::from("ch_0"), 0, 0)).unwrap();
+        wait_until(|| data_reader.read_bytes().unwrap().is_some());
+        let health = data_reader.health(DEFAULT_HEALTH_RECV_WINDOW_MS);
+        assert!(health.is_healthy());
+        assert_eq!(health.channels_receiving, HashMap::from([(String::from("ch_0"), true), (String::from("ch_1"), false)]));
+
+        // idle
+        clock.advance(Duration::from_millis(20));
+        let health = data_reader.health(10);
+        assert!(health.is_healthy());
+        assert!(!health.channels_receiving["ch_0"]);
+
+        data_reader.close();
+        let health = data_reader.health(DEFAULT_HEALTH_RECV_WINDOW_MS);
+        assert!(!health.running);
+        assert!(!health.dispatcher_alive);
+    }
+
+    #[test]
+    fn test_dispatcher_failure() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        assert!(!data_reader.restart_dispatcher());
+        data_reader.start();
+        assert!(!data_reader.restart_dispatcher());
+
+        // dispatcher fails on shared lock poisoned elsewhere
+        let res = std::thread::scope(|scope| scope.spawn(|| {
+            let _locked_out_queue = data_reader.out_queue.lock().unwrap();
+            panic!("failed while holding out_queue lock");
+        }).join());
+        assert!(res.is_err());
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        wait_until(|| !data_reader.health(DEFAULT_HEALTH_RECV_WINDOW_MS).dispatcher_alive);
+        let health = data_reader.health(DEFAULT_HEALTH_RECV_WINDOW_MS);
+        assert!(health.running);
+        assert!(!health.is_healthy());
+        assert!(health.dispatcher_error.is_some());
+        assert_eq!(data_reader.get_dispatcher_error(), health.dispatcher_error);
+
+        assert!(data_reader.restart_dispatcher());
+        assert!(data_reader.health(DEFAULT_HEALTH_RECV_WINDOW_MS).is_healthy());
+        assert!(data_reader.get_dispatcher_error().is_none());
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![1, 2, 3]), String::from("ch_0"), 0, 0)).unwrap();
+        let b = wait_for(|| data_reader.read_bytes().unwrap());
+        assert_eq!(*b, vec![1, 2, 3]);
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_inspect_hook() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        let inspected = Arc::new(Mutex::new(Vec::new()));
+        let this_inspected = inspected.clone();
+        data_reader.set_inspect_hook(Some(Arc::new(move |channel: &Channel, b: &Bytes| {
+            this_inspected.lock().unwrap().push((channel.get_channel_id().clone(), get_buffer_id(Box::new(b.clone()))));
+        })), 0.5).unwrap();
+        assert_eq!(data_reader.set_inspect_hook(None, 0.0), Err(NetworkError::InvalidConfig(String::from("sample_rate must be in (0, 1], got 0"))));
+        assert!(data_reader.set_inspect_hook(None, f64::NAN).is_err());
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_and_read = |buffer_ids: std::ops::Range<u64>| {
+            for buffer_id in buffer_ids {
+                recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+                wait_until(|| data_reader.read_bytes().unwrap().is_some());
+            }
+        };
+
+        // every other buffer
+        send_and_read(0..4);
+        assert_eq!(*inspected.lock().unwrap(), vec![(String::from("ch_0"), 0), (String::from("ch_0"), 2)]);
+
+        data_reader.set_inspect_hook(None, 1.0).unwrap();
+        send_and_read(4..6);
+        assert_eq!(inspected.lock().unwrap().len(), 2);
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_overflow_handler() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig::new(2), vec![ch_0]).unwrap();
+        let overflowed = Arc::new(Mutex::new(Vec::new()));
+        let this_overflowed = overflowed.clone();
+        data_reader.set_overflow_handler(Some(Arc::new(move |b: Box<Bytes>| {
+            this_overflowed.lock().unwrap().push(get_buffer_id(b));
+        }))).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+
+        // out_queue takes 2, the rest goes to handler
+        for buffer_id in 0..5 {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        }
+        wait_until(|| overflowed.lock().unwrap().len() >= 3);
+        assert_eq!(*overflowed.lock().unwrap(), vec![2, 3, 4]);
+        assert_eq!(data_reader.get_metrics_snapshot()["ch_0"].num_overflowed, 3);
+
+        // handler owns overflowed ones, they are acked and watermark moves past them once there is room
+        let mut acked = Vec::new();
+        while acked.len() < 5 {
+            let b = wait_for(|| send_chan.1.try_recv().ok());
+            acked.push(AckMessage::de(b).buffer_id);
+        }
+        acked.sort();
+        assert_eq!(acked, vec![0, 1, 2, 3, 4]);
+        let mut read = Vec::new();
+        while read.len() < 2 {
+            let b = wait_for(|| data_reader.read_bytes().unwrap());
+            read.push(b[0]);
+        }
+        // a resent one is a duplicate, re-acked and not handed over again
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![2]), String::from("ch_0"), 2, 0)).unwrap();
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![5]), String::from("ch_0"), 5, 0)).unwrap();
+        while read.len() < 3 {
+            let b = wait_for(|| data_reader.read_bytes().unwrap());
+            read.push(b[0]);
+        }
+        assert_eq!(read, vec![0, 1, 5]);
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 2);
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 5);
+        assert_eq!(overflowed.lock().unwrap().len(), 3);
+        data_reader.set_overflow_handler(None).unwrap();
+        data_reader.close();
+
+        let config = DataReaderConfig{manual_commit: true, ..DataReaderConfig::new(2)};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), config, vec![]).unwrap();
+        assert!(matches!(data_reader.set_overflow_handler(Some(Arc::new(|_| {}))), Err(NetworkError::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_full_queue_policy() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let new_reader = |full_queue_policy| DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{full_queue_policy, ..DataReaderConfig::new(2)}, vec![ch_0.clone()]).unwrap();
+
+        // out_queue takes 2, older ones make room for newer ones
+        let data_reader = new_reader(FullQueuePolicy::DropOldest);
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        for buffer_id in 0..5 {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        }
+        wait_until(|| data_reader.get_metrics_snapshot().get("ch_0").map_or(0, |stats| stats.num_evicted) >= 3);
+        let mut read = Vec::new();
+        while read.len() < 2 {
+            let b = wait_for(|| data_reader.read_bytes().unwrap());
+            read.push(b[0]);
+        }
+        assert_eq!(read, vec![3, 4]);
+        // evicted ones were acked when delivered
+        let mut acked = Vec::new();
+        while acked.len() < 5 {
+            let b = wait_for(|| send_chan.1.try_recv().ok());
+            acked.push(AckMessage::de(b).buffer_id);
+        }
+        assert_eq!(acked, (0..5).collect::<Vec<u64>>());
+        data_reader.close();
+
+        // dispatcher waits for reads and loses nothing
+        let data_reader = new_reader(FullQueuePolicy::Block);
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let _send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        for buffer_id in 0..5 {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        }
+        wait_until(|| data_reader.out_queue.lock().unwrap().len() >= 2);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(recv_chan.1.len(), 3);
+        let mut read = Vec::new();
+        while read.len() < 5 {
+            let b = wait_for(|| data_reader.read_bytes().unwrap());
+            read.push(b[0]);
+        }
+        assert_eq!(read, vec![0, 1, 2, 3, 4]);
+        assert_eq!(data_reader.get_metrics_snapshot()["ch_0"].num_evicted, 0);
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_channel_failure() {
+        let channels = vec![
+            Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")},
+            Channel::Local{channel_id: String::from("ch_1"), ipc_addr: String::from("ipc:///tmp/ipc_1")}
+        ];
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, decode_error_policy: DecodeErrorPolicy::Fail, ..DataReaderConfig::new(10)}, channels).unwrap();
+        data_reader.start();
+        let recv_chan_0 = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let recv_chan_1 = data_reader.get_recv_chan(&socket_meta("ch_1")).unwrap();
+        let read = || {
+            {
+                let (channel_id, _, b) = wait_for(|| data_reader.read_message().unwrap());
+                (channel_id, *b)
+            }
+        };
+
+        // malformed buffer fails ch_0, ch_1 is still dispatched
+        recv_chan_0.0.send(Box::new(vec![1])).unwrap();
+        wait_until(|| !data_reader.health(DEFAULT_HEALTH_RECV_WINDOW_MS).channels_failed.is_empty());
+        recv_chan_0.0.send(new_buffer_with_meta(Box::new(vec![0]), String::from("ch_0"), 0, 0)).unwrap();
+        recv_chan_1.0.send(new_buffer_with_meta(Box::new(vec![1]), String::from("ch_1"), 0, 0)).unwrap();
+        assert_eq!(read(), (String::from("ch_1"), vec![1]));
+        let health = data_reader.health(DEFAULT_HEALTH_RECV_WINDOW_MS);
+        assert!(health.is_healthy());
+        assert!(health.dispatcher_error.is_none());
+        assert_eq!(health.channels_failed.keys().collect::<Vec<_>>(), vec!["ch_0"]);
+
+        // resumed channel picks up what was queued meanwhile
+        assert!(data_reader.restart_dispatcher());
+        assert_eq!(read(), (String::from("ch_0"), vec![0]));
+        assert!(data_reader.health(DEFAULT_HEALTH_RECV_WINDOW_MS).channels_failed.is_empty());
+        assert!(!data_reader.restart_dispatcher());
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_decode_error_skip() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig::new(10), vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+
+        // truncated meta, then a valid buffer whose meta is cut off after the channel id
+        recv_chan.0.send(Box::new(vec![1])).unwrap();
+        let mut truncated = *new_buffer_with_meta(Box::new(vec![0]), String::from("ch_0"), 0, 0);
+        truncated.truncate(truncated.len() - 3);
+        recv_chan.0.send(Box::new(truncated)).unwrap();
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![0]), String::from("ch_0"), 0, 0)).unwrap();
+        let b = wait_for(|| data_reader.read_bytes().unwrap());
+        assert_eq!(*b, vec![0]);
+        assert_eq!(data_reader.get_metrics_snapshot()["ch_0"].num_decode_errors, 2);
+        let health = data_reader.health(DEFAULT_HEALTH_RECV_WINDOW_MS);
+        assert!(health.channels_failed.is_empty());
+        assert!(health.dispatcher_error.is_none());
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_idle_channel_polling() {
+        let config = DataReaderConfig{metrics_enabled: false, max_idle_backoff_micros: 0, idle_channel_misses: 4, idle_channel_poll_every: 100, ..DataReaderConfig::new(10)};
+        assert!(!config.skip_idle_channel(3, 1));
+        assert!(config.skip_idle_channel(4, 1));
+        assert!(!config.skip_idle_channel(4, 200));
+
+        let channels = vec![
+            Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")},
+            Channel::Local{channel_id: String::from("ch_1"), ipc_addr: String::from("ipc:///tmp/ipc_1")}
+        ];
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), config, channels).unwrap();
+        data_reader.start();
+        let recv_chan_0 = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let recv_chan_1 = data_reader.get_recv_chan(&socket_meta("ch_1")).unwrap();
+        let read = || {
+            {
+                let (channel_id, _, b) = wait_for(|| data_reader.read_message().unwrap());
+                (channel_id, *b)
+            }
+        };
+        // both go idle
+        thread::sleep(Duration::from_millis(50));
+
+        // active channel is back to full rate polling and not starved by idle one
+        for buffer_id in 0..100 {
+            recv_chan_1.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_1"), buffer_id, 0)).unwrap();
+            assert_eq!(read(), (String::from("ch_1"), vec![buffer_id as u8]));
+        }
+        // idle channel is still polled, just less often
+        recv_chan_0.0.send(new_buffer_with_meta(Box::new(vec![0]), String::from("ch_0"), 0, 0)).unwrap();
+        assert_eq!(read(), (String::from("ch_0"), vec![0]));
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_out_queue_ring() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, out_queue_ring: true, ..DataReaderConfig::new(4)}, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        for buffer_id in 0..6 {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        }
+
+        // limit covers entries handed off to ring
+        wait_until(|| data_reader.available_capacity().unwrap() == 0);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(data_reader.ring.as_ref().unwrap().len(), 4);
+        assert_eq!(data_reader.out_queue.lock().unwrap().len(), 0);
+        assert!(matches!(data_reader.read_bytes_from("ch_0"), Err(NetworkError::Unsupported(_))));
+
+        let mut read = Vec::new();
+        while read.len() < 6 {
+            let b = wait_for(|| data_reader.try_read_bytes().unwrap());
+            read.push(b[0]);
+        }
+        assert_eq!(read, (0..6).collect::<Vec<u8>>());
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_queue_depth_sampling() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig::new(4), vec![ch_0]).unwrap();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        for buffer_id in 0..3 {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        }
+        // not dispatched yet
+        data_reader.metrics_recorder.sample_now();
+        assert_eq!(data_reader.get_metrics_snapshot()["ch_0"].in_queue_depth_p50, 3);
+        assert_eq!(data_reader.metrics_recorder.job_totals().out_queue_depth_p50, 0);
+
+        data_reader.start();
+        wait_until(|| data_reader.available_capacity().unwrap() == 1);
+        data_reader.metrics_recorder.reset();
+        data_reader.metrics_recorder.sample_now();
+        assert_eq!(data_reader.get_metrics_snapshot()["ch_0"].in_queue_depth_p50, 0);
+        assert_eq!(data_reader.metrics_recorder.job_totals().out_queue_depth_p50, 3);
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_output_receiver() {
+        let channels = vec![
+            Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")},
+            Channel::Local{channel_id: String::from("ch_1"), ipc_addr: String::from("ipc:///tmp/ipc_1")}
+        ];
+        let config = DataReaderConfig{metrics_enabled: false, dispatcher_threads: 2, output_chan: true, ..DataReaderConfig::new(4)};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), config, channels).unwrap();
+        data_reader.start();
+        let output = data_reader.output_receiver().unwrap();
+        for channel_id in ["ch_0", "ch_1"] {
+            let recv_chan = data_reader.get_recv_chan(&socket_meta(channel_id)).unwrap();
+            for buffer_id in 0..3 {
+                recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), channel_id.to_string(), buffer_id, 0)).unwrap();
+            }
+            recv_chan.0.send(new_buffer_with_meta_and_flags(Box::default(), channel_id.to_string(), 3, 0, None, None, BUFFER_FLAG_EOF, None)).unwrap();
+        }
+
+        // limit covers what is handed off, the rest waits in out_queue
+        wait_until(|| output.len() == 4);
+        assert_eq!(data_reader.available_capacity().unwrap(), 0);
+        assert!(matches!(data_reader.read_message(), Err(NetworkError::Unsupported(_))));
+
+        // selected together with consumer's own channel, EOF markers come after their channel's payloads
+        let (stop_sender, stop_receiver) = bounded::<()>(1);
+        let mut read = Vec::new();
+        let mut markers = Vec::new();
+        while markers.len() < 2 {
+            crossbeam::select! {
+                recv(output) -> output => match output.unwrap() {
+                    Output::Payload(b) => read.push(b[0]),
+                    Output::Eof(marker) => {
+                        assert!(read.len() >= 3 * (markers.len() + 1));
+                        markers.push(marker);
+                    }
+                },
+                recv(stop_receiver) -> _ => unreachable!(),
+                default(TEST_WAIT_TIMEOUT) => panic!("timed out after {TEST_WAIT_TIMEOUT:?}")
+            }
+        }
+        drop(stop_sender);
+        read.sort();
+        assert_eq!(read, vec![0, 0, 1, 1, 2, 2]);
+        // handed off is not completed, consumer has to drop the marker
+        assert!(data_reader.completed_channels().unwrap().is_empty());
+        let mut channel_ids: Vec<String> = markers.iter().map(|marker| marker.channel_id().to_string()).collect();
+        channel_ids.sort();
+        drop(markers);
+        assert_eq!(data_reader.completed_channels().unwrap(), channel_ids);
+        assert_eq!(data_reader.read_bytes().unwrap(), None);
+        data_reader.close();
+
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(4)}, vec![]).unwrap();
+        assert!(matches!(data_reader.output_receiver(), Err(NetworkError::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_sharded_dispatchers() {
+        let channel_ids: Vec<String> = (0..8).map(|i| format!("ch_{i}")).collect();
+        let channels = channel_ids.iter().map(|channel_id| Channel::Local{channel_id: channel_id.clone(), ipc_addr: format!("ipc:///tmp/ipc_{channel_id}")}).collect();
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, dispatcher_threads: 3, ..DataReaderConfig::new(100)}, channels).unwrap();
+        data_reader.start();
+        assert_eq!(data_reader.dispatcher_thread_handles.len(), 3);
+        assert!(data_reader.health(DEFAULT_HEALTH_RECV_WINDOW_MS).dispatcher_alive);
+
+        for channel_id in &channel_ids {
+            let recv_chan = data_reader.get_recv_chan(&socket_meta(channel_id)).unwrap();
+            for buffer_id in 0..3 {
+                recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), channel_id.clone(), buffer_id, 0)).unwrap();
+            }
+        }
+
+        // order is kept within each channel
+        let mut received: HashMap<String, Vec<u64>> = HashMap::new();
+        for _ in 0..channel_ids.len() * 3 {
+            let (channel_id, buffer_id, _) = wait_for(|| data_reader.read_message().unwrap());
+            received.entry(channel_id).or_default().push(buffer_id);
+        }
+        for channel_id in &channel_ids {
+            assert_eq!(received[channel_id], vec![0, 1, 2]);
+        }
+
+        data_reader.close();
+        assert!(data_reader.dispatcher_thread_handles.is_empty());
+        assert!(!data_reader.health(DEFAULT_HEALTH_RECV_WINDOW_MS).dispatcher_alive);
+    }
+
+    #[test]
+    fn test_unordered_channel() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ordered = HashMap::from([(String::from("ch_0"), false)]);
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ordered, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+
+        // delivered and acked in arrival order, without waiting for buffer 0
+        for buffer_id in [2, 1, 2] {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        }
+        for buffer_id in [2, 1, 2] {
+            assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, buffer_id);
+        }
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            let (_, buffer_id, _) = wait_for(|| data_reader.read_message().unwrap());
+            received.push(buffer_id);
+        }
+        // duplicate is not dropped
+        assert_eq!(received, vec![2, 1, 2]);
+        assert_eq!(data_reader.watermarks.read().unwrap()["ch_0"].load(Ordering::Relaxed), -1);
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_at_most_once_channel() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let at_most_once = HashMap::from([(String::from("ch_0"), true)]);
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, at_most_once, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+
+        // delivered in arrival order, gaps and duplicates included
+        for buffer_id in [2, 0, 2, 5] {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        }
+        let mut received = Vec::new();
+        while received.len() < 4 {
+            let (_, buffer_id, _) = wait_for(|| data_reader.read_message().unwrap());
+            received.push(buffer_id);
+        }
+        assert_eq!(received, vec![2, 0, 2, 5]);
+        // no watermark kept and nothing acked
+        assert_eq!(data_reader.watermarks.read().unwrap()["ch_0"].load(Ordering::Relaxed), -1);
+        data_reader.close();
+        assert!(send_chan.1.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_priority_buffer() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        let send = |buffer_id: u64, flags: u8| {
+            recv_chan.0.send(new_buffer_with_meta_and_flags(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0, None, Some(100), flags, None)).unwrap();
+        };
+
+        // priority buffer is delivered and acked without waiting for buffer 0, regular one waits
+        send(2, 0);
+        send(1, BUFFER_FLAG_PRIORITY);
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 1);
+        let read = || {
+            let message = wait_for(|| data_reader.read_message().unwrap());
+            message.1
+        };
+        assert_eq!(read(), 1);
+        // does not claim event time of missing buffer 0
+        assert_eq!(data_reader.current_event_time_watermark(), Ok(None));
+        assert_eq!(data_reader.gaps()["ch_0"], vec![1, 2]);
+
+        // watermark passes priority buffer without delivering it again
+        send(0, 0);
+        assert_eq!(read(), 0);
+        assert_eq!(read(), 2);
+        assert_eq!(data_reader.watermarks.read().unwrap()["ch_0"].load(Ordering::Relaxed), 2);
+        assert_eq!(data_reader.read_message(), Ok(None));
+        let acks: Vec<u64> = (0..2).map(|_| AckMessage::de(send_chan.1.recv().unwrap()).buffer_id).collect();
+        assert_eq!(acks, vec![0, 2]);
+
+        // resent priority buffer is a duplicate
+        send(1, BUFFER_FLAG_PRIORITY);
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 1);
+        assert_eq!(data_reader.read_message(), Ok(None));
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_fragments() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_1 = Channel::Local{channel_id: String::from("ch_1"), ipc_addr: String::from("ipc:///tmp/ipc_1")};
+        let ordered = HashMap::from([(String::from("ch_1"), false)]);
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ordered, ..DataReaderConfig::new(10)}, vec![ch_0, ch_1]).unwrap();
+        data_reader.start();
+        let payload: Vec<u8> = (0..4 * 1024 * 1024 + 7).map(|i| (i % 251) as u8).collect();
+        let fragments = split_fragments(&payload, 1024 * 1024);
+        let send = |channel_id: &str, buffer_id: u64, b: Box<Bytes>, flags: u8| {
+            let recv_chan = data_reader.get_recv_chan(&socket_meta(channel_id)).unwrap();
+            recv_chan.0.send(new_buffer_with_meta_and_flags(b, channel_id.to_string(), buffer_id, 0, None, None, flags, None)).unwrap();
+        };
+        let read = || wait_for(|| data_reader.read_message().unwrap());
+
+        // ordered channel: buffer 0 is a regular one, message is released once all its fragments are in
+        for (i, fragment) in fragments.iter().enumerate().rev() {
+            send("ch_0", i as u64 + 1, fragment.clone(), BUFFER_FLAG_FRAGMENT);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert_eq!(data_reader.read_message(), Ok(None));
+        send("ch_0", 0, Box::new(vec![0]), 0);
+        assert_eq!(read(), (String::from("ch_0"), 0, Box::new(vec![0])));
+        assert_eq!(read(), (String::from("ch_0"), 5, Box::new(payload.clone())));
+
+        // unordered channel reassembles in any arrival order
+        for i in [2, 0, 4, 1, 3] {
+            send("ch_1", i + 10, fragments[i as usize].clone(), BUFFER_FLAG_FRAGMENT);
+        }
+        assert_eq!(read(), (String::from("ch_1"), 13, Box::new(payload)));
+        assert_eq!(data_reader.read_message(), Ok(None));
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_gaps() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_1 = Channel::Local{channel_id: String::from("ch_1"), ipc_addr: String::from("ipc:///tmp/ipc_1")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_0, ch_1]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+        for buffer_id in [0, 3, 2] {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        }
+        // only buffer 0 is delivered and acked, 1 is missing
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 0);
+        wait_until(|| data_reader.gaps()["ch_0"].len() >= 2);
+        assert_eq!(data_reader.gaps(), HashMap::from([(String::from("ch_0"), vec![2, 3]), (String::from("ch_1"), vec![])]));
+
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![1]), String::from("ch_0"), 1, 0)).unwrap();
+        for buffer_id in 1..4 {
+            assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, buffer_id);
+        }
+        // ack is sent before buffer is removed from out_of_order
+        wait_until(|| data_reader.gaps()["ch_0"].is_empty());
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_out_of_order_bytes_limit() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let b = |buffer_id: u64, size: usize| new_buffer_with_meta(Box::new(vec![0; size]), String::from("ch_0"), buffer_id, 0);
+        // fits buffers 1 and 2, but not 3
+        let max_bytes = b(1, 100).len() + b(2, 10).len() + b(3, 100).len() - 1;
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{max_out_of_order_bytes: Some(max_bytes), ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        for (buffer_id, size) in [(1, 100), (2, 10), (3, 100)] {
+            recv_chan.0.send(b(buffer_id, size)).unwrap();
+        }
+        wait_until(|| data_reader.get_metrics_snapshot().get("ch_0").map_or(0, |stats| stats.num_dropped_mem) != 0);
+        assert_eq!(data_reader.gaps()["ch_0"], vec![1, 2]);
+        assert_eq!(data_reader.get_metrics_snapshot()["ch_0"].out_of_order_bytes, (b(1, 100).len() + b(2, 10).len()) as u64);
+
+        // next expected buffer is taken over the limit and releases held ones
+        recv_chan.0.send(b(0, 1000)).unwrap();
+        for _ in 0..3 {
+            wait_until(|| data_reader.read_bytes().unwrap().is_some());
+        }
+        recv_chan.0.send(b(3, 100)).unwrap();
+        wait_until(|| data_reader.read_bytes().unwrap().is_some());
+        assert_eq!(data_reader.get_metrics_snapshot()["ch_0"].out_of_order_bytes, 0);
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_out_of_order_ring() {
+        let mut held = OutOfOrder::new(4);
+        assert!(held.fits(4, 0) && !held.fits(5, 0));
+        held.insert(2, Box::new(vec![2]));
+        held.insert(4, Box::new(vec![4, 4]));
+        held.insert(4, Box::new(vec![4]));
+        assert_eq!((held.len(), held.num_bytes()), (2, 2));
+        // 8 shares the slot of 4 but is not held
+        assert!(held.contains_key(&4) && !held.contains_key(&8));
+        assert_eq!(held.remove(&8), None);
+        let mut keys: Vec<i64> = held.keys().collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec![2, 4]);
+        assert_eq!(held.remove(&2), Some(Box::new(vec![2])));
+        assert_eq!((held.len(), held.num_bytes()), (1, 1));
+        held.clear();
+        assert_eq!((held.len(), held.num_bytes(), held.get(&4)), (0, 0, None));
+    }
+
+    #[test]
+    fn test_out_of_order_slots() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{out_of_order_slots: 4, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        // 5 is past the window while watermark is at -1
+        for buffer_id in [3, 1, 5] {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        }
+        wait_until(|| data_reader.get_metrics_snapshot().get("ch_0").map_or(0, |stats| stats.num_dropped_full) != 0);
+        assert_eq!(data_reader.gaps()["ch_0"], vec![1, 3]);
+
+        // fits once watermark moved
+        for buffer_id in [0, 2, 5, 4] {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        }
+        let mut read = Vec::new();
+        while read.len() < 6 {
+            let b = wait_for(|| data_reader.read_bytes().unwrap());
+            read.push(b[0]);
+        }
+        assert_eq!(read, (0..6).collect::<Vec<u8>>());
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_available_capacity() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, output_queue_full_threshold: 0.5, ..DataReaderConfig::new(10)}, vec![ch_0]).unwrap();
+        assert_eq!(data_reader.available_capacity(), Ok(5));
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        for buffer_id in 0..8 {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        }
+        wait_until(|| data_reader.available_capacity().unwrap() == 0);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        // dispatcher stops at threshold
+        assert_eq!(data_reader.out_queue.lock().unwrap().len(), 5);
+
+        assert!(data_reader.read_bytes().unwrap().is_some());
+        for _ in 0..7 {
+            wait_until(|| data_reader.read_bytes().unwrap().is_some());
+        }
+        assert_eq!(data_reader.available_capacity(), Ok(5));
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_prefetch() {
+        // (prefetch, prefetch_max_bytes) -> buffers in first batch
+        for (i, (prefetch, prefetch_max_bytes, expected)) in [(0, DEFAULT_PREFETCH_MAX_BYTES, 2), (4, DEFAULT_PREFETCH_MAX_BYTES, 6), (4, 15, 3)].into_iter().enumerate() {
+            let channel_id = format!("ch_{i}");
+            let ch = Channel::Local{channel_id: channel_id.clone(), ipc_addr: format!("ipc:///tmp/ipc_{i}")};
+            let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{prefetch, prefetch_max_bytes, ..DataReaderConfig::new(2)}, vec![ch]).unwrap();
+            data_reader.start();
+            let recv_chan = data_reader.get_recv_chan(&socket_meta(&channel_id)).unwrap();
+            // held out-of-order until 0 arrives, then drained at once
+            for buffer_id in (1..6).chain(0..1) {
+                recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8; 10]), channel_id.clone(), buffer_id, 0)).unwrap();
+            }
+            let batch = wait_for(|| Some(data_reader.read_batch(10).unwrap()).filter(|batch| !batch.is_empty()));
+            assert_eq!(batch.len(), expected);
+            assert_eq!(batch.iter().map(|b| b[0]).collect::<Vec<_>>(), (0..expected as u8).collect::<Vec<_>>());
+
+            // the rest waits for channel's next receive
+            let num_empty = data_reader.get_job_totals().num_empty_read_batches;
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            assert!(data_reader.read_batch(10).unwrap().is_empty());
+            assert_eq!(data_reader.get_job_totals().num_empty_read_batches, num_empty + 1);
+            data_reader.close();
+        }
+    }
+
+    #[test]
+    fn test_event_time_watermark() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_1 = Channel::Local{channel_id: String::from("ch_1"), ipc_addr: String::from("ipc:///tmp/ipc_1")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}, vec![ch_0, ch_1]).unwrap();
+        data_reader.start();
+        let send = |channel_id: &str, buffer_id: u64, event_time_wm: u64, b: Box<Bytes>, flags: u8| {
+            let recv_chan = data_reader.get_recv_chan(&socket_meta(channel_id)).unwrap();
+            recv_chan.0.send(new_buffer_with_meta_and_flags(b, channel_id.to_string(), buffer_id, 0, None, Some(event_time_wm), flags, None)).unwrap();
+        };
+        let read = || wait_for(|| data_reader.read_message().unwrap());
+
+        send("ch_0", 0, 100, pack_batch(&[vec![0], vec![1]]), BUFFER_FLAG_BATCH);
+        // not reached until last entry of the batch is consumed
+        read();
+        assert_eq!(data_reader.current_event_time_watermark(), Ok(None));
+        read();
+        // ch_1 has not reported yet
+        assert_eq!(data_reader.current_event_time_watermark(), Ok(None));
+
+        send("ch_1", 0, 50, Box::new(vec![2]), 0);
+        read();
+        assert_eq!(data_reader.current_event_time_watermark(), Ok(Some(50)));
+        send("ch_1", 1, 200, Box::new(vec![3]), 0);
+        read();
+        assert_eq!(data_reader.current_event_time_watermark(), Ok(Some(100)));
+
+        // sequence watermarks are unaffected
+        assert_eq!(data_reader.watermarks.read().unwrap()["ch_1"].load(Ordering::Relaxed), 1);
+        data_reader.close();
+    }
+
+    #[test]
+    fn test_send_ack_closed_chan() {
+        let metrics_recorder = Arc::new(MetricsRecorder::new(String::from("test_reader"), String::from("test_job"), DEFAULT_FLUSH_INTERVAL_MS));
+        let (sender, receiver) = unbounded();
+        drop(receiver);
+        DataReader::<SystemClock>::send_ack(&String::from("ch_0"), 0, sender, metrics_recorder.clone());
+        let stats = &metrics_recorder.snapshot()["ch_0"];
+        assert_eq!(stats.num_acks_dropped, 1);
+        assert_eq!(stats.num_bytes_sent, 0);
+    }
+
+    #[test]
+    fn test_bounded_ack_chan() {
+        assert_eq!(DataReaderConfig{metrics_enabled: false, ack_chan_capacity: Some(0), ..DataReaderConfig::new(100)}.validate().err(), Some(String::from("ack_chan_capacity must be greater than 0")));
+
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let data_reader = DataReader::new(String::from("test_reader"), String::from("test_job"), DataReaderConfig{ack_chan_capacity: Some(4), ..DataReaderConfig::new(100)}, vec![ch_0]).unwrap();
+        data_reader.start();
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        let send_chan = data_reader.get_send_chan(&socket_meta("ch_0")).unwrap();
+
+        // nobody drains acks, dispatcher keeps delivering
+        for buffer_id in 0..50 {
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+        }
+        for _ in 0..50 {
+            wait_until(|| data_reader.read_message().unwrap().is_some());
+        }
+        assert_eq!(send_chan.1.len(), 4);
+        assert_eq!(data_reader.get_metrics_snapshot()["ch_0"].num_acks_dropped, 46);
+
+        // resent buffer is re-acked once there is room
+        while send_chan.1.try_recv().is_ok() {}
+        recv_chan.0.send(new_buffer_with_meta(Box::new(vec![10]), String::from("ch_0"), 10, 0)).unwrap();
+        assert_eq!(AckMessage::de(send_chan.1.recv().unwrap()).buffer_id, 10);
+        data_reader.close();
+    }
+
+    // utime + stime of this process' thread, in clock ticks
+    #[cfg(target_os = "linux")]
+    fn thread_cpu_ticks(comm: &str) -> Option<u64> {
+        std::fs::read_dir("/proc/self/task").unwrap().find_map(|task| {
+            let path = task.unwrap().path();
+            if std::fs::read_to_string(path.join("comm")).ok()?.trim() != comm {
+                return None;
+            }
+            let stat = std::fs::read_to_string(path.join("stat")).ok()?;
+            // fields after comm, which may contain spaces; utime and stime are fields 14 and 15
+            let fields: Vec<&str> = stat[stat.rfind(')')? + 2..].split(' ').collect();
+            Some(fields[11].parse::<u64>().ok()? + fields[12].parse::<u64>().ok()?)
+        })
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_idle_backoff() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let config = DataReaderConfig{metrics_enabled: false, max_idle_backoff_micros: 1_000_000, ..DataReaderConfig::new(10)};
+        let data_reader = DataReader::new(String::from("idle"), String::from("test_job"), config, vec![ch_0]).unwrap();
+        data_reader.start();
+        // thread names are truncated to 15 bytes
+        let comm = "volga_idle_disp";
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let start_ticks = thread_cpu_ticks(comm).unwrap();
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let idle_ticks = thread_cpu_ticks(comm).unwrap() - start_ticks;
+        // ticks are usually 10ms, a spinning dispatcher would take ~100
+        assert!(idle_ticks < 10, "dispatcher used {idle_ticks} ticks while idle");
+
+        // backed off to the max by now, still a receive is picked up without waiting it out, and backoff is reset
+        let recv_chan = data_reader.get_recv_chan(&socket_meta("ch_0")).unwrap();
+        for buffer_id in 0..2 {
+            let sent_at = Instant::now();
+            recv_chan.0.send(new_buffer_with_meta(Box::new(vec![buffer_id as u8]), String::from("ch_0"), buffer_id, 0)).unwrap();
+            wait_until(|| data_reader.read_bytes().unwrap().is_some());
+            assert!(sent_at.elapsed() < Duration::from_millis(200), "delivered after {:?}", sent_at.elapsed());
+        }
+        data_reader.close();
+    }
+}
@@ -1,44 +1,260 @@
-use std::{collections::{HashMap, VecDeque}, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, RwLock}, thread::{self, JoinHandle}, time::{Duration, SystemTime}};
+use std::{collections::{HashMap, VecDeque}, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, RwLock}, thread::{self, JoinHandle}, time::{Duration, Instant, SystemTime}};
 
-use super::{buffer_queues::{BufferQueues}, buffer_utils::get_buffer_id, channel::{AckMessage, Channel}, io_loop::{IOHandler, IOHandlerType}, metrics::{MetricsRecorder, NUM_BUFFERS_RECVD, NUM_BUFFERS_RESENT, NUM_BUFFERS_SENT, NUM_BYTES_RECVD, NUM_BYTES_SENT}, sockets::SocketMetadata};
+use super::{buffer_queues::{BufferQueues}, buffer_utils::{get_buffer_id, pack_batch, split_fragments, BUFFER_FLAG_BATCH, BUFFER_FLAG_EOF, BUFFER_FLAG_PRIORITY, MAX_FRAGMENTED_MESSAGE_SIZE, MIN_FRAGMENT_SIZE}, channel::{in_memory_addr, validate_channels, Channel, ReaderMessage}, io_loop::{BytesChan, IOHandler, IOHandlerType}, partitioner::{Partitioner, PartitionerType}, rate_limiter::RateLimit, error::{poisoned, DecodeErrorPolicy, NetworkError, NetworkResult}, metrics::{default_metrics_enabled, default_metrics_flush_interval_ms, ChannelStats, JobStats, MetricsRecorder, NUM_BUFFERS_RECVD, NUM_BUFFERS_RESENT, NUM_BUFFERS_SENT, NUM_BYTES_RECVD, NUM_BYTES_SENT, NUM_DECODE_ERRORS, NUM_EXPIRED, NUM_RETRANSMITS, THROTTLED_MICROS}, sockets::{normalize_ipc_addr, SocketMetadata}, trace::buffer_span};
 use super::io_loop::Bytes;
-use crossbeam::{channel::{bounded, Receiver, Sender}, queue::ArrayQueue};
-use pyo3::{pyclass, pymethods};
+use crossbeam::{channel::bounded, queue::ArrayQueue};
+use pyo3::{exceptions::{PyTypeError, PyValueError}, pyclass, pymethods, types::PyDict, PyAny, PyResult};
 use serde::{Deserialize, Serialize};
 
 // const IN_FLIGHT_TIMEOUT_S: usize = 1; // how long to wait before re-sending un-acked buffers
 
+pub const DEFAULT_BUFFER_BATCH_LINGER_MS: u64 = 10;
+pub const DEFAULT_CLOSE_LINGER_MS: u64 = 1000;
+
+fn default_buffer_batch_size() -> usize {
+    1
+}
+
+fn default_buffer_batch_linger_ms() -> u64 {
+    DEFAULT_BUFFER_BATCH_LINGER_MS
+}
+
+fn default_close_linger_ms() -> u64 {
+    DEFAULT_CLOSE_LINGER_MS
+}
+
+type PendingBatches = RwLock<HashMap<String, Arc<Mutex<PendingBatch>>>>;
+
+// buffers written to a channel but not yet packed into a single queued buffer
+#[derive(Default)]
+struct PendingBatch {
+    bs: Vec<Bytes>,
+    expire_tss: Vec<Option<u64>>,
+    num_bytes: usize,
+    first_write_ts: u128
+}
+
+impl PendingBatch {
+    fn push(&mut self, b: Bytes, expire_ts_micros: Option<u64>) {
+        if self.bs.is_empty() {
+            self.first_write_ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        }
+        self.num_bytes += b.len();
+        self.bs.push(b);
+        self.expire_tss.push(expire_ts_micros);
+    }
+
+    fn pop(&mut self) {
+        if let Some(b) = self.bs.pop() {
+            self.num_bytes -= b.len();
+            self.expire_tss.pop();
+        }
+    }
+
+    fn is_full(&self, config: &DataWriterConfig) -> bool {
+        self.bs.len() >= config.buffer_batch_size || (config.buffer_batch_max_bytes != 0 && self.num_bytes >= config.buffer_batch_max_bytes)
+    }
+
+    // packs pending buffers into one queued buffer, keeps them if queue is full
+    fn try_flush(&mut self, channel_id: &String, buffer_queues: &BufferQueues) -> NetworkResult<bool> {
+        if self.bs.is_empty() {
+            return Ok(true);
+        }
+        if !buffer_queues.try_push_with_meta(channel_id, pack_batch(&self.bs), self.expire_ts(), BUFFER_FLAG_BATCH)? {
+            return Ok(false);
+        }
+        self.bs.clear();
+        self.expire_tss.clear();
+        self.num_bytes = 0;
+        Ok(true)
+    }
+
+    // batch is dropped only when all its buffers have expired
+    fn expire_ts(&self) -> Option<u64> {
+        self.expire_tss.iter().copied().reduce(|a, b| a.zip(b).map(|(a, b)| a.max(b))).flatten()
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 #[pyclass(name="RustDataWriterConfig")]
 pub struct DataWriterConfig {
-    in_flight_timeout_s: usize,
-    max_buffers_per_channel: usize
+    // un-acked buffers are re-sent after this long (retransmit timeout)
+    pub in_flight_timeout_s: usize,
+    // per channel queue size, bounds buffers in flight and waiting to be sent
+    pub max_buffers_per_channel: usize,
+    #[serde(default)]
+    pub retention: usize, // number of acked buffers per channel kept for replay
+    #[serde(default = "default_metrics_enabled")]
+    pub metrics_enabled: bool,
+    #[serde(default = "default_metrics_flush_interval_ms")]
+    pub metrics_flush_interval_ms: u64,
+    // up to this many written buffers (or buffer_batch_max_bytes, 0 - no limit) are packed into one sent buffer,
+    // reader unpacks them back. Partial batches are sent after buffer_batch_linger_ms. 1 - no batching
+    #[serde(default = "default_buffer_batch_size")]
+    pub buffer_batch_size: usize,
+    #[serde(default)]
+    pub buffer_batch_max_bytes: usize,
+    #[serde(default = "default_buffer_batch_linger_ms")]
+    pub buffer_batch_linger_ms: u64,
+    // how write_bytes_by_key picks a channel
+    #[serde(default)]
+    pub partitioner: PartitionerType,
+    // channel_id -> send rate limit, channels not listed are not limited
+    #[serde(default)]
+    pub rate_limits: HashMap<String, RateLimit>,
+    // written buffers larger than this are split into fragments of this size, sent as separate buffers and
+    // reassembled by reader. Batches are not split. 0 - no limit, otherwise at least MIN_FRAGMENT_SIZE, and messages
+    // over MAX_FRAGMENTED_MESSAGE_SIZE are rejected
+    #[serde(default)]
+    pub max_buffer_size: usize,
+    // close() waits up to this long for queued buffers to be sent and acked before stopping io threads,
+    // anything left is dropped and reported. 0 - no linger, close() drops whatever is still queued or in flight
+    #[serde(default = "default_close_linger_ms")]
+    pub close_linger_ms: u64,
+    // channel_id -> compacted, channels not listed are not compacted. On compacted channels write_bytes_by_key
+    // replaces a still queued (not yet sent) buffer with the same key, so only the newest value per key is sent.
+    // Buffers that need fragmenting are queued as usual
+    #[serde(default)]
+    pub compacted: HashMap<String, bool>,
+    // malformed messages from readers (acks, backpressure) are dropped, or stop the ack loop
+    #[serde(default)]
+    pub decode_error_policy: DecodeErrorPolicy,
+    // channel_id -> at most once, channels not listed are not. Fire-and-forget, e.g. for metrics: a buffer is released
+    // as soon as it is sent, never waits for an ack and is never re-sent, so buffers lost on the way are gone for good.
+    // Reader has to list the channel in its at_most_once too, otherwise it acks for nothing
+    #[serde(default)]
+    pub at_most_once: HashMap<String, bool>
 }
 
 #[pymethods]
 impl DataWriterConfig { 
+    // keyword arguments are the fields below, see set_py
     #[new]
+    #[pyo3(signature = (in_flight_timeout_s, max_buffers_per_channel, **kwargs))]
+    pub fn py_new(in_flight_timeout_s: usize, max_buffers_per_channel: usize, kwargs: Option<&PyDict>) -> PyResult<Self> {
+        let mut config = Self::new(in_flight_timeout_s, max_buffers_per_channel);
+        for (name, value) in kwargs.into_iter().flatten() {
+            config.set_py(name.extract()?, value)?;
+        }
+        config.validate().map_err(PyValueError::new_err)?;
+        Ok(config)
+    }
+}
+
+impl DataWriterConfig {
+    // Everything else at the same defaults as fields left out of a persisted config. Set options with struct update,
+    // e.g. DataWriterConfig{compacted, ..DataWriterConfig::new(1, 10)}, DataWriter::new validates the result
     pub fn new(in_flight_timeout_s: usize, max_buffers_per_channel: usize) -> Self {
         DataWriterConfig{
             in_flight_timeout_s,
-            max_buffers_per_channel
+            max_buffers_per_channel,
+            retention: 0,
+            metrics_enabled: default_metrics_enabled(),
+            metrics_flush_interval_ms: default_metrics_flush_interval_ms(),
+            buffer_batch_size: default_buffer_batch_size(),
+            buffer_batch_max_bytes: 0,
+            buffer_batch_linger_ms: default_buffer_batch_linger_ms(),
+            partitioner: PartitionerType::default(),
+            rate_limits: HashMap::new(),
+            max_buffer_size: 0,
+            close_linger_ms: DEFAULT_CLOSE_LINGER_MS,
+            compacted: HashMap::new(),
+            decode_error_policy: DecodeErrorPolicy::default(),
+            at_most_once: HashMap::new()
         }
     }
+
+    // one keyword argument of the Python constructor
+    fn set_py(&mut self, name: &str, value: &PyAny) -> PyResult<()> {
+        match name {
+            "metrics_enabled" => self.metrics_enabled = value.extract()?,
+            "metrics_flush_interval_ms" => self.metrics_flush_interval_ms = value.extract()?,
+            "retention" => self.retention = value.extract()?,
+            "buffer_batch_size" => self.buffer_batch_size = value.extract()?,
+            "buffer_batch_max_bytes" => self.buffer_batch_max_bytes = value.extract()?,
+            "buffer_batch_linger_ms" => self.buffer_batch_linger_ms = value.extract()?,
+            "partitioner" => self.partitioner = value.extract()?,
+            "rate_limits" => self.rate_limits = value.extract()?,
+            "max_buffer_size" => self.max_buffer_size = value.extract()?,
+            "close_linger_ms" => self.close_linger_ms = value.extract()?,
+            "compacted" => self.compacted = value.extract()?,
+            "decode_error_policy" => self.decode_error_policy = value.extract()?,
+            "at_most_once" => self.at_most_once = value.extract()?,
+            _ => return Err(PyTypeError::new_err(format!("RustDataWriterConfig got an unexpected keyword argument {name:?}")))
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_buffers_per_channel == 0 {
+            // queue would never take a buffer
+            return Err(String::from("max_buffers_per_channel must be greater than 0"));
+        }
+        if self.in_flight_timeout_s == 0 {
+            // every io loop pass would re-send everything in flight
+            return Err(String::from("in_flight_timeout_s must be greater than 0"));
+        }
+        if self.metrics_enabled && self.metrics_flush_interval_ms == 0 {
+            return Err(String::from("metrics_flush_interval_ms must be greater than 0 when metrics are enabled"));
+        }
+        if self.buffer_batch_size == 0 {
+            return Err(String::from("buffer_batch_size must be greater than 0, 1 disables batching"));
+        }
+        if self.max_buffer_size != 0 && (self.max_buffer_size as u64) < MIN_FRAGMENT_SIZE {
+            // reader rejects messages split in more than MAX_FRAGMENTS
+            return Err(format!("max_buffer_size must be 0 or at least {MIN_FRAGMENT_SIZE}"));
+        }
+        for (channel_id, rate_limit) in &self.rate_limits {
+            if rate_limit.bytes_per_sec == Some(0) || rate_limit.buffers_per_sec == Some(0) {
+                return Err(format!("rate limit of channel {channel_id} must be greater than 0"));
+            }
+        }
+        Ok(())
+    }
+
+    fn batching_enabled(&self) -> bool {
+        self.buffer_batch_size > 1
+    }
+
+    fn needs_fragmenting(&self, b: &Bytes) -> bool {
+        self.max_buffer_size != 0 && b.len() > self.max_buffer_size
+    }
+
+    fn is_compacted(&self, channel_id: &str) -> bool {
+        self.compacted.get(channel_id).copied().unwrap_or(false)
+    }
+
+    pub(crate) fn is_at_most_once(&self, channel_id: &str) -> bool {
+        self.at_most_once.get(channel_id).copied().unwrap_or(false)
+    }
 }
 
+// (writer side ipc addr, chan) per subscriber of a channel, in order of writer's channel list
+type SubscriberChans = Vec<(String, BytesChan)>;
+
+// A channel listed more than once (with different ipc addrs) is fanned out: each entry's reader receives every buffer
+// and a buffer is released only after all of them acked it, see BufferQueue::ack. Memory is bounded by
+// max_buffers_per_channel per channel, not per subscriber, so the slowest subscriber backpressures the writer.
+// Only local channels can be fanned out, as transfer handlers route remote buffers by channel id
 pub struct DataWriter {
     name: String,
     job_name: String,
-    channels: Vec<Channel>,
-    send_chans: Arc<RwLock<HashMap<String, (Sender<Box<Bytes>>, Receiver<Box<Bytes>>)>>>,
-    recv_chans: Arc<RwLock<HashMap<String, (Sender<Box<Bytes>>, Receiver<Box<Bytes>>)>>>,
+    channels: RwLock<Vec<Channel>>,
+    send_chans: Arc<RwLock<HashMap<String, SubscriberChans>>>,
+    recv_chans: Arc<RwLock<HashMap<String, SubscriberChans>>>,
     buffer_queues: Arc<BufferQueues>,
+    pending_batches: Arc<PendingBatches>,
+    partitioner: Box<dyn Partitioner>,
 
-    in_flight: Arc<RwLock<HashMap<String, Arc<RwLock<HashMap<u32, (u128, Box<Bytes>)>>>>>>,
+    in_flight: Arc<RwLock<HashMap<String, Arc<RwLock<HashMap<u64, (u128, Box<Bytes>)>>>>>>,
 
     metrics_recorder: Arc<MetricsRecorder>,
 
     running: Arc<AtomicBool>,
+    // set by first start and never cleared, see DataReader::start
+    started: AtomicBool,
+    // set by drain, new writes are rejected
+    draining: AtomicBool,
     io_thread_handles: Arc<ArrayQueue<JoinHandle<()>>>, // array queue so we do not mutate DataReader and keep ownership
 
     // config options
@@ -47,50 +263,98 @@ pub struct DataWriter {
 
 impl DataWriter {
 
-    pub fn new(name: String, job_name: String, config: DataWriterConfig, channels: Vec<Channel>) -> DataWriter {
+    // Err(InvalidConfig) or Err(InvalidChannel) if config or channels do not validate
+    pub fn new(name: String, job_name: String, config: DataWriterConfig, channels: Vec<Channel>) -> NetworkResult<DataWriter> {
+        // config may come deserialized or built with struct update, without any validation
+        config.validate().map_err(NetworkError::InvalidConfig)?;
+        validate_channels(&channels, true)?;
+        let config = Arc::new(config);
         let n_channels = channels.len();
         let mut send_chans = HashMap::with_capacity(n_channels);
         let mut recv_chans = HashMap::with_capacity(n_channels);
         let mut in_flight = HashMap::with_capacity(n_channels);
+        let mut pending_batches = HashMap::with_capacity(n_channels);
 
         for ch in &channels {
-            send_chans.insert(ch.get_channel_id().clone(), bounded(config.max_buffers_per_channel));
-            recv_chans.insert(ch.get_channel_id().clone(), bounded(config.max_buffers_per_channel));
+            if ch.is_remote() && channels.iter().filter(|other| other.get_channel_id() == ch.get_channel_id()).count() > 1 {
+                return Err(NetworkError::InvalidChannel(format!("{}: remote channel can not be fanned out", ch.get_channel_id())));
+            }
+            let addr = writer_ipc_addr(ch);
+            send_chans.entry(ch.get_channel_id().clone()).or_insert_with(Vec::new).push((addr.clone(), bounded(config.max_buffers_per_channel)));
+            recv_chans.entry(ch.get_channel_id().clone()).or_insert_with(Vec::new).push((addr, bounded(config.max_buffers_per_channel)));
             in_flight.insert(ch.get_channel_id().clone(), Arc::new(RwLock::new(HashMap::new())));
+            pending_batches.insert(ch.get_channel_id().clone(), Arc::new(Mutex::new(PendingBatch::default())));
         }
+        let metrics_recorder = Arc::new(if config.metrics_enabled {
+            MetricsRecorder::new(name.clone(), job_name.clone(), config.metrics_flush_interval_ms)
+        } else {
+            MetricsRecorder::new_disabled(name.clone(), job_name.clone())
+        });
 
-        DataWriter{
+        Ok(DataWriter{
             name: name.clone(),
             job_name: job_name.clone(),
-            channels: channels.to_vec(),
+            channels: RwLock::new(channels.to_vec()),
             send_chans: Arc::new(RwLock::new(send_chans)),
             recv_chans: Arc::new(RwLock::new(recv_chans)),
-            buffer_queues: Arc::new(BufferQueues::new(channels.to_vec(), config.max_buffers_per_channel)),
+            buffer_queues: Arc::new(BufferQueues::new(channels.to_vec(), config.clone(), metrics_recorder.clone())),
+            pending_batches: Arc::new(RwLock::new(pending_batches)),
+            partitioner: config.partitioner.new_partitioner(),
             in_flight: Arc::new(RwLock::new(in_flight)),
-            metrics_recorder: Arc::new(MetricsRecorder::new(name.clone(), job_name.clone())),
+            metrics_recorder,
             running: Arc::new(AtomicBool::new(false)),
+            started: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
             io_thread_handles: Arc::new(ArrayQueue::new(2)),
-            config: Arc::new(config)
-        }
+            config
+        })
+    }
+
+    // Ok(None) if not written in time
+    pub fn write_bytes(&self, channel_id: &String, b: Box<Bytes>, block: bool, timeout_ms: i32, retry_step_micros: u64) -> NetworkResult<Option<u128>> {
+        self.write_bytes_with_ttl(channel_id, b, None, block, timeout_ms, retry_step_micros)
+    }
+
+    // buffer not sent (or not read) within ttl_ms from this call is dropped, e.g. stale data in a backlog flushed after reconnect
+    pub fn write_bytes_with_ttl(&self, channel_id: &String, b: Box<Bytes>, ttl_ms: Option<u64>, block: bool, timeout_ms: i32, retry_step_micros: u64) -> NetworkResult<Option<u128>> {
+        let t: u128 = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_micros();
+        let expire_ts = ttl_ms.map(|ttl_ms| t as u64 + ttl_ms * 1000);
+        self.write_with_retries(block, timeout_ms, retry_step_micros, || self.try_push(channel_id, b.clone(), expire_ts))
+    }
+
+    // Control buffer (e.g. flush signal or watermark), sent ahead of data already queued on the channel and
+    // delivered by reader on arrival, so it is not stuck behind a backlog. Never batched.
+    // Order is kept among priority buffers of a channel but not relative to regular ones.
+    // ExactlyOnce readers still deliver it in order
+    pub fn write_priority_bytes(&self, channel_id: &String, b: Box<Bytes>, block: bool, timeout_ms: i32, retry_step_micros: u64) -> NetworkResult<Option<u128>> {
+        self.write_with_retries(block, timeout_ms, retry_step_micros, || self.try_push_priority(channel_id, b.clone()))
+    }
+
+    // End of channel's stream, e.g. bounded source is exhausted. Queued after everything written to the channel so far,
+    // reader completes the channel once all of it was read, see DataReader::completed_channels.
+    // Nothing should be written to the channel afterwards
+    pub fn write_eof(&self, channel_id: &String, block: bool, timeout_ms: i32, retry_step_micros: u64) -> NetworkResult<Option<u128>> {
+        self.write_with_retries(block, timeout_ms, retry_step_micros, || self.try_push_eof(channel_id))
     }
 
-    pub fn write_bytes(&self, channel_id: &String, b: Box<Bytes>, block: bool, timeout_ms: i32, retry_step_micros: u64) -> Option<u128> {
+    // backpressured time micros, None if not pushed in time
+    fn write_with_retries(&self, block: bool, timeout_ms: i32, retry_step_micros: u64, try_push: impl Fn() -> NetworkResult<bool>) -> NetworkResult<Option<u128>> {
         let t: u128 = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_micros();
         let mut num_retries = 0;
         loop {
             if !block {
-                let succ = self.buffer_queues.try_push(channel_id, b.clone());
+                let succ = try_push()?;
                 if succ {
-                    return Some(0);
+                    return Ok(Some(0));
                 } else {
-                    return None
+                    return Ok(None)
                 }
             }
             let _t = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_micros();
             if _t - t > timeout_ms as u128 * 1000 {
-                return None
+                return Ok(None)
             }
-            let succ = self.buffer_queues.try_push(channel_id, b.clone());
+            let succ = try_push()?;
             if !succ {
                 num_retries += 1;
                 thread::sleep(Duration::from_micros(retry_step_micros));
@@ -99,7 +363,294 @@ impl DataWriter {
             break;
         }
         let backpressured_time = if num_retries == 0 {0} else {SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_micros() - t};
-        Some(backpressured_time)
+        Ok(Some(backpressured_time))
+    }
+
+    // channel for a message with given key, index is position in writer's channel list (fanned out channels
+    // count once), so writers of the same stream should list channels in the same order. ConsistentHash places
+    // keys by channel id instead, order does not matter there
+    pub fn partition(&self, key: Option<&[u8]>) -> NetworkResult<String> {
+        let channel_ids = self.channel_ids()?;
+        if channel_ids.is_empty() {
+            panic!("Writer {} has no channels", self.name);
+        }
+        let index = self.partitioner.partition_channel(key, &channel_ids);
+        Ok(channel_ids[index].clone())
+    }
+
+    // distinct, in order of writer's channel list
+    fn channel_ids(&self) -> NetworkResult<Vec<String>> {
+        let locked_channels = self.channels.read().map_err(poisoned("channels"))?;
+        let mut res: Vec<String> = Vec::with_capacity(locked_channels.len());
+        for ch in locked_channels.iter() {
+            if !res.contains(ch.get_channel_id()) {
+                res.push(ch.get_channel_id().clone());
+            }
+        }
+        Ok(res)
+    }
+
+    // same as write_bytes, returns channel picked by partitioner as well. Compacted channels keep only the newest
+    // not yet sent buffer per key
+    pub fn write_bytes_by_key(&self, key: Option<&[u8]>, b: Box<Bytes>, block: bool, timeout_ms: i32, retry_step_micros: u64) -> NetworkResult<Option<(String, u128)>> {
+        let channel_id = self.partition(key)?;
+        let res = match key.filter(|_| self.config.is_compacted(&channel_id)) {
+            Some(key) => self.write_with_retries(block, timeout_ms, retry_step_micros, || self.try_push_compacted(&channel_id, key, b.clone()))?,
+            None => self.write_bytes(&channel_id, b, block, timeout_ms, retry_step_micros)?
+        };
+        Ok(res.map(|t| (channel_id, t)))
+    }
+
+    // Pushes a copy to every channel, each gets its own per-channel buffer id.
+    // Channels that are full are retried every retry_step_micros, independently of each other, until timeout_ms,
+    // so one backpressured channel delays the call by at most timeout_ms and does not hold back the others.
+    // Returns channels that were not written to (e.g. to retry only those), empty if all succeeded.
+    // Channels whose reader shut down are skipped
+    pub fn broadcast(&self, b: Box<Bytes>, timeout_ms: i32, retry_step_micros: u64) -> NetworkResult<Vec<String>> {
+        let t = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_micros();
+        let closed = self.buffer_queues.closed_channels()?;
+        let mut pending = self.channel_ids()?;
+        pending.retain(|channel_id| !closed.contains(channel_id));
+        loop {
+            let mut still_pending = Vec::with_capacity(pending.len());
+            for channel_id in pending {
+                if !self.try_push(&channel_id, b.clone(), None)? {
+                    still_pending.push(channel_id);
+                }
+            }
+            pending = still_pending;
+            let _t = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_micros();
+            if pending.is_empty() || _t - t > timeout_ms as u128 * 1000 {
+                return Ok(pending);
+            }
+            thread::sleep(Duration::from_micros(retry_step_micros));
+        }
+    }
+
+    // with batching, buffer is added to channel's pending batch, which is queued once full.
+    // Returns false (and does not keep the buffer) if batch is full and queue has no room
+    fn try_push(&self, channel_id: &String, b: Box<Bytes>, expire_ts_micros: Option<u64>) -> NetworkResult<bool> {
+        if self.draining.load(Ordering::Relaxed) {
+            return Err(NetworkError::ChannelClosed(format!("writer {}", self.name)));
+        }
+        if self.config.needs_fragmenting(&b) {
+            if b.len() as u64 > MAX_FRAGMENTED_MESSAGE_SIZE {
+                return Err(NetworkError::MessageTooLarge(format!("{} bytes exceed max fragmented message size {MAX_FRAGMENTED_MESSAGE_SIZE}", b.len())));
+            }
+            let fragments = split_fragments(&b, self.config.max_buffer_size);
+            if fragments.len() > self.config.max_buffers_per_channel {
+                return Err(NetworkError::MessageTooLarge(format!("{} bytes need {} fragments, channel {channel_id} holds {}", b.len(), fragments.len(), self.config.max_buffers_per_channel)));
+            }
+            if !self.config.batching_enabled() {
+                return self.buffer_queues.try_push_fragments(channel_id, fragments, expire_ts_micros);
+            }
+            // pending batch goes first to keep write order, it stays locked so nothing gets in between
+            let locked_pending_batches = self.pending_batches.read().map_err(poisoned("pending_batches"))?;
+            let pending_batch = locked_pending_batches.get(channel_id).ok_or_else(|| NetworkError::UnknownChannel(channel_id.clone()))?;
+            let mut locked_pending_batch = pending_batch.lock().map_err(poisoned("pending_batch"))?;
+            if !locked_pending_batch.try_flush(channel_id, &self.buffer_queues)? {
+                return Ok(false);
+            }
+            return self.buffer_queues.try_push_fragments(channel_id, fragments, expire_ts_micros);
+        }
+        if !self.config.batching_enabled() {
+            return self.buffer_queues.try_push_with_meta(channel_id, b, expire_ts_micros, 0);
+        }
+        let locked_pending_batches = self.pending_batches.read().map_err(poisoned("pending_batches"))?;
+        let pending_batch = locked_pending_batches.get(channel_id).ok_or_else(|| NetworkError::UnknownChannel(channel_id.clone()))?;
+        let mut locked_pending_batch = pending_batch.lock().map_err(poisoned("pending_batch"))?;
+        locked_pending_batch.push(*b, expire_ts_micros);
+        if locked_pending_batch.is_full(&self.config) && !locked_pending_batch.try_flush(channel_id, &self.buffer_queues)? {
+            locked_pending_batch.pop();
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    // never batched, pending batch is queued first to keep write order
+    fn try_push_compacted(&self, channel_id: &String, key: &[u8], b: Box<Bytes>) -> NetworkResult<bool> {
+        if self.draining.load(Ordering::Relaxed) {
+            return Err(NetworkError::ChannelClosed(format!("writer {}", self.name)));
+        }
+        if self.config.needs_fragmenting(&b) {
+            return self.try_push(channel_id, b, None);
+        }
+        if !self.config.batching_enabled() {
+            return self.buffer_queues.try_push_compacted(channel_id, key, b, None);
+        }
+        let locked_pending_batches = self.pending_batches.read().map_err(poisoned("pending_batches"))?;
+        let pending_batch = locked_pending_batches.get(channel_id).ok_or_else(|| NetworkError::UnknownChannel(channel_id.clone()))?;
+        let mut locked_pending_batch = pending_batch.lock().map_err(poisoned("pending_batch"))?;
+        if !locked_pending_batch.try_flush(channel_id, &self.buffer_queues)? {
+            return Ok(false);
+        }
+        self.buffer_queues.try_push_compacted(channel_id, key, b, None)
+    }
+
+    // pending batch is queued first to keep write order
+    fn try_push_eof(&self, channel_id: &String) -> NetworkResult<bool> {
+        if self.draining.load(Ordering::Relaxed) {
+            return Err(NetworkError::ChannelClosed(format!("writer {}", self.name)));
+        }
+        if self.config.batching_enabled() {
+            let locked_pending_batches = self.pending_batches.read().map_err(poisoned("pending_batches"))?;
+            let pending_batch = locked_pending_batches.get(channel_id).ok_or_else(|| NetworkError::UnknownChannel(channel_id.clone()))?;
+            let mut locked_pending_batch = pending_batch.lock().map_err(poisoned("pending_batch"))?;
+            if !locked_pending_batch.try_flush(channel_id, &self.buffer_queues)? {
+                return Ok(false);
+            }
+            return self.buffer_queues.try_push_with_meta(channel_id, Box::default(), None, BUFFER_FLAG_EOF);
+        }
+        self.buffer_queues.try_push_with_meta(channel_id, Box::default(), None, BUFFER_FLAG_EOF)
+    }
+
+    fn try_push_priority(&self, channel_id: &String, b: Box<Bytes>) -> NetworkResult<bool> {
+        if self.draining.load(Ordering::Relaxed) {
+            return Err(NetworkError::ChannelClosed(format!("writer {}", self.name)));
+        }
+        // delivered on arrival, so can not wait for other fragments
+        if self.config.needs_fragmenting(&b) {
+            return Err(NetworkError::MessageTooLarge(format!("priority buffer of {} bytes exceeds max_buffer_size {}", b.len(), self.config.max_buffer_size)));
+        }
+        self.buffer_queues.try_push_with_meta(channel_id, b, None, BUFFER_FLAG_PRIORITY)
+    }
+
+    // Producer's promise that nothing written from now on has event time below event_time_wm, ignored if lower than
+    // current one. Carried by buffers queued after this call on all channels, see DataReader::current_event_time_watermark
+    pub fn advance_event_time_watermark(&self, event_time_wm: u64) {
+        self.buffer_queues.advance_event_time_watermark(event_time_wm);
+    }
+
+    // queues partial batches, returns number of channels whose batches did not fit
+    pub fn flush_batches(&self) -> NetworkResult<usize> {
+        let locked_pending_batches = self.pending_batches.read().map_err(poisoned("pending_batches"))?;
+        let mut num_not_flushed = 0;
+        for (channel_id, pending_batch) in locked_pending_batches.iter() {
+            if !pending_batch.lock().map_err(poisoned("pending_batch"))?.try_flush(channel_id, &self.buffer_queues)? {
+                num_not_flushed += 1;
+            }
+        }
+        Ok(num_not_flushed)
+    }
+
+    // Queues partial batches and waits up to timeout_ms for everything written so far to be sent and acked,
+    // writes are still accepted meanwhile. Returns number of buffers still unacked, counted as in stop()
+    pub fn flush(&self, timeout_ms: u64) -> NetworkResult<usize> {
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+        loop {
+            // batches that do not fit are retried as acks free up queue
+            self.flush_batches()?;
+            let num_unacked = self.num_undelivered()?;
+            if num_unacked == 0 || Instant::now() >= deadline {
+                return Ok(num_unacked);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    // First phase of graceful shutdown: rejects further writes and flushes. Returns false if something is
+    // still undelivered, io threads keep running either way, so it can be retried before stop
+    pub fn drain(&self, timeout_ms: u64) -> NetworkResult<bool> {
+        self.draining.store(true, Ordering::Relaxed);
+        Ok(self.flush(timeout_ms)? == 0)
+    }
+
+    // between start and stop (or close)
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    // Second phase: stops io threads without waiting for acks.
+    // Returns number of buffers left undelivered (queued or in flight, a batch counting as one, plus entries of partial
+    // batches), 0 after successful drain
+    pub fn stop(&self) -> NetworkResult<usize> {
+        self.running.store(false, Ordering::Relaxed);
+        while let Some(handle) = self.io_thread_handles.pop() {
+            handle.join().map_err(|_| NetworkError::ThreadPanicked(format!("writer {} io thread", self.name)))?;
+        }
+        self.metrics_recorder.close();
+        self.num_undelivered()
+    }
+
+    fn num_undelivered(&self) -> NetworkResult<usize> {
+        let mut num_undelivered: usize = self.buffer_queues.queue_depths()?.values().sum();
+        for pending_batch in self.pending_batches.read().map_err(poisoned("pending_batches"))?.values() {
+            num_undelivered += pending_batch.lock().map_err(poisoned("pending_batch"))?.bs.len();
+        }
+        Ok(num_undelivered)
+    }
+
+    // Safe to call while io threads are running.
+    // Note that IOLoop creates sockets only on connect, so channels added after connect have no transport until reconnect.
+    pub fn add_channel(&self, channel: Channel) -> NetworkResult<()> {
+        channel.validate()?;
+        let channel_id = channel.get_channel_id().clone();
+        let mut locked_channels = self.channels.write().map_err(poisoned("channels"))?;
+        if locked_channels.iter().any(|ch| *ch.get_channel_id() == channel_id) {
+            return Err(NetworkError::ChannelExists(channel_id));
+        }
+        let mut locked_in_flights = self.in_flight.write().map_err(poisoned("in_flight"))?;
+        let mut locked_send_chans = self.send_chans.write().map_err(poisoned("send_chans"))?;
+        let mut locked_recv_chans = self.recv_chans.write().map_err(poisoned("recv_chans"))?;
+        let mut locked_pending_batches = self.pending_batches.write().map_err(poisoned("pending_batches"))?;
+        self.buffer_queues.add_channel(&channel_id)?;
+        locked_pending_batches.insert(channel_id.clone(), Arc::new(Mutex::new(PendingBatch::default())));
+        locked_in_flights.insert(channel_id.clone(), Arc::new(RwLock::new(HashMap::new())));
+        locked_send_chans.insert(channel_id.clone(), vec![(writer_ipc_addr(&channel), bounded(self.config.max_buffers_per_channel))]);
+        locked_recv_chans.insert(channel_id.clone(), vec![(writer_ipc_addr(&channel), bounded(self.config.max_buffers_per_channel))]);
+        locked_channels.push(channel);
+        Ok(())
+    }
+
+    // Discards all queued and in-flight (un-acked) buffers for this channel, removes all its subscribers
+    pub fn remove_channel(&self, channel_id: &str) -> NetworkResult<()> {
+        let mut locked_channels = self.channels.write().map_err(poisoned("channels"))?;
+        let mut locked_in_flights = self.in_flight.write().map_err(poisoned("in_flight"))?;
+        let mut locked_send_chans = self.send_chans.write().map_err(poisoned("send_chans"))?;
+        let mut locked_recv_chans = self.recv_chans.write().map_err(poisoned("recv_chans"))?;
+        let mut locked_pending_batches = self.pending_batches.write().map_err(poisoned("pending_batches"))?;
+        locked_pending_batches.remove(channel_id);
+        locked_in_flights.remove(channel_id);
+        locked_send_chans.remove(channel_id);
+        locked_recv_chans.remove(channel_id);
+        self.buffer_queues.remove_channel(channel_id)?;
+        locked_channels.retain(|ch| ch.get_channel_id() != channel_id);
+        Ok(())
+    }
+
+    // re-sends buffers starting from buffer_id, pairs with DataReader::seek.
+    // Returns false if buffer was already acked and is out of retention window
+    pub fn replay(&self, channel_id: &String, buffer_id: u64) -> NetworkResult<bool> {
+        self.buffer_queues.replay_from(channel_id, buffer_id)
+    }
+
+    pub fn get_metrics_snapshot(&self) -> HashMap<String, ChannelStats> {
+        self.metrics_recorder.snapshot()
+    }
+
+    pub fn get_job_totals(&self) -> JobStats {
+        self.metrics_recorder.job_totals()
+    }
+
+    // channel_id -> buffers sent but not acked yet. Window constantly at max_buffers_per_channel means reader
+    // (or network) is the bottleneck, empty window means producer is
+    pub fn get_in_flight(&self) -> NetworkResult<HashMap<String, usize>> {
+        self.buffer_queues.in_flight()
+    }
+
+    // channel_id -> buffers not acked yet, including not yet sent
+    pub fn get_queue_depths(&self) -> NetworkResult<HashMap<String, usize>> {
+        self.buffer_queues.queue_depths()
+    }
+
+    // channels whose reader (every subscriber, if fanned out) shut down, writes to them fail with ChannelClosed
+    // until one of the readers is back
+    pub fn get_closed_channels(&self) -> NetworkResult<Vec<String>> {
+        self.buffer_queues.closed_channels()
+    }
+
+    pub fn reset_metrics(&self) {
+        self.metrics_recorder.reset()
     }
 
     
@@ -115,23 +666,26 @@ impl IOHandler for DataWriter {
         IOHandlerType::DataWriter
     }
 
-    fn get_channels(&self) -> &Vec<Channel> {
-        &self.channels
+    fn get_channels(&self) -> Vec<Channel> {
+        self.channels.read().unwrap().clone()
     }
 
-    fn get_send_chan(&self, sm: &SocketMetadata) -> (Sender<Box<Bytes>>, Receiver<Box<Bytes>>) {
+    fn get_send_chan(&self, sm: &SocketMetadata) -> Option<BytesChan> {
         let hm = &self.send_chans.read().unwrap();
-        let v = hm.get(&sm.channel_id).unwrap();
-        v.clone()
+        subscriber_chan(hm.get(&sm.channel_id)?, sm)
     }
 
-    fn get_recv_chan(&self, sm: &SocketMetadata) -> (Sender<Box<Bytes>>, Receiver<Box<Bytes>>) {
+    fn get_recv_chan(&self, sm: &SocketMetadata) -> Option<BytesChan> {
         let hm = &self.recv_chans.read().unwrap();
-        let v = hm.get(&sm.channel_id).unwrap();
-        v.clone()
+        subscriber_chan(hm.get(&sm.channel_id)?, sm)
     }
 
+    // no-op if already started, a stopped writer is not started again
     fn start(&self) {
+        if self.started.swap(true, Ordering::SeqCst) {
+            println!("[Writer {}] Already started, ignoring start", self.name);
+            return;
+        }
         // start io threads to send buffers and receive acks
         self.running.store(true, Ordering::Relaxed);
         self.metrics_recorder.start();
@@ -139,33 +693,48 @@ impl IOHandler for DataWriter {
         let this_send_chans = self.send_chans.clone();
         let this_buffer_queues = self.buffer_queues.clone();
         let this_in_flights = self.in_flight.clone();
+        let this_pending_batches = self.pending_batches.clone();
         let this_runnning = self.running.clone();
         let this_metrics_recorder = self.metrics_recorder.clone();
         
         let this_config = self.config.clone();
 
-        let output_loop = move || {
+        let output_loop = move || -> NetworkResult<()> {
 
             while this_runnning.load(Ordering::Relaxed) {
 
-                let locked_in_flights = this_in_flights.read().unwrap();
-                let locked_send_chans = this_send_chans.read().unwrap();
+                let locked_in_flights = this_in_flights.read().map_err(poisoned("in_flight"))?;
+                let locked_send_chans = this_send_chans.read().map_err(poisoned("send_chans"))?;
+                let locked_pending_batches = this_pending_batches.read().map_err(poisoned("pending_batches"))?;
                 
                 for channel_id in  locked_send_chans.keys() {
 
+                    // queue partial batch if it waited long enough
+                    if this_config.batching_enabled() {
+                        let mut locked_pending_batch = locked_pending_batches.get(channel_id).unwrap().lock().map_err(poisoned("pending_batch"))?;
+                        let now_ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+                        if !locked_pending_batch.bs.is_empty() && now_ts - locked_pending_batch.first_write_ts >= this_config.buffer_batch_linger_ms as u128 {
+                            locked_pending_batch.try_flush(channel_id, &this_buffer_queues)?;
+                        }
+                    }
+
                     // check if in-flight buffers need to be resent first
-                    let locked_in_flight = locked_in_flights.get(channel_id).unwrap().read().unwrap();
+                    let locked_in_flight = locked_in_flights.get(channel_id).unwrap().read().map_err(poisoned("in_flight"))?;
                     for in_flight_buffer_id in locked_in_flight.keys() {
                         let ts_and_b = locked_in_flight.get(in_flight_buffer_id).unwrap();
                         let now_ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
                         if now_ts - ts_and_b.0 > this_config.in_flight_timeout_s as u128 {
-                            let send_chan = locked_send_chans.get(channel_id).unwrap();
-                            let sender = send_chan.0.clone();
-                            if !sender.is_full() {
-                                sender.send(ts_and_b.1.clone()).unwrap();
-                                let size = ts_and_b.1.len();
+                            // not tracked per subscriber, ones that already acked re-ack the duplicate
+                            let subscribers = locked_send_chans.get(channel_id).unwrap();
+                            if subscribers.iter().all(|(_, send_chan)| !send_chan.0.is_full()) {
+                                let _span = buffer_span!("sent", channel_id, *in_flight_buffer_id);
+                                for (_, send_chan) in subscribers {
+                                    send_chan.0.send(ts_and_b.1.clone()).map_err(|_| NetworkError::ChannelClosed(format!("send chan {channel_id}")))?;
+                                }
+                                let size = ts_and_b.1.len() * subscribers.len();
                                 locked_in_flight.clone().insert(*in_flight_buffer_id, (now_ts, ts_and_b.1.clone()));
-                                this_metrics_recorder.inc(NUM_BUFFERS_RESENT, &channel_id, 1);
+                                this_metrics_recorder.inc(NUM_BUFFERS_RESENT, &channel_id, subscribers.len() as u64);
+                                this_metrics_recorder.inc(NUM_RETRANSMITS, channel_id, subscribers.len() as u64);
                                 this_metrics_recorder.inc(NUM_BYTES_SENT, &channel_id, size as u64);
                             }
                         }
@@ -176,25 +745,41 @@ impl IOHandler for DataWriter {
                         continue;
                     }
                     
-                    let send_chan = locked_send_chans.get(channel_id).unwrap();
-                    let sender = send_chan.0.clone();
-                    if !sender.is_full() {
+                    // every subscriber gets the buffer, so all of them need room
+                    let subscribers = locked_send_chans.get(channel_id).unwrap();
+                    if subscribers.iter().all(|(_, send_chan)| !send_chan.0.is_full()) {
 
-                        let b = this_buffer_queues.schedule_next(channel_id);
+                        let b = this_buffer_queues.schedule_next(channel_id)?;
+                        let throttled_micros = this_buffer_queues.take_throttled_micros(channel_id)?;
+                        if throttled_micros != 0 {
+                            this_metrics_recorder.inc(THROTTLED_MICROS, channel_id, throttled_micros);
+                        }
+                        let num_expired = this_buffer_queues.take_num_expired(channel_id)?;
+                        if num_expired != 0 {
+                            this_metrics_recorder.inc(NUM_EXPIRED, channel_id, num_expired);
+                        }
+                        let num_retransmits = this_buffer_queues.take_num_retransmits(channel_id)?;
+                        if num_retransmits != 0 {
+                            this_metrics_recorder.inc(NUM_RETRANSMITS, channel_id, num_retransmits);
+                        }
                         if b.is_some() {
                             let b = b.unwrap();
-                            let size = b.len();
-                            sender.send(b.clone()).unwrap();
+                            let size = b.len() * subscribers.len();
                             let buffer_id = get_buffer_id(b.clone());
+                            let _span = buffer_span!("sent", channel_id, buffer_id);
+                            for (_, send_chan) in subscribers {
+                                send_chan.0.send(b.clone()).map_err(|_| NetworkError::ChannelClosed(format!("send chan {channel_id}")))?;
+                            }
                             let now_ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
                             locked_in_flight.clone().insert(buffer_id, (now_ts, b.clone()));
 
-                            this_metrics_recorder.inc(NUM_BUFFERS_SENT, &channel_id, 1);
+                            this_metrics_recorder.inc(NUM_BUFFERS_SENT, &channel_id, subscribers.len() as u64);
                             this_metrics_recorder.inc(NUM_BYTES_SENT, &channel_id, size as u64);
                         }
                     }
                 }
             }
+            Ok(())
         };
 
         let this_runnning = self.running.clone();
@@ -202,49 +787,504 @@ impl IOHandler for DataWriter {
         let this_buffer_queues = self.buffer_queues.clone();
         let this_in_flights = self.in_flight.clone();
         let this_metrics_recorder = self.metrics_recorder.clone();
-        let input_loop = move || {
+        let decode_error_policy = self.config.decode_error_policy;
+        let this_name = self.name.clone();
+        let input_loop = move || -> NetworkResult<()> {
             loop {
                 let running = this_runnning.load(Ordering::Relaxed);
                 if !running {
                     break;
                 }
-                let locked_in_flights = this_in_flights.write().unwrap();
-                let locked_recv_chans = this_recv_chans.read().unwrap();
-                for channel_id in  locked_recv_chans.keys() {
+                let locked_in_flights = this_in_flights.write().map_err(poisoned("in_flight"))?;
+                let locked_recv_chans = this_recv_chans.read().map_err(poisoned("recv_chans"))?;
+                for (channel_id, subscribers) in locked_recv_chans.iter() {
                     // poll for acks
-                    let recv_chan = locked_recv_chans.get(channel_id).unwrap();
-                    let receiver = recv_chan.1.clone();
-                    let b = receiver.try_recv();
-                    if b.is_ok() {
-                        let b = b.unwrap();
-                        let size = b.len();
-                        let ack = AckMessage::de(b);
-                        let buffer_id = &ack.buffer_id;
-                        // remove from in-flights
-                        locked_in_flights.get(channel_id).unwrap().write().unwrap().remove(buffer_id);
-
-                        // requets in-order pop
-                        this_buffer_queues.request_pop(channel_id, *buffer_id);
-                        this_metrics_recorder.inc(NUM_BUFFERS_RECVD, &channel_id, 1);
-                        this_metrics_recorder.inc(NUM_BYTES_RECVD, &channel_id, size as u64);
+                    for (subscriber, (_, recv_chan)) in subscribers.iter().enumerate() {
+                        let b = recv_chan.1.try_recv();
+                        if b.is_ok() {
+                            let b = b.unwrap();
+                            let size = b.len();
+                            let msg = match ReaderMessage::try_de(&b) {
+                                Ok(msg) => msg,
+                                Err(err) => {
+                                    decode_error_policy.handle(err)?;
+                                    this_metrics_recorder.inc(NUM_DECODE_ERRORS, channel_id, 1);
+                                    continue;
+                                }
+                            };
+                            // a reader that shut down and is back (e.g. restarted) is sent to again
+                            if !matches!(msg, ReaderMessage::Shutdown(_)) {
+                                this_buffer_queues.set_open(channel_id, subscriber)?;
+                            }
+                            match msg {
+                                ReaderMessage::Ack(ack) => {
+                                    let buffer_id = &ack.buffer_id;
+                                    let _span = buffer_span!("acked", channel_id, *buffer_id);
+                                    // requests in-order pop once every subscriber acked
+                                    if this_buffer_queues.ack(channel_id, subscriber, *buffer_id)? {
+                                        // remove from in-flights
+                                        locked_in_flights.get(channel_id).unwrap().write().map_err(poisoned("in_flight"))?.remove(buffer_id);
+                                    }
+                                }
+                                ReaderMessage::AckBatch(acks) => {
+                                    let mut locked_in_flight = locked_in_flights.get(channel_id).unwrap().write().map_err(poisoned("in_flight"))?;
+                                    for buffer_id in &acks.buffer_ids {
+                                        let _span = buffer_span!("acked", channel_id, *buffer_id);
+                                        if this_buffer_queues.ack(channel_id, subscriber, *buffer_id)? {
+                                            locked_in_flight.remove(buffer_id);
+                                        }
+                                    }
+                                }
+                                ReaderMessage::Backpressure(bp) => {
+                                    this_buffer_queues.set_paused(channel_id, subscriber, bp.paused)?;
+                                }
+                                ReaderMessage::Nack(nack) => {
+                                    // reader is missing it, re-send from it on without waiting for in-flight timeout.
+                                    // Reader drops what it already has. Already acked and popped buffer can not be replayed, nothing to do then
+                                    this_buffer_queues.replay_from(channel_id, nack.buffer_id)?;
+                                }
+                                ReaderMessage::Shutdown(_) => {
+                                    // nothing left to ack what is in flight, stop waiting for this reader. With fan-out
+                                    // the other subscribers keep the channel open
+                                    println!("[Writer {this_name}] Reader of channel {channel_id} shut down, closing it");
+                                    let popped = this_buffer_queues.set_closed(channel_id, subscriber)?;
+                                    let mut locked_in_flight = locked_in_flights.get(channel_id).unwrap().write().map_err(poisoned("in_flight"))?;
+                                    for buffer_id in &popped {
+                                        locked_in_flight.remove(buffer_id);
+                                    }
+                                }
+                            }
+                            this_metrics_recorder.inc(NUM_BUFFERS_RECVD, &channel_id, 1);
+                            this_metrics_recorder.inc(NUM_BYTES_RECVD, &channel_id, size as u64);
+                        }
                     }
                 }
             }
+            Ok(())
         };
 
         let name = &self.name;
         let in_thread_name = format!("volga_{name}_in_thread");
         let out_thread_name = format!("volga_{name}_out_thread");
-        self.io_thread_handles.push(std::thread::Builder::new().name(in_thread_name).spawn(input_loop).unwrap()).unwrap();
-        self.io_thread_handles.push(std::thread::Builder::new().name(out_thread_name).spawn(output_loop).unwrap()).unwrap();
+        let in_name = name.clone();
+        let out_name = name.clone();
+        self.io_thread_handles.push(std::thread::Builder::new().name(in_thread_name).spawn(move || {
+            if let Err(err) = input_loop() {
+                println!("[Writer {in_name}] Input loop failed: {err}");
+            }
+        }).unwrap()).unwrap();
+        self.io_thread_handles.push(std::thread::Builder::new().name(out_thread_name).spawn(move || {
+            if let Err(err) = output_loop() {
+                println!("[Writer {out_name}] Output loop failed: {err}");
+            }
+        }).unwrap()).unwrap();
     }
 
     fn close (&self) {
-        self.running.store(false, Ordering::Relaxed);
-        while self.io_thread_handles.len() != 0 {
-            let handle = self.io_thread_handles.pop();
-            handle.unwrap().join().unwrap();
+        if let Err(err) = self.flush(self.config.close_linger_ms) {
+            println!("[Writer {}] Failed to flush on close: {err}", self.name);
         }
-        self.metrics_recorder.close();
+        match self.stop() {
+            Ok(0) => {},
+            Ok(num_undelivered) => println!("[Writer {}] Dropped {num_undelivered} undelivered buffers on close", self.name),
+            Err(err) => println!("[Writer {}] Failed to stop: {err}", self.name)
+        }
+    }
+}
+
+fn writer_ipc_addr(channel: &Channel) -> String {
+    match channel {
+        Channel::Local{ipc_addr, ..} => normalize_ipc_addr(ipc_addr),
+        Channel::Remote{source_local_ipc_addr, ..} => normalize_ipc_addr(source_local_ipc_addr),
+        Channel::InMemory{channel_id} => in_memory_addr(channel_id)
+    }
+}
+
+// chan of the subscriber socket belongs to, matched by address only if channel is fanned out
+fn subscriber_chan(subscribers: &SubscriberChans, sm: &SocketMetadata) -> Option<BytesChan> {
+    if subscribers.len() == 1 {
+        return Some(subscribers[0].1.clone());
     }
-}
\ No newline at end of file
+    subscribers.iter().find(|(addr, _)| *addr == sm.addr).map(|(_, chan)| chan.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::network::{partitioner::hash_key, buffer_utils::{get_buffer_flags, new_buffer_drop_meta, parse_fragment, unpack_batch, BUFFER_FLAG_FRAGMENT}, channel::{AckBatchMessage, AckMessage, NackMessage, ShutdownMessage}, sockets::{SocketKind, SocketOwner}, utils::wait_until};
+
+    use super::*;
+
+    #[test]
+    fn test_config_validation() {
+        let err = DataWriterConfig{metrics_enabled: false, ..DataWriterConfig::new(1, 0)}.validate().err();
+        assert_eq!(err.unwrap(), "max_buffers_per_channel must be greater than 0");
+        let config = DataWriterConfig{metrics_enabled: false, ..DataWriterConfig::new(1, 10)};
+        assert_eq!(DataWriterConfig{in_flight_timeout_s: 0, ..config.clone()}.validate().unwrap_err(), "in_flight_timeout_s must be greater than 0");
+        assert_eq!(DataWriterConfig{metrics_enabled: true, metrics_flush_interval_ms: 0, ..config.clone()}.validate().unwrap_err(), "metrics_flush_interval_ms must be greater than 0 when metrics are enabled");
+        assert_eq!(DataWriterConfig{buffer_batch_size: 0, ..config.clone()}.validate().unwrap_err(), "buffer_batch_size must be greater than 0, 1 disables batching");
+        let rate_limits = HashMap::from([(String::from("ch_0"), RateLimit::new(Some(0), None))]);
+        assert_eq!(DataWriterConfig{rate_limits, ..config.clone()}.validate().unwrap_err(), "rate limit of channel ch_0 must be greater than 0");
+        assert_eq!(DataWriterConfig{max_buffer_size: 1024, ..config.clone()}.validate().unwrap_err(), format!("max_buffer_size must be 0 or at least {MIN_FRAGMENT_SIZE}"));
+        assert!(DataWriterConfig{metrics_enabled: true, ..config.clone()}.validate().is_ok());
+
+        // writer is not built from invalid config or channels
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let res = DataWriter::new(String::from("test_writer"), String::from("test_job"), DataWriterConfig{buffer_batch_size: 0, ..config.clone()}, vec![ch_0]);
+        assert!(matches!(res, Err(NetworkError::InvalidConfig(msg)) if msg == "buffer_batch_size must be greater than 0, 1 disables batching"));
+        let remote = |target_node_id: &str| Channel::Remote{channel_id: String::from("ch_0"), source_local_ipc_addr: String::from("ipc:///tmp/src_0"), source_node_ip: String::from("127.0.0.1"), source_node_id: String::from("node_0"), target_local_ipc_addr: String::from("ipc:///tmp/dst_0"), target_node_ip: String::from("127.0.0.2"), target_node_id: String::from(target_node_id), port: 4321};
+        let res = DataWriter::new(String::from("test_writer"), String::from("test_job"), config, vec![remote("node_1"), remote("node_2")]);
+        assert!(matches!(res, Err(NetworkError::InvalidChannel(msg)) if msg == "ch_0: remote channel can not be fanned out"));
+    }
+
+    #[test]
+    fn test_batching() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_id = String::from("ch_0");
+        let config = DataWriterConfig{metrics_enabled: false, buffer_batch_size: 3, ..DataWriterConfig::new(1, 1)};
+        let data_writer = DataWriter::new(String::from("test_writer"), String::from("test_job"), config, vec![ch_0]).unwrap();
+        let write = |i: u8| data_writer.write_bytes(&ch_id, Box::new(vec![i]), false, 0, 0).unwrap().is_some();
+        assert!(write(0));
+        assert!(write(1));
+        assert!(data_writer.buffer_queues.schedule_next(&ch_id).unwrap().is_none());
+
+        // full batch is queued as one buffer
+        assert!(write(2));
+        let b = data_writer.buffer_queues.schedule_next(&ch_id).unwrap().unwrap();
+        assert_eq!(get_buffer_flags(&b), BUFFER_FLAG_BATCH);
+        assert_eq!(unpack_batch(*new_buffer_drop_meta(b)), vec![Box::new(vec![0]), Box::new(vec![1]), Box::new(vec![2])]);
+
+        // queue is full - last buffer of a batch is rejected
+        assert!(write(3));
+        assert!(write(4));
+        assert!(!write(5));
+        assert_eq!(data_writer.flush_batches().unwrap(), 1);
+
+        data_writer.buffer_queues.request_pop(&ch_id, 0).unwrap();
+        assert_eq!(data_writer.flush_batches().unwrap(), 0);
+        let b = data_writer.buffer_queues.schedule_next(&ch_id).unwrap().unwrap();
+        assert_eq!(unpack_batch(*new_buffer_drop_meta(b)), vec![Box::new(vec![3]), Box::new(vec![4])]);
+    }
+
+    #[test]
+    fn test_write_eof() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_id = String::from("ch_0");
+        let config = DataWriterConfig{metrics_enabled: false, buffer_batch_size: 3, ..DataWriterConfig::new(1, 10)};
+        let data_writer = DataWriter::new(String::from("test_writer"), String::from("test_job"), config, vec![ch_0]).unwrap();
+        data_writer.write_bytes(&ch_id, Box::new(vec![0]), false, 0, 0).unwrap().unwrap();
+        data_writer.write_eof(&ch_id, false, 0, 0).unwrap().unwrap();
+
+        // partial batch goes first
+        let b = data_writer.buffer_queues.schedule_next(&ch_id).unwrap().unwrap();
+        assert_eq!(unpack_batch(*new_buffer_drop_meta(b)), vec![Box::new(vec![0])]);
+        let b = data_writer.buffer_queues.schedule_next(&ch_id).unwrap().unwrap();
+        assert_eq!((get_buffer_id(b.clone()), get_buffer_flags(&b)), (1, BUFFER_FLAG_EOF));
+        assert!(new_buffer_drop_meta(b).is_empty());
+        assert_eq!(data_writer.write_eof(&String::from("ch_1"), false, 0, 0), Err(NetworkError::UnknownChannel(String::from("ch_1"))));
+    }
+
+    #[test]
+    fn test_fragments() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_id = String::from("ch_0");
+        let max_buffer_size = 1024 * 1024;
+        let config = DataWriterConfig{metrics_enabled: false, buffer_batch_size: 3, max_buffer_size, ..DataWriterConfig::new(1, 8)};
+        let data_writer = DataWriter::new(String::from("test_writer"), String::from("test_job"), config, vec![ch_0]).unwrap();
+        let payload: Vec<u8> = (0..5 * max_buffer_size + 1).map(|i| (i % 251) as u8).collect();
+
+        // pending batch is queued first
+        data_writer.write_bytes(&ch_id, Box::new(vec![0]), false, 0, 0).unwrap().unwrap();
+        data_writer.write_bytes(&ch_id, Box::new(payload.clone()), false, 0, 0).unwrap().unwrap();
+        let b = data_writer.buffer_queues.schedule_next(&ch_id).unwrap().unwrap();
+        assert_eq!(get_buffer_flags(&b), BUFFER_FLAG_BATCH);
+        let mut joined = Vec::new();
+        for i in 0..6 {
+            let b = data_writer.buffer_queues.schedule_next(&ch_id).unwrap().unwrap();
+            assert_eq!(get_buffer_id(b.clone()), i + 1);
+            assert_eq!(get_buffer_flags(&b), BUFFER_FLAG_FRAGMENT);
+            let fragment = new_buffer_drop_meta(b);
+            let (fragment_index, num_fragments, part) = parse_fragment(&fragment).unwrap();
+            assert_eq!((fragment_index, num_fragments), (i as u32, 6));
+            assert!(part.len() <= max_buffer_size);
+            joined.extend_from_slice(part);
+        }
+        assert_eq!(joined, payload);
+
+        // all fragments or nothing
+        assert_eq!(data_writer.write_bytes(&ch_id, Box::new(payload.clone()), false, 0, 0), Ok(None));
+        assert_eq!(data_writer.buffer_queues.queue_depths().unwrap()[&ch_id], 7);
+        // would never fit
+        let too_large = Box::new(vec![0; 9 * max_buffer_size]);
+        assert!(matches!(data_writer.write_bytes(&ch_id, too_large, false, 0, 0), Err(NetworkError::MessageTooLarge(_))));
+        assert!(matches!(data_writer.write_priority_bytes(&ch_id, Box::new(payload), false, 0, 0), Err(NetworkError::MessageTooLarge(_))));
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let channels: Vec<Channel> = (0..2).map(|i| Channel::Local{channel_id: format!("ch_{i}"), ipc_addr: format!("ipc:///tmp/ipc_{i}")}).collect();
+        let config = DataWriterConfig{metrics_enabled: false, ..DataWriterConfig::new(1, 2)};
+        let data_writer = DataWriter::new(String::from("test_writer"), String::from("test_job"), config, channels).unwrap();
+        let ch_0 = String::from("ch_0");
+        let ch_1 = String::from("ch_1");
+        assert!(data_writer.write_bytes(&ch_0, Box::new(vec![0]), false, 0, 0).unwrap().is_some());
+        assert!(data_writer.broadcast(Box::new(vec![1]), 0, 0).unwrap().is_empty());
+        assert_eq!(get_buffer_id(data_writer.buffer_queues.schedule_next(&ch_0).unwrap().unwrap()), 0);
+        assert_eq!(get_buffer_id(data_writer.buffer_queues.schedule_next(&ch_0).unwrap().unwrap()), 1);
+        assert_eq!(get_buffer_id(data_writer.buffer_queues.schedule_next(&ch_1).unwrap().unwrap()), 0);
+
+        // ch_0 is full, ch_1 is still written
+        assert_eq!(data_writer.broadcast(Box::new(vec![2]), 10, 1000).unwrap(), vec![ch_0.clone()]);
+        assert_eq!(get_buffer_id(data_writer.buffer_queues.schedule_next(&ch_1).unwrap().unwrap()), 1);
+    }
+
+    #[test]
+    fn test_rescale_consistent_hash() {
+        let channels: Vec<Channel> = (0..4).map(|i| Channel::Local{channel_id: format!("ch_{i}"), ipc_addr: format!("ipc:///tmp/ipc_{i}")}).collect();
+        let config = DataWriterConfig{metrics_enabled: false, partitioner: PartitionerType::ConsistentHash, ..DataWriterConfig::new(1, 10)};
+        let data_writer = DataWriter::new(String::from("test_writer"), String::from("test_job"), config, channels).unwrap();
+        let keys: Vec<Vec<u8>> = (0..1000).map(|i| format!("key_{i}").into_bytes()).collect();
+        let assign = || -> Vec<String> { keys.iter().map(|key| data_writer.partition(Some(key)).unwrap()).collect() };
+        let before = assign();
+
+        assert!(matches!(data_writer.add_channel(Channel::InMemory{channel_id: String::new()}), Err(NetworkError::InvalidChannel(_))));
+        data_writer.add_channel(Channel::Local{channel_id: String::from("ch_4"), ipc_addr: String::from("ipc:///tmp/ipc_4")}).unwrap();
+        let after_add = assign();
+        let num_moved = before.iter().zip(&after_add).filter(|(b, a)| b != a).count();
+        assert!(num_moved > 0 && num_moved < 300, "moved {num_moved}");
+
+        // back where they were
+        data_writer.remove_channel("ch_4").unwrap();
+        assert_eq!(assign(), before);
+    }
+
+    #[test]
+    fn test_write_by_key() {
+        let channels: Vec<Channel> = (0..3).map(|i| Channel::Local{channel_id: format!("ch_{i}"), ipc_addr: format!("ipc:///tmp/ipc_{i}")}).collect();
+        let config = DataWriterConfig{metrics_enabled: false, partitioner: PartitionerType::Hash, ..DataWriterConfig::new(1, 10)};
+        let data_writer = DataWriter::new(String::from("test_writer"), String::from("test_job"), config, channels).unwrap();
+        let (channel_id, _) = data_writer.write_bytes_by_key(Some(b"key_1"), Box::new(vec![0]), false, 0, 0).unwrap().unwrap();
+        let (same_channel_id, _) = data_writer.write_bytes_by_key(Some(b"key_1"), Box::new(vec![1]), false, 0, 0).unwrap().unwrap();
+        assert_eq!(channel_id, same_channel_id);
+        for i in 0..2 {
+            assert_eq!(get_buffer_id(data_writer.buffer_queues.schedule_next(&channel_id).unwrap().unwrap()), i);
+        }
+
+        // compacted, partial batch of ch_0 goes first
+        let channels = vec![Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")}];
+        let compacted = HashMap::from([(String::from("ch_0"), true)]);
+        let config = DataWriterConfig{metrics_enabled: false, buffer_batch_size: 2, partitioner: PartitionerType::Hash, compacted, ..DataWriterConfig::new(1, 10)};
+        let data_writer = DataWriter::new(String::from("test_writer"), String::from("test_job"), config, channels).unwrap();
+        data_writer.write_bytes(&String::from("ch_0"), Box::new(vec![0]), false, 0, 0).unwrap().unwrap();
+        for i in 1..4 {
+            data_writer.write_bytes_by_key(Some(b"key_1"), Box::new(vec![i]), false, 0, 0).unwrap().unwrap();
+        }
+        let b = data_writer.buffer_queues.schedule_next(&String::from("ch_0")).unwrap().unwrap();
+        assert_eq!(get_buffer_flags(&b), BUFFER_FLAG_BATCH);
+        let b = data_writer.buffer_queues.schedule_next(&String::from("ch_0")).unwrap().unwrap();
+        assert_eq!((get_buffer_id(b.clone()), *new_buffer_drop_meta(b)), (1, vec![3]));
+        assert!(data_writer.buffer_queues.schedule_next(&String::from("ch_0")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_empty_key_and_value() {
+        let channels: Vec<Channel> = (0..3).map(|i| Channel::Local{channel_id: format!("ch_{i}"), ipc_addr: format!("ipc:///tmp/ipc_{i}")}).collect();
+        let compacted = (0..3).map(|i| (format!("ch_{i}"), true)).collect();
+        let config = DataWriterConfig{metrics_enabled: false, partitioner: PartitionerType::Hash, compacted, ..DataWriterConfig::new(1, 10)};
+        let data_writer = DataWriter::new(String::from("test_writer"), String::from("test_job"), config, channels).unwrap();
+
+        // empty key is a key like any other: hashed, and compacted on its own. No key goes to first channel
+        assert_eq!(hash_key(b"") % 3, 2);
+        assert_eq!(data_writer.write_bytes_by_key(Some(b""), Box::new(vec![1]), false, 0, 0).unwrap().unwrap().0, "ch_2");
+        assert_eq!(data_writer.write_bytes_by_key(Some(b""), Box::default(), false, 0, 0).unwrap().unwrap().0, "ch_2");
+        assert_eq!(data_writer.write_bytes_by_key(Some(b"a"), Box::default(), false, 0, 0).unwrap().unwrap().0, "ch_1");
+        assert_eq!(data_writer.write_bytes_by_key(None, Box::default(), false, 0, 0).unwrap().unwrap().0, "ch_0");
+
+        // empty values are queued as regular buffers
+        for channel_id in ["ch_0", "ch_1", "ch_2"] {
+            let b = data_writer.buffer_queues.schedule_next(&String::from(channel_id)).unwrap().unwrap();
+            assert_eq!((get_buffer_id(b.clone()), get_buffer_flags(&b), *new_buffer_drop_meta(b)), (0, 0, vec![]));
+            assert!(data_writer.buffer_queues.schedule_next(&String::from(channel_id)).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_drain_and_stop() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_id = String::from("ch_0");
+        let config = DataWriterConfig{metrics_enabled: false, ..DataWriterConfig::new(10000, 10)};
+        let data_writer = DataWriter::new(String::from("test_writer"), String::from("test_job"), config, vec![ch_0]).unwrap();
+        let sm = SocketMetadata{owner: SocketOwner::Client, kind: SocketKind::Bind, channel_id: ch_id.clone(), addr: String::from("ipc:///tmp/ipc_test")};
+        let send_chan = data_writer.get_send_chan(&sm).unwrap();
+        let recv_chan = data_writer.get_recv_chan(&sm).unwrap();
+        data_writer.start();
+        for i in 0..3 {
+            assert!(data_writer.write_bytes(&ch_id, Box::new(vec![i]), false, 0, 0).unwrap().is_some());
+        }
+        for _ in 0..3 {
+            send_chan.1.recv().unwrap();
+        }
+
+        // nothing acked yet
+        assert_eq!(data_writer.drain(10), Ok(false));
+        assert_eq!(data_writer.write_bytes(&ch_id, Box::new(vec![3]), false, 0, 0), Err(NetworkError::ChannelClosed(String::from("writer test_writer"))));
+        for buffer_id in 0..3 {
+            recv_chan.0.send(AckMessage{channel_id: ch_id.clone(), buffer_id}.ser()).unwrap();
+        }
+        assert_eq!(data_writer.drain(5000), Ok(true));
+        assert_eq!(data_writer.stop(), Ok(0));
+
+        // forced, queued buffers are reported
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let config = DataWriterConfig{metrics_enabled: false, buffer_batch_size: 3, ..DataWriterConfig::new(10000, 10)};
+        let data_writer = DataWriter::new(String::from("test_writer"), String::from("test_job"), config, vec![ch_0]).unwrap();
+        for i in 0..4 {
+            assert!(data_writer.write_bytes(&ch_id, Box::new(vec![i]), false, 0, 0).unwrap().is_some());
+        }
+        // one full batch and one pending entry
+        assert_eq!(data_writer.stop(), Ok(2));
+    }
+
+    #[test]
+    fn test_fanout() {
+        let channels: Vec<Channel> = ["a", "b"].iter().map(|s| Channel::Local{channel_id: String::from("ch_0"), ipc_addr: format!("ipc:///tmp/ipc_0_{s}")})
+            .chain([Channel::Local{channel_id: String::from("ch_1"), ipc_addr: String::from("ipc:///tmp/ipc_1")}]).collect();
+        let ch_id = String::from("ch_0");
+        let config = DataWriterConfig{metrics_enabled: false, ..DataWriterConfig::new(10000, 10)};
+        let data_writer = DataWriter::new(String::from("test_writer"), String::from("test_job"), config, channels).unwrap();
+        // fanned out channel counts once
+        let partitioned: Vec<String> = (0..3).map(|_| data_writer.partition(None).unwrap()).collect();
+        assert_eq!(partitioned, vec!["ch_0", "ch_1", "ch_0"]);
+
+        let chans: Vec<(BytesChan, BytesChan)> = ["a", "b"].iter().map(|s| {
+            let sm = SocketMetadata{owner: SocketOwner::Client, kind: SocketKind::Bind, channel_id: ch_id.clone(), addr: format!("ipc:///tmp/ipc_0_{s}")};
+            (data_writer.get_send_chan(&sm).unwrap(), data_writer.get_recv_chan(&sm).unwrap())
+        }).collect();
+        data_writer.start();
+        assert!(data_writer.write_bytes(&ch_id, Box::new(vec![0]), false, 0, 0).unwrap().is_some());
+        // every subscriber gets the buffer
+        for (send_chan, _) in &chans {
+            assert_eq!(get_buffer_id(send_chan.1.recv().unwrap()), 0);
+        }
+
+        // released only after both acked
+        chans[0].1.0.send(AckMessage{channel_id: ch_id.clone(), buffer_id: 0}.ser()).unwrap();
+        assert_eq!(data_writer.flush(50), Ok(1));
+        // batched acks count the same
+        chans[1].1.0.send(ReaderMessage::AckBatch(AckBatchMessage{channel_id: ch_id.clone(), buffer_ids: vec![0]}).ser()).unwrap();
+        assert_eq!(data_writer.flush(5000), Ok(0));
+        assert_eq!(data_writer.stop(), Ok(0));
+    }
+
+    #[test]
+    fn test_nack() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_id = String::from("ch_0");
+        let config = DataWriterConfig{metrics_enabled: false, ..DataWriterConfig::new(10000, 10)};
+        let data_writer = DataWriter::new(String::from("test_writer"), String::from("test_job"), config, vec![ch_0]).unwrap();
+        let sm = SocketMetadata{owner: SocketOwner::Client, kind: SocketKind::Bind, channel_id: ch_id.clone(), addr: String::from("ipc:///tmp/ipc_test")};
+        let send_chan = data_writer.get_send_chan(&sm).unwrap();
+        let recv_chan = data_writer.get_recv_chan(&sm).unwrap();
+        data_writer.start();
+        for i in 0..2 {
+            assert!(data_writer.write_bytes(&ch_id, Box::new(vec![i]), false, 0, 0).unwrap().is_some());
+        }
+        for i in 0..2 {
+            assert_eq!(get_buffer_id(send_chan.1.recv().unwrap()), i);
+        }
+
+        // re-sent right away, long before in-flight timeout. Unknown ids are ignored
+        recv_chan.0.send(ReaderMessage::Nack(NackMessage{channel_id: ch_id.clone(), buffer_id: 5}).ser()).unwrap();
+        recv_chan.0.send(ReaderMessage::Nack(NackMessage{channel_id: ch_id.clone(), buffer_id: 1}).ser()).unwrap();
+        assert_eq!(get_buffer_id(send_chan.1.recv_timeout(Duration::from_secs(5)).unwrap()), 1);
+        assert!(send_chan.1.recv_timeout(Duration::from_millis(50)).is_err());
+        for buffer_id in 0..2 {
+            recv_chan.0.send(AckMessage{channel_id: ch_id.clone(), buffer_id}.ser()).unwrap();
+        }
+        assert_eq!(data_writer.flush(5000), Ok(0));
+        assert_eq!(data_writer.stop(), Ok(0));
+    }
+
+    #[test]
+    fn test_reader_shutdown() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_1 = Channel::Local{channel_id: String::from("ch_1"), ipc_addr: String::from("ipc:///tmp/ipc_1")};
+        let ch_id = String::from("ch_0");
+        let config = DataWriterConfig{metrics_enabled: false, ..DataWriterConfig::new(10000, 10)};
+        let data_writer = DataWriter::new(String::from("test_writer"), String::from("test_job"), config, vec![ch_0, ch_1]).unwrap();
+        let sm = SocketMetadata{owner: SocketOwner::Client, kind: SocketKind::Bind, channel_id: ch_id.clone(), addr: String::from("ipc:///tmp/ipc_test")};
+        let send_chan = data_writer.get_send_chan(&sm).unwrap();
+        let recv_chan = data_writer.get_recv_chan(&sm).unwrap();
+        data_writer.start();
+        assert!(data_writer.write_bytes(&ch_id, Box::new(vec![0]), false, 0, 0).unwrap().is_some());
+        assert_eq!(get_buffer_id(send_chan.1.recv().unwrap()), 0);
+
+        recv_chan.0.send(ReaderMessage::Shutdown(ShutdownMessage{channel_id: ch_id.clone()}).ser()).unwrap();
+        wait_until(|| !data_writer.get_closed_channels().unwrap().is_empty());
+        assert_eq!(data_writer.get_closed_channels(), Ok(vec![ch_id.clone()]));
+        assert_eq!(data_writer.write_bytes(&ch_id, Box::new(vec![1]), true, 100, 100), Err(NetworkError::ChannelClosed(String::from("channel ch_0"))));
+        // not resent, other channels are not affected
+        assert!(send_chan.1.recv_timeout(Duration::from_millis(50)).is_err());
+        assert_eq!(data_writer.broadcast(Box::new(vec![2]), 0, 0), Ok(vec![]));
+        assert_eq!(data_writer.get_queue_depths().unwrap(), HashMap::from([(ch_id.clone(), 1), (String::from("ch_1"), 1)]));
+
+        // reader is back and acks, channel is open again
+        recv_chan.0.send(AckMessage{channel_id: ch_id.clone(), buffer_id: 0}.ser()).unwrap();
+        wait_until(|| data_writer.get_closed_channels().unwrap().is_empty());
+        assert!(data_writer.write_bytes(&ch_id, Box::new(vec![3]), false, 0, 0).unwrap().is_some());
+        assert_eq!(get_buffer_id(send_chan.1.recv().unwrap()), 1);
+        assert_eq!(data_writer.stop(), Ok(2));
+    }
+
+    #[test]
+    fn test_at_most_once() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_id = String::from("ch_0");
+        let config = DataWriterConfig{metrics_enabled: false, at_most_once: HashMap::from([(ch_id.clone(), true)]), ..DataWriterConfig::new(1, 10)};
+        let data_writer = DataWriter::new(String::from("test_writer"), String::from("test_job"), config, vec![ch_0]).unwrap();
+        let sm = SocketMetadata{owner: SocketOwner::Client, kind: SocketKind::Bind, channel_id: ch_id.clone(), addr: String::from("ipc:///tmp/ipc_test")};
+        let send_chan = data_writer.get_send_chan(&sm).unwrap();
+        data_writer.start();
+        for i in 0..3 {
+            assert!(data_writer.write_bytes(&ch_id, Box::new(vec![i]), false, 0, 0).unwrap().is_some());
+        }
+        for i in 0..3 {
+            assert_eq!(get_buffer_id(send_chan.1.recv().unwrap()), i);
+        }
+        // nothing waits for acks, so flush does not either and nothing is re-sent after in-flight timeout
+        assert_eq!(data_writer.flush(1000), Ok(0));
+        assert!(send_chan.1.recv_timeout(Duration::from_millis(1500)).is_err());
+        assert_eq!(data_writer.stop(), Ok(0));
+    }
+
+    #[test]
+    fn test_flush() {
+        let ch_0 = Channel::Local{channel_id: String::from("ch_0"), ipc_addr: String::from("ipc:///tmp/ipc_0")};
+        let ch_id = String::from("ch_0");
+        let config = DataWriterConfig{metrics_enabled: false, buffer_batch_size: 2, buffer_batch_linger_ms: 60000, ..DataWriterConfig::new(10000, 10)};
+        let data_writer = DataWriter::new(String::from("test_writer"), String::from("test_job"), config, vec![ch_0]).unwrap();
+        let sm = SocketMetadata{owner: SocketOwner::Client, kind: SocketKind::Bind, channel_id: ch_id.clone(), addr: String::from("ipc:///tmp/ipc_test")};
+        let send_chan = data_writer.get_send_chan(&sm).unwrap();
+        let recv_chan = data_writer.get_recv_chan(&sm).unwrap();
+        data_writer.start();
+        for i in 0..3 {
+            assert!(data_writer.write_bytes(&ch_id, Box::new(vec![i]), false, 0, 0).unwrap().is_some());
+        }
+        // full batch is sent, partial one is queued by flush despite long linger
+        assert_eq!(data_writer.flush(10), Ok(2));
+        for _ in 0..2 {
+            send_chan.1.recv().unwrap();
+        }
+        recv_chan.0.send(AckMessage{channel_id: ch_id.clone(), buffer_id: 0}.ser()).unwrap();
+        wait_until(|| data_writer.flush(0).unwrap() == 1);
+
+        // writes are still accepted
+        assert!(data_writer.write_bytes(&ch_id, Box::new(vec![3]), false, 0, 0).unwrap().is_some());
+        assert_eq!(data_writer.flush(0), Ok(2));
+        send_chan.1.recv().unwrap();
+        for buffer_id in 1..3 {
+            recv_chan.0.send(AckMessage{channel_id: ch_id.clone(), buffer_id}.ser()).unwrap();
+        }
+        assert_eq!(data_writer.flush(5000), Ok(0));
+        assert_eq!(data_writer.stop(), Ok(0));
+    }
+}
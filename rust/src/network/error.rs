@@ -0,0 +1,111 @@
+use std::{fmt, sync::{PoisonError, TryLockError, TryLockResult}};
+
+use pyo3::{exceptions::{PyConnectionError, PyIOError, PyKeyError, PyNotImplementedError, PyRuntimeError, PyValueError}, pyclass, PyErr};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetworkError {
+    // a thread panicked while holding this lock, state behind it may be inconsistent
+    LockPoisoned(String),
+    UnknownChannel(String),
+    ChannelExists(String),
+    // other end of an internal channel is gone, e.g. handler was closed
+    ChannelClosed(String),
+    Io(String),
+    ThreadPanicked(String),
+    NotConnected(String),
+    // malformed bytes from a peer
+    Decode(String),
+    // written buffer can never fit the channel, e.g. needs more fragments than queue holds
+    MessageTooLarge(String),
+    // operation not available with current config
+    Unsupported(String),
+    // malformed channel or channel list given to a handler, see channel::validate_channels
+    InvalidChannel(String),
+    // handler config that fails validation, e.g. DataReaderConfig::validate
+    InvalidConfig(String)
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::LockPoisoned(name) => write!(f, "lock {name} is poisoned"),
+            NetworkError::UnknownChannel(channel_id) => write!(f, "unknown channel {channel_id}"),
+            NetworkError::ChannelExists(channel_id) => write!(f, "channel {channel_id} already exists"),
+            NetworkError::ChannelClosed(name) => write!(f, "{name} is closed"),
+            NetworkError::Io(msg) => write!(f, "io error: {msg}"),
+            NetworkError::ThreadPanicked(msg) => write!(f, "thread panicked: {msg}"),
+            NetworkError::NotConnected(msg) => write!(f, "not connected: {msg}"),
+            NetworkError::Decode(msg) => write!(f, "decode error: {msg}"),
+            NetworkError::MessageTooLarge(msg) => write!(f, "message too large: {msg}"),
+            NetworkError::Unsupported(msg) => write!(f, "not supported: {msg}"),
+            NetworkError::InvalidChannel(msg) => write!(f, "invalid channel: {msg}"),
+            NetworkError::InvalidConfig(msg) => write!(f, "invalid config: {msg}")
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+// What a handler does with bytes from a peer it can not decode, e.g. corrupt or from an incompatible version
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[pyclass(name="RustDecodeErrorPolicy")]
+pub enum DecodeErrorPolicy {
+    // drop it, count num_decode_errors and go on, so one bad message does not halt the stream
+    #[default]
+    Skip,
+    // stop with NetworkError::Decode: the channel that got it on a reader, the input loop on a writer or transfer handler
+    Fail
+}
+
+impl DecodeErrorPolicy {
+    // Ok(()) if the message is to be skipped
+    pub fn handle(&self, err: NetworkError) -> NetworkResult<()> {
+        match self {
+            DecodeErrorPolicy::Skip => Ok(()),
+            DecodeErrorPolicy::Fail => Err(err)
+        }
+    }
+}
+
+impl From<NetworkError> for PyErr {
+    fn from(err: NetworkError) -> PyErr {
+        let msg = err.to_string();
+        match err {
+            NetworkError::LockPoisoned(_) | NetworkError::ThreadPanicked(_) => PyRuntimeError::new_err(msg),
+            NetworkError::UnknownChannel(_) => PyKeyError::new_err(msg),
+            NetworkError::ChannelExists(_) | NetworkError::Decode(_) | NetworkError::MessageTooLarge(_) | NetworkError::InvalidChannel(_) | NetworkError::InvalidConfig(_) => PyValueError::new_err(msg),
+            NetworkError::ChannelClosed(_) | NetworkError::NotConnected(_) => PyConnectionError::new_err(msg),
+            NetworkError::Io(_) => PyIOError::new_err(msg),
+            NetworkError::Unsupported(_) => PyNotImplementedError::new_err(msg)
+        }
+    }
+}
+
+impl From<std::io::Error> for NetworkError {
+    fn from(err: std::io::Error) -> NetworkError {
+        NetworkError::Io(err.to_string())
+    }
+}
+
+impl From<zmq::Error> for NetworkError {
+    fn from(err: zmq::Error) -> NetworkError {
+        NetworkError::Io(err.to_string())
+    }
+}
+
+// for map_err on lock results, e.g. self.out_queue.lock().map_err(poisoned("out_queue"))?
+pub fn poisoned<T>(lock_name: &'static str) -> impl FnOnce(PoisonError<T>) -> NetworkError {
+    move |_| NetworkError::LockPoisoned(String::from(lock_name))
+}
+
+// for try_lock/try_read/try_write results, Ok(None) if lock is held by someone else
+pub fn try_locked<G>(res: TryLockResult<G>, lock_name: &'static str) -> NetworkResult<Option<G>> {
+    match res {
+        Ok(guard) => Ok(Some(guard)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Poisoned(_)) => Err(NetworkError::LockPoisoned(String::from(lock_name)))
+    }
+}
+
+pub type NetworkResult<T> = Result<T, NetworkError>;
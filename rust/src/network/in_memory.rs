@@ -0,0 +1,228 @@
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, thread::{self, JoinHandle}, time::Duration};
+
+use crossbeam::channel::{Receiver, Sender, TrySendError};
+
+use super::{channel::{in_memory_addr, Channel}, error::{poisoned, NetworkError, NetworkResult}, io_loop::{Bytes, IOHandler, IOHandlerType}, sockets::{SocketKind, SocketMetadata, SocketOwner}};
+
+const IDLE_SLEEP: Duration = Duration::from_micros(100);
+
+// one direction of an in-memory channel: from a handler's send chan to the peer's recv chan
+struct Pipe {
+    name: String,
+    src: Receiver<Box<Bytes>>,
+    dst: Sender<Box<Bytes>>,
+    // taken from src while dst was full
+    pending: Option<Box<Bytes>>
+}
+
+impl Pipe {
+    // moves what is available now, stops at a full dst. Returns number of buffers moved
+    fn pump(&mut self) -> NetworkResult<usize> {
+        let mut num_moved = 0;
+        for _ in 0..=self.src.len() {
+            let b = match self.pending.take() {
+                Some(b) => b,
+                None => match self.src.try_recv() {
+                    Ok(b) => b,
+                    Err(_) => break
+                }
+            };
+            match self.dst.try_send(b) {
+                Ok(()) => num_moved += 1,
+                Err(TrySendError::Full(b)) => {
+                    self.pending = Some(b);
+                    break;
+                },
+                Err(TrySendError::Disconnected(_)) => return Err(NetworkError::ChannelClosed(self.name.clone()))
+            }
+        }
+        Ok(num_moved)
+    }
+}
+
+// Links DataWriter and DataReader handlers over Channel::InMemory channels in process, without sockets or io threads,
+// so operators can be tested against the network layer fast and without ipc files. Buffers are passed as is, the
+// handlers do not know the difference: data goes from writer's send chan to reader's recv chan, acks and backpressure
+// the other way. Same lifecycle as IOLoop: register handlers, connect, start, close. Each channel needs exactly one
+// writer and one reader, non in-memory channels of registered handlers are ignored
+pub struct InMemoryTransport {
+    name: String,
+    handlers: Mutex<Vec<Arc<dyn IOHandler + Send + Sync>>>,
+    pipes: Arc<Mutex<Vec<Pipe>>>,
+    running: Arc<AtomicBool>,
+    pump_thread: Mutex<Option<JoinHandle<()>>>
+}
+
+impl InMemoryTransport {
+
+    pub fn new(name: String) -> Self {
+        InMemoryTransport{
+            name,
+            handlers: Mutex::new(Vec::new()),
+            pipes: Arc::new(Mutex::new(Vec::new())),
+            running: Arc::new(AtomicBool::new(false)),
+            pump_thread: Mutex::new(None)
+        }
+    }
+
+    pub fn register_handler(&self, handler: Arc<dyn IOHandler + Send + Sync>) -> NetworkResult<()> {
+        self.handlers.lock().map_err(poisoned("handlers"))?.push(handler);
+        Ok(())
+    }
+
+    // pairs writer and reader of every in-memory channel, fails if one is missing or there are several
+    pub fn connect(&self) -> NetworkResult<()> {
+        let locked_handlers = self.handlers.lock().map_err(poisoned("handlers"))?;
+        let in_memory_channel_ids = |handler: &Arc<dyn IOHandler + Send + Sync>| -> Vec<String> {
+            handler.get_channels().into_iter().filter_map(|ch| match ch {
+                Channel::InMemory{channel_id} => Some(channel_id),
+                _ => None
+            }).collect()
+        };
+        let mut pipes = Vec::new();
+        for writer in locked_handlers.iter().filter(|handler| handler.get_handler_type() == IOHandlerType::DataWriter) {
+            for channel_id in in_memory_channel_ids(writer) {
+                let readers: Vec<_> = locked_handlers.iter()
+                    .filter(|handler| handler.get_handler_type() == IOHandlerType::DataReader && in_memory_channel_ids(handler).contains(&channel_id))
+                    .collect();
+                if readers.len() != 1 {
+                    return Err(NetworkError::NotConnected(format!("in-memory channel {channel_id} needs one reader, found {}", readers.len())));
+                }
+                let writer_sm = SocketMetadata{owner: SocketOwner::Client, kind: SocketKind::Bind, channel_id: channel_id.clone(), addr: in_memory_addr(&channel_id)};
+                let reader_sm = SocketMetadata{kind: SocketKind::Connect, ..writer_sm.clone()};
+                let chans = (writer.get_send_chan(&writer_sm), readers[0].get_recv_chan(&reader_sm), readers[0].get_send_chan(&reader_sm), writer.get_recv_chan(&writer_sm));
+                let (Some(data_src), Some(data_dst), Some(ack_src), Some(ack_dst)) = chans else {
+                    return Err(NetworkError::UnknownChannel(channel_id));
+                };
+                pipes.push(Pipe{name: format!("recv chan {channel_id}"), src: data_src.1, dst: data_dst.0, pending: None});
+                pipes.push(Pipe{name: format!("ack chan {channel_id}"), src: ack_src.1, dst: ack_dst.0, pending: None});
+            }
+        }
+        // readers with no writer would silently get nothing
+        for reader in locked_handlers.iter().filter(|handler| handler.get_handler_type() == IOHandlerType::DataReader) {
+            for channel_id in in_memory_channel_ids(reader) {
+                if !pipes.iter().any(|pipe| pipe.name == format!("recv chan {channel_id}")) {
+                    return Err(NetworkError::NotConnected(format!("in-memory channel {channel_id} has no writer")));
+                }
+            }
+        }
+        *self.pipes.lock().map_err(poisoned("pipes"))? = pipes;
+        Ok(())
+    }
+
+    // moves everything currently available over all channels once, both ways. Lets a test step the transport
+    // deterministically instead of start-ing the pump thread. Returns number of buffers moved
+    pub fn pump(&self) -> NetworkResult<usize> {
+        Self::pump_pipes(&self.pipes)
+    }
+
+    fn pump_pipes(pipes: &Mutex<Vec<Pipe>>) -> NetworkResult<usize> {
+        let mut locked_pipes = pipes.lock().map_err(poisoned("pipes"))?;
+        let mut num_moved = 0;
+        for pipe in locked_pipes.iter_mut() {
+            num_moved += pipe.pump()?;
+        }
+        Ok(num_moved)
+    }
+
+    // pumps in background until close
+    pub fn start(&self) -> NetworkResult<()> {
+        let mut locked_pump_thread = self.pump_thread.lock().map_err(poisoned("pump_thread"))?;
+        if locked_pump_thread.is_some() {
+            return Ok(());
+        }
+        self.running.store(true, Ordering::Relaxed);
+        let this_running = self.running.clone();
+        let this_pipes = self.pipes.clone();
+        let this_name = self.name.clone();
+        let handle = thread::Builder::new().name(format!("{}_in_memory_pump", self.name)).spawn(move || {
+            while this_running.load(Ordering::Relaxed) {
+                match Self::pump_pipes(&this_pipes) {
+                    Ok(0) => thread::sleep(IDLE_SLEEP),
+                    Ok(_) => {},
+                    Err(err) => {
+                        println!("[InMemoryTransport {this_name}] Pump failed: {err}");
+                        return
+                    }
+                }
+            }
+        }).map_err(|err| NetworkError::Io(err.to_string()))?;
+        *locked_pump_thread = Some(handle);
+        Ok(())
+    }
+
+    pub fn close(&self) -> NetworkResult<()> {
+        self.running.store(false, Ordering::Relaxed);
+        let handle = self.pump_thread.lock().map_err(poisoned("pump_thread"))?.take();
+        if let Some(handle) = handle {
+            if handle.join().is_err() {
+                return Err(NetworkError::ThreadPanicked(format!("pump thread of in-memory transport {}", self.name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::network::{data_reader::{DataReader, DataReaderConfig}, data_writer::{DataWriter, DataWriterConfig}, utils::wait_for};
+
+    use super::*;
+
+    fn reader_config() -> DataReaderConfig {
+        DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)}
+    }
+
+    fn writer_config() -> DataWriterConfig {
+        DataWriterConfig{metrics_enabled: false, ..DataWriterConfig::new(10000, 10)}
+    }
+
+    // example of an operator-level test: upstream writes, downstream reads, all in process
+    #[test]
+    fn test_writer_to_reader() {
+        let channel = Channel::InMemory{channel_id: String::from("ch_0")};
+        let data_reader = Arc::new(DataReader::new(String::from("test_reader"), String::from("test_job"), reader_config(), vec![channel.clone()]).unwrap());
+        let data_writer = Arc::new(DataWriter::new(String::from("test_writer"), String::from("test_job"), writer_config(), vec![channel]).unwrap());
+        let transport = InMemoryTransport::new(String::from("test_transport"));
+        transport.register_handler(data_reader.clone()).unwrap();
+        transport.register_handler(data_writer.clone()).unwrap();
+        transport.connect().unwrap();
+        data_reader.start();
+        data_writer.start();
+        transport.start().unwrap();
+
+        // more than writer's queue and reader's out_queue hold, so acks and backpressure have to flow back
+        let mut read = Vec::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..100u8 {
+                    data_writer.write_bytes(&String::from("ch_0"), Box::new(vec![i; 10]), true, 5000, 100).unwrap().unwrap();
+                }
+            });
+            while read.len() < 100 {
+                let b = wait_for(|| data_reader.read_bytes().unwrap());
+                read.push(b[0]);
+            }
+        });
+        assert_eq!(read, (0..100).collect::<Vec<u8>>());
+        // acks made it back
+        assert_eq!(data_writer.flush(5000), Ok(0));
+
+        data_writer.close();
+        data_reader.close();
+        transport.close().unwrap();
+    }
+
+    #[test]
+    fn test_connect_errors() {
+        let channel = Channel::InMemory{channel_id: String::from("ch_0")};
+        let transport = InMemoryTransport::new(String::from("test_transport"));
+        transport.register_handler(Arc::new(DataWriter::new(String::from("test_writer"), String::from("test_job"), writer_config(), vec![channel.clone()]).unwrap())).unwrap();
+        assert!(matches!(transport.connect(), Err(NetworkError::NotConnected(_))));
+
+        let transport = InMemoryTransport::new(String::from("test_transport"));
+        transport.register_handler(Arc::new(DataReader::new(String::from("test_reader"), String::from("test_job"), reader_config(), vec![channel]).unwrap())).unwrap();
+        assert!(matches!(transport.connect(), Err(NetworkError::NotConnected(_))));
+        assert_eq!(transport.pump(), Ok(0));
+    }
+}
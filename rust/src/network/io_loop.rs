@@ -1,15 +1,23 @@
 use core::time;
-use std::{cmp::min, collections::HashMap, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, RwLock}, thread::{self, sleep, JoinHandle}, time::{Duration, SystemTime}};
+use std::{cmp::min, collections::HashMap, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex, RwLock}, thread::{self, sleep, JoinHandle}, time::{Duration, SystemTime}};
 
 use crossbeam::{channel::{Sender, Receiver}, queue::SegQueue};
 use pyo3::{pyclass, pymethods};
 use serde::{Deserialize, Serialize};
 
-use super::{channel::Channel, sockets::{SocketMetadata, SocketsManager, SocketsMeatadataManager}, sockets_monitor::SocketsMonitor};
+use super::{channel::Channel, error::{poisoned, NetworkError, NetworkResult}, sockets::{SocketMetadata, SocketsManager, SocketsMeatadataManager}, sockets_monitor::SocketsMonitor, threads::ThreadConfig};
 
 pub type Bytes = Vec<u8>;
 
+pub type BytesChan = (Sender<Box<Bytes>>, Receiver<Box<Bytes>>);
 
+
+// Options applied to every socket when io loop creates it, None keeps zmq's default.
+// sndbuf/rcvbuf set SO_SNDBUF/SO_RCVBUF in bytes. zmq's default leaves them to the OS: on Linux tcp sockets start at
+// net.ipv4.tcp_wmem/tcp_rmem defaults and are autotuned per connection up to their max, which usually covers high
+// bandwidth-delay links as long as the max is large enough. An explicit size turns autotuning off for the socket and
+// is capped by net.core.wmem_max/rmem_max, so only set it when autotuning falls short or to bound memory per socket.
+// zmq always disables Nagle (TCP_NODELAY) on tcp sockets, so small messages are not delayed and there is nothing to set
 #[derive(Serialize, Deserialize, Clone)]
 #[pyclass(name="RustZmqConfig")]
 pub struct ZmqConfig {
@@ -18,14 +26,37 @@ pub struct ZmqConfig {
     pub sndbuf: Option<i32>,
     pub rcvbuf: Option<i32>,
     pub linger: Option<i32>,
-    pub connect_timeout_s: Option<i32>
+    pub connect_timeout_s: Option<i32>,
+    // for hostnames resolving to both address families: Some(true) - use IPv6, Some(false) - use IPv4 (and disable IPv6 on sockets),
+    // None - first resolved address
+    #[serde(default)]
+    pub prefer_ipv6: Option<bool>,
+    // Override sndbuf/rcvbuf for tcp sockets between nodes, e.g. to size them for the link while local ipc sockets keep
+    // small buffers. Remote channels to the same peer share one connection, so this is per connection, not per channel
+    #[serde(default)]
+    pub remote_sndbuf: Option<i32>,
+    #[serde(default)]
+    pub remote_rcvbuf: Option<i32>
 }
 
 #[pymethods]
 impl ZmqConfig { 
     #[new]
-    pub fn new(sndhwm: Option<i32>, rcvhwm: Option<i32>, sndbuf: Option<i32>, rcvbuf: Option<i32>, linger: Option<i32>, connect_timeout_s: Option<i32>) -> Self {
-        ZmqConfig{sndhwm, rcvhwm, sndbuf, rcvbuf, linger, connect_timeout_s}
+    #[pyo3(signature = (sndhwm, rcvhwm, sndbuf, rcvbuf, linger, connect_timeout_s, prefer_ipv6=None, remote_sndbuf=None, remote_rcvbuf=None))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(sndhwm: Option<i32>, rcvhwm: Option<i32>, sndbuf: Option<i32>, rcvbuf: Option<i32>, linger: Option<i32>, connect_timeout_s: Option<i32>, prefer_ipv6: Option<bool>, remote_sndbuf: Option<i32>, remote_rcvbuf: Option<i32>) -> Self {
+        ZmqConfig{sndhwm, rcvhwm, sndbuf, rcvbuf, linger, connect_timeout_s, prefer_ipv6, remote_sndbuf, remote_rcvbuf}
+    }
+}
+
+impl ZmqConfig {
+    // (sndbuf, rcvbuf) for a socket, remote ones apply to tcp sockets only
+    pub fn socket_bufs(&self, remote: bool) -> (Option<i32>, Option<i32>) {
+        if remote {
+            (self.remote_sndbuf.or(self.sndbuf), self.remote_rcvbuf.or(self.rcvbuf))
+        } else {
+            (self.sndbuf, self.rcvbuf)
+        }
     }
 }
 
@@ -35,7 +66,8 @@ pub enum Direction {
     Receiver
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[pyclass(name="RustIOHandlerType")]
 pub enum IOHandlerType {
     DataReader,
     DataWriter,
@@ -43,17 +75,93 @@ pub enum IOHandlerType {
     TransferReceiver
 }
 
+// Socket level counters as seen by io loop, to tell where bytes stall: recv_chan_full means the handler is not keeping up
+// (application backpressure), send_blocked means zmq/kernel send buffers are full, e.g. slow peer or network.
+// zmq delivers whole messages, so there are no partial reads to count
+#[derive(Debug, Clone, Default, PartialEq)]
+#[pyclass(name="RustSocketStats")]
+pub struct SocketStats {
+    // name of the io handler the socket serves
+    #[pyo3(get)]
+    pub handler: String,
+    #[pyo3(get)]
+    pub channel_id: String,
+    #[pyo3(get)]
+    pub addr: String,
+    #[pyo3(get)]
+    pub bytes_read: u64,
+    #[pyo3(get)]
+    pub bytes_written: u64,
+    #[pyo3(get)]
+    pub msgs_read: u64,
+    #[pyo3(get)]
+    pub msgs_written: u64,
+    // poll passes where socket had data to read but handler's recv chan was full
+    #[pyo3(get)]
+    pub recv_chan_full: u64,
+    // poll passes where handler had data to send but socket was not writable
+    #[pyo3(get)]
+    pub send_blocked: u64,
+}
+
+#[pymethods]
+impl SocketStats {
+    pub fn to_dict(&self) -> HashMap<&'static str, u64> {
+        HashMap::from([
+            ("bytes_read", self.bytes_read),
+            ("bytes_written", self.bytes_written),
+            ("msgs_read", self.msgs_read),
+            ("msgs_written", self.msgs_written),
+            ("recv_chan_full", self.recv_chan_full),
+            ("send_blocked", self.send_blocked),
+        ])
+    }
+}
+
+// updated by the socket's io thread only, read by socket_stats
+#[derive(Default)]
+struct SocketCounters {
+    handler: String,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    msgs_read: AtomicU64,
+    msgs_written: AtomicU64,
+    recv_chan_full: AtomicU64,
+    send_blocked: AtomicU64
+}
+
+impl SocketCounters {
+    fn inc(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn to_stats(&self, sm: &SocketMetadata) -> SocketStats {
+        SocketStats{
+            handler: self.handler.clone(),
+            channel_id: sm.channel_id.clone(),
+            addr: sm.addr.clone(),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            msgs_read: self.msgs_read.load(Ordering::Relaxed),
+            msgs_written: self.msgs_written.load(Ordering::Relaxed),
+            recv_chan_full: self.recv_chan_full.load(Ordering::Relaxed),
+            send_blocked: self.send_blocked.load(Ordering::Relaxed)
+        }
+    }
+}
+
 pub trait IOHandler {
 
     fn get_name(&self) -> String;
 
     fn get_handler_type(&self) -> IOHandlerType;
 
-    fn get_channels(&self) -> &Vec<Channel>;
+    fn get_channels(&self) -> Vec<Channel>;
 
-    fn get_send_chan(&self, sm: &SocketMetadata) -> (Sender<Box<Bytes>>, Receiver<Box<Bytes>>);
+    // None if handler has no such channel (e.g. it was removed at runtime), io loop skips such sockets
+    fn get_send_chan(&self, sm: &SocketMetadata) -> Option<BytesChan>;
 
-    fn get_recv_chan(&self, sm: &SocketMetadata) -> (Sender<Box<Bytes>>, Receiver<Box<Bytes>>);
+    fn get_recv_chan(&self, sm: &SocketMetadata) -> Option<BytesChan>;
 
     fn start(&self);
 
@@ -68,12 +176,15 @@ pub struct IOLoop {
     io_threads: Arc<SegQueue<JoinHandle<()>>>,
     sockets_metadata_manager: Arc<SocketsMeatadataManager>,
     zmq_config: Option<ZmqConfig>,
+    // io thread i is pinned to cpu_affinity[i % len]
+    thread_config: ThreadConfig,
     sockets_monitor: Arc<SocketsMonitor>,
+    socket_counters: Arc<RwLock<HashMap<SocketMetadata, Arc<SocketCounters>>>>,
 }
 
 impl IOLoop {
 
-    pub fn new(name: String, zmq_config: Option<ZmqConfig>) -> IOLoop {
+    pub fn new(name: String, zmq_config: Option<ZmqConfig>, thread_config: ThreadConfig) -> IOLoop {
         let zmq_ctx = Arc::new(zmq::Context::new());
         IOLoop{
             name,
@@ -83,26 +194,29 @@ impl IOLoop {
             io_threads: Arc::new(SegQueue::new()),
             sockets_metadata_manager: Arc::new(SocketsMeatadataManager::new()),
             zmq_config: zmq_config,
+            thread_config,
 
             sockets_monitor: Arc::new(SocketsMonitor::new(zmq_ctx.clone())),
+            socket_counters: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
-    pub fn register_handler(&self, handler: Arc<dyn IOHandler + Send + Sync>) {
-        self.handlers.lock().unwrap().push(handler);
+    pub fn register_handler(&self, handler: Arc<dyn IOHandler + Send + Sync>) -> NetworkResult<()> {
+        self.handlers.lock().map_err(poisoned("handlers"))?.push(handler);
+        Ok(())
     }
 
-    fn _run_io_threads(&self, num_threads: usize, connection_timeout_ms: u128) {
+    fn _run_io_threads(&self, num_threads: usize, connection_timeout_ms: u128) -> NetworkResult<()> {
         self.sockets_monitor.start(num_threads);
         
         // since zmq::Sockets are not thread safe we will have a model where each socket can be polled by only 1 IO thread
         // each IO thread can have multiple sockets associated with it
         let name = self.name.clone();
         println!("[Loop {name}] Launched {num_threads} io threads");
-        let locked_handlers = self.handlers.lock().unwrap();
+        let locked_handlers = self.handlers.lock().map_err(poisoned("handlers"))?;
 
         if locked_handlers.len() == 0 {
-            panic!("{name} loop started with no registered handlers");
+            return Err(NetworkError::NotConnected(format!("{name} loop started with no registered handlers")));
         }
 
         let sockets_metadata = self.sockets_metadata_manager.create_for_handlers(&locked_handlers);
@@ -130,16 +244,23 @@ impl IOLoop {
             let this_zmqctx = self.zmq_context.clone();
             let this_socket_metadata_manager = self.sockets_metadata_manager.clone();
             let this_name = self.name.clone();
+            let this_socket_counters = self.socket_counters.clone();
 
             let new_sms = sms.to_vec();
             let this_zmq_config = self.zmq_config.clone();
+            let this_thread_config = self.thread_config.clone();
 
             let f = move |metas: &Vec<SocketMetadata>| {
+                this_thread_config.apply_to_current_thread(this_thread_id);
                 let mut sockets_manager = SocketsManager::new();
                 sockets_manager.create_sockets(&this_zmqctx, metas, this_zmq_config.as_ref());
                 this_sockets_monitor.register_sockets(this_thread_id, sockets_manager.get_sockets_and_metas());
                 this_sockets_monitor.wait_for_monitor_ready();
-                sockets_manager.bind_and_connect();
+                let prefer_ipv6 = this_zmq_config.as_ref().and_then(|config| config.prefer_ipv6);
+                if let Err(err) = sockets_manager.bind_and_connect(prefer_ipv6) {
+                    this_sockets_monitor.report_error(err);
+                    return
+                }
                 let err = this_sockets_monitor.wait_for_all_connected(Some(connection_timeout_ms));
                 if err.is_some() {
                     return
@@ -148,52 +269,118 @@ impl IOLoop {
                 Self::_wait_to_start_running(this_running.clone());
 
                 let mut handlers = Vec::new();
+                let mut counters = Vec::new();
                 for i in 0..sockets_manager.get_sockets_and_metas().len() {
                     let sm = sockets_manager.get_sockets_and_metas()[i].1.clone(); 
                     let handler = this_socket_metadata_manager.get_handler_for_meta(&sm);
+                    let socket_counters = Arc::new(SocketCounters{handler: handler.get_name(), ..Default::default()});
                     handlers.push(handler);
+                    this_socket_counters.write().unwrap().insert(sm, socket_counters.clone());
+                    counters.push(socket_counters);
                 }
 
+                // zmq messages are atomic: ZMTP length-prefixes them on the wire and reassembles partial tcp reads and
+                // writes internally, so a socket takes or returns a whole buffer or nothing. The only partial outcome is
+                // EAGAIN on a non-blocking call despite poll, e.g. while peer reconnects - a buffer already taken from
+                // send chan is then kept here and retried next pass instead of being lost
+                let mut pending_sends: Vec<Option<Box<Bytes>>> = (0..handlers.len()).map(|_| None).collect();
+
                 // run loop
-                while this_running.load(Ordering::Relaxed) {
-                    let mut poll_list = Vec::new();
-                    for i in 0..sockets_manager.get_sockets_and_metas().len() {
-                        let socket = &sockets_manager.get_sockets_and_metas()[i].0;
-                        poll_list.push(socket.as_poll_item(zmq::POLLIN|zmq::POLLOUT));
-                    }
+                let mut run = || -> NetworkResult<()> {
+                    while this_running.load(Ordering::Relaxed) {
+                        let mut poll_list = Vec::new();
+                        for i in 0..sockets_manager.get_sockets_and_metas().len() {
+                            let socket = &sockets_manager.get_sockets_and_metas()[i].0;
+                            poll_list.push(socket.as_poll_item(zmq::POLLIN|zmq::POLLOUT));
+                        }
 
-                    zmq::poll(&mut poll_list, 1).unwrap();
-
-                    for i in 0..poll_list.len() {
-                        let handler = handlers[i].clone();
-                        let (socket, sm)  = &sockets_manager.get_sockets_and_metas()[i];
-                        if poll_list[i].is_readable() {
-                            // this goes on heap
-                            let recv_chan = handler.get_recv_chan(sm);
-                            if !recv_chan.0.is_full() {
-                                let bytes = socket.recv_bytes(zmq::DONTWAIT).unwrap();
-                                let recv_chan = handler.get_recv_chan(sm);
-                                recv_chan.0.send(Box::new(bytes)).unwrap();
+                        zmq::poll(&mut poll_list, 1)?;
+
+                        let mut recv_chan_full = false;
+                        for i in 0..poll_list.len() {
+                            let handler = handlers[i].clone();
+                            let (socket, sm)  = &sockets_manager.get_sockets_and_metas()[i];
+                            let socket_counters = &counters[i];
+                            if poll_list[i].is_readable() {
+                                // this goes on heap
+                                if let Some(recv_chan) = handler.get_recv_chan(sm) {
+                                    if !recv_chan.0.is_full() {
+                                        match socket.recv_bytes(zmq::DONTWAIT) {
+                                            Ok(bytes) => {
+                                                SocketCounters::inc(&socket_counters.msgs_read, 1);
+                                                SocketCounters::inc(&socket_counters.bytes_read, bytes.len() as u64);
+                                                recv_chan.0.send(Box::new(bytes)).map_err(|_| NetworkError::ChannelClosed(format!("recv chan {}", sm.channel_id)))?;
+                                            },
+                                            Err(zmq::Error::EAGAIN) => {},
+                                            Err(err) => return Err(err.into())
+                                        }
+                                    } else {
+                                        recv_chan_full = true;
+                                        SocketCounters::inc(&socket_counters.recv_chan_full, 1);
+                                    }
+                                }
                             }
-                        }
 
-                        if poll_list[i].is_writable() {
-                            let send_chan = handler.get_send_chan(sm);
-                            if !send_chan.1.is_empty() {
-                                let bytes = send_chan.1.recv().unwrap();
-                                socket.send(bytes.as_ref(), zmq::DONTWAIT).unwrap();
+                            if let Some(send_chan) = handler.get_send_chan(sm) {
+                                Self::send_next(&mut pending_sends[i], &send_chan, sm, poll_list[i].is_writable(), socket_counters, |bytes| socket.send(bytes, zmq::DONTWAIT))?;
                             }
                         }
+                        if recv_chan_full {
+                            // poll returns right away while the socket stays readable, so without giving up the core
+                            // this loop would spin until its time slice ends, starving the dispatcher that drains the chan
+                            thread::yield_now();
+                        }
                     }
+                    Ok(())
+                };
+                if let Err(err) = run() {
+                    println!("[Loop {this_name}] IO thread {this_thread_id} failed: {err}");
                 }
+                sockets_manager.close_sockets();
             };
-            let thread_name = format!("volga_io_thread_{thread_id}");
+            let thread_name = self.thread_config.thread_name(&format!("io_thread_{thread_id}"));
             self.io_threads.push(
                 std::thread::Builder::new().name(thread_name).spawn(
                     move || {f(&new_sms)}
                 ).unwrap()
             );
         }
+        Ok(())
+    }
+
+    // One send per socket and pass: pending buffer if an earlier send hit EAGAIN, otherwise next one from send chan.
+    // On EAGAIN the buffer is kept in pending instead of being lost
+    fn send_next(
+        pending: &mut Option<Box<Bytes>>,
+        send_chan: &BytesChan,
+        sm: &SocketMetadata,
+        writable: bool,
+        socket_counters: &SocketCounters,
+        send: impl FnOnce(&[u8]) -> Result<(), zmq::Error>
+    ) -> NetworkResult<()> {
+        if pending.is_none() && send_chan.1.is_empty() {
+            return Ok(());
+        }
+        if !writable {
+            SocketCounters::inc(&socket_counters.send_blocked, 1);
+            return Ok(());
+        }
+        let bytes = match pending.take() {
+            Some(bytes) => bytes,
+            None => send_chan.1.recv().map_err(|_| NetworkError::ChannelClosed(format!("send chan {}", sm.channel_id)))?
+        };
+        match send(bytes.as_ref()) {
+            Ok(()) => {
+                SocketCounters::inc(&socket_counters.msgs_written, 1);
+                SocketCounters::inc(&socket_counters.bytes_written, bytes.len() as u64);
+            },
+            Err(zmq::Error::EAGAIN) => {
+                *pending = Some(bytes);
+                SocketCounters::inc(&socket_counters.send_blocked, 1);
+            },
+            Err(err) => return Err(err.into())
+        }
+        Ok(())
     }
 
     fn _wait_to_start_running(running: Arc<AtomicBool>) -> bool {
@@ -208,18 +395,21 @@ impl IOLoop {
         false
     }
 
-    pub fn start(&self) {
+    pub fn start(&self) -> NetworkResult<()> {
         let err = self.sockets_monitor.wait_for_all_connected(None);
-        if err.is_some() {
-            panic!("Can not start io loop - connection error")
+        if let Some(err) = err {
+            return Err(NetworkError::NotConnected(format!("Can not start io loop - connection error: {err}")));
         }
         let name = &self.name;
         self.running.store(true, Ordering::Relaxed);
         println!("[Loop {name}] Started data flow");
+        Ok(())
     }
 
     pub fn connect(&self, num_io_threads: usize, timeout_ms: u128) -> Option<String> {
-        self._run_io_threads(num_io_threads, timeout_ms);
+        if let Err(err) = self._run_io_threads(num_io_threads, timeout_ms) {
+            return Some(err.to_string());
+        }
         self.sockets_monitor.wait_for_monitor_ready();
         let err = self.sockets_monitor.wait_for_all_connected(Some(timeout_ms));
         let io_loop_name = self.name.clone();
@@ -230,15 +420,147 @@ impl IOLoop {
         err
     }
 
-    pub fn close(&self) {
+    // cumulative per socket, sockets show up once their io thread is running (after connect).
+    // A channel has one socket per handler side in this loop, more with fan-out or remote transfer
+    pub fn socket_stats(&self) -> HashMap<SocketMetadata, SocketStats> {
+        self.socket_counters.read().unwrap().iter().map(|(sm, counters)| (sm.clone(), counters.to_stats(sm))).collect()
+    }
+
+    // joins all io threads even if some of them panicked, reports first panic
+    pub fn close(&self) -> NetworkResult<()> {
         let name = &self.name;
         self.sockets_monitor.close();
         self.running.store(false, Ordering::Relaxed);
-        while !self.io_threads.is_empty() {
-            let handle = self.io_threads.pop();
-            handle.unwrap().join().unwrap();
+        let mut res = Ok(());
+        while let Some(handle) = self.io_threads.pop() {
+            if handle.join().is_err() && res.is_ok() {
+                res = Err(NetworkError::ThreadPanicked(format!("io thread of loop {name}")));
+            }
         }
         // TODO destroy zmq context
         println!("Closed loop {name}");
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::network::{data_reader::{DataReader, DataReaderConfig}, data_writer::{DataWriter, DataWriterConfig}, sockets::{SocketKind, SocketOwner}, utils::{wait_for, wait_until}};
+
+    use super::*;
+
+    #[test]
+    fn test_socket_stats() {
+        let ch_id = String::from("ch_0");
+        let channel = Channel::Local{channel_id: ch_id.clone(), ipc_addr: String::from("ipc:///tmp/ipc_socket_stats")};
+        let reader_config = DataReaderConfig{metrics_enabled: false, ..DataReaderConfig::new(10)};
+        let writer_config = DataWriterConfig{metrics_enabled: false, ..DataWriterConfig::new(10000, 10)};
+        let data_reader = Arc::new(DataReader::new(String::from("test_reader"), String::from("test_job"), reader_config, vec![channel.clone()]).unwrap());
+        let data_writer = Arc::new(DataWriter::new(String::from("test_writer"), String::from("test_job"), writer_config, vec![channel]).unwrap());
+        let io_loop = IOLoop::new(String::from("test_loop"), None, ThreadConfig::default());
+        io_loop.register_handler(data_reader.clone()).unwrap();
+        io_loop.register_handler(data_writer.clone()).unwrap();
+        data_reader.start();
+        data_writer.start();
+        assert_eq!(io_loop.connect(1, 5000), None);
+        io_loop.start().unwrap();
+
+        for i in 0..3 {
+            data_writer.write_bytes(&ch_id, Box::new(vec![i; 100]), true, 5000, 100).unwrap().unwrap();
+        }
+        for _ in 0..3 {
+            wait_until(|| data_reader.read_bytes().unwrap().is_some());
+        }
+        assert_eq!(data_writer.flush(5000), Ok(0));
+
+        let stats = io_loop.socket_stats();
+        assert_eq!(stats.len(), 2);
+        let handler_stats = |handler: &str| stats.values().find(|stats| stats.handler == handler).unwrap().clone();
+        let (writer_stats, reader_stats) = (handler_stats("test_writer"), handler_stats("test_reader"));
+        // data one way, acks the other
+        assert_eq!((writer_stats.msgs_written, reader_stats.msgs_read), (3, 3));
+        assert_eq!(writer_stats.bytes_written, reader_stats.bytes_read);
+        assert!(writer_stats.bytes_written > 300);
+        assert_eq!((reader_stats.msgs_written, writer_stats.msgs_read), (3, 3));
+        assert_eq!(reader_stats.bytes_written, writer_stats.bytes_read);
+        assert_eq!(writer_stats.channel_id, ch_id);
+
+        data_writer.close();
+        data_reader.close();
+        io_loop.close().unwrap();
+    }
+
+    #[test]
+    fn test_send_next_keeps_buffer_on_eagain() {
+        let send_chan: BytesChan = crossbeam::channel::unbounded();
+        for i in 0..3 {
+            send_chan.0.send(Box::new(vec![i])).unwrap();
+        }
+        let sm = SocketMetadata{owner: SocketOwner::Client, kind: SocketKind::Connect, channel_id: String::from("ch_0"), addr: String::from("ipc:///tmp/ipc_send_next")};
+        let counters = SocketCounters::default();
+        let mut pending = None;
+        let mut sent = Vec::new();
+
+        // not writable, nothing is taken from send chan
+        IOLoop::send_next(&mut pending, &send_chan, &sm, false, &counters, |_| unreachable!()).unwrap();
+        assert_eq!((pending.is_none(), send_chan.1.len()), (true, 3));
+        // EAGAIN despite poll, buffer 0 is kept and goes out before the ones still in send chan
+        for _ in 0..2 {
+            IOLoop::send_next(&mut pending, &send_chan, &sm, true, &counters, |_| Err(zmq::Error::EAGAIN)).unwrap();
+            assert_eq!((pending.as_deref(), send_chan.1.len()), (Some(&vec![0]), 2));
+        }
+        while pending.is_some() || !send_chan.1.is_empty() {
+            IOLoop::send_next(&mut pending, &send_chan, &sm, true, &counters, |bytes| {
+                sent.push(bytes[0]);
+                Ok(())
+            }).unwrap();
+        }
+        assert_eq!(sent, vec![0, 1, 2]);
+        assert_eq!(counters.send_blocked.load(Ordering::Relaxed), 3);
+        assert_eq!(counters.msgs_written.load(Ordering::Relaxed), 3);
+        // other errors are not retried
+        send_chan.0.send(Box::new(vec![3])).unwrap();
+        assert!(IOLoop::send_next(&mut pending, &send_chan, &sm, true, &counters, |_| Err(zmq::Error::ETERM)).is_err());
+    }
+
+    #[test]
+    fn test_send_blocked_by_peer() {
+        let ch_id = String::from("ch_0");
+        let channel = Channel::Local{channel_id: ch_id.clone(), ipc_addr: String::from("ipc:///tmp/ipc_send_blocked")};
+        // reader takes one buffer off the socket at a time and only as it is read, so writer's socket fills up
+        let reader_config = DataReaderConfig{metrics_enabled: false, recv_chan_capacity: Some(1), ..DataReaderConfig::new(1)};
+        let writer_config = DataWriterConfig{metrics_enabled: false, ..DataWriterConfig::new(10000, 100)};
+        let data_reader = Arc::new(DataReader::new(String::from("test_reader"), String::from("test_job"), reader_config, vec![channel.clone()]).unwrap());
+        let data_writer = Arc::new(DataWriter::new(String::from("test_writer"), String::from("test_job"), writer_config, vec![channel]).unwrap());
+        let zmq_config = ZmqConfig::new(Some(1), Some(1), Some(4096), Some(4096), None, None, None, None, None);
+        let io_loop = IOLoop::new(String::from("test_loop"), Some(zmq_config), ThreadConfig::default());
+        io_loop.register_handler(data_reader.clone()).unwrap();
+        io_loop.register_handler(data_writer.clone()).unwrap();
+        data_reader.start();
+        data_writer.start();
+        assert_eq!(io_loop.connect(1, 5000), None);
+        io_loop.start().unwrap();
+
+        let num_buffers = 50;
+        for i in 0..num_buffers {
+            data_writer.write_bytes(&ch_id, Box::new(vec![i; 64 * 1024]), true, 5000, 100).unwrap().unwrap();
+        }
+        let writer_stats = || wait_for(|| io_loop.socket_stats().into_values().find(|stats| stats.handler == "test_writer"));
+        wait_until(|| writer_stats().send_blocked > 0);
+
+        let mut read = Vec::new();
+        while read.len() < num_buffers as usize {
+            let b = wait_for(|| data_reader.read_bytes().unwrap());
+            read.push(b[0]);
+        }
+        assert_eq!(read, (0..num_buffers).collect::<Vec<u8>>());
+        assert_eq!(data_writer.flush(5000), Ok(0));
+        // each buffer went out once, none was dropped or resent while socket was blocked
+        assert_eq!(writer_stats().msgs_written, num_buffers as u64);
+        assert_eq!(data_reader.read_bytes().unwrap(), None);
+
+        data_writer.close();
+        data_reader.close();
+        io_loop.close().unwrap();
     }
 }
@@ -0,0 +1,192 @@
+use std::{cell::RefCell, ops::{Deref, DerefMut}, sync::{LockResult, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError, TryLockResult}};
+
+// Order in which DataReader takes its locks: a thread holding a lock only takes locks ranked after it, so no two
+// threads can wait on each other. Locks of the same rank (e.g. out_of_order of two channels) are not held together.
+// Taking them in this order is enough, skipping some is fine. Locks outside DataReader (metrics, acks channels)
+// are leaves - nothing is taken while holding them.
+// Debug builds check every ranked acquisition and panic on a violation naming both locks, so a wrong order shows
+// up in any test going through that path instead of as a rare hang. Release builds only take the lock
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) enum LockRank {
+    Channels,
+    RecvChans,
+    SendChans,
+    Watermarks,
+    ConsumedWatermarks,
+    OutOfOrderBuffers,
+    DedupWindows,
+    LastRecvTs,
+    LastActivity,
+    Fragments,
+    OutQueue,
+    // per channel, below out_queue as dispatcher holds it for the whole pass
+    DedupWindow,
+    OutOfOrder,
+    // updated under out_queue, see DataReader::uncommitted
+    Uncommitted,
+    Backpressured,
+    EventTimeWatermarks,
+    Completed,
+    FailedChannels,
+    // leaves, taken alone or last
+    PendingAcks,
+    Inspector,
+    OverflowHandler,
+    DispatcherError,
+    CheckpointThread
+}
+
+thread_local! {
+    // ranks of ranked locks held by this thread, debug builds only
+    static HELD: RefCell<Vec<LockRank>> = const { RefCell::new(Vec::new()) };
+}
+
+// blocking acquisitions are checked, try_* ones can not block so they are only recorded
+fn check(rank: LockRank) {
+    if cfg!(debug_assertions) {
+        HELD.with(|held| {
+            if let Some(&last) = held.borrow().iter().max() {
+                assert!(rank > last, "lock order violation: taking {rank:?} while holding {last:?}");
+            }
+        });
+    }
+}
+
+fn enter(rank: LockRank) {
+    if cfg!(debug_assertions) {
+        HELD.with(|held| held.borrow_mut().push(rank));
+    }
+}
+
+// guards are not always dropped in reverse order
+fn exit(rank: LockRank) {
+    if cfg!(debug_assertions) {
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(pos) = held.iter().rposition(|&r| r == rank) {
+                held.remove(pos);
+            }
+        });
+    }
+}
+
+// guard of a lock taken with its rank, derefs to the guarded value
+pub(crate) struct Ranked<G> {
+    guard: G,
+    rank: LockRank
+}
+
+impl<G> Ranked<G> {
+    fn new(rank: LockRank, guard: G) -> Self {
+        enter(rank);
+        Ranked{guard, rank}
+    }
+}
+
+impl<G: Deref> Deref for Ranked<G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<G: DerefMut> DerefMut for Ranked<G> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<G> Drop for Ranked<G> {
+    fn drop(&mut self) {
+        exit(self.rank);
+    }
+}
+
+fn ranked<G>(rank: LockRank, res: LockResult<G>) -> LockResult<Ranked<G>> {
+    match res {
+        Ok(guard) => Ok(Ranked::new(rank, guard)),
+        Err(err) => Err(PoisonError::new(Ranked::new(rank, err.into_inner())))
+    }
+}
+
+fn try_ranked<G>(rank: LockRank, res: TryLockResult<G>) -> TryLockResult<Ranked<G>> {
+    match res {
+        Ok(guard) => Ok(Ranked::new(rank, guard)),
+        Err(TryLockError::Poisoned(err)) => Err(TryLockError::Poisoned(PoisonError::new(Ranked::new(rank, err.into_inner())))),
+        Err(TryLockError::WouldBlock) => Err(TryLockError::WouldBlock)
+    }
+}
+
+pub(crate) trait RankedMutex<T> {
+    fn lock_ranked(&self, rank: LockRank) -> LockResult<Ranked<MutexGuard<'_, T>>>;
+
+    fn try_lock_ranked(&self, rank: LockRank) -> TryLockResult<Ranked<MutexGuard<'_, T>>>;
+}
+
+impl<T> RankedMutex<T> for Mutex<T> {
+    fn lock_ranked(&self, rank: LockRank) -> LockResult<Ranked<MutexGuard<'_, T>>> {
+        check(rank);
+        ranked(rank, self.lock())
+    }
+
+    fn try_lock_ranked(&self, rank: LockRank) -> TryLockResult<Ranked<MutexGuard<'_, T>>> {
+        try_ranked(rank, self.try_lock())
+    }
+}
+
+pub(crate) trait RankedRwLock<T> {
+    fn read_ranked(&self, rank: LockRank) -> LockResult<Ranked<RwLockReadGuard<'_, T>>>;
+
+    fn write_ranked(&self, rank: LockRank) -> LockResult<Ranked<RwLockWriteGuard<'_, T>>>;
+
+    fn try_read_ranked(&self, rank: LockRank) -> TryLockResult<Ranked<RwLockReadGuard<'_, T>>>;
+}
+
+impl<T> RankedRwLock<T> for RwLock<T> {
+    fn read_ranked(&self, rank: LockRank) -> LockResult<Ranked<RwLockReadGuard<'_, T>>> {
+        check(rank);
+        ranked(rank, self.read())
+    }
+
+    fn write_ranked(&self, rank: LockRank) -> LockResult<Ranked<RwLockWriteGuard<'_, T>>> {
+        check(rank);
+        ranked(rank, self.write())
+    }
+
+    fn try_read_ranked(&self, rank: LockRank) -> TryLockResult<Ranked<RwLockReadGuard<'_, T>>> {
+        try_ranked(rank, self.try_read())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic;
+
+    use super::*;
+
+    #[test]
+    fn test_order() {
+        let out_queue = Mutex::new(0);
+        let send_chans = RwLock::new(0);
+        let out_of_order = RwLock::new(0);
+        {
+            let _send_chans = send_chans.read_ranked(LockRank::SendChans).unwrap();
+            let mut locked_out_queue = out_queue.lock_ranked(LockRank::OutQueue).unwrap();
+            *locked_out_queue += 1;
+            // dropped out of order
+            drop(_send_chans);
+            *out_of_order.write_ranked(LockRank::OutOfOrder).unwrap() += 1;
+        }
+        // all released
+        let _out_of_order = out_of_order.read_ranked(LockRank::OutOfOrder).unwrap();
+        // try_lock can not block, so it is not checked
+        assert_eq!(*out_queue.try_lock_ranked(LockRank::OutQueue).unwrap(), 1);
+        if cfg!(debug_assertions) {
+            let res = panic::catch_unwind(|| {
+                let _out_queue = out_queue.lock_ranked(LockRank::OutQueue).unwrap();
+            });
+            assert!(res.is_err());
+        }
+    }
+}
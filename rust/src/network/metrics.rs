@@ -1,27 +1,336 @@
 
-use std::{collections::HashMap, fs::{self, File}, io::{Read, Seek, SeekFrom, Write}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, RwLock, RwLockReadGuard}, thread::JoinHandle, time::Duration};
+use std::{collections::HashMap, fs::{self, File}, io::{Read, Seek, SeekFrom, Write}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex, RwLock, RwLockReadGuard}, thread::JoinHandle, time::Duration};
 use advisory_lock::{AdvisoryFileLock, FileLockMode};
 use crossbeam::queue::ArrayQueue;
+use pyo3::{pyclass, pymethods};
 
 // TODO we need to explicitly add new metric names to MetricsRecorder counters map
 pub const NUM_BUFFERS_SENT: &str = "volga_num_buffers_sent";
 pub const NUM_BUFFERS_RECVD: &str = "volga_num_buffers_recvd";
 
-pub const NUM_BUFFERS_RESENT: &str = "volga_num_buffers_resent";
+pub const NUM_BUFFERS_RESENT: &str = "volga_num_buffers_resent"; // in-flight timeout only
+pub const NUM_RETRANSMITS: &str = "volga_num_retransmits"; // any send of an already sent buffer: in-flight timeout or replay
 
 pub const NUM_BYTES_SENT: &str = "volga_num_bytes_sent";
 pub const NUM_BYTES_RECVD: &str = "volga_num_bytes_recvd";
 
+// reader drops
+pub const NUM_DUP_BELOW_WM: &str = "volga_num_dup_below_wm"; // already delivered, re-acked
+pub const NUM_DUP_OOO: &str = "volga_num_dup_ooo"; // already buffered out-of-order, re-acked
+pub const NUM_DROPPED_FULL: &str = "volga_num_dropped_full"; // out-of-order buffer full, not acked so writer resends
+pub const NUM_DROPPED_MEM: &str = "volga_num_dropped_mem"; // same, out-of-order byte limit reached
+pub const NUM_ACKS_DROPPED: &str = "volga_num_acks_dropped"; // ack channel disconnected, e.g. racing with shutdown
+pub const NUM_SKIPPED: &str = "volga_num_skipped"; // dropped and acked by DataReader::skip_to, never delivered (or consumed)
+pub const NUM_FORCE_SKIPPED: &str = "volga_num_force_skipped"; // missing ids given up on by DataReader::force_advance, acked
+pub const NUM_WRITER_RESTARTS: &str = "volga_num_writer_restarts"; // new writer epoch seen, channel's sequence state was reset
+// malformed buffer or message from a peer dropped with DecodeErrorPolicy::Skip, by readers, writers (acks) and transfer receivers
+pub const NUM_DECODE_ERRORS: &str = "volga_num_decode_errors";
+// received while out_queue was full and handed over to DataReader::set_overflow_handler's handler, acked
+pub const NUM_OVERFLOWED: &str = "volga_num_overflowed";
+// delivered but unread, discarded from the front of a full out_queue by FullQueuePolicy::DropOldest, already acked
+pub const NUM_EVICTED: &str = "volga_num_evicted";
+// head-of-line gap of an ordered channel outlasted its max_ooo_wait_ms and the missing buffer was asked for again
+pub const NUM_NACKS_SENT: &str = "volga_num_nacks_sent";
+// head-of-line gaps of ordered channels with max_ooo_wait_ms the dispatcher started timing, a gap moving on starts a new one
+pub const NUM_GAP_WAITS: &str = "volga_num_gap_waits";
+// DataReader::read_batch calls returning nothing, recorded under reader's name - job totals only, not per channel
+pub const NUM_EMPTY_READ_BATCHES: &str = "volga_num_empty_read_batches";
+
+// TTL passed, payload dropped by writer before sending or buffer dropped by reader on receipt
+pub const NUM_EXPIRED: &str = "volga_num_expired";
+
+// writer
+pub const THROTTLED_MICROS: &str = "volga_throttled_micros"; // time channel was held back by rate limiter
+pub const NUM_PUSH_REJECTED: &str = "volga_num_push_rejected"; // push attempts on a full buffer queue, incl. write retries
+pub const NUM_COMPACTED: &str = "volga_num_compacted"; // queued buffers replaced by a newer one with the same key, never sent
+
+// gauges
+pub const QUEUE_DEPTH: &str = "volga_queue_depth"; // writer's buffer queue length, in flight and waiting
+pub const OUT_OF_ORDER_BYTES: &str = "volga_out_of_order_bytes"; // payload bytes reader holds out-of-order
+
+// histograms
+pub const DELIVERY_LATENCY_MICROS: &str = "volga_delivery_latency_micros";
+pub const OUT_QUEUE_DWELL_MICROS: &str = "volga_out_queue_dwell_micros"; // reader's out_queue push to consumer's read
+// sampled (see Sampler), so the distribution shows how full a queue usually is, not just how full it is right now
+pub const OUT_QUEUE_DEPTH: &str = "volga_out_queue_depth"; // reader's out_queue entries incl. handed off ones, per reader
+pub const IN_QUEUE_DEPTH: &str = "volga_in_queue_depth"; // reader's recv chan, received but not dispatched yet
+
 
 const METRICS_PATH_PREFIX: &str = "/tmp/volga/rust/metrics";
-const FLUSH_PERIOD_S: u64 = 1;
+pub const DEFAULT_FLUSH_INTERVAL_MS: u64 = 1000;
+const FLUSH_SLEEP_STEP_MS: u64 = 100; // so close() does not wait for full flush interval, also sampling interval
+
+pub fn default_metrics_enabled() -> bool {
+    true
+}
+
+pub fn default_metrics_flush_interval_ms() -> u64 {
+    DEFAULT_FLUSH_INTERVAL_MS
+}
 
 const METRIC_KEY_DELIMITER: &str = ";";
 
+// Called by flush thread every FLUSH_SLEEP_STEP_MS, returns (metric_name, channel_or_peer_id, value) observations to
+// record into histograms. For state that is cheap to read but would cost the hot path to record on every change,
+// e.g. queue depths. Holds whatever it reads from, so it should only capture what its owner outlives anyway
+pub type Sampler = Box<dyn Fn() -> Vec<(&'static str, String, u64)> + Send + Sync>;
+
+// HDR-style log-linear buckets: each power of two is split into 2^HISTOGRAM_SUB_BUCKET_BITS linear sub-buckets,
+// which bounds relative error to 1/2^HISTOGRAM_SUB_BUCKET_BITS (12.5%) over the whole u64 range
+const HISTOGRAM_SUB_BUCKET_BITS: u32 = 3;
+const HISTOGRAM_SUB_BUCKETS: usize = 1 << HISTOGRAM_SUB_BUCKET_BITS;
+const HISTOGRAM_NUM_BUCKETS: usize = (64 - HISTOGRAM_SUB_BUCKET_BITS as usize + 1) * HISTOGRAM_SUB_BUCKETS;
+
+pub struct Histogram {
+    counts: Vec<AtomicU64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyPercentiles {
+    pub p50: u64,
+    pub p99: u64,
+    pub p999: u64,
+}
+
+impl Histogram {
+
+    pub fn new() -> Self {
+        let mut counts = Vec::with_capacity(HISTOGRAM_NUM_BUCKETS);
+        for _ in 0..HISTOGRAM_NUM_BUCKETS {
+            counts.push(AtomicU64::new(0));
+        }
+        Histogram{counts}
+    }
+
+    pub fn record(&self, value: u64) {
+        self.counts[bucket_index(value)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+    }
+
+    // returns the highest value equivalent to the bucket containing given quantile, None if empty
+    pub fn value_at_quantile(&self, quantile: f64) -> Option<u64> {
+        let loaded: Vec<u64> = self.counts.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+        let total: u64 = loaded.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let target = ((quantile * total as f64).ceil() as u64).max(1);
+        let mut cumulative = 0;
+        for (index, count) in loaded.iter().enumerate() {
+            cumulative += count;
+            if cumulative >= target {
+                return Some(bucket_upper_bound(index));
+            }
+        }
+        Some(bucket_upper_bound(loaded.len() - 1))
+    }
+
+    pub fn reset(&self) {
+        for c in self.counts.iter() {
+            c.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn percentiles(&self) -> Option<LatencyPercentiles> {
+        Some(LatencyPercentiles{
+            p50: self.value_at_quantile(0.5)?,
+            p99: self.value_at_quantile(0.99)?,
+            p999: self.value_at_quantile(0.999)?,
+        })
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < HISTOGRAM_SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let msb = 63 - value.leading_zeros();
+    let shift = msb - HISTOGRAM_SUB_BUCKET_BITS;
+    let top_bits = (value >> shift) as usize; // in [HISTOGRAM_SUB_BUCKETS, 2 * HISTOGRAM_SUB_BUCKETS)
+    (shift as usize + 1) * HISTOGRAM_SUB_BUCKETS + top_bits - HISTOGRAM_SUB_BUCKETS
+}
+
+fn bucket_upper_bound(index: usize) -> u64 {
+    if index < HISTOGRAM_SUB_BUCKETS {
+        return index as u64;
+    }
+    let shift = (index / HISTOGRAM_SUB_BUCKETS - 1) as u32;
+    let top_bits = (index % HISTOGRAM_SUB_BUCKETS + HISTOGRAM_SUB_BUCKETS) as u64;
+    ((top_bits + 1) << shift).wrapping_sub(1)
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[pyclass(name="RustChannelStats")]
+pub struct ChannelStats {
+    #[pyo3(get)]
+    pub num_buffers_sent: u64,
+    #[pyo3(get)]
+    pub num_buffers_recvd: u64,
+    #[pyo3(get)]
+    pub num_buffers_resent: u64,
+    #[pyo3(get)]
+    pub num_bytes_sent: u64,
+    #[pyo3(get)]
+    pub num_bytes_recvd: u64,
+    #[pyo3(get)]
+    pub num_dup_below_wm: u64,
+    #[pyo3(get)]
+    pub num_dup_ooo: u64,
+    #[pyo3(get)]
+    pub num_dropped_full: u64,
+    #[pyo3(get)]
+    pub throttled_micros: u64,
+    #[pyo3(get)]
+    pub num_expired: u64,
+    #[pyo3(get)]
+    pub num_acks_dropped: u64,
+    #[pyo3(get)]
+    pub num_push_rejected: u64,
+    #[pyo3(get)]
+    pub queue_depth: u64,
+    #[pyo3(get)]
+    pub num_skipped: u64,
+    #[pyo3(get)]
+    pub num_retransmits: u64,
+    #[pyo3(get)]
+    pub num_dropped_mem: u64,
+    #[pyo3(get)]
+    pub out_of_order_bytes: u64,
+    #[pyo3(get)]
+    pub num_compacted: u64,
+    #[pyo3(get)]
+    pub num_writer_restarts: u64,
+    #[pyo3(get)]
+    pub num_force_skipped: u64,
+    #[pyo3(get)]
+    pub num_decode_errors: u64,
+    #[pyo3(get)]
+    pub num_overflowed: u64,
+    #[pyo3(get)]
+    pub num_evicted: u64,
+    #[pyo3(get)]
+    pub num_nacks_sent: u64,
+    #[pyo3(get)]
+    pub num_gap_waits: u64,
+    // 0 until a buffer of the channel was read
+    #[pyo3(get)]
+    pub out_queue_dwell_p50_micros: u64,
+    #[pyo3(get)]
+    pub out_queue_dwell_p99_micros: u64,
+    #[pyo3(get)]
+    pub out_queue_dwell_p999_micros: u64,
+    // 0 until sampled, see IN_QUEUE_DEPTH
+    #[pyo3(get)]
+    pub in_queue_depth_p50: u64,
+    #[pyo3(get)]
+    pub in_queue_depth_p99: u64,
+    #[pyo3(get)]
+    pub in_queue_depth_p999: u64,
+}
+
+#[pymethods]
+impl ChannelStats {
+    // plain dict for logging from Python
+    pub fn to_dict(&self) -> HashMap<&'static str, u64> {
+        HashMap::from([
+            ("num_buffers_sent", self.num_buffers_sent),
+            ("num_buffers_recvd", self.num_buffers_recvd),
+            ("num_buffers_resent", self.num_buffers_resent),
+            ("num_bytes_sent", self.num_bytes_sent),
+            ("num_bytes_recvd", self.num_bytes_recvd),
+            ("num_dup_below_wm", self.num_dup_below_wm),
+            ("num_dup_ooo", self.num_dup_ooo),
+            ("num_dropped_full", self.num_dropped_full),
+            ("throttled_micros", self.throttled_micros),
+            ("num_expired", self.num_expired),
+            ("num_acks_dropped", self.num_acks_dropped),
+            ("num_push_rejected", self.num_push_rejected),
+            ("queue_depth", self.queue_depth),
+            ("num_skipped", self.num_skipped),
+            ("num_retransmits", self.num_retransmits),
+            ("num_dropped_mem", self.num_dropped_mem),
+            ("out_of_order_bytes", self.out_of_order_bytes),
+            ("num_compacted", self.num_compacted),
+            ("num_writer_restarts", self.num_writer_restarts),
+            ("num_force_skipped", self.num_force_skipped),
+            ("num_decode_errors", self.num_decode_errors),
+            ("num_overflowed", self.num_overflowed),
+            ("num_evicted", self.num_evicted),
+            ("num_nacks_sent", self.num_nacks_sent),
+            ("num_gap_waits", self.num_gap_waits),
+            ("out_queue_dwell_p50_micros", self.out_queue_dwell_p50_micros),
+            ("out_queue_dwell_p99_micros", self.out_queue_dwell_p99_micros),
+            ("out_queue_dwell_p999_micros", self.out_queue_dwell_p999_micros),
+            ("in_queue_depth_p50", self.in_queue_depth_p50),
+            ("in_queue_depth_p99", self.in_queue_depth_p99),
+            ("in_queue_depth_p999", self.in_queue_depth_p999),
+        ])
+    }
+}
+
+// top-line throughput of one io handler, summed over all its channels (or peers)
+#[derive(Debug, Clone, Default, PartialEq)]
+#[pyclass(name="RustJobStats")]
+pub struct JobStats {
+    #[pyo3(get)]
+    pub job_name: String,
+    #[pyo3(get)]
+    pub num_buffers_sent: u64,
+    #[pyo3(get)]
+    pub num_buffers_recvd: u64,
+    #[pyo3(get)]
+    pub num_bytes_sent: u64,
+    #[pyo3(get)]
+    pub num_bytes_recvd: u64,
+    #[pyo3(get)]
+    pub num_empty_read_batches: u64,
+    // readers only, 0 until sampled, see OUT_QUEUE_DEPTH
+    #[pyo3(get)]
+    pub out_queue_depth_p50: u64,
+    #[pyo3(get)]
+    pub out_queue_depth_p99: u64,
+    #[pyo3(get)]
+    pub out_queue_depth_p999: u64,
+}
+
+#[pymethods]
+impl JobStats {
+    pub fn to_dict(&self) -> HashMap<&'static str, u64> {
+        HashMap::from([
+            ("num_buffers_sent", self.num_buffers_sent),
+            ("num_buffers_recvd", self.num_buffers_recvd),
+            ("num_bytes_sent", self.num_bytes_sent),
+            ("num_bytes_recvd", self.num_bytes_recvd),
+            ("num_empty_read_batches", self.num_empty_read_batches),
+            ("out_queue_depth_p50", self.out_queue_depth_p50),
+            ("out_queue_depth_p99", self.out_queue_depth_p99),
+            ("out_queue_depth_p999", self.out_queue_depth_p999),
+        ])
+    }
+}
+
 pub struct MetricsRecorder {
+    // counters are cumulative, flush thread writes deltas since last flush
     counters: Arc<RwLock<HashMap<String, AtomicU64>>>,
+    // signed since reset() carries unflushed deltas over as negative offsets
+    last_flushed: Arc<Mutex<HashMap<String, i64>>>,
+    histograms: Arc<RwLock<HashMap<String, Arc<Histogram>>>>,
+    // last set value, only reported by snapshot() - flushed metrics are summed deltas
+    gauges: Arc<RwLock<HashMap<String, AtomicU64>>>,
+    samplers: Arc<RwLock<Vec<Sampler>>>,
     io_handler_name: String,
     job_name: String,
+    flush_interval_ms: u64,
+    enabled: bool, // disabled recorder is a no-op: no flush thread, nothing is recorded
 
     running: Arc<AtomicBool>,
     flush_thread_handle: Arc<ArrayQueue<JoinHandle<()>>> // array queue so we do not mutate and keep ownership
@@ -29,17 +338,39 @@ pub struct MetricsRecorder {
 
 impl MetricsRecorder {
 
-    pub fn new(io_handler_name: String, job_name: String) -> Self {
+    pub fn new(io_handler_name: String, job_name: String, flush_interval_ms: u64) -> Self {
+        Self::_new(io_handler_name, job_name, flush_interval_ms, true)
+    }
+
+    pub fn new_disabled(io_handler_name: String, job_name: String) -> Self {
+        Self::_new(io_handler_name, job_name, DEFAULT_FLUSH_INTERVAL_MS, false)
+    }
+
+    fn _new(io_handler_name: String, job_name: String, flush_interval_ms: u64, enabled: bool) -> Self {
         MetricsRecorder{
             counters: Arc::new(RwLock::new(HashMap::new())),
+            histograms: Arc::new(RwLock::new(HashMap::new())),
+            gauges: Arc::new(RwLock::new(HashMap::new())),
+            samplers: Arc::new(RwLock::new(Vec::new())),
+            last_flushed: Arc::new(Mutex::new(HashMap::new())),
             io_handler_name,
             job_name,
+            flush_interval_ms,
+            enabled,
             running: Arc::new(AtomicBool::new(false)),
             flush_thread_handle: Arc::new(ArrayQueue::new(1))
         }
     }
 
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    #[inline]
     pub fn inc(&self, metric_name: &str, channel_or_peer_id: &str, value: u64) {
+        if !self.enabled {
+            return;
+        }
         let metric_key = metric_key(metric_name, channel_or_peer_id);
         let locked_read = self.counters.read().unwrap();
         if locked_read.contains_key(&metric_key) {
@@ -52,20 +383,227 @@ impl MetricsRecorder {
         }
     }
 
+    #[inline]
+    pub fn observe(&self, metric_name: &str, channel_or_peer_id: &str, value: u64) {
+        if !self.enabled {
+            return;
+        }
+        Self::observe_into(&self.histograms, metric_key(metric_name, channel_or_peer_id), value);
+    }
+
+    fn observe_into(histograms: &RwLock<HashMap<String, Arc<Histogram>>>, metric_key: String, value: u64) {
+        let locked_read = histograms.read().unwrap();
+        if locked_read.contains_key(&metric_key) {
+            locked_read.get(&metric_key).unwrap().record(value);
+        } else {
+            drop(locked_read); // avoid deadlock
+            let mut locked_write = histograms.write().unwrap();
+            locked_write.entry(metric_key).or_insert_with(|| Arc::new(Histogram::new())).record(value);
+        }
+    }
+
+    // sampled by flush thread from start() to close()
+    pub fn add_sampler(&self, sampler: Sampler) {
+        if !self.enabled {
+            return;
+        }
+        self.samplers.write().unwrap().push(sampler);
+    }
+
+    // takes one sample from every sampler right away, on top of flush thread's schedule
+    pub fn sample_now(&self) {
+        if !self.enabled {
+            return;
+        }
+        Self::sample_all(&self.samplers, &self.histograms);
+    }
+
+    fn sample_all(samplers: &RwLock<Vec<Sampler>>, histograms: &RwLock<HashMap<String, Arc<Histogram>>>) {
+        for sampler in samplers.read().unwrap().iter() {
+            for (metric_name, channel_or_peer_id, value) in sampler() {
+                Self::observe_into(histograms, metric_key(metric_name, &channel_or_peer_id), value);
+            }
+        }
+    }
+
+    #[inline]
+    pub fn set(&self, metric_name: &str, channel_or_peer_id: &str, value: u64) {
+        if !self.enabled {
+            return;
+        }
+        let metric_key = metric_key(metric_name, channel_or_peer_id);
+        let locked_read = self.gauges.read().unwrap();
+        if let Some(gauge) = locked_read.get(&metric_key) {
+            gauge.store(value, Ordering::Relaxed);
+        } else {
+            drop(locked_read); // avoid deadlock
+            let mut locked_write = self.gauges.write().unwrap();
+            locked_write.insert(metric_key, AtomicU64::new(value));
+        }
+    }
+
+    pub fn get_percentiles(&self, metric_name: &str, channel_or_peer_id: &str) -> Option<LatencyPercentiles> {
+        let metric_key = metric_key(metric_name, channel_or_peer_id);
+        let locked_read = self.histograms.read().unwrap();
+        let histogram = locked_read.get(&metric_key)?;
+        histogram.percentiles()
+    }
+
+    // same counters as snapshot() summed across channels, without building per channel stats
+    pub fn job_totals(&self) -> JobStats {
+        let mut res = JobStats{job_name: self.job_name.clone(), ..Default::default()};
+        let locked_counters = self.counters.read().unwrap();
+        for (metric_key, counter) in locked_counters.iter() {
+            let (metric_name, _) = parse_metric_key(metric_key);
+            let total = match metric_name {
+                NUM_BUFFERS_SENT => &mut res.num_buffers_sent,
+                NUM_BUFFERS_RECVD => &mut res.num_buffers_recvd,
+                NUM_BYTES_SENT => &mut res.num_bytes_sent,
+                NUM_BYTES_RECVD => &mut res.num_bytes_recvd,
+                NUM_EMPTY_READ_BATCHES => &mut res.num_empty_read_batches,
+                _ => continue
+            };
+            *total += counter.load(Ordering::Relaxed);
+        }
+        drop(locked_counters);
+        if let Some(p) = self.get_percentiles(OUT_QUEUE_DEPTH, &self.io_handler_name) {
+            res.out_queue_depth_p50 = p.p50;
+            res.out_queue_depth_p99 = p.p99;
+            res.out_queue_depth_p999 = p.p999;
+        }
+        res
+    }
+
+    // cumulative per channel (or peer) counters, only does atomic loads under read lock
+    pub fn snapshot(&self) -> HashMap<String, ChannelStats> {
+        let mut res: HashMap<String, ChannelStats> = HashMap::new();
+        let locked_counters = self.counters.read().unwrap();
+        for (metric_key, counter) in locked_counters.iter() {
+            let (metric_name, channel_or_peer_id) = parse_metric_key(metric_key);
+            if metric_name == NUM_EMPTY_READ_BATCHES {
+                // not a channel
+                continue;
+            }
+            let val = counter.load(Ordering::Relaxed);
+            let stats = res.entry(channel_or_peer_id.to_string()).or_default();
+            match metric_name {
+                NUM_BUFFERS_SENT => stats.num_buffers_sent = val,
+                NUM_BUFFERS_RECVD => stats.num_buffers_recvd = val,
+                NUM_BUFFERS_RESENT => stats.num_buffers_resent = val,
+                NUM_BYTES_SENT => stats.num_bytes_sent = val,
+                NUM_BYTES_RECVD => stats.num_bytes_recvd = val,
+                NUM_DUP_BELOW_WM => stats.num_dup_below_wm = val,
+                NUM_DUP_OOO => stats.num_dup_ooo = val,
+                NUM_DROPPED_FULL => stats.num_dropped_full = val,
+                THROTTLED_MICROS => stats.throttled_micros = val,
+                NUM_EXPIRED => stats.num_expired = val,
+                NUM_ACKS_DROPPED => stats.num_acks_dropped = val,
+                NUM_PUSH_REJECTED => stats.num_push_rejected = val,
+                NUM_SKIPPED => stats.num_skipped = val,
+                NUM_RETRANSMITS => stats.num_retransmits = val,
+                NUM_DROPPED_MEM => stats.num_dropped_mem = val,
+                NUM_COMPACTED => stats.num_compacted = val,
+                NUM_WRITER_RESTARTS => stats.num_writer_restarts = val,
+                NUM_FORCE_SKIPPED => stats.num_force_skipped = val,
+                NUM_DECODE_ERRORS => stats.num_decode_errors = val,
+                NUM_OVERFLOWED => stats.num_overflowed = val,
+                NUM_EVICTED => stats.num_evicted = val,
+                NUM_NACKS_SENT => stats.num_nacks_sent = val,
+                NUM_GAP_WAITS => stats.num_gap_waits = val,
+                _ => {}
+            }
+        }
+        drop(locked_counters);
+        let locked_gauges = self.gauges.read().unwrap();
+        for (metric_key, gauge) in locked_gauges.iter() {
+            let (metric_name, channel_or_peer_id) = parse_metric_key(metric_key);
+            let val = gauge.load(Ordering::Relaxed);
+            let stats = res.entry(channel_or_peer_id.to_string()).or_default();
+            match metric_name {
+                QUEUE_DEPTH => stats.queue_depth = val,
+                OUT_OF_ORDER_BYTES => stats.out_of_order_bytes = val,
+                _ => {}
+            }
+        }
+        drop(locked_gauges);
+        let locked_histograms = self.histograms.read().unwrap();
+        for (metric_key, histogram) in locked_histograms.iter() {
+            let (metric_name, channel_or_peer_id) = parse_metric_key(metric_key);
+            if metric_name != OUT_QUEUE_DWELL_MICROS && metric_name != IN_QUEUE_DEPTH {
+                continue;
+            }
+            // empty after reset(), channel keeps zeroes like other stats
+            let stats = res.entry(channel_or_peer_id.to_string()).or_default();
+            let Some(p) = histogram.percentiles() else {
+                continue
+            };
+            if metric_name == OUT_QUEUE_DWELL_MICROS {
+                stats.out_queue_dwell_p50_micros = p.p50;
+                stats.out_queue_dwell_p99_micros = p.p99;
+                stats.out_queue_dwell_p999_micros = p.p999;
+            } else {
+                stats.in_queue_depth_p50 = p.p50;
+                stats.in_queue_depth_p99 = p.p99;
+                stats.in_queue_depth_p999 = p.p999;
+            }
+        }
+        res
+    }
+
+    // Zeroes all counters and histograms as seen by snapshot()/get_percentiles().
+    // Holds the flush lock, so it is serialized with flushes: whatever was counted but not yet flushed
+    // is carried over as a negative last_flushed offset and will still be flushed exactly once.
+    // Concurrent inc() is not lost - swap is atomic, so increments land either before (carried over) or after (kept) the reset.
+    // Histogram reset is not atomic across buckets, observations racing with reset may survive it.
+    // Gauges are current state and are kept.
+    pub fn reset(&self) {
+        if !self.enabled {
+            return;
+        }
+        let locked_counters = self.counters.read().unwrap();
+        let mut locked_last_flushed = self.last_flushed.lock().unwrap();
+        for (metric_key, counter) in locked_counters.iter() {
+            let val = counter.swap(0, Ordering::Relaxed) as i64;
+            let prev = locked_last_flushed.get(metric_key).copied().unwrap_or(0);
+            locked_last_flushed.insert(metric_key.clone(), prev - val);
+        }
+        drop(locked_last_flushed);
+        drop(locked_counters);
+
+        let locked_histograms = self.histograms.read().unwrap();
+        for histogram in locked_histograms.values() {
+            histogram.reset();
+        }
+    }
+
     pub fn start(&self) {
+        if !self.enabled {
+            return;
+        }
         self.running.store(true, Ordering::Relaxed);
 
-
+        let flush_interval_ms = self.flush_interval_ms;
         let this_runnning = self.running.clone();
         let this_counters = self.counters.clone();
+        let this_last_flushed = self.last_flushed.clone();
+        let this_samplers = self.samplers.clone();
+        let this_histograms = self.histograms.clone();
         let this_io_handler_name = self.io_handler_name.clone();
         let this_job_name = self.job_name.clone();
         let f = move || {
             while this_runnning.load(Ordering::Relaxed) {
                 let locked_counters = this_counters.read().unwrap();
-                MetricsRecorder::flush_all(locked_counters, this_io_handler_name.clone(), this_job_name.clone());
-
-                std::thread::sleep(Duration::from_secs(FLUSH_PERIOD_S));
+                let mut locked_last_flushed = this_last_flushed.lock().unwrap();
+                MetricsRecorder::flush_all(locked_counters, &mut locked_last_flushed, this_io_handler_name.clone(), this_job_name.clone());
+                drop(locked_last_flushed);
+
+                let mut slept_ms = 0;
+                while slept_ms < flush_interval_ms && this_runnning.load(Ordering::Relaxed) {
+                    let step_ms = FLUSH_SLEEP_STEP_MS.min(flush_interval_ms - slept_ms);
+                    std::thread::sleep(Duration::from_millis(step_ms));
+                    slept_ms += step_ms;
+                    MetricsRecorder::sample_all(&this_samplers, &this_histograms);
+                }
             }
         };
 
@@ -74,19 +612,25 @@ impl MetricsRecorder {
     }
 
     pub fn close(&self) {
+        if !self.enabled {
+            return;
+        }
         self.running.store(false, Ordering::Relaxed);
-        let handle = self.flush_thread_handle.pop();
-        handle.unwrap().join().unwrap();
+        if let Some(handle) = self.flush_thread_handle.pop() {
+            handle.join().unwrap();
+        }
         let locked_counters = self.counters.read().unwrap();
-        MetricsRecorder::flush_all(locked_counters, self.io_handler_name.clone(), self.job_name.clone());
+        let mut locked_last_flushed = self.last_flushed.lock().unwrap();
+        MetricsRecorder::flush_all(locked_counters, &mut locked_last_flushed, self.io_handler_name.clone(), self.job_name.clone());
     }
 
-    fn flush_all(counters: RwLockReadGuard<HashMap<String, AtomicU64>>, io_handler_name: String, job_name: String) {
+    fn flush_all(counters: RwLockReadGuard<HashMap<String, AtomicU64>>, last_flushed: &mut HashMap<String, i64>, io_handler_name: String, job_name: String) {
         let mut to_flush = HashMap::new();
         for (metric_key, counter) in counters.iter() {
-            // load value and reset counter
-            let val = counter.swap(0, Ordering::Relaxed);
-            to_flush.insert(metric_key.clone(), val);
+            // flush delta since last flush, counter itself keeps cumulative value for snapshots
+            let val = counter.load(Ordering::Relaxed) as i64;
+            let prev = last_flushed.insert(metric_key.clone(), val).unwrap_or(0);
+            to_flush.insert(metric_key.clone(), (val - prev) as u64);
         }
         flush_map(to_flush, io_handler_name, job_name.clone());
     }
@@ -97,6 +641,10 @@ fn metric_key(metric_name: &str, channel_or_peer_id: &str) -> String {
     format!("{metric_name}{METRIC_KEY_DELIMITER}{channel_or_peer_id}")
 }
 
+fn parse_metric_key(metric_key: &str) -> (&str, &str) {
+    metric_key.split_once(METRIC_KEY_DELIMITER).unwrap()
+}
+
 fn flush_map(to_flush: HashMap<String, u64>, io_handler_name: String, job_name: String) {
     // load previously stored data
     let path = format!("{METRICS_PATH_PREFIX}/{job_name}");
@@ -106,7 +654,7 @@ fn flush_map(to_flush: HashMap<String, u64>, io_handler_name: String, job_name:
     // locking
     // https://rust.code-maven.com/update-file-using-advisory-lock
     let mut file =  File::options().read(true).write(true).create(true).open(filename).unwrap();
-    file.lock(FileLockMode::Exclusive).unwrap();
+    AdvisoryFileLock::lock(&file, FileLockMode::Exclusive).unwrap();
     let mut v = Vec::new();
     file.read_to_end(&mut v).unwrap();
     let mut stored: HashMap<String, u64> = HashMap::new();
@@ -127,7 +675,7 @@ fn flush_map(to_flush: HashMap<String, u64>, io_handler_name: String, job_name:
     file.seek(SeekFrom::Start(0)).unwrap();
     file.set_len(0).unwrap(); // truncate
     file.write_all(&b).unwrap();
-    file.unlock().unwrap();
+    AdvisoryFileLock::unlock(&file).unwrap();
 }
 
 #[cfg(test)]
@@ -170,6 +718,39 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_histogram_buckets() {
+        // bucket bounds are monotonic and every value falls into a bucket covering it
+        for value in [0, 1, 7, 8, 9, 15, 16, 17, 100, 1000, 123456, u32::MAX as u64, u64::MAX] {
+            let index = bucket_index(value);
+            assert!(index < HISTOGRAM_NUM_BUCKETS);
+            assert!(bucket_upper_bound(index) >= value);
+            if index > 0 {
+                assert!(bucket_upper_bound(index - 1) < value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_histogram_percentiles() {
+        let h = Histogram::new();
+        assert_eq!(h.percentiles(), None);
+        for v in 1..=1000 {
+            h.record(v);
+        }
+        assert_eq!(h.count(), 1000);
+        let p = h.percentiles().unwrap();
+        // bucket resolution is 12.5%
+        assert!(p.p50 >= 500 && p.p50 <= 500 * 9 / 8);
+        assert!(p.p99 >= 990 && p.p99 <= 990 * 9 / 8);
+        assert!(p.p999 >= 999 && p.p999 <= 999 * 9 / 8);
+
+        let mr = MetricsRecorder::new(String::from("dummy_handler"), String::from("dummy_job"), DEFAULT_FLUSH_INTERVAL_MS);
+        mr.observe(DELIVERY_LATENCY_MICROS, "ch_0", 5);
+        assert_eq!(mr.get_percentiles(DELIVERY_LATENCY_MICROS, "ch_0"), Some(LatencyPercentiles{p50: 5, p99: 5, p999: 5}));
+        assert_eq!(mr.get_percentiles(DELIVERY_LATENCY_MICROS, "ch_1"), None);
+    }
+
     #[test]
     fn test_metrics_recorder() {
         let now_ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
@@ -177,12 +758,12 @@ mod tests {
         let io_handler_name = String::from("dummy_handler");
         let channel_id = "ch_0";
 
-        let mr = MetricsRecorder::new(io_handler_name.clone(), job_name.clone());
+        let mr = MetricsRecorder::new(io_handler_name.clone(), job_name.clone(), DEFAULT_FLUSH_INTERVAL_MS);
         mr.start();
         mr.inc(NUM_BUFFERS_SENT, channel_id, 1);
-        std::thread::sleep(Duration::from_secs(FLUSH_PERIOD_S));
+        std::thread::sleep(Duration::from_millis(DEFAULT_FLUSH_INTERVAL_MS));
         mr.inc(NUM_BUFFERS_SENT, channel_id, 2);
-        std::thread::sleep(Duration::from_secs(FLUSH_PERIOD_S));
+        std::thread::sleep(Duration::from_millis(DEFAULT_FLUSH_INTERVAL_MS));
         mr.inc(NUM_BUFFERS_RECVD, channel_id, 4);
         std::thread::sleep(Duration::from_millis(100));
         mr.close();
@@ -204,4 +785,141 @@ mod tests {
 
         assert_eq!(res, expected);
     }
+
+    #[test]
+    fn test_snapshot() {
+        let mr = MetricsRecorder::new(String::from("dummy_handler"), String::from("dummy_job"), DEFAULT_FLUSH_INTERVAL_MS);
+        mr.inc(NUM_BUFFERS_SENT, "ch_0", 1);
+        mr.inc(NUM_BUFFERS_SENT, "ch_0", 2);
+        mr.inc(NUM_BYTES_SENT, "ch_0", 100);
+        mr.inc(NUM_BUFFERS_RECVD, "ch_1", 4);
+        mr.inc(NUM_DUP_BELOW_WM, "ch_1", 1);
+        mr.inc(NUM_DUP_OOO, "ch_1", 2);
+        mr.inc(NUM_DROPPED_FULL, "ch_1", 3);
+
+        let snapshot = mr.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot.get("ch_0").unwrap(), &ChannelStats{num_buffers_sent: 3, num_bytes_sent: 100, ..Default::default()});
+        assert_eq!(snapshot.get("ch_1").unwrap(), &ChannelStats{num_buffers_recvd: 4, num_dup_below_wm: 1, num_dup_ooo: 2, num_dropped_full: 3, ..Default::default()});
+
+        let d = snapshot.get("ch_0").unwrap().to_dict();
+        assert_eq!(d.len(), 31);
+        assert_eq!(d["num_buffers_sent"], 3);
+        assert_eq!(d["num_bytes_recvd"], 0);
+
+        // gauge keeps last value and survives reset
+        mr.set(QUEUE_DEPTH, "ch_0", 5);
+        mr.set(QUEUE_DEPTH, "ch_0", 2);
+        mr.set(QUEUE_DEPTH, "ch_2", 1);
+        mr.reset();
+        let snapshot = mr.snapshot();
+        assert_eq!(snapshot.get("ch_0").unwrap(), &ChannelStats{queue_depth: 2, ..Default::default()});
+        assert_eq!(snapshot.get("ch_2").unwrap(), &ChannelStats{queue_depth: 1, ..Default::default()});
+
+        mr.observe(OUT_QUEUE_DWELL_MICROS, "ch_3", 5);
+        mr.observe(DELIVERY_LATENCY_MICROS, "ch_4", 5);
+        let snapshot = mr.snapshot();
+        assert_eq!(snapshot.get("ch_3").unwrap(), &ChannelStats{out_queue_dwell_p50_micros: 5, out_queue_dwell_p99_micros: 5, out_queue_dwell_p999_micros: 5, ..Default::default()});
+        assert!(!snapshot.contains_key("ch_4"));
+    }
+
+    #[test]
+    fn test_job_totals() {
+        let mr = MetricsRecorder::new(String::from("dummy_handler"), String::from("dummy_job"), DEFAULT_FLUSH_INTERVAL_MS);
+        assert_eq!(mr.job_totals(), JobStats{job_name: String::from("dummy_job"), ..Default::default()});
+        mr.inc(NUM_BUFFERS_SENT, "ch_0", 3);
+        mr.inc(NUM_BUFFERS_SENT, "ch_1", 2);
+        mr.inc(NUM_BYTES_SENT, "ch_0", 100);
+        mr.inc(NUM_BYTES_RECVD, "ch_1", 7);
+        mr.inc(NUM_DUP_OOO, "ch_1", 1);
+        mr.inc(NUM_EMPTY_READ_BATCHES, "dummy_handler", 2);
+        assert_eq!(mr.job_totals(), JobStats{job_name: String::from("dummy_job"), num_buffers_sent: 5, num_bytes_sent: 100, num_bytes_recvd: 7, num_empty_read_batches: 2, ..Default::default()});
+        assert!(!mr.snapshot().contains_key("dummy_handler"));
+        mr.reset();
+        assert_eq!(mr.job_totals().num_buffers_sent, 0);
+    }
+
+    #[test]
+    fn test_samplers() {
+        let mr = MetricsRecorder::new(String::from("dummy_handler"), String::from("dummy_job"), DEFAULT_FLUSH_INTERVAL_MS);
+        let depth = Arc::new(AtomicU64::new(3));
+        let this_depth = depth.clone();
+        mr.add_sampler(Box::new(move || vec![
+            (OUT_QUEUE_DEPTH, String::from("dummy_handler"), this_depth.load(Ordering::Relaxed)),
+            (IN_QUEUE_DEPTH, String::from("ch_0"), 2)
+        ]));
+        assert_eq!(mr.job_totals().out_queue_depth_p50, 0);
+        mr.sample_now();
+        depth.store(7, Ordering::Relaxed);
+        mr.sample_now();
+
+        let totals = mr.job_totals();
+        assert_eq!((totals.out_queue_depth_p50, totals.out_queue_depth_p999), (3, 7));
+        let snapshot = mr.snapshot();
+        assert_eq!(snapshot.get("ch_0").unwrap(), &ChannelStats{in_queue_depth_p50: 2, in_queue_depth_p99: 2, in_queue_depth_p999: 2, ..Default::default()});
+        assert!(!snapshot.contains_key("dummy_handler"));
+
+        // flush thread samples too
+        mr.reset();
+        mr.start();
+        std::thread::sleep(Duration::from_millis(3 * FLUSH_SLEEP_STEP_MS));
+        mr.close();
+        assert_eq!(mr.job_totals().out_queue_depth_p50, 7);
+
+        let disabled = MetricsRecorder::new_disabled(String::from("dummy_handler"), String::from("dummy_job"));
+        disabled.add_sampler(Box::new(|| vec![(IN_QUEUE_DEPTH, String::from("ch_0"), 1)]));
+        disabled.sample_now();
+        assert!(disabled.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_reset() {
+        let now_ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let job_name = format!("job-{now_ts}");
+        let io_handler_name = String::from("reset_handler");
+        let channel_id = "ch_0";
+
+        let mr = MetricsRecorder::new(io_handler_name.clone(), job_name.clone(), DEFAULT_FLUSH_INTERVAL_MS);
+        let flush = |mr: &MetricsRecorder| {
+            let mut locked_last_flushed = mr.last_flushed.lock().unwrap();
+            MetricsRecorder::flush_all(mr.counters.read().unwrap(), &mut locked_last_flushed, io_handler_name.clone(), job_name.clone());
+        };
+        mr.inc(NUM_BUFFERS_SENT, channel_id, 3);
+        mr.observe(DELIVERY_LATENCY_MICROS, channel_id, 10);
+        flush(&mr);
+        mr.inc(NUM_BUFFERS_SENT, channel_id, 2);
+        mr.reset();
+
+        assert_eq!(mr.snapshot().get(channel_id).unwrap(), &ChannelStats::default());
+        assert_eq!(mr.get_percentiles(DELIVERY_LATENCY_MICROS, channel_id), None);
+
+        // increments made before reset but not yet flushed are still flushed once
+        mr.inc(NUM_BUFFERS_SENT, channel_id, 1);
+        flush(&mr);
+        assert_eq!(mr.snapshot().get(channel_id).unwrap().num_buffers_sent, 1);
+
+        let filename = format!("{METRICS_PATH_PREFIX}/{job_name}/{io_handler_name}_metrics.metrics");
+        let b = fs::read(filename.clone()).unwrap();
+        fs::remove_file(filename).unwrap();
+        let res: HashMap<String, u64> = rmp_serde::from_slice(&b).unwrap();
+        assert_eq!(*res.get(&metric_key(NUM_BUFFERS_SENT, channel_id)).unwrap(), 6);
+    }
+
+    #[test]
+    fn test_disabled_recorder() {
+        let now_ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let job_name = format!("job-{now_ts}");
+        let io_handler_name = String::from("disabled_handler");
+
+        let mr = MetricsRecorder::new_disabled(io_handler_name.clone(), job_name.clone());
+        mr.start();
+        mr.inc(NUM_BUFFERS_SENT, "ch_0", 1);
+        mr.observe(DELIVERY_LATENCY_MICROS, "ch_0", 1);
+        mr.close();
+
+        assert!(mr.snapshot().is_empty());
+        assert_eq!(mr.get_percentiles(DELIVERY_LATENCY_MICROS, "ch_0"), None);
+        let filename = format!("{METRICS_PATH_PREFIX}/{job_name}/{io_handler_name}_metrics.metrics");
+        assert!(!std::path::Path::new(&filename).exists());
+    }
 }
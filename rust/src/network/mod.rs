@@ -10,4 +10,15 @@ pub mod buffer_queues;
 pub mod remote_transfer_handler;
 pub mod metrics;
 pub mod network_config;
-pub mod sockets_monitor;
\ No newline at end of file
+pub mod sockets_monitor;
+pub mod partitioner;
+pub mod rate_limiter;
+pub mod error;
+pub mod clock;
+pub mod threads;
+pub mod in_memory;
+pub mod checkpoint_store;
+pub mod lock_order;
+pub mod trace;
+#[cfg(feature = "protobuf")]
+pub mod proto;
\ No newline at end of file
@@ -1,4 +1,4 @@
-use super::{data_reader::DataReaderConfig, data_writer::DataWriterConfig, io_loop::ZmqConfig, remote_transfer_handler::TransferConfig};
+use super::{data_reader::DataReaderConfig, data_writer::DataWriterConfig, io_loop::ZmqConfig, remote_transfer_handler::TransferConfig, threads::ThreadConfig};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
@@ -7,6 +7,8 @@ pub struct NetworkConfig {
     pub data_writer: DataWriterConfig,
     pub transfer: TransferConfig,
     pub zmq: Option<ZmqConfig>,
+    #[serde(default)]
+    pub io_threads: ThreadConfig,
 }
 
 impl NetworkConfig {
@@ -0,0 +1,232 @@
+use std::sync::{atomic::{AtomicUsize, Ordering}, PoisonError, RwLock};
+
+use pyo3::pyclass;
+use serde::{Deserialize, Serialize};
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+pub const DEFAULT_VIRTUAL_NODES: usize = 128;
+
+// Selects which of writer's channels (index in writer's channel list) a message goes to
+pub trait Partitioner: Send + Sync {
+    fn partition(&self, key: Option<&[u8]>, num_channels: usize) -> usize;
+
+    // same, for partitioners placing keys by channel id rather than position, so channels added or removed
+    // anywhere in the list do not move keys of the others
+    fn partition_channel(&self, key: Option<&[u8]>, channel_ids: &[String]) -> usize {
+        self.partition(key, channel_ids.len())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[pyclass(name="RustPartitionerType")]
+pub enum PartitionerType {
+    #[default]
+    RoundRobin,
+    Hash,
+    // consistent hashing, see ConsistentHashPartitioner
+    ConsistentHash
+}
+
+impl PartitionerType {
+    pub fn new_partitioner(&self) -> Box<dyn Partitioner> {
+        match self {
+            PartitionerType::RoundRobin => Box::new(RoundRobinPartitioner::new()),
+            PartitionerType::Hash => Box::new(HashPartitioner::new()),
+            PartitionerType::ConsistentHash => Box::new(ConsistentHashPartitioner::new(DEFAULT_VIRTUAL_NODES))
+        }
+    }
+}
+
+// FNV-1a, unlike std hashers it is stable across processes and Rust versions,
+// so writers on different workers route same key to same channel
+pub fn hash_key(key: &[u8]) -> u64 {
+    key.iter().fold(FNV_OFFSET_BASIS, |h, v| (h ^ *v as u64).wrapping_mul(FNV_PRIME))
+}
+
+// same key always goes to same channel, messages without key are spread round robin
+pub struct HashPartitioner {
+    keyless: RoundRobinPartitioner
+}
+
+impl HashPartitioner {
+    pub fn new() -> Self {
+        HashPartitioner{keyless: RoundRobinPartitioner::new()}
+    }
+}
+
+impl Default for HashPartitioner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Partitioner for HashPartitioner {
+    fn partition(&self, key: Option<&[u8]>, num_channels: usize) -> usize {
+        match key {
+            Some(key) => (hash_key(key) % num_channels as u64) as usize,
+            None => self.keyless.partition(None, num_channels)
+        }
+    }
+}
+
+// murmur3 finalizer, FNV-1a alone spreads similar short strings (e.g. ids of virtual nodes) unevenly over the ring
+fn mix(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+// Consistent hashing: every channel owns virtual_nodes points on a hash ring, placed by its channel id, and a key
+// goes to the owner of the first point at or after the key's hash. Adding a channel to N moves only the ~1/(N+1) of
+// keys landing on its new points, removing one moves only its own keys, so stateful downstream operators migrate
+// that much state on rescale instead of almost all of it as with Hash. More virtual nodes spread keys more evenly
+// at the cost of ring size. Messages without key are spread round robin
+pub struct ConsistentHashPartitioner {
+    virtual_nodes: usize,
+    keyless: RoundRobinPartitioner,
+    // rebuilt when writer's channel list changes
+    ring: RwLock<HashRing>
+}
+
+#[derive(Default)]
+struct HashRing {
+    channel_ids: Vec<String>,
+    // (point, channel index), sorted by point
+    points: Vec<(u64, usize)>
+}
+
+impl HashRing {
+    fn new(channel_ids: &[String], virtual_nodes: usize) -> Self {
+        let mut points: Vec<(u64, usize)> = channel_ids.iter().enumerate()
+            .flat_map(|(i, channel_id)| (0..virtual_nodes).map(move |vnode| (mix(hash_key(format!("{channel_id}#{vnode}").as_bytes())), i)))
+            .collect();
+        points.sort_unstable();
+        HashRing{channel_ids: channel_ids.to_vec(), points}
+    }
+
+    fn lookup(&self, key: &[u8]) -> usize {
+        let h = mix(hash_key(key));
+        let pos = self.points.partition_point(|(point, _)| *point < h);
+        self.points.get(pos).or(self.points.first()).map_or(0, |(_, i)| *i)
+    }
+}
+
+impl ConsistentHashPartitioner {
+    pub fn new(virtual_nodes: usize) -> Self {
+        ConsistentHashPartitioner{virtual_nodes: virtual_nodes.max(1), keyless: RoundRobinPartitioner::new(), ring: RwLock::new(HashRing::default())}
+    }
+}
+
+impl Partitioner for ConsistentHashPartitioner {
+    // channels are placed by position only, prefer partition_channel
+    fn partition(&self, key: Option<&[u8]>, num_channels: usize) -> usize {
+        let channel_ids: Vec<String> = (0..num_channels).map(|i| i.to_string()).collect();
+        self.partition_channel(key, &channel_ids)
+    }
+
+    fn partition_channel(&self, key: Option<&[u8]>, channel_ids: &[String]) -> usize {
+        let Some(key) = key else {
+            return self.keyless.partition(None, channel_ids.len())
+        };
+        // a panic while rebuilding leaves the old ring, which is still a valid one
+        {
+            let ring = self.ring.read().unwrap_or_else(PoisonError::into_inner);
+            if ring.channel_ids == channel_ids {
+                return ring.lookup(key);
+            }
+        }
+        let mut ring = self.ring.write().unwrap_or_else(PoisonError::into_inner);
+        if ring.channel_ids != channel_ids {
+            *ring = HashRing::new(channel_ids, self.virtual_nodes);
+        }
+        ring.lookup(key)
+    }
+}
+
+// ignores key
+pub struct RoundRobinPartitioner {
+    seq: AtomicUsize
+}
+
+impl RoundRobinPartitioner {
+    pub fn new() -> Self {
+        RoundRobinPartitioner{seq: AtomicUsize::new(0)}
+    }
+}
+
+impl Default for RoundRobinPartitioner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Partitioner for RoundRobinPartitioner {
+    fn partition(&self, _key: Option<&[u8]>, num_channels: usize) -> usize {
+        self.seq.fetch_add(1, Ordering::Relaxed) % num_channels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partitioners() {
+        // reference FNV-1a values
+        assert_eq!(hash_key(b""), 0xcbf29ce484222325);
+        assert_eq!(hash_key(b"a"), 0xaf63dc4c8601ec8c);
+
+        let p = PartitionerType::Hash.new_partitioner();
+        let i = p.partition(Some(b"key_1"), 4);
+        assert!(i < 4);
+        assert!((0..10).all(|_| p.partition(Some(b"key_1"), 4) == i));
+        let keyless: Vec<usize> = (0..5).map(|_| p.partition(None, 4)).collect();
+        assert_eq!(keyless, vec![0, 1, 2, 3, 0]);
+
+        let p = PartitionerType::RoundRobin.new_partitioner();
+        let res: Vec<usize> = (0..5).map(|_| p.partition(Some(b"key_1"), 3)).collect();
+        assert_eq!(res, vec![0, 1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        let p = PartitionerType::ConsistentHash.new_partitioner();
+        let channel_ids = |n: usize| -> Vec<String> { (0..n).map(|i| format!("ch_{i}")).collect() };
+        let keys: Vec<Vec<u8>> = (0..10000).map(|i| format!("key_{i}").into_bytes()).collect();
+        let assign = |channel_ids: &[String]| -> Vec<String> {
+            keys.iter().map(|key| channel_ids[p.partition_channel(Some(key), channel_ids)].clone()).collect()
+        };
+        let keyless: Vec<usize> = (0..5).map(|_| p.partition_channel(None, &channel_ids(4))).collect();
+        assert_eq!(keyless, vec![0, 1, 2, 3, 0]);
+
+        let before = assign(&channel_ids(4));
+        // roughly even
+        for channel_id in channel_ids(4) {
+            let n = before.iter().filter(|c| **c == channel_id).count();
+            assert!(n > 1500 && n < 3500, "{channel_id} got {n} keys");
+        }
+
+        // added channel takes ~1/5 of keys, the rest stay where they were
+        let after_add = assign(&channel_ids(5));
+        let moved: Vec<_> = before.iter().zip(&after_add).filter(|(b, a)| b != a).collect();
+        assert!(moved.iter().all(|(_, a)| *a == "ch_4"));
+        let moved_fraction = moved.len() as f64 / keys.len() as f64;
+        assert!(moved_fraction > 0.1 && moved_fraction < 0.3, "moved {moved_fraction}");
+
+        // removing a channel from the middle moves only its keys
+        let without_ch_1: Vec<String> = channel_ids(4).into_iter().filter(|c| c != "ch_1").collect();
+        let after_remove = assign(&without_ch_1);
+        for (b, a) in before.iter().zip(&after_remove) {
+            assert!(b == a || b == "ch_1");
+        }
+
+        // Hash reshuffles most of them
+        let hash = PartitionerType::Hash.new_partitioner();
+        let hash_moved = keys.iter().filter(|key| hash.partition(Some(key), 4) != hash.partition(Some(key), 5)).count();
+        assert!(hash_moved as f64 / keys.len() as f64 > 0.5);
+    }
+}
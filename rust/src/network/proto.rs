@@ -0,0 +1,317 @@
+// Protobuf encoding of network messages for peers that can not use bincode or the native buffer header.
+// Wire types are generated from proto/network.proto by prost (see build.rs) and converted to and from the native
+// messages here, so the .proto file is the single definition: proto3 semantics, default values are not encoded,
+// unknown fields are skipped. Tests below pin the wire bytes, encoded by hand, as a check that the two agree.
+
+use prost::Message;
+
+use super::{buffer_utils::{get_buffer_event_time_watermark, get_buffer_expire_ts, get_buffer_flags, get_buffer_id, get_buffer_send_ts, get_buffer_writer_epoch, get_channeld_id, new_buffer_drop_meta, new_buffer_with_meta_and_flags}, channel::{AckBatchMessage, AckMessage, BackpressureMessage, NackMessage, ReaderMessage, ShutdownMessage}, error::{NetworkError, NetworkResult}, io_loop::Bytes};
+
+// generated, package volga.network
+mod pb {
+    include!(concat!(env!("OUT_DIR"), "/volga.network.rs"));
+}
+
+pub trait ProtoMessage: Sized {
+    fn encode_proto(&self) -> Vec<u8>;
+    fn decode_proto(b: &[u8]) -> NetworkResult<Self>;
+}
+
+// data buffer as a standalone message, converts to and from native buffer layout
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct ChannelMessage {
+    pub channel_id: String,
+    pub buffer_id: u64,
+    pub send_ts_micros: u64,
+    pub expire_ts_micros: Option<u64>,
+    pub event_time_wm: Option<u64>,
+    pub flags: u8,
+    pub payload: Vec<u8>,
+    pub writer_epoch: Option<u64>
+}
+
+impl ChannelMessage {
+    pub fn from_buffer(b: &Bytes) -> Self {
+        ChannelMessage{
+            channel_id: get_channeld_id(Box::new(b.clone())),
+            buffer_id: get_buffer_id(Box::new(b.clone())),
+            send_ts_micros: get_buffer_send_ts(Box::new(b.clone())),
+            expire_ts_micros: get_buffer_expire_ts(b),
+            event_time_wm: get_buffer_event_time_watermark(b),
+            flags: get_buffer_flags(b),
+            payload: *new_buffer_drop_meta(Box::new(b.clone())),
+            writer_epoch: get_buffer_writer_epoch(b)
+        }
+    }
+
+    pub fn to_buffer(&self) -> Box<Bytes> {
+        new_buffer_with_meta_and_flags(
+            Box::new(self.payload.clone()), self.channel_id.clone(), self.buffer_id, self.send_ts_micros,
+            self.expire_ts_micros, self.event_time_wm, self.flags, self.writer_epoch
+        )
+    }
+}
+
+fn decode<M: Message + Default>(b: &[u8]) -> NetworkResult<M> {
+    M::decode(b).map_err(|err| NetworkError::Decode(format!("protobuf: {err}")))
+}
+
+impl From<&AckMessage> for pb::AckMessage {
+    fn from(ack: &AckMessage) -> Self {
+        pb::AckMessage{channel_id: ack.channel_id.clone(), buffer_id: ack.buffer_id}
+    }
+}
+
+impl From<pb::AckMessage> for AckMessage {
+    fn from(ack: pb::AckMessage) -> Self {
+        AckMessage{channel_id: ack.channel_id, buffer_id: ack.buffer_id}
+    }
+}
+
+impl From<&NackMessage> for pb::NackMessage {
+    fn from(nack: &NackMessage) -> Self {
+        pb::NackMessage{channel_id: nack.channel_id.clone(), buffer_id: nack.buffer_id}
+    }
+}
+
+impl From<pb::NackMessage> for NackMessage {
+    fn from(nack: pb::NackMessage) -> Self {
+        NackMessage{channel_id: nack.channel_id, buffer_id: nack.buffer_id}
+    }
+}
+
+impl From<&ShutdownMessage> for pb::ShutdownMessage {
+    fn from(shutdown: &ShutdownMessage) -> Self {
+        pb::ShutdownMessage{channel_id: shutdown.channel_id.clone()}
+    }
+}
+
+impl From<pb::ShutdownMessage> for ShutdownMessage {
+    fn from(shutdown: pb::ShutdownMessage) -> Self {
+        ShutdownMessage{channel_id: shutdown.channel_id}
+    }
+}
+
+impl From<&BackpressureMessage> for pb::BackpressureMessage {
+    fn from(bp: &BackpressureMessage) -> Self {
+        pb::BackpressureMessage{channel_id: bp.channel_id.clone(), paused: bp.paused}
+    }
+}
+
+impl From<pb::BackpressureMessage> for BackpressureMessage {
+    fn from(bp: pb::BackpressureMessage) -> Self {
+        BackpressureMessage{channel_id: bp.channel_id, paused: bp.paused}
+    }
+}
+
+impl From<&AckBatchMessage> for pb::AckBatchMessage {
+    fn from(acks: &AckBatchMessage) -> Self {
+        pb::AckBatchMessage{channel_id: acks.channel_id.clone(), buffer_ids: acks.buffer_ids.clone()}
+    }
+}
+
+impl From<pb::AckBatchMessage> for AckBatchMessage {
+    fn from(acks: pb::AckBatchMessage) -> Self {
+        AckBatchMessage{channel_id: acks.channel_id, buffer_ids: acks.buffer_ids}
+    }
+}
+
+impl From<&ReaderMessage> for pb::ReaderMessage {
+    fn from(msg: &ReaderMessage) -> Self {
+        let msg = match msg {
+            ReaderMessage::Ack(ack) => pb::reader_message::Msg::Ack(ack.into()),
+            ReaderMessage::Backpressure(bp) => pb::reader_message::Msg::Backpressure(bp.into()),
+            ReaderMessage::AckBatch(acks) => pb::reader_message::Msg::AckBatch(acks.into()),
+            ReaderMessage::Nack(nack) => pb::reader_message::Msg::Nack(nack.into()),
+            ReaderMessage::Shutdown(shutdown) => pb::reader_message::Msg::Shutdown(shutdown.into())
+        };
+        pb::ReaderMessage{msg: Some(msg)}
+    }
+}
+
+impl TryFrom<pb::ReaderMessage> for ReaderMessage {
+    type Error = NetworkError;
+
+    fn try_from(msg: pb::ReaderMessage) -> NetworkResult<Self> {
+        match msg.msg {
+            Some(pb::reader_message::Msg::Ack(ack)) => Ok(ReaderMessage::Ack(ack.into())),
+            Some(pb::reader_message::Msg::Backpressure(bp)) => Ok(ReaderMessage::Backpressure(bp.into())),
+            Some(pb::reader_message::Msg::AckBatch(acks)) => Ok(ReaderMessage::AckBatch(acks.into())),
+            Some(pb::reader_message::Msg::Nack(nack)) => Ok(ReaderMessage::Nack(nack.into())),
+            Some(pb::reader_message::Msg::Shutdown(shutdown)) => Ok(ReaderMessage::Shutdown(shutdown.into())),
+            None => Err(NetworkError::Decode(String::from("protobuf: reader message is not set")))
+        }
+    }
+}
+
+// optional fields are 0 on the wire when not set
+impl From<&ChannelMessage> for pb::ChannelMessage {
+    fn from(msg: &ChannelMessage) -> Self {
+        pb::ChannelMessage{
+            channel_id: msg.channel_id.clone(),
+            buffer_id: msg.buffer_id,
+            send_ts_micros: msg.send_ts_micros,
+            expire_ts_micros: msg.expire_ts_micros.unwrap_or(0),
+            event_time_wm: msg.event_time_wm.unwrap_or(0),
+            flags: msg.flags as u32,
+            payload: msg.payload.clone(),
+            writer_epoch: msg.writer_epoch.unwrap_or(0)
+        }
+    }
+}
+
+impl From<pb::ChannelMessage> for ChannelMessage {
+    fn from(msg: pb::ChannelMessage) -> Self {
+        ChannelMessage{
+            channel_id: msg.channel_id,
+            buffer_id: msg.buffer_id,
+            send_ts_micros: msg.send_ts_micros,
+            expire_ts_micros: Some(msg.expire_ts_micros).filter(|ts| *ts != 0),
+            event_time_wm: Some(msg.event_time_wm).filter(|wm| *wm != 0),
+            flags: msg.flags as u8,
+            payload: msg.payload,
+            writer_epoch: Some(msg.writer_epoch).filter(|epoch| *epoch != 0)
+        }
+    }
+}
+
+// encoded through the generated type, native one is converted on the way in and out
+macro_rules! impl_proto_message {
+    ($native:ty, $generated:ty) => {
+        impl ProtoMessage for $native {
+            fn encode_proto(&self) -> Vec<u8> {
+                <$generated>::from(self).encode_to_vec()
+            }
+
+            fn decode_proto(b: &[u8]) -> NetworkResult<Self> {
+                Ok(decode::<$generated>(b)?.into())
+            }
+        }
+    };
+}
+
+impl_proto_message!(AckMessage, pb::AckMessage);
+impl_proto_message!(NackMessage, pb::NackMessage);
+impl_proto_message!(ShutdownMessage, pb::ShutdownMessage);
+impl_proto_message!(BackpressureMessage, pb::BackpressureMessage);
+impl_proto_message!(AckBatchMessage, pb::AckBatchMessage);
+impl_proto_message!(ChannelMessage, pb::ChannelMessage);
+
+impl ProtoMessage for ReaderMessage {
+    fn encode_proto(&self) -> Vec<u8> {
+        pb::ReaderMessage::from(self).encode_to_vec()
+    }
+
+    fn decode_proto(b: &[u8]) -> NetworkResult<Self> {
+        decode::<pb::ReaderMessage>(b)?.try_into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::network::buffer_utils::BUFFER_FLAG_BATCH;
+
+    use super::*;
+
+    // expected bytes are encoded by hand following protobuf encoding spec
+
+    #[test]
+    fn test_ack_wire_format() {
+        let ack = AckMessage{channel_id: String::from("ch_0"), buffer_id: 300};
+        // field 1, len 4, "ch_0"; field 2, varint 300
+        let expected = vec![0x0a, 0x04, b'c', b'h', b'_', b'0', 0x10, 0xac, 0x02];
+        assert_eq!(ack.encode_proto(), expected);
+        assert_eq!(AckMessage::decode_proto(&expected), Ok(ack));
+
+        // defaults are not on the wire
+        assert_eq!(AckMessage{channel_id: String::new(), buffer_id: 0}.encode_proto(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_reader_message_wire_format() {
+        let bp = ReaderMessage::Backpressure(BackpressureMessage{channel_id: String::from("c"), paused: true});
+        // field 2 (backpressure), len 5: field 1, len 1, "c"; field 2, varint 1
+        let expected = vec![0x12, 0x05, 0x0a, 0x01, b'c', 0x10, 0x01];
+        assert_eq!(bp.encode_proto(), expected);
+        assert_eq!(ReaderMessage::decode_proto(&expected), Ok(bp));
+
+        // empty ack is still set
+        let ack = ReaderMessage::Ack(AckMessage{channel_id: String::new(), buffer_id: 0});
+        assert_eq!(ack.encode_proto(), vec![0x0a, 0x00]);
+        assert_eq!(ReaderMessage::decode_proto(&[0x0a, 0x00]), Ok(ack));
+
+        assert!(ReaderMessage::decode_proto(&[]).is_err());
+
+        let acks = ReaderMessage::AckBatch(AckBatchMessage{channel_id: String::from("c"), buffer_ids: vec![1, 300]});
+        // field 3 (ack_batch), len 8: field 1, len 1, "c"; field 2, packed len 3: varint 1, varint 300
+        let expected = vec![0x1a, 0x08, 0x0a, 0x01, b'c', 0x12, 0x03, 0x01, 0xac, 0x02];
+        assert_eq!(acks.encode_proto(), expected);
+        assert_eq!(ReaderMessage::decode_proto(&expected), Ok(acks));
+        // unpacked
+        let unpacked = vec![0x0a, 0x01, b'c', 0x10, 0x01, 0x10, 0xac, 0x02];
+        assert_eq!(AckBatchMessage::decode_proto(&unpacked), Ok(AckBatchMessage{channel_id: String::from("c"), buffer_ids: vec![1, 300]}));
+
+        let nack = ReaderMessage::Nack(NackMessage{channel_id: String::from("c"), buffer_id: 5});
+        // field 4 (nack), len 5: field 1, len 1, "c"; field 2, varint 5
+        let expected = vec![0x22, 0x05, 0x0a, 0x01, b'c', 0x10, 0x05];
+        assert_eq!(nack.encode_proto(), expected);
+        assert_eq!(ReaderMessage::decode_proto(&expected), Ok(nack));
+
+        let shutdown = ReaderMessage::Shutdown(ShutdownMessage{channel_id: String::from("c")});
+        // field 5 (shutdown), len 3: field 1, len 1, "c"
+        let expected = vec![0x2a, 0x03, 0x0a, 0x01, b'c'];
+        assert_eq!(shutdown.encode_proto(), expected);
+        assert_eq!(ReaderMessage::decode_proto(&expected), Ok(shutdown));
+    }
+
+    #[test]
+    fn test_channel_message_wire_format() {
+        let msg = ChannelMessage{
+            channel_id: String::from("ch_1"),
+            buffer_id: 1,
+            send_ts_micros: 150,
+            expire_ts_micros: None,
+            event_time_wm: Some(2),
+            flags: BUFFER_FLAG_BATCH,
+            payload: vec![0xff, 0x00],
+            writer_epoch: Some(3)
+        };
+        let expected = vec![
+            0x0a, 0x04, b'c', b'h', b'_', b'1', // channel_id
+            0x10, 0x01, // buffer_id
+            0x18, 0x96, 0x01, // send_ts_micros
+            0x28, 0x02, // event_time_wm, expire_ts_micros is not set
+            0x30, 0x01, // flags
+            0x3a, 0x02, 0xff, 0x00, // payload
+            0x40, 0x03 // writer_epoch
+        ];
+        assert_eq!(msg.encode_proto(), expected);
+        assert_eq!(ChannelMessage::decode_proto(&expected), Ok(msg.clone()));
+
+        // same message through native buffer layout
+        assert_eq!(ChannelMessage::from_buffer(&msg.to_buffer()), msg);
+
+        // empty payload is omitted on the wire and decoded back as empty
+        let empty = ChannelMessage{payload: Vec::new(), ..msg};
+        assert_eq!(ChannelMessage::decode_proto(&empty.encode_proto()), Ok(empty.clone()));
+        assert_eq!(ChannelMessage::from_buffer(&empty.to_buffer()), empty);
+    }
+
+    #[test]
+    fn test_decode_unknown_and_malformed() {
+        // unknown varint, fixed64, fixed32 and length-delimited fields are skipped
+        let mut b = vec![0x0a, 0x01, b'c', 0x10, 0x07];
+        b.extend_from_slice(&[0x18, 0x01]);
+        b.extend_from_slice(&[0x21, 0, 0, 0, 0, 0, 0, 0, 0]);
+        b.extend_from_slice(&[0x2d, 0, 0, 0, 0]);
+        b.extend_from_slice(&[0x32, 0x01, 0x00]);
+        assert_eq!(AckMessage::decode_proto(&b), Ok(AckMessage{channel_id: String::from("c"), buffer_id: 7}));
+
+        // truncated string
+        assert!(AckMessage::decode_proto(&[0x0a, 0x05, b'c']).is_err());
+        // truncated varint
+        assert!(AckMessage::decode_proto(&[0x10, 0x80]).is_err());
+        // wrong wire type for buffer_id
+        assert!(AckMessage::decode_proto(&[0x12, 0x00]).is_err());
+    }
+}
@@ -1,13 +1,39 @@
-use std::{any::Any, borrow::{Borrow, BorrowMut}, hash::Hash, sync::{Arc, RwLock}};
+use std::{any::Any, borrow::{Borrow, BorrowMut}, collections::HashMap, hash::Hash, sync::{Arc, RwLock}, time::Instant};
 
-use pyo3::{pyclass, pymethods, types::{PyBytes, PyTuple}, IntoPy, Py, PyAny, PyResult, PyTryFrom, Python};
+use pyo3::{exceptions::{PyIOError, PyRuntimeError, PyTimeoutError, PyValueError}, pyclass, pymethods, types::{PyByteArray, PyBytes, PyTuple}, IntoPy, Py, PyAny, PyErr, PyRef, PyResult, PyTryFrom, Python};
 
-use super::{channel::Channel, data_reader::{self, DataReader, DataReaderConfig}, data_writer::{DataWriter, DataWriterConfig}, io_loop::{Direction, IOHandler, IOLoop, ZmqConfig}, remote_transfer_handler::{RemoteTransferHandler, TransferConfig}};
+use super::{channel::Channel, data_reader::{self, CloseError, DataReader, DataReaderConfig, HealthStatus, DEFAULT_HEALTH_RECV_WINDOW_MS}, data_writer::{DataWriter, DataWriterConfig}, in_memory::InMemoryTransport, io_loop::{Direction, IOHandler, IOHandlerType, IOLoop, SocketStats, ZmqConfig}, metrics::{ChannelStats, JobStats}, remote_transfer_handler::{RemoteTransferHandler, TransferConfig}, threads::ThreadConfig};
 
 pub trait ToRustChannel {
     fn to_rust_channel(&self) -> Channel;
 }
 
+// accepts RustLocalChannel, RustRemoteChannel or RustInMemoryChannel
+fn extract_rust_channel(ch: &PyAny) -> Channel {
+    let ext: Result<PyLocalChannel, pyo3::PyErr> = ch.extract();
+    if ext.is_ok() {
+        return ext.unwrap().to_rust_channel();
+    }
+    if let Ok(ext) = ch.extract::<PyInMemoryChannel>() {
+        return ext.to_rust_channel();
+    }
+    let ext: Result<PyRemoteChannel, pyo3::PyErr> = ch.extract();
+    ext.unwrap().to_rust_channel()
+}
+
+// channel_id -> (is_remote, address_summary)
+fn channel_addresses(channels: Vec<Channel>) -> HashMap<String, (bool, String)> {
+    channels.iter().map(|ch| (ch.get_channel_id().clone(), (ch.is_remote(), ch.address_summary()))).collect()
+}
+
+fn close_error_to_py(err: CloseError) -> PyErr {
+    match err {
+        CloseError::Timeout => PyTimeoutError::new_err(err.to_string()),
+        CloseError::ThreadPanicked(_) | CloseError::Drain(_) => PyRuntimeError::new_err(err.to_string()),
+        CloseError::Checkpoint(_) => PyIOError::new_err(err.to_string())
+    }
+}
+
 #[derive(Clone)]
 #[pyclass(name="RustLocalChannel")]
 pub struct PyLocalChannel {
@@ -24,6 +50,14 @@ impl PyLocalChannel {
     pub fn new(channel_id: String, ipc_addr: String) -> Self {
         PyLocalChannel{channel_id: channel_id.clone(), ipc_addr: ipc_addr.clone()}
     }
+
+    pub fn is_remote(&self) -> bool {
+        false
+    }
+
+    pub fn address_summary(&self) -> String {
+        self.to_rust_channel().address_summary()
+    }
 }
 
 impl ToRustChannel for PyLocalChannel {
@@ -70,6 +104,37 @@ impl ToRustChannel for PyRemoteChannel {
     }
 }
 
+#[derive(Clone)]
+#[pyclass(name="RustInMemoryChannel")]
+pub struct PyInMemoryChannel {
+    #[pyo3(get, set)]
+    channel_id: String
+}
+
+#[pymethods]
+impl PyInMemoryChannel {
+
+    #[new]
+    pub fn new(channel_id: String) -> Self {
+        PyInMemoryChannel{channel_id}
+    }
+
+    pub fn is_remote(&self) -> bool {
+        false
+    }
+
+    pub fn address_summary(&self) -> String {
+        self.to_rust_channel().address_summary()
+    }
+}
+
+impl ToRustChannel for PyInMemoryChannel {
+
+    fn to_rust_channel(&self) -> Channel {
+        Channel::InMemory {channel_id: self.channel_id.clone()}
+    }
+}
+
 #[pymethods]
 impl PyRemoteChannel {
 
@@ -95,6 +160,14 @@ impl PyRemoteChannel {
             port: port.clone()
         }
     }
+
+    pub fn is_remote(&self) -> bool {
+        true
+    }
+
+    pub fn address_summary(&self) -> String {
+        self.to_rust_channel().address_summary()
+    }
 }
 
 
@@ -108,38 +181,217 @@ pub struct PyDataReader {
 impl PyDataReader {
 
     #[new]
-    pub fn new(name: String, job_name: String, config: &DataReaderConfig, channels: Vec<&PyAny>) -> PyDataReader {
+    pub fn new(name: String, job_name: String, config: &DataReaderConfig, channels: Vec<&PyAny>) -> PyResult<PyDataReader> {
         let mut rust_channels = Vec::new();
         for ch in channels {
-            let ext: Result<PyLocalChannel, pyo3::PyErr> = ch.extract();
-            if ext.is_ok() {
-                rust_channels.push(ext.unwrap().to_rust_channel());
-            } else {
-                let ext: Result<PyRemoteChannel, pyo3::PyErr> = ch.extract();
-                rust_channels.push(ext.unwrap().to_rust_channel());
-            }
+            rust_channels.push(extract_rust_channel(ch));
         };
-        let data_reader = DataReader::new(name, job_name, config.clone(), rust_channels);
-        PyDataReader{data_reader: Arc::new(data_reader)}
+        let data_reader = DataReader::new(name, job_name, config.clone(), rust_channels)?;
+        Ok(PyDataReader{data_reader: Arc::new(data_reader)})
     }
 
     pub fn start(&self) {
         (self.data_reader.clone() as Arc<dyn IOHandler>).start();
     }
 
-    pub fn close(&self) {
-        (self.data_reader.clone() as Arc<dyn IOHandler>).close();
+    // raises like close_timeout, e.g. IOError if final checkpoint could not be saved, GIL is released while waiting
+    pub fn close(&self, py: Python) -> PyResult<()> {
+        let data_reader = self.data_reader.clone();
+        py.allow_threads(move || data_reader.close_timeout(u64::MAX)).map_err(close_error_to_py)
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.data_reader.is_running()
     }
 
-    pub fn read_bytes(&self, py: Python) -> Option<Py<PyBytes>>{
-        let bytes = self.data_reader.read_bytes();
+    // raises TimeoutError instead of hanging if dispatcher does not exit in time, GIL is released while waiting
+    pub fn close_timeout(&self, py: Python, timeout_ms: u64) -> PyResult<()> {
+        let data_reader = self.data_reader.clone();
+        py.allow_threads(move || data_reader.close_timeout(timeout_ms)).map_err(close_error_to_py)
+    }
+
+    // final close, writers stop waiting for this reader's acks, see DataReader::shutdown
+    pub fn shutdown(&self, py: Python, timeout_ms: u64) -> PyResult<()> {
+        let data_reader = self.data_reader.clone();
+        py.allow_threads(move || data_reader.shutdown(timeout_ms)).map_err(close_error_to_py)
+    }
+
+    // closes and returns buffers left in out_queue in read order, see DataReader::close_and_drain for what is excluded
+    #[pyo3(signature = (drain_out_of_order=false))]
+    pub fn close_and_drain(&self, py: Python, drain_out_of_order: bool) -> PyResult<Vec<Py<PyBytes>>> {
+        let data_reader = self.data_reader.clone();
+        let drained = py.allow_threads(move || data_reader.close_and_drain(drain_out_of_order)).map_err(close_error_to_py)?;
+        Ok(drained.into_iter().map(|b| PyBytes::new(py, b.as_slice()).into()).collect())
+    }
+
+    // with reader: ... - starts on enter, closes (joins dispatcher thread) on exit, exceptions are propagated
+    pub fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf.start();
+        slf
+    }
+
+    pub fn __exit__(&self, py: Python, _exc_type: Option<&PyAny>, _exc_value: Option<&PyAny>, _traceback: Option<&PyAny>) -> PyResult<bool> {
+        self.close(py)?;
+        Ok(false)
+    }
+
+    // raises if dispatcher thread failed, otherwise reader would silently return None forever
+    pub fn read_bytes(&self, py: Python) -> PyResult<Option<Py<PyBytes>>> {
+        if let Some(err) = self.data_reader.get_dispatcher_error() {
+            return Err(PyRuntimeError::new_err(format!("Dispatcher thread failed: {err}")));
+        }
+        let bytes = self.data_reader.read_bytes()?;
         if !bytes.is_none() {
             let bytes = bytes.unwrap();
             let pb = PyBytes::new(py, bytes.as_slice());
-            Some(pb.into())
+            Ok(Some(pb.into()))
         } else {
-            None
+            Ok(None)
+        }
+    }
+
+    // up to max_size buffers, empty list if nothing is delivered yet
+    pub fn read_batch(&self, py: Python, max_size: usize) -> PyResult<Vec<Py<PyBytes>>> {
+        if let Some(err) = self.data_reader.get_dispatcher_error() {
+            return Err(PyRuntimeError::new_err(format!("Dispatcher thread failed: {err}")));
+        }
+        Ok(self.data_reader.read_batch(max_size)?.into_iter().map(|b| PyBytes::new(py, b.as_slice()).into()).collect())
+    }
+
+    // same as read_bytes, but returns None instead of waiting while dispatcher holds the queue
+    pub fn try_read_bytes(&self, py: Python) -> PyResult<Option<Py<PyBytes>>> {
+        if let Some(err) = self.data_reader.get_dispatcher_error() {
+            return Err(PyRuntimeError::new_err(format!("Dispatcher thread failed: {err}")));
+        }
+        Ok(self.data_reader.try_read_bytes()?.map(|b| PyBytes::new(py, b.as_slice()).into()))
+    }
+
+    // same as read_bytes, but copies the payload into dst, resized to fit it, and returns its length. Payload's storage
+    // is recycled, see DataReader::recycle
+    pub fn read_into(&self, dst: &PyByteArray) -> PyResult<Option<usize>> {
+        if let Some(err) = self.data_reader.get_dispatcher_error() {
+            return Err(PyRuntimeError::new_err(format!("Dispatcher thread failed: {err}")));
+        }
+        let Some(b) = self.data_reader.read_bytes()? else {
+            return Ok(None)
+        };
+        dst.resize(b.len())?;
+        // safe as GIL is held and no python code runs between resize and copy, so dst can not change size meanwhile
+        unsafe { dst.as_bytes_mut() }.copy_from_slice(&b);
+        let len = b.len();
+        self.data_reader.recycle(*b);
+        Ok(Some(len))
+    }
+
+    // same as read_bytes, but only returns buffers of given channel
+    pub fn read_bytes_from(&self, py: Python, channel_id: String) -> PyResult<Option<Py<PyBytes>>> {
+        if let Some(err) = self.data_reader.get_dispatcher_error() {
+            return Err(PyRuntimeError::new_err(format!("Dispatcher thread failed: {err}")));
+        }
+        Ok(self.data_reader.read_bytes_from(&channel_id)?.map(|b| PyBytes::new(py, b.as_slice()).into()))
+    }
+
+    // same as read_bytes, but returns (channel_id, buffer_id, payload)
+    pub fn read_message(&self, py: Python) -> PyResult<Option<(String, u64, Py<PyBytes>)>> {
+        if let Some(err) = self.data_reader.get_dispatcher_error() {
+            return Err(PyRuntimeError::new_err(format!("Dispatcher thread failed: {err}")));
         }
+        Ok(self.data_reader.read_message()?.map(|(channel_id, buffer_id, b)| {
+            (channel_id, buffer_id, PyBytes::new(py, b.as_slice()).into())
+        }))
+    }
+
+    pub fn commit(&self, channel_id: String, buffer_id: u64) -> PyResult<bool> {
+        Ok(self.data_reader.commit(&channel_id, buffer_id)?)
+    }
+
+    pub fn uncommitted(&self) -> HashMap<String, Vec<u64>> {
+        self.data_reader.uncommitted()
+    }
+
+    pub fn restart_dispatcher(&self) -> bool {
+        self.data_reader.restart_dispatcher()
+    }
+
+    pub fn add_channel(&self, channel: &PyAny) -> PyResult<()> {
+        Ok(self.data_reader.add_channel(extract_rust_channel(channel))?)
+    }
+
+    pub fn seek(&self, channel_id: String, watermark: i64) -> PyResult<()> {
+        Ok(self.data_reader.seek(&channel_id, watermark)?)
+    }
+
+    pub fn skip_to(&self, channel_id: String, buffer_id: u64) -> PyResult<usize> {
+        Ok(self.data_reader.skip_to(&channel_id, buffer_id)?)
+    }
+
+    pub fn force_advance(&self, channel_id: String, up_to: i64) -> PyResult<usize> {
+        Ok(self.data_reader.force_advance(&channel_id, up_to)?)
+    }
+
+    pub fn checkpoint(&self, path: String) -> PyResult<()> {
+        Ok(self.data_reader.checkpoint(&path)?)
+    }
+
+    pub fn remove_channel(&self, channel_id: String) -> PyResult<()> {
+        Ok(self.data_reader.remove_channel(&channel_id)?)
+    }
+
+    pub fn get_name(&self) -> String {
+        self.data_reader.get_name()
+    }
+
+    pub fn get_handler_type(&self) -> IOHandlerType {
+        self.data_reader.get_handler_type()
+    }
+
+    pub fn get_channel_addresses(&self) -> HashMap<String, (bool, String)> {
+        channel_addresses(self.data_reader.get_channels())
+    }
+
+    pub fn get_metrics_snapshot(&self) -> HashMap<String, ChannelStats> {
+        self.data_reader.get_metrics_snapshot()
+    }
+
+    pub fn get_job_totals(&self) -> JobStats {
+        self.data_reader.get_job_totals()
+    }
+
+    #[pyo3(signature = (recv_window_ms=DEFAULT_HEALTH_RECV_WINDOW_MS))]
+    pub fn health(&self, recv_window_ms: u64) -> HealthStatus {
+        self.data_reader.health(recv_window_ms)
+    }
+
+    pub fn reset_metrics(&self) {
+        self.data_reader.reset_metrics()
+    }
+
+    pub fn gaps(&self) -> HashMap<String, Vec<u64>> {
+        self.data_reader.gaps()
+    }
+
+    pub fn available_capacity(&self) -> PyResult<usize> {
+        Ok(self.data_reader.available_capacity()?)
+    }
+
+    pub fn current_event_time_watermark(&self) -> PyResult<Option<u64>> {
+        Ok(self.data_reader.current_event_time_watermark()?)
+    }
+
+    pub fn completed_channels(&self) -> PyResult<Vec<String>> {
+        Ok(self.data_reader.completed_channels()?)
+    }
+
+    // channel_id -> millis since the channel last delivered a buffer, see DataReader::last_activity
+    pub fn last_activity_elapsed_ms(&self) -> HashMap<String, u64> {
+        let now = Instant::now();
+        self.data_reader.last_activity().into_iter().map(|(channel_id, ts)| (channel_id, now.saturating_duration_since(ts).as_millis() as u64)).collect()
+    }
+
+    // (p50, p99, p999) in micros
+    pub fn get_delivery_latency(&self, channel_id: String) -> Option<(u64, u64, u64)> {
+        let p = self.data_reader.get_delivery_latency(&channel_id)?;
+        Some((p.p50, p.p99, p.p999))
     }
 }
 
@@ -153,19 +405,13 @@ pub struct PyDataWriter {
 impl PyDataWriter {
 
     #[new]
-    pub fn new(name: String, job_name: String, config: &DataWriterConfig, channels: Vec<&PyAny>) -> PyDataWriter {
+    pub fn new(name: String, job_name: String, config: &DataWriterConfig, channels: Vec<&PyAny>) -> PyResult<PyDataWriter> {
         let mut rust_channels = Vec::new();
         for ch in channels {
-            let ext: Result<PyLocalChannel, pyo3::PyErr> = ch.extract();
-            if ext.is_ok() {
-                rust_channels.push(ext.unwrap().to_rust_channel());
-            } else {
-                let ext: Result<PyRemoteChannel, pyo3::PyErr> = ch.extract();
-                rust_channels.push(ext.unwrap().to_rust_channel());
-            }
+            rust_channels.push(extract_rust_channel(ch));
         };
-        let data_writer = DataWriter::new(name, job_name, config.clone(), rust_channels);
-        PyDataWriter{data_writer: Arc::new(data_writer)}
+        let data_writer = DataWriter::new(name, job_name, config.clone(), rust_channels)?;
+        Ok(PyDataWriter{data_writer: Arc::new(data_writer)})
     }
 
     pub fn start(&self) {
@@ -176,9 +422,119 @@ impl PyDataWriter {
         self.data_writer.close();
     }
 
-    pub fn write_bytes(&self, channel_id: String, b: &PyBytes, block: bool, timeout_ms: i32, retry_step_micros: u64) -> Option<u128> {
+    pub fn is_running(&self) -> bool {
+        self.data_writer.is_running()
+    }
+
+    // number of buffers still unacked after waiting up to timeout_ms, writes are still accepted. GIL is released while waiting
+    pub fn flush(&self, py: Python, timeout_ms: u64) -> PyResult<usize> {
+        let data_writer = self.data_writer.clone();
+        Ok(py.allow_threads(move || data_writer.flush(timeout_ms))?)
+    }
+
+    // True if everything written was acked within timeout_ms, further writes raise ConnectionError. GIL is released while waiting
+    pub fn drain(&self, py: Python, timeout_ms: u64) -> PyResult<bool> {
+        let data_writer = self.data_writer.clone();
+        Ok(py.allow_threads(move || data_writer.drain(timeout_ms))?)
+    }
+
+    // number of buffers left undelivered
+    pub fn stop(&self, py: Python) -> PyResult<usize> {
+        let data_writer = self.data_writer.clone();
+        Ok(py.allow_threads(move || data_writer.stop())?)
+    }
+
+    // with writer: ... - starts on enter, closes (joins io threads) on exit, exceptions are propagated
+    pub fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf.start();
+        slf
+    }
+
+    pub fn __exit__(&self, _exc_type: Option<&PyAny>, _exc_value: Option<&PyAny>, _traceback: Option<&PyAny>) -> bool {
+        self.close();
+        false
+    }
+
+    #[pyo3(signature = (channel_id, b, block, timeout_ms, retry_step_micros, ttl_ms=None))]
+    pub fn write_bytes(&self, channel_id: String, b: &PyBytes, block: bool, timeout_ms: i32, retry_step_micros: u64, ttl_ms: Option<u64>) -> PyResult<Option<u128>> {
+        let bytes = b.as_bytes().to_vec();
+        Ok(self.data_writer.write_bytes_with_ttl(&channel_id, Box::new(bytes), ttl_ms, block, timeout_ms, retry_step_micros)?)
+    }
+
+    pub fn write_priority_bytes(&self, channel_id: String, b: &PyBytes, block: bool, timeout_ms: i32, retry_step_micros: u64) -> PyResult<Option<u128>> {
+        let bytes = b.as_bytes().to_vec();
+        Ok(self.data_writer.write_priority_bytes(&channel_id, Box::new(bytes), block, timeout_ms, retry_step_micros)?)
+    }
+
+    pub fn write_eof(&self, channel_id: String, block: bool, timeout_ms: i32, retry_step_micros: u64) -> PyResult<Option<u128>> {
+        Ok(self.data_writer.write_eof(&channel_id, block, timeout_ms, retry_step_micros)?)
+    }
+
+    pub fn broadcast(&self, b: &PyBytes, timeout_ms: i32, retry_step_micros: u64) -> PyResult<Vec<String>> {
         let bytes = b.as_bytes().to_vec();
-        self.data_writer.write_bytes(&channel_id, Box::new(bytes), block, timeout_ms, retry_step_micros)
+        Ok(self.data_writer.broadcast(Box::new(bytes), timeout_ms, retry_step_micros)?)
+    }
+
+    pub fn partition(&self, key: Option<&PyBytes>) -> PyResult<String> {
+        Ok(self.data_writer.partition(key.map(|k| k.as_bytes()))?)
+    }
+
+    #[pyo3(signature = (key, b, block, timeout_ms, retry_step_micros))]
+    pub fn write_bytes_by_key(&self, key: Option<&PyBytes>, b: &PyBytes, block: bool, timeout_ms: i32, retry_step_micros: u64) -> PyResult<Option<(String, u128)>> {
+        let bytes = b.as_bytes().to_vec();
+        Ok(self.data_writer.write_bytes_by_key(key.map(|k| k.as_bytes()), Box::new(bytes), block, timeout_ms, retry_step_micros)?)
+    }
+
+    pub fn add_channel(&self, channel: &PyAny) -> PyResult<()> {
+        Ok(self.data_writer.add_channel(extract_rust_channel(channel))?)
+    }
+
+    pub fn replay(&self, channel_id: String, buffer_id: u64) -> PyResult<bool> {
+        Ok(self.data_writer.replay(&channel_id, buffer_id)?)
+    }
+
+    pub fn remove_channel(&self, channel_id: String) -> PyResult<()> {
+        Ok(self.data_writer.remove_channel(&channel_id)?)
+    }
+
+    pub fn advance_event_time_watermark(&self, event_time_wm: u64) {
+        self.data_writer.advance_event_time_watermark(event_time_wm)
+    }
+
+    pub fn get_in_flight(&self) -> PyResult<HashMap<String, usize>> {
+        Ok(self.data_writer.get_in_flight()?)
+    }
+
+    pub fn get_queue_depths(&self) -> PyResult<HashMap<String, usize>> {
+        Ok(self.data_writer.get_queue_depths()?)
+    }
+
+    pub fn get_closed_channels(&self) -> PyResult<Vec<String>> {
+        Ok(self.data_writer.get_closed_channels()?)
+    }
+
+    pub fn get_name(&self) -> String {
+        self.data_writer.get_name()
+    }
+
+    pub fn get_handler_type(&self) -> IOHandlerType {
+        self.data_writer.get_handler_type()
+    }
+
+    pub fn get_channel_addresses(&self) -> HashMap<String, (bool, String)> {
+        channel_addresses(self.data_writer.get_channels())
+    }
+
+    pub fn get_metrics_snapshot(&self) -> HashMap<String, ChannelStats> {
+        self.data_writer.get_metrics_snapshot()
+    }
+
+    pub fn get_job_totals(&self) -> JobStats {
+        self.data_writer.get_job_totals()
+    }
+
+    pub fn reset_metrics(&self) {
+        self.data_writer.reset_metrics()
     }
 }
 
@@ -208,6 +564,26 @@ impl PyTransferSender {
     pub fn close(&self) {
         self.transfer_sender.close();
     }
+
+    pub fn get_name(&self) -> String {
+        self.transfer_sender.get_name()
+    }
+
+    pub fn get_handler_type(&self) -> IOHandlerType {
+        self.transfer_sender.get_handler_type()
+    }
+
+    pub fn get_channel_addresses(&self) -> HashMap<String, (bool, String)> {
+        channel_addresses(self.transfer_sender.get_channels())
+    }
+
+    pub fn get_metrics_snapshot(&self) -> HashMap<String, ChannelStats> {
+        self.transfer_sender.get_metrics_snapshot()
+    }
+
+    pub fn get_job_totals(&self) -> JobStats {
+        self.transfer_sender.get_job_totals()
+    }
 }
 
 #[pyclass(name="RustTransferReceiver")]
@@ -235,6 +611,26 @@ impl PyTransferReceiver {
     pub fn close(&self) {
         self.transfer_receiver.close();
     }
+
+    pub fn get_name(&self) -> String {
+        self.transfer_receiver.get_name()
+    }
+
+    pub fn get_handler_type(&self) -> IOHandlerType {
+        self.transfer_receiver.get_handler_type()
+    }
+
+    pub fn get_channel_addresses(&self) -> HashMap<String, (bool, String)> {
+        channel_addresses(self.transfer_receiver.get_channels())
+    }
+
+    pub fn get_metrics_snapshot(&self) -> HashMap<String, ChannelStats> {
+        self.transfer_receiver.get_metrics_snapshot()
+    }
+
+    pub fn get_job_totals(&self) -> JobStats {
+        self.transfer_receiver.get_job_totals()
+    }
 }
 
 #[pyclass(name="RustIOLoop")]
@@ -246,37 +642,87 @@ pub struct PyIOLoop {
 impl PyIOLoop {
 
     #[new]
-    pub fn new(name: String, zmq_config: Option<ZmqConfig>) -> PyIOLoop {
-        PyIOLoop{
-            io_loop: IOLoop::new(name, zmq_config),
-        }
+    #[pyo3(signature = (name, zmq_config, thread_config=None))]
+    pub fn new(name: String, zmq_config: Option<ZmqConfig>, thread_config: Option<ThreadConfig>) -> PyResult<PyIOLoop> {
+        let thread_config = thread_config.unwrap_or_default();
+        thread_config.validate().map_err(PyValueError::new_err)?;
+        Ok(PyIOLoop{
+            io_loop: IOLoop::new(name, zmq_config, thread_config),
+        })
     }
 
-    pub fn register_data_writer(&self, dw: &PyDataWriter) {
-        self.io_loop.register_handler(dw.data_writer.clone());
+    pub fn register_data_writer(&self, dw: &PyDataWriter) -> PyResult<()> {
+        Ok(self.io_loop.register_handler(dw.data_writer.clone())?)
     }
 
-    pub fn register_data_reader(&self, dr: &PyDataReader) {
-        self.io_loop.register_handler(dr.data_reader.clone());
+    pub fn register_data_reader(&self, dr: &PyDataReader) -> PyResult<()> {
+        Ok(self.io_loop.register_handler(dr.data_reader.clone())?)
     }
 
-    pub fn register_transfer_sender(&self, ts: &PyTransferSender) {
-        self.io_loop.register_handler(ts.transfer_sender.clone());
+    pub fn register_transfer_sender(&self, ts: &PyTransferSender) -> PyResult<()> {
+        Ok(self.io_loop.register_handler(ts.transfer_sender.clone())?)
     }
 
-    pub fn register_transfer_receiver(&self, tr: &PyTransferReceiver) {
-        self.io_loop.register_handler(tr.transfer_receiver.clone());
+    pub fn register_transfer_receiver(&self, tr: &PyTransferReceiver) -> PyResult<()> {
+        Ok(self.io_loop.register_handler(tr.transfer_receiver.clone())?)
     }
 
     pub fn connect(&self, num_io_threads: usize, timeout_ms: u128) -> Option<String> {
         self.io_loop.connect(num_io_threads, timeout_ms)
     }
 
-    pub fn start(&self) {
-        self.io_loop.start()
+    pub fn start(&self) -> PyResult<()> {
+        Ok(self.io_loop.start()?)
     }
 
-    pub fn close(&self) {
-        self.io_loop.close()
+    pub fn close(&self) -> PyResult<()> {
+        Ok(self.io_loop.close()?)
+    }
+
+    // sorted by (handler, channel_id, addr)
+    pub fn get_socket_stats(&self) -> Vec<SocketStats> {
+        let mut res: Vec<SocketStats> = self.io_loop.socket_stats().into_values().collect();
+        res.sort_by(|a, b| (&a.handler, &a.channel_id, &a.addr).cmp(&(&b.handler, &b.channel_id, &b.addr)));
+        res
+    }
+}
+
+#[pyclass(name="RustInMemoryTransport")]
+pub struct PyInMemoryTransport {
+    transport: InMemoryTransport,
+}
+
+#[pymethods]
+impl PyInMemoryTransport {
+
+    #[new]
+    pub fn new(name: String) -> PyInMemoryTransport {
+        PyInMemoryTransport{
+            transport: InMemoryTransport::new(name),
+        }
     }
-}
\ No newline at end of file
+
+    pub fn register_data_writer(&self, dw: &PyDataWriter) -> PyResult<()> {
+        Ok(self.transport.register_handler(dw.data_writer.clone())?)
+    }
+
+    pub fn register_data_reader(&self, dr: &PyDataReader) -> PyResult<()> {
+        Ok(self.transport.register_handler(dr.data_reader.clone())?)
+    }
+
+    pub fn connect(&self) -> PyResult<()> {
+        Ok(self.transport.connect()?)
+    }
+
+    pub fn pump(&self) -> PyResult<usize> {
+        Ok(self.transport.pump()?)
+    }
+
+    pub fn start(&self) -> PyResult<()> {
+        Ok(self.transport.start()?)
+    }
+
+    pub fn close(&self) -> PyResult<()> {
+        Ok(self.transport.close()?)
+    }
+}
@@ -0,0 +1,108 @@
+use std::time::Instant;
+
+use pyo3::{pyclass, pymethods};
+use serde::{Deserialize, Serialize};
+
+// per channel send limits, None - not limited. Bursts of up to 1 second worth of traffic are allowed
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[pyclass(name="RustRateLimit")]
+pub struct RateLimit {
+    #[serde(default)]
+    pub bytes_per_sec: Option<u64>,
+    #[serde(default)]
+    pub buffers_per_sec: Option<u64>
+}
+
+#[pymethods]
+impl RateLimit {
+    #[new]
+    #[pyo3(signature = (bytes_per_sec=None, buffers_per_sec=None))]
+    pub fn new(bytes_per_sec: Option<u64>, buffers_per_sec: Option<u64>) -> Self {
+        RateLimit{bytes_per_sec, buffers_per_sec}
+    }
+}
+
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill_ts: Instant
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        TokenBucket{rate: rate as f64, tokens: rate as f64, last_refill_ts: now}
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed_s = now.saturating_duration_since(self.last_refill_ts).as_secs_f64();
+        self.tokens = (self.tokens + elapsed_s * self.rate).min(self.rate);
+        self.last_refill_ts = now;
+    }
+
+    // cost larger than bucket itself only needs a full bucket
+    fn has(&self, cost: f64) -> bool {
+        self.tokens >= cost.min(self.rate)
+    }
+}
+
+// Token bucket per limit. A buffer larger than bucket passes once bucket is full and takes it below zero,
+// so it is not stuck forever - following buffers wait for the debt to refill
+pub struct RateLimiter {
+    bytes: Option<TokenBucket>,
+    buffers: Option<TokenBucket>
+}
+
+impl RateLimiter {
+    // buckets start full at now, time comes from caller's Clock
+    pub fn new(rate_limit: &RateLimit, now: Instant) -> Self {
+        RateLimiter{
+            bytes: rate_limit.bytes_per_sec.map(|rate| TokenBucket::new(rate, now)),
+            buffers: rate_limit.buffers_per_sec.map(|rate| TokenBucket::new(rate, now))
+        }
+    }
+
+    pub fn try_acquire(&mut self, num_bytes: usize, now: Instant) -> bool {
+        for (bucket, cost) in [(&mut self.bytes, num_bytes as f64), (&mut self.buffers, 1.0)] {
+            if let Some(bucket) = bucket {
+                bucket.refill(now);
+                if !bucket.has(cost) {
+                    return false;
+                }
+            }
+        }
+        if let Some(bucket) = &mut self.bytes {
+            bucket.tokens -= num_bytes as f64;
+        }
+        if let Some(bucket) = &mut self.buffers {
+            bucket.tokens -= 1.0;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let start = Instant::now();
+        let mut rl = RateLimiter::new(&RateLimit::new(Some(100), Some(2)), start);
+
+        assert!(rl.try_acquire(10, start));
+        assert!(rl.try_acquire(10, start));
+        // out of buffers
+        assert!(!rl.try_acquire(10, start));
+        assert!(rl.try_acquire(10, start + Duration::from_millis(500)));
+
+        // oversized buffer passes, but takes bytes bucket into debt
+        assert!(rl.try_acquire(200, start + Duration::from_millis(1000)));
+        assert!(!rl.try_acquire(10, start + Duration::from_millis(1500)));
+        assert!(rl.try_acquire(10, start + Duration::from_millis(2500)));
+
+        let mut unlimited = RateLimiter::new(&RateLimit::default(), start);
+        assert!((0..1000).all(|_| unlimited.try_acquire(1000, start)));
+    }
+}
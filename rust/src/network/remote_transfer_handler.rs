@@ -4,22 +4,27 @@ use crossbeam::{channel::{unbounded, bounded, Receiver, Sender}, queue::ArrayQue
 use pyo3::{pyclass, pymethods};
 use serde::{Deserialize, Serialize};
 
-use super::{buffer_utils::{get_buffer_id, get_channeld_id}, channel::{self, Channel}, io_loop::{Bytes, Direction, IOHandler, IOHandlerType}, metrics::{MetricsRecorder, NUM_BUFFERS_RECVD, NUM_BUFFERS_SENT, NUM_BYTES_RECVD, NUM_BYTES_SENT}, sockets::{SocketMetadata, SocketOwner}};
+use super::{buffer_utils::{check_channel_id_header, get_buffer_id, get_channeld_id}, channel::{self, Channel}, error::{DecodeErrorPolicy, NetworkError, NetworkResult}, io_loop::{Bytes, BytesChan, Direction, IOHandler, IOHandlerType}, metrics::{ChannelStats, JobStats, MetricsRecorder, DEFAULT_FLUSH_INTERVAL_MS, NUM_BUFFERS_RECVD, NUM_BUFFERS_SENT, NUM_BYTES_RECVD, NUM_BYTES_SENT, NUM_DECODE_ERRORS}, sockets::{SocketMetadata, SocketOwner}};
 
 // const TRANSFER_QUEUE_SIZE: usize = 10; // TODO should we separate local and remote channel sizes?
 
 #[derive(Serialize, Deserialize, Clone)]
 #[pyclass(name="RustTransferConfig")]
 pub struct TransferConfig {
-    transfer_queue_size: usize
+    transfer_queue_size: usize,
+    // received buffers with malformed header or of unknown channels are dropped, or stop the receiving loop
+    #[serde(default)]
+    decode_error_policy: DecodeErrorPolicy
 }
 
 #[pymethods]
 impl TransferConfig { 
     #[new]
-    pub fn new(transfer_queue_size: usize) -> Self {
+    #[pyo3(signature = (transfer_queue_size, decode_error_policy=DecodeErrorPolicy::Skip))]
+    pub fn new(transfer_queue_size: usize, decode_error_policy: DecodeErrorPolicy) -> Self {
         TransferConfig{
-            transfer_queue_size
+            transfer_queue_size,
+            decode_error_policy
         }
     }
 }
@@ -62,7 +67,7 @@ impl RemoteTransferHandler {
 
         for channel in &channels {
             match channel {
-                Channel::Local{..} => {panic!("RemoteTransferHandler does not use Local Channels")}
+                Channel::Local{..} | Channel::InMemory{..} => {panic!("RemoteTransferHandler does not use Local Channels")}
                 Channel::Remote {
                     channel_id, 
                     target_node_id, 
@@ -93,12 +98,21 @@ impl RemoteTransferHandler {
             remote_send_chans: Arc::new(RwLock::new(remote_send_chans)),
             remote_recv_chans: Arc::new(RwLock::new(remote_recv_chans)),
             channel_id_to_node_id: Arc::new(RwLock::new(channel_id_to_node_id)),
-            metrics_recorder: Arc::new(MetricsRecorder::new(name.clone(), job_name.clone())),
+            metrics_recorder: Arc::new(MetricsRecorder::new(name.clone(), job_name.clone(), DEFAULT_FLUSH_INTERVAL_MS)),
             running: Arc::new(AtomicBool::new(false)),
             io_thread_handles: Arc::new(ArrayQueue::new(2)),
             config: Arc::new(config)
         }
     }
+
+    // stats are per peer node, not per channel
+    pub fn get_metrics_snapshot(&self) -> HashMap<String, ChannelStats> {
+        self.metrics_recorder.snapshot()
+    }
+
+    pub fn get_job_totals(&self) -> JobStats {
+        self.metrics_recorder.job_totals()
+    }
 }
 
 impl IOHandler for RemoteTransferHandler {
@@ -115,37 +129,33 @@ impl IOHandler for RemoteTransferHandler {
         }
     }
 
-    fn get_channels(&self) -> &Vec<Channel> {
-        &self.channels
+    fn get_channels(&self) -> Vec<Channel> {
+        self.channels.clone()
     }
 
-    fn get_send_chan(&self, sm: &SocketMetadata) -> (Sender<Box<Bytes>>, Receiver<Box<Bytes>>) {
+    fn get_send_chan(&self, sm: &SocketMetadata) -> Option<BytesChan> {
         if sm.owner == SocketOwner::TransferLocal {
             let l = &self.local_send_chans.read().unwrap();
-            let v = l.get(&sm.channel_id).unwrap();
-            v.clone()
+            l.get(&sm.channel_id).cloned()
         } else if sm.owner == SocketOwner::TransferRemote {
             let hm = &self.remote_send_chans.read().unwrap();
             let peers = &self.channel_id_to_node_id.read().unwrap();
-            let peer_node_id = peers.get(&sm.channel_id).unwrap();
-            let v = hm.get(peer_node_id).unwrap();
-            v.clone()
+            let peer_node_id = peers.get(&sm.channel_id)?;
+            hm.get(peer_node_id).cloned()
         } else {
             panic!("RemoteTransferHandler only deals with remote socket owners");
         }
     }
 
-    fn get_recv_chan(&self, sm: &SocketMetadata) -> (Sender<Box<Bytes>>, Receiver<Box<Bytes>>) {
+    fn get_recv_chan(&self, sm: &SocketMetadata) -> Option<BytesChan> {
         if sm.owner == SocketOwner::TransferLocal {
             let l = &self.local_recv_chans.read().unwrap();
-            let v = l.get(&sm.channel_id).unwrap();
-            v.clone()
+            l.get(&sm.channel_id).cloned()
         } else if sm.owner == SocketOwner::TransferRemote {
             let hm = &self.remote_recv_chans.read().unwrap();
             let peers = &self.channel_id_to_node_id.read().unwrap();
-            let peer_node_id = peers.get(&sm.channel_id).unwrap();
-            let v = hm.get(peer_node_id).unwrap();
-            v.clone()
+            let peer_node_id = peers.get(&sm.channel_id)?;
+            hm.get(peer_node_id).cloned()
         } else {
             panic!("RemoteTransferHandler only deals with remote socket owners");
         }
@@ -192,8 +202,9 @@ impl IOHandler for RemoteTransferHandler {
         let this_remote_recv_chans = self.remote_recv_chans.clone();
         let this_metrics_recorder = self.metrics_recorder.clone();
         let this_runnning = self.running.clone();
+        let decode_error_policy = self.config.decode_error_policy;
 
-        let input_loop = move || {
+        let input_loop = move || -> NetworkResult<()> {
 
             while this_runnning.load(Ordering::Relaxed) {
 
@@ -206,8 +217,22 @@ impl IOHandler for RemoteTransferHandler {
                     if !receiver.is_empty() {
                         let b = receiver.recv().unwrap();
                         let size = b.len();
-                        let channel_id = get_channeld_id(b.clone());
-                        let send_chan = locked_local_send_chans.get(&channel_id).unwrap();
+                        // routed by channel id only, the rest is checked by reader
+                        let send_chan = match check_channel_id_header(&b) {
+                            Ok(_) => {
+                                let channel_id = get_channeld_id(b.clone());
+                                locked_local_send_chans.get(&channel_id).ok_or(NetworkError::UnknownChannel(channel_id))
+                            },
+                            Err(err) => Err(err)
+                        };
+                        let send_chan = match send_chan {
+                            Ok(send_chan) => send_chan,
+                            Err(err) => {
+                                decode_error_policy.handle(err)?;
+                                this_metrics_recorder.inc(NUM_DECODE_ERRORS, peer_node_id, 1);
+                                continue;
+                            }
+                        };
                         let sender = send_chan.0.clone();
 
                         // this will cause backpressure for all local channels sharing this remote channel
@@ -218,12 +243,18 @@ impl IOHandler for RemoteTransferHandler {
                     }
                 }
             }
+            Ok(())
         };
 
         let name = &self.name;
         let in_thread_name = format!("volga_{name}_in_thread");
         let out_thread_name = format!("volga_{name}_out_thread");
-        self.io_thread_handles.push(std::thread::Builder::new().name(in_thread_name).spawn(input_loop).unwrap()).unwrap();
+        let in_name = name.clone();
+        self.io_thread_handles.push(std::thread::Builder::new().name(in_thread_name).spawn(move || {
+            if let Err(err) = input_loop() {
+                println!("[Transfer {in_name}] Input loop failed: {err}");
+            }
+        }).unwrap()).unwrap();
         self.io_thread_handles.push(std::thread::Builder::new().name(out_thread_name).spawn(output_loop).unwrap()).unwrap();
     }
 
@@ -1,5 +1,5 @@
 use core::{panic, time};
-use std::{collections::{HashMap, HashSet}, fs, rc::Rc, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, RwLock}, thread, time::Instant};
+use std::{collections::{HashMap, HashSet}, fs, net::{SocketAddr, ToSocketAddrs}, rc::Rc, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, RwLock}, thread, time::Instant};
 
 use super::{channel::Channel, io_loop::{Direction, IOHandler, IOHandlerType, ZmqConfig}};
 use crossbeam_skiplist::SkipMap;
@@ -43,13 +43,19 @@ impl SocketsManager {
     pub fn create_sockets(&mut self, zmq_context: &zmq::Context, socket_metas: &Vec<SocketMetadata>, zmq_config: Option<&ZmqConfig>) {
         for sm in socket_metas {
             let socket = zmq_context.socket(zmq::PAIR).unwrap();
+            if sm.owner == SocketOwner::TransferRemote {
+                // dual-stack, zmq falls back to IPv4 if host has no IPv6 support
+                let prefer_ipv6 = zmq_config.and_then(|config| config.prefer_ipv6);
+                socket.set_ipv6(prefer_ipv6 != Some(false)).unwrap();
+            }
             if zmq_config.is_some() {
                 let config = zmq_config.unwrap();
-                if config.sndbuf.is_some() {
-                    socket.set_sndbuf(config.sndbuf.unwrap()).unwrap();
+                let (sndbuf, rcvbuf) = config.socket_bufs(sm.owner == SocketOwner::TransferRemote);
+                if let Some(sndbuf) = sndbuf {
+                    socket.set_sndbuf(sndbuf).unwrap();
                 }
-                if config.rcvbuf.is_some() {
-                    socket.set_rcvbuf(config.rcvbuf.unwrap()).unwrap();
+                if let Some(rcvbuf) = rcvbuf {
+                    socket.set_rcvbuf(rcvbuf).unwrap();
                 }
                 if config.sndhwm.is_some() {
                     socket.set_sndhwm(config.sndhwm.unwrap()).unwrap();
@@ -69,7 +75,8 @@ impl SocketsManager {
         }
     }
 
-    pub fn bind_and_connect(&mut self) {
+    // returns error if remote address can not be resolved
+    pub fn bind_and_connect(&mut self, prefer_ipv6: Option<bool>) -> Result<(), String> {
         for (socket, sm) in &self.sockets_and_metas {
             if sm.kind == SocketKind::Bind {
                 // TODO handle Address already in use
@@ -80,14 +87,24 @@ impl SocketsManager {
                     panic!("Unable to bind addr {addr}: {err}")
                 }
             } else {
-                socket.connect(&sm.addr).unwrap();
+                let channel_id = &sm.channel_id;
+                let addr = resolve_tcp_addr(&sm.addr, prefer_ipv6).map_err(|err| format!("Channel {channel_id}: {err}"))?;
+                socket.connect(&addr).unwrap();
             }
         }
+        Ok(())
     }
 
+    // unbinds ipc sockets and removes their socket files
     pub fn close_sockets(&mut self) {
-        for (socket, _) in &self.sockets_and_metas {
-            // TODO unbind/disconnect?
+        for (socket, sm) in &self.sockets_and_metas {
+            // TODO disconnect?
+            if sm.kind == SocketKind::Bind {
+                if let Some(path) = sm.addr.strip_prefix("ipc://") {
+                    let _ = socket.unbind(&sm.addr);
+                    let _ = fs::remove_file(path);
+                }
+            }
         }
     }
 
@@ -99,13 +116,18 @@ impl SocketsManager {
 // global (for io loop) sockets metadata manager
 pub struct SocketsMeatadataManager {
     socket_meta_to_handler: RwLock<HashMap<SocketMetadata, Arc<dyn IOHandler + Send + Sync>>>,
-    _remote_node_ids: Mutex<HashSet<String>>
+    // pooled remote connections - all channels to the same peer share one socket (in each direction),
+    // buffers are framed with channel_id header and demultiplexed by RemoteTransferHandler
+    _remote_connections: Mutex<HashSet<RemoteConnection>>
 }
 
+// (peer_node_id, port, is_sender)
+type RemoteConnection = (String, i32, bool);
+
 impl SocketsMeatadataManager {
 
     pub fn new() -> Self {
-        SocketsMeatadataManager{socket_meta_to_handler: RwLock::new(HashMap::new()), _remote_node_ids: Mutex::new(HashSet::new())}
+        SocketsMeatadataManager{socket_meta_to_handler: RwLock::new(HashMap::new()), _remote_connections: Mutex::new(HashSet::new())}
     }
     
     pub fn create_for_handlers(&self, handlers: &Vec<Arc<dyn IOHandler + Send + Sync>>) -> Vec<SocketMetadata> {
@@ -121,9 +143,9 @@ impl SocketsMeatadataManager {
             }
             let sockets_meta;
             if (handler_type == IOHandlerType::DataWriter) | (handler_type == IOHandlerType::DataReader) {
-                sockets_meta = SocketsMeatadataManager::create_local_sockets_meta(channels, dir);
+                sockets_meta = SocketsMeatadataManager::create_local_sockets_meta(&channels, dir);
             } else {
-                sockets_meta = self.create_remote_transfer_sockets_meta(channels, dir);
+                sockets_meta = self.create_remote_transfer_sockets_meta(&channels, dir);
             }
 
             for sm in sockets_meta {
@@ -149,13 +171,14 @@ impl SocketsMeatadataManager {
         for channel in channels {
             match channel {
                 Channel::Local{channel_id, ipc_addr} => {
-                    let ipc_path = parse_ipc_path_from_addr(ipc_addr);
+                    let ipc_addr = normalize_ipc_addr(ipc_addr);
+                    let ipc_path = parse_ipc_path_from_addr(&ipc_addr);
                     fs::create_dir_all(ipc_path).unwrap();
                     let socket_meta = SocketMetadata{
                         owner: SocketOwner::Client,
                         kind: if is_reader {SocketKind::Connect} else {SocketKind::Bind},
                         channel_id: channel_id.clone(),
-                        addr: ipc_addr.clone(),
+                        addr: ipc_addr,
                     };
                     v.push(socket_meta);
                 }
@@ -165,18 +188,19 @@ impl SocketsMeatadataManager {
                     target_local_ipc_addr, 
                     ..
                 } => {
-                    let ipc_path = parse_ipc_path_from_addr(
-                        if is_reader {target_local_ipc_addr} else {source_local_ipc_addr}
-                    );
+                    let ipc_addr = normalize_ipc_addr(if is_reader {target_local_ipc_addr} else {source_local_ipc_addr});
+                    let ipc_path = parse_ipc_path_from_addr(&ipc_addr);
                     fs::create_dir_all(ipc_path).unwrap();
                     let socket_meta = SocketMetadata{
                         owner: SocketOwner::Client,
                         kind: if is_reader {SocketKind::Connect} else {SocketKind::Bind},
                         channel_id: channel_id.clone(),
-                        addr: if is_reader {target_local_ipc_addr.clone()} else {source_local_ipc_addr.clone()},
+                        addr: ipc_addr,
                     };
                     v.push(socket_meta);
                 }
+                // no sockets, linked by InMemoryTransport
+                Channel::InMemory{..} => {}
             }
         }
         v
@@ -189,7 +213,7 @@ impl SocketsMeatadataManager {
         let is_sender = direction == Direction::Sender;
         for channel in channels {
             match channel {
-                Channel::Local{..} => {panic!("Remote Transfer should have no local channels")} 
+                Channel::Local{..} | Channel::InMemory{..} => {panic!("Remote Transfer should have no local channels")} 
                 Channel::Remote { 
                     channel_id, 
                     source_local_ipc_addr, 
@@ -200,19 +224,17 @@ impl SocketsMeatadataManager {
                     target_node_id, 
                     port 
                 } => {
-                    let ipc_path;
                     let local_addr;
                     let local_socket_kind;
 
                     if is_sender {
-                        ipc_path = parse_ipc_path_from_addr(source_local_ipc_addr);
-                        local_addr = source_local_ipc_addr;
+                        local_addr = normalize_ipc_addr(source_local_ipc_addr);
                         local_socket_kind = SocketKind::Connect;
                     } else {
-                        ipc_path = parse_ipc_path_from_addr(target_local_ipc_addr);
-                        local_addr = target_local_ipc_addr;
+                        local_addr = normalize_ipc_addr(target_local_ipc_addr);
                         local_socket_kind = SocketKind::Bind;
                     }
+                    let ipc_path = parse_ipc_path_from_addr(&local_addr);
                     fs::create_dir_all(ipc_path).unwrap();
                     let local_socket_metadata = SocketMetadata{
                         owner: SocketOwner::TransferLocal,
@@ -223,20 +245,28 @@ impl SocketsMeatadataManager {
                     v.push(local_socket_metadata);
 
                     let peer_node_id =  if is_sender {target_node_id} else {source_node_id};
-                    let mut locked_remote_node_ids = self._remote_node_ids.lock().unwrap();
-                    if locked_remote_node_ids.contains(peer_node_id) {
+                    let connection = (peer_node_id.clone(), *port, is_sender);
+                    let mut locked_remote_connections = self._remote_connections.lock().unwrap();
+                    if locked_remote_connections.contains(&connection) {
                         // already inited for this peer
                         continue;
                     }
-                    locked_remote_node_ids.insert(peer_node_id.clone());
+                    locked_remote_connections.insert(connection);
 
                     let tcp_addr;
                     let remote_socket_kind;
                     if is_sender {
-                        tcp_addr = format!("tcp://{target_node_ip}:{port}");
+                        // resolved on connect
+                        if target_node_ip.contains(':') {
+                            // IPv6 literal
+                            tcp_addr = format!("tcp://[{target_node_ip}]:{port}");
+                        } else {
+                            tcp_addr = format!("tcp://{target_node_ip}:{port}");
+                        }
                         remote_socket_kind = SocketKind::Connect;
                     } else {
-                        tcp_addr = format!("tcp://0.0.0.0:{port}");
+                        // all interfaces, both IPv4 and IPv6 if socket has IPv6 enabled
+                        tcp_addr = format!("tcp://*:{port}");
                         remote_socket_kind = SocketKind::Bind;
                     }
                     let remote_socket_metadata = SocketMetadata{
@@ -255,6 +285,36 @@ impl SocketsMeatadataManager {
 }
 
 
+// Resolves host (hostname, IPv4 or bracketed IPv6 literal) of tcp://host:port address, other addresses are returned as is
+pub fn resolve_tcp_addr(addr: &str, prefer_ipv6: Option<bool>) -> Result<String, String> {
+    let host_port = match addr.strip_prefix("tcp://") {
+        Some(host_port) => host_port,
+        None => return Ok(addr.to_string())
+    };
+    let (host, port) = host_port.rsplit_once(':').ok_or(format!("Malformed tcp addr: {addr}"))?;
+    let port: u16 = port.parse().map_err(|_| format!("Malformed port in tcp addr: {addr}"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let resolved: Vec<SocketAddr> = (host, port).to_socket_addrs().map_err(|err| format!("Unable to resolve {host}: {err}"))?.collect();
+    let selected = match prefer_ipv6 {
+        Some(ipv6) => resolved.iter().find(|a| a.is_ipv6() == ipv6).or(resolved.first()),
+        None => resolved.first()
+    };
+    match selected {
+        // SocketAddr formats IPv6 in brackets
+        Some(socket_addr) => Ok(format!("tcp://{socket_addr}")),
+        None => Err(format!("No addresses resolved for {host}"))
+    }
+}
+
+// zmq ipc transport is a unix domain socket, so a plain filesystem path is used as ipc://path
+pub fn normalize_ipc_addr(ipc_addr: &String) -> String {
+    if ipc_addr.starts_with('/') {
+        format!("ipc://{ipc_addr}")
+    } else {
+        ipc_addr.clone()
+    }
+}
+
 // TODO this should be in sync with Py's Channel ipc_addr format
 fn parse_ipc_path_from_addr(ipc_addr: &String) -> String {
     let parts = ipc_addr.split("/");
@@ -275,6 +335,10 @@ fn parse_ipc_path_from_addr(ipc_addr: &String) -> String {
 #[cfg(test)]
 mod tests {
 
+    use std::{path::Path, time::SystemTime};
+
+    use crate::network::{buffer_utils::{get_buffer_id, new_buffer_drop_meta, new_buffer_with_meta}, channel::AckMessage};
+
     use super::*;
 
     #[test]
@@ -284,4 +348,119 @@ mod tests {
         let expected = String::from("/tmp/");
         assert_eq!(res, expected);
     }
+
+    #[test]
+    fn test_unix_socket_path() {
+        let now_ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        let path = format!("/tmp/volga/rust/uds-{now_ts}/ch_0");
+        let addr = normalize_ipc_addr(&path);
+        assert_eq!(addr, format!("ipc://{path}"));
+        assert_eq!(normalize_ipc_addr(&addr), addr);
+        fs::create_dir_all(parse_ipc_path_from_addr(&addr)).unwrap();
+
+        let bind_meta = SocketMetadata{owner: SocketOwner::Client, kind: SocketKind::Bind, channel_id: String::from("ch_0"), addr: addr.clone()};
+        let connect_meta = SocketMetadata{owner: SocketOwner::Client, kind: SocketKind::Connect, channel_id: String::from("ch_0"), addr: addr.clone()};
+        let zmq_context = zmq::Context::new();
+        let mut sockets_manager = SocketsManager::new();
+        sockets_manager.create_sockets(&zmq_context, &vec![bind_meta, connect_meta], None);
+        sockets_manager.bind_and_connect(None).unwrap();
+        assert!(Path::new(&path).exists());
+
+        let (bind_socket, _) = &sockets_manager.get_sockets_and_metas()[0];
+        let (connect_socket, _) = &sockets_manager.get_sockets_and_metas()[1];
+        let b = new_buffer_with_meta(Box::new(vec![1, 2, 3]), String::from("ch_0"), 7, 0);
+        bind_socket.send(b.as_ref(), 0).unwrap();
+        let recvd = Box::new(connect_socket.recv_bytes(0).unwrap());
+        assert_eq!(get_buffer_id(recvd.clone()), 7);
+        assert_eq!(*new_buffer_drop_meta(recvd), vec![1, 2, 3]);
+
+        let ack = AckMessage{channel_id: String::from("ch_0"), buffer_id: 7};
+        connect_socket.send(ack.ser().as_ref(), 0).unwrap();
+        assert_eq!(AckMessage::de(Box::new(bind_socket.recv_bytes(0).unwrap())), ack);
+
+        sockets_manager.close_sockets();
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[test]
+    fn test_remote_connection_pooling() {
+        let remote_channel = |channel_id: &str, source_node_id: &str, target_node_id: &str, port: i32| {
+            Channel::Remote{
+                channel_id: channel_id.to_string(),
+                source_local_ipc_addr: format!("ipc:///tmp/volga/rust/source_local_{channel_id}"),
+                source_node_ip: String::from("127.0.0.1"),
+                source_node_id: source_node_id.to_string(),
+                target_local_ipc_addr: format!("ipc:///tmp/volga/rust/target_local_{channel_id}"),
+                target_node_ip: String::from("127.0.0.1"),
+                target_node_id: target_node_id.to_string(),
+                port
+            }
+        };
+        let num_remote = |sms: &Vec<SocketMetadata>| sms.iter().filter(|sm| sm.owner == SocketOwner::TransferRemote).count();
+        let manager = SocketsMeatadataManager::new();
+
+        // one connection for all channels to the same peer
+        let out_channels = vec![remote_channel("ch_0", "node_a", "node_b", 1234), remote_channel("ch_1", "node_a", "node_b", 1234), remote_channel("ch_2", "node_a", "node_c", 1234)];
+        let sms = manager.create_remote_transfer_sockets_meta(&out_channels, Direction::Sender);
+        assert_eq!(sms.len(), 5);
+        assert_eq!(num_remote(&sms), 2);
+
+        // same peer in other direction needs its own connection
+        let in_channels = vec![remote_channel("ch_3", "node_b", "node_a", 2345), remote_channel("ch_4", "node_b", "node_a", 2345)];
+        let sms = manager.create_remote_transfer_sockets_meta(&in_channels, Direction::Receiver);
+        assert_eq!(num_remote(&sms), 1);
+    }
+
+    #[test]
+    fn test_socket_bufs() {
+        let config = ZmqConfig::new(None, None, Some(64 * 1024), None, None, None, None, Some(4 * 1024 * 1024), None);
+        let local_meta = SocketMetadata{owner: SocketOwner::Client, kind: SocketKind::Connect, channel_id: String::from("ch_0"), addr: String::from("ipc:///tmp/ipc_0")};
+        let remote_meta = SocketMetadata{owner: SocketOwner::TransferRemote, kind: SocketKind::Connect, channel_id: String::from("ch_0"), addr: String::from("tcp://127.0.0.1:1234")};
+        let zmq_context = zmq::Context::new();
+        let mut sockets_manager = SocketsManager::new();
+        sockets_manager.create_sockets(&zmq_context, &vec![local_meta, remote_meta], Some(&config));
+        let bufs: Vec<(i32, i32)> = sockets_manager.get_sockets_and_metas().iter().map(|(socket, _)| (socket.get_sndbuf().unwrap(), socket.get_rcvbuf().unwrap())).collect();
+        // rcvbuf is left to the OS
+        assert_eq!(bufs, vec![(64 * 1024, -1), (4 * 1024 * 1024, -1)]);
+    }
+
+    #[test]
+    fn test_tcp_partial_reads_reassembled() {
+        // kernel buffers much smaller than messages, so every message crosses tcp in many partial reads and writes
+        let zmq_context = zmq::Context::new();
+        let bind_socket = zmq_context.socket(zmq::PAIR).unwrap();
+        let connect_socket = zmq_context.socket(zmq::PAIR).unwrap();
+        for socket in [&bind_socket, &connect_socket] {
+            socket.set_sndbuf(4096).unwrap();
+            socket.set_rcvbuf(4096).unwrap();
+        }
+        bind_socket.bind("tcp://127.0.0.1:*").unwrap();
+        connect_socket.connect(&bind_socket.get_last_endpoint().unwrap().unwrap()).unwrap();
+
+        let sizes = [1, 4095, 4097, 100 * 1024, 1024 * 1024, 0, 3];
+        let msgs: Vec<Vec<u8>> = sizes.iter().enumerate().map(|(i, size)| (0..*size).map(|j| (i + j) as u8).collect()).collect();
+        let to_send = &msgs;
+        thread::scope(|s| {
+            s.spawn(move || {
+                for msg in to_send {
+                    connect_socket.send(msg.as_slice(), 0).unwrap();
+                }
+            });
+            for msg in &msgs {
+                assert_eq!(&bind_socket.recv_bytes(0).unwrap(), msg);
+            }
+        });
+    }
+
+    #[test]
+    fn test_resolve_tcp_addr() {
+        assert_eq!(resolve_tcp_addr("tcp://127.0.0.1:1234", None).unwrap(), "tcp://127.0.0.1:1234");
+        assert_eq!(resolve_tcp_addr("tcp://[::1]:1234", None).unwrap(), "tcp://[::1]:1234");
+        assert_eq!(resolve_tcp_addr("tcp://[fe80::1]:1234", Some(false)).unwrap(), "tcp://[fe80::1]:1234");
+        assert!(resolve_tcp_addr("tcp://localhost:1234", Some(false)).unwrap().starts_with("tcp://127."));
+        assert_eq!(resolve_tcp_addr("ipc:///tmp/ipc_0", None).unwrap(), "ipc:///tmp/ipc_0");
+
+        assert!(resolve_tcp_addr("tcp://localhost", None).is_err());
+        assert!(resolve_tcp_addr("tcp://no-such-host.invalid:1234", None).is_err());
+    }
 }
\ No newline at end of file
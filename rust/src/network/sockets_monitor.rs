@@ -1,5 +1,5 @@
 use core::time;
-use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, thread::{self, JoinHandle}, time::SystemTime};
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, thread::{self, JoinHandle}, time::SystemTime};
 
 use crossbeam::queue::SegQueue;
 use crossbeam_skiplist::SkipMap;
@@ -13,7 +13,9 @@ pub struct SocketsMonitor {
     zmq_context: Arc<zmq::Context>,
     monitor_thread: Arc<SegQueue<JoinHandle<()>>>,
     running: Arc<AtomicBool>,
-    ready: Arc<AtomicBool>
+    ready: Arc<AtomicBool>,
+    // errors reported by io threads which make waiting for connection pointless (e.g. unresolvable address)
+    errors: Arc<Mutex<Vec<String>>>
 }
 
 impl SocketsMonitor {
@@ -25,7 +27,8 @@ impl SocketsMonitor {
             zmq_context: zmq_context,
             monitor_thread: Arc::new(SegQueue::new()),
             running: Arc::new(AtomicBool::new(false)),
-            ready: Arc::new(AtomicBool::new(false))
+            ready: Arc::new(AtomicBool::new(false)),
+            errors: Arc::new(Mutex::new(Vec::new()))
         }
     }
 
@@ -55,6 +58,10 @@ impl SocketsMonitor {
         panic!("Timeout waiting for monitor to be ready");
     }
 
+    pub fn report_error(&self, err: String) {
+        self.errors.lock().unwrap().push(err);
+    }
+
     pub fn wait_for_all_connected(&self, timeout_ms: Option<u128>) -> Option<String> {
         let timeout = 1000 as u128; // default
         if timeout_ms.is_some() {
@@ -62,12 +69,21 @@ impl SocketsMonitor {
         }
         let start = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
         while SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() - start < timeout {
+            if !self.errors.lock().unwrap().is_empty() {
+                break;
+            }
             if self.all_connected() {
                 return None
             }
             thread::sleep(time::Duration::from_millis(100));
         }
 
+        let errors = self.errors.lock().unwrap();
+        if !errors.is_empty() {
+            return Some(format!("{:?}", errors))
+        }
+        drop(errors);
+
         let mut not_connected = Vec::new();
         let this_sockets_connected_status = self.sockets_connected_status.clone();
         for e in this_sockets_connected_status.as_ref() {
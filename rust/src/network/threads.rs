@@ -0,0 +1,148 @@
+use pyo3::{exceptions::PyValueError, pyclass, pymethods, PyResult};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_THREAD_NAME_PREFIX: &str = "volga";
+
+// cpu ids in cpu_affinity must be below this, size of Linux cpu_set_t (CPU_SETSIZE)
+pub const MAX_CPUS: usize = 1024;
+
+// Naming and scheduling of threads spawned by network components (dispatcher, io threads),
+// e.g. to pin a latency-critical reader's dispatcher to a core and raise its priority.
+// Default keeps "volga_" names and OS scheduling as is
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[pyclass(name="RustThreadConfig")]
+pub struct ThreadConfig {
+    // None - DEFAULT_THREAD_NAME_PREFIX
+    #[serde(default)]
+    pub name_prefix: Option<String>,
+    // Linux nice value applied to each thread, lower is higher priority. Negative values need CAP_SYS_NICE
+    #[serde(default)]
+    pub nice: Option<i32>,
+    // cpu ids threads may run on (Linux only). With several threads of same kind, thread i is pinned
+    // to cpu_affinity[i % len] so they do not compete for one core
+    #[serde(default)]
+    pub cpu_affinity: Option<Vec<usize>>
+}
+
+#[pymethods]
+impl ThreadConfig {
+    #[new]
+    #[pyo3(signature = (name_prefix=None, nice=None, cpu_affinity=None))]
+    pub fn new(name_prefix: Option<String>, nice: Option<i32>, cpu_affinity: Option<Vec<usize>>) -> PyResult<Self> {
+        let config = ThreadConfig{name_prefix, nice, cpu_affinity};
+        config.validate().map_err(PyValueError::new_err)?;
+        Ok(config)
+    }
+}
+
+impl ThreadConfig {
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(cpu) = self.cpu_affinity.iter().flatten().find(|cpu| **cpu >= MAX_CPUS) {
+            return Err(format!("cpu_affinity cpu {cpu} out of range, must be below {MAX_CPUS}"));
+        }
+        Ok(())
+    }
+
+    pub fn thread_name(&self, suffix: &str) -> String {
+        let prefix = self.name_prefix.as_deref().unwrap_or(DEFAULT_THREAD_NAME_PREFIX);
+        format!("{prefix}_{suffix}")
+    }
+
+    // called from inside the spawned thread, index is thread's number among threads of same kind.
+    // Failures are logged and not fatal, thread keeps running with default scheduling
+    pub fn apply_to_current_thread(&self, index: usize) {
+        if let Some(nice) = self.nice {
+            if let Err(err) = set_current_thread_nice(nice) {
+                println!("[Threads] Failed to set nice {nice} for {:?}: {err}", std::thread::current().name());
+            }
+        }
+        if let Some(cpus) = self.cpu_affinity.as_ref().filter(|cpus| !cpus.is_empty()) {
+            let cpu = cpus[index % cpus.len()];
+            if let Err(err) = set_current_thread_affinity(&[cpu]) {
+                println!("[Threads] Failed to pin {:?} to cpu {cpu}: {err}", std::thread::current().name());
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_current_thread_nice(nice: i32) -> std::io::Result<()> {
+    // on Linux nice is per thread, PRIO_PROCESS with a tid changes only that thread
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, nice) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_current_thread_affinity(cpus: &[usize]) -> std::io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for cpu in cpus {
+            // CPU_SET panics past the set, config may come deserialized without validate
+            if *cpu >= libc::CPU_SETSIZE as usize {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("cpu {cpu} out of range")));
+            }
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        // pid 0 - calling thread
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_current_thread_nice(_nice: i32) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "thread priority is only supported on Linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_current_thread_affinity(_cpus: &[usize]) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "thread affinity is only supported on Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thread_name() {
+        assert_eq!(ThreadConfig::default().thread_name("io_thread_0"), "volga_io_thread_0");
+        let config = ThreadConfig{name_prefix: Some(String::from("lowlat")), ..Default::default()};
+        assert_eq!(config.thread_name("io_thread_0"), "lowlat_io_thread_0");
+
+        let config: ThreadConfig = serde_yaml::from_str("nice: 5").unwrap();
+        assert_eq!(config, ThreadConfig{nice: Some(5), ..Default::default()});
+
+        assert!(ThreadConfig{cpu_affinity: Some(vec![0, MAX_CPUS]), ..Default::default()}.validate().is_err());
+        let config: ThreadConfig = serde_yaml::from_str("cpu_affinity: [4096]").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    fn current_thread_cpus() -> Vec<usize> {
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            assert_eq!(libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set), 0);
+            (0..libc::CPU_SETSIZE as usize).filter(|cpu| libc::CPU_ISSET(*cpu, &set)).collect()
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_apply_to_current_thread() {
+        std::thread::spawn(move || {
+            let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+            // lowering priority needs no privileges
+            let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, tid) } + 1;
+            let cpu = *current_thread_cpus().last().unwrap();
+            ThreadConfig{name_prefix: None, nice: Some(nice), cpu_affinity: Some(vec![cpu])}.apply_to_current_thread(3);
+            assert_eq!(unsafe { libc::getpriority(libc::PRIO_PROCESS, tid) }, nice);
+            assert_eq!(current_thread_cpus(), vec![cpu]);
+        }).join().unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+// Spans around the stages of a buffer's life, with the `tracing` feature: pushed to writer's BufferQueue, scheduled,
+// sent, received by reader, reassembled from fragments, delivered to out_queue and acked back to writer. Every span
+// carries channel_id and buffer_id, so a trace viewer can follow one buffer from writer to reader, e.g. one that
+// got stuck. Spans are at debug level and last while the stage runs, later stages on the same thread nest in them.
+// Without the feature buffer_span! expands to a unit value and its arguments are not evaluated, so it costs nothing
+#[cfg(feature = "tracing")]
+macro_rules! buffer_span {
+    ($stage:literal, $channel_id:expr, $buffer_id:expr) => {
+        tracing::debug_span!($stage, channel_id = %$channel_id, buffer_id = $buffer_id).entered()
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! buffer_span {
+    ($stage:literal, $channel_id:expr, $buffer_id:expr) => {
+        $crate::network::trace::NoSpan
+    };
+}
+
+pub(crate) use buffer_span;
+
+// what buffer_span! is without the feature, held like an entered span
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct NoSpan;
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::{collections::HashMap, fmt, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}};
+
+    use tracing::{field::{Field, Visit}, span::{Attributes, Id, Record}, Event, Metadata, Subscriber};
+
+    use crate::network::{buffer_queues::BufferQueue, buffer_utils::get_buffer_id, data_writer::DataWriterConfig};
+
+    // (span name, field -> value)
+    type RecordedSpan = (String, HashMap<String, String>);
+
+    // every span created
+    #[derive(Default, Clone)]
+    struct Recorder {
+        spans: Arc<Mutex<Vec<RecordedSpan>>>,
+        next_id: Arc<AtomicU64>
+    }
+
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut fields = HashMap::new();
+            span.record(&mut FieldVisitor(&mut fields));
+            self.spans.lock().unwrap().push((span.metadata().name().to_string(), fields));
+            Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+        }
+
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn test_buffer_spans() {
+        let recorder = Recorder::default();
+        let config = DataWriterConfig{metrics_enabled: false, ..DataWriterConfig::new(1, 10)};
+        let channel_id = String::from("ch_0");
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let mut queue = BufferQueue::new(&config, &channel_id);
+            assert!(queue.try_push(channel_id.clone(), Box::new(vec![1])));
+            assert!(queue.try_push(channel_id.clone(), Box::new(vec![2])));
+            assert_eq!(get_buffer_id(queue.schedule_next().unwrap()), 0);
+        });
+        let spans = recorder.spans.lock().unwrap();
+        let stages: Vec<_> = spans.iter().map(|(name, fields)| (name.as_str(), fields["channel_id"].as_str(), fields["buffer_id"].as_str())).collect();
+        assert_eq!(stages, vec![("pushed", "ch_0", "0"), ("pushed", "ch_0", "1"), ("scheduled", "ch_0", "0")]);
+    }
+}
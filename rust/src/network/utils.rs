@@ -6,4 +6,27 @@ pub fn random_string(len: usize) -> String {
         .take(len)
         .map(char::from)
         .collect()
-}
\ No newline at end of file
+}
+// Tests waiting on another thread (dispatcher, io loop) give up after this, so a broken test fails instead of hanging
+#[cfg(test)]
+pub const TEST_WAIT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// Polls f until it returns something. Panics once TEST_WAIT_TIMEOUT passes, pointing at the waiting test line
+#[cfg(test)]
+#[track_caller]
+pub fn wait_for<T>(mut f: impl FnMut() -> Option<T>) -> T {
+    let deadline = std::time::Instant::now() + TEST_WAIT_TIMEOUT;
+    loop {
+        if let Some(res) = f() {
+            return res;
+        }
+        assert!(std::time::Instant::now() < deadline, "timed out after {TEST_WAIT_TIMEOUT:?}");
+        std::thread::yield_now();
+    }
+}
+
+#[cfg(test)]
+#[track_caller]
+pub fn wait_until(mut cond: impl FnMut() -> bool) {
+    wait_for(|| cond().then_some(()))
+}
@@ -45,19 +45,19 @@ fn test_one_to_one(local: bool) {
         job_name.clone(),
         network_config.data_reader,
         vec![channel.clone()],
-    ));
+    ).unwrap());
     let data_writer = Arc::new(DataWriter::new(
         String::from("data_writer"),
         job_name.clone(),
         network_config.data_writer,
         vec![channel.clone()],
-    ));
+    ).unwrap());
 
     let mut remote_transfer_handlers = Vec::new();
 
-    let io_loop = IOLoop::new(String::from("io_loop"), network_config.zmq);
-    io_loop.register_handler(data_reader.clone());
-    io_loop.register_handler(data_writer.clone());
+    let io_loop = IOLoop::new(String::from("io_loop"), network_config.zmq, network_config.io_threads);
+    io_loop.register_handler(data_reader.clone()).unwrap();
+    io_loop.register_handler(data_writer.clone()).unwrap();
     if !local {
         let transfer_sender = Arc::new(RemoteTransferHandler::new(
             String::from("transfer_sender"),
@@ -73,8 +73,8 @@ fn test_one_to_one(local: bool) {
             network_config.transfer.clone(),
             Direction::Receiver
         ));
-        io_loop.register_handler(transfer_sender.clone());
-        io_loop.register_handler(transfer_receiver.clone());
+        io_loop.register_handler(transfer_sender.clone()).unwrap();
+        io_loop.register_handler(transfer_receiver.clone()).unwrap();
         remote_transfer_handlers.push(transfer_sender.clone());
         remote_transfer_handlers.push(transfer_receiver.clone());
         transfer_sender.start();
@@ -89,7 +89,7 @@ fn test_one_to_one(local: bool) {
         let err = err.unwrap();
         panic!("{err}")
     }
-    io_loop.start();
+    io_loop.start().unwrap();
 
     let num_msgs = 100000;
     let payload_size = 128;
@@ -113,7 +113,7 @@ fn test_one_to_one(local: bool) {
     let j_handle = std::thread::spawn(move|| {
         let mut backp = 0;
         for msg in local_to_send.as_ref() {
-            backp += moved_data_writer.write_bytes(channel.get_channel_id(), msg.clone(), true, 1000, 0).unwrap();
+            backp += moved_data_writer.write_bytes(channel.get_channel_id(), msg.clone(), true, 1000, 0).unwrap().unwrap();
         }
         backp
     });
@@ -121,7 +121,7 @@ fn test_one_to_one(local: bool) {
     let mut recvd = vec![];
 
     while recvd.len() != to_send.len() {
-        let _msg = data_reader.read_bytes();
+        let _msg = data_reader.read_bytes().unwrap();
         if _msg.is_some() {
             recvd.push(_msg.unwrap());
         }
@@ -142,7 +142,7 @@ fn test_one_to_one(local: bool) {
         }
     }
 
-    io_loop.close();
+    io_loop.close().unwrap();
     assert_eq!(to_send.len(), recvd.len());
     for i in 0..to_send.len() {
         assert_eq!(to_send[i], recvd[i])
@@ -0,0 +1,280 @@
+# Partial type stubs for the native module
+from typing import Any, Dict, List, Optional, Tuple
+
+
+class RustChannelStats:
+    num_buffers_sent: int
+    num_buffers_recvd: int
+    num_buffers_resent: int
+    num_bytes_sent: int
+    num_bytes_recvd: int
+    num_dup_below_wm: int
+    num_dup_ooo: int
+    num_dropped_full: int
+    throttled_micros: int
+    num_expired: int
+    num_acks_dropped: int
+    num_push_rejected: int
+    queue_depth: int
+    num_skipped: int
+    num_retransmits: int
+    num_dropped_mem: int
+    out_of_order_bytes: int
+    num_compacted: int
+    num_writer_restarts: int
+    num_force_skipped: int
+    num_decode_errors: int
+    num_overflowed: int
+    num_evicted: int
+    num_nacks_sent: int
+    num_gap_waits: int
+    out_queue_dwell_p50_micros: int
+    out_queue_dwell_p99_micros: int
+    out_queue_dwell_p999_micros: int
+    # sampled queue depths, 0 until first sample
+    in_queue_depth_p50: int
+    in_queue_depth_p99: int
+    in_queue_depth_p999: int
+
+    # same keys as attributes, see ChannelStatsDict in volga/streaming/runtime/network/metrics.py
+    def to_dict(self) -> Dict[str, int]: ...
+
+
+class RustJobStats:
+    job_name: str
+    num_buffers_sent: int
+    num_buffers_recvd: int
+    num_bytes_sent: int
+    num_bytes_recvd: int
+    num_empty_read_batches: int
+    out_queue_depth_p50: int
+    out_queue_depth_p99: int
+    out_queue_depth_p999: int
+
+    # counters and sampled out_queue depth percentiles
+    def to_dict(self) -> Dict[str, int]: ...
+
+
+# RustIOLoop.get_socket_stats(), recv_chan_full - handler not keeping up, send_blocked - zmq/kernel send buffers full
+class RustSocketStats:
+    handler: str
+    channel_id: str
+    addr: str
+    bytes_read: int
+    bytes_written: int
+    msgs_read: int
+    msgs_written: int
+    recv_chan_full: int
+    send_blocked: int
+
+    # counters only, see SocketStatsDict in volga/streaming/runtime/network/io_loop.py
+    def to_dict(self) -> Dict[str, int]: ...
+
+
+class RustHealthStatus:
+    running: bool
+    dispatcher_alive: bool
+    # channel_id -> received anything within recv window
+    channels_receiving: Dict[str, bool]
+    # channel_id -> writer is asked to pause by backpressure
+    channels_backpressured: Dict[str, bool]
+    # panic message if dispatcher thread failed
+    dispatcher_error: Optional[str]
+    # channel_id -> panic message of channels no longer dispatched, not counted by is_healthy
+    channels_failed: Dict[str, str]
+
+    def is_healthy(self) -> bool: ...
+
+
+class RustIOHandlerType:
+    DataReader: 'RustIOHandlerType'
+    DataWriter: 'RustIOHandlerType'
+    TransferSender: 'RustIOHandlerType'
+    TransferReceiver: 'RustIOHandlerType'
+
+
+class RustLocalChannel:
+    channel_id: str
+    ipc_addr: str
+
+    def __init__(self, channel_id: str, ipc_addr: str) -> None: ...
+    def is_remote(self) -> bool: ...
+    # ipc_addr
+    def address_summary(self) -> str: ...
+
+
+class RustRemoteChannel:
+    channel_id: str
+    source_local_ipc_addr: str
+    source_node_ip: str
+    source_node_id: str
+    target_local_ipc_addr: str
+    target_node_ip: str
+    target_node_id: str
+    port: int
+
+    def __init__(self, channel_id: str, source_local_ipc_addr: str, source_node_ip: str, source_node_id: str, target_local_ipc_addr: str, target_node_ip: str, target_node_id: str, port: int) -> None: ...
+    def is_remote(self) -> bool: ...
+    # source_node_ip:port -> target_node_ip:port
+    def address_summary(self) -> str: ...
+
+
+class RustInMemoryChannel:
+    channel_id: str
+
+    def __init__(self, channel_id: str) -> None: ...
+    def is_remote(self) -> bool: ...
+    # inmem://channel_id
+    def address_summary(self) -> str: ...
+
+
+# links writers and readers over RustInMemoryChannel in process, used instead of an io loop in tests
+class RustInMemoryTransport:
+    def __init__(self, name: str) -> None: ...
+    def register_data_writer(self, dw: RustDataWriter) -> None: ...
+    def register_data_reader(self, dr: RustDataReader) -> None: ...
+    # raises if a channel has no writer or not exactly one reader
+    def connect(self) -> None: ...
+    # moves what is available once instead of start-ing the background pump, returns number of buffers moved
+    def pump(self) -> int: ...
+    def start(self) -> None: ...
+    def close(self) -> None: ...
+
+
+class RustDataReader:
+    # raises ValueError for malformed channels (empty id, bad ipc addr, node ip or port) and duplicate channel ids
+    def __init__(self, name: str, job_name: str, config: Any, channels: List[Any]) -> None: ...
+    def __enter__(self) -> 'RustDataReader': ...
+    def __exit__(self, exc_type: Any, exc_value: Any, traceback: Any) -> bool: ...
+    # channel_id -> cumulative stats
+    def get_metrics_snapshot(self) -> Dict[str, RustChannelStats]: ...
+    # sum of get_metrics_snapshot() counters over all channels
+    def get_job_totals(self) -> RustJobStats: ...
+    def reset_metrics(self) -> None: ...
+    # (p50, p99, p999) delivery latency in micros
+    def get_delivery_latency(self, channel_id: str) -> Optional[Tuple[int, int, int]]: ...
+    def health(self, recv_window_ms: int = 5000) -> RustHealthStatus: ...
+    # channel_id -> sorted buffer ids waiting on a missing predecessor
+    def gaps(self) -> Dict[str, List[int]]: ...
+    # entries dispatcher can still put in output queue before it is considered full, 0 - consumer is falling behind
+    def available_capacity(self) -> int: ...
+    # min event-time watermark of consumed buffers across channels, None until every channel has one.
+    # Unrelated to delivery sequence (buffer id) watermarks
+    def current_event_time_watermark(self) -> Optional[int]: ...
+    # channels whose EOF was consumed, sorted. Not restored from checkpoint
+    def completed_channels(self) -> List[str]: ...
+    # channel_id -> millis since the channel last put a buffer in output queue, channels with none yet are left out.
+    # For stall detection, e.g. flag channels idle beyond an SLA
+    def last_activity_elapsed_ms(self) -> Dict[str, int]: ...
+    # raises RuntimeError if dispatcher thread failed
+    def read_bytes(self) -> Optional[bytes]: ...
+    # up to max_size buffers already delivered, empty list counts towards RustJobStats.num_empty_read_batches
+    def read_batch(self, max_size: int) -> List[bytes]: ...
+    # never waits on dispatcher, may return None under contention even if buffers are available - poll again
+    def try_read_bytes(self) -> Optional[bytes]: ...
+    # copies next payload into dst, resized to it, and returns its length - reusing one bytearray saves allocations per read,
+    # as payload storage is recycled too
+    def read_into(self, dst: bytearray) -> Optional[int]: ...
+    # next buffer of given channel only, raises KeyError for unknown channel
+    def read_bytes_from(self, channel_id: str) -> Optional[bytes]: ...
+    # (channel_id, buffer_id, payload), raises like read_bytes
+    def read_message(self) -> Optional[Tuple[str, int, bytes]]: ...
+    # manual_commit only: acks a buffer returned by read_message, False if it was not read or is already committed
+    def commit(self, channel_id: str, buffer_id: int) -> bool: ...
+    # channel_id -> sorted ids of buffers read but not committed
+    def uncommitted(self) -> Dict[str, List[int]]: ...
+    def restart_dispatcher(self) -> bool: ...
+    # drops and acks channel's backlog below buffer_id, returns number of skipped buffer ids
+    def skip_to(self, channel_id: str, buffer_id: int) -> int: ...
+    # delivers buffers held past a gap up to up_to (at most the highest held one), missing ids are lost and acked,
+    # returns their number
+    def force_advance(self, channel_id: str, up_to: int) -> int: ...
+    # raises RuntimeError if dispatcher thread panicked, IOError if final checkpoint could not be saved
+    def close(self) -> None: ...
+    # raises TimeoutError if dispatcher thread does not exit within timeout_ms, can be retried
+    def close_timeout(self, timeout_ms: int) -> None: ...
+    # final close for a reader that is not restarted: writers stop waiting for its acks. Plain close does not tell
+    # writers, so they re-send unacked buffers to a reader restarted from checkpoint
+    def shutdown(self, timeout_ms: int) -> None: ...
+    # closes and returns buffers still queued, in read order; held buffers behind a gap are not included.
+    # With manual_commit returns everything not committed, including buffers read before
+    def close_and_drain(self, drain_out_of_order: bool = False) -> List[bytes]: ...
+    # between start and close. A second start is a no-op, so is close of a reader never started
+    def is_running(self) -> bool: ...
+    def get_name(self) -> str: ...
+    def get_handler_type(self) -> RustIOHandlerType: ...
+    # channel_id -> (is_remote, address_summary)
+    def get_channel_addresses(self) -> Dict[str, Tuple[bool, str]]: ...
+    def __getattr__(self, name: str) -> Any: ...
+
+
+class RustDataWriter:
+    # raises ValueError for malformed channels (empty id, bad ipc addr, node ip or port) and the same channel listed twice (a channel id may repeat with different addressing, one entry per reader)
+    def __init__(self, name: str, job_name: str, config: Any, channels: List[Any]) -> None: ...
+    def __enter__(self) -> 'RustDataWriter': ...
+    def __exit__(self, exc_type: Any, exc_value: Any, traceback: Any) -> bool: ...
+    # channel_id -> cumulative stats
+    def get_metrics_snapshot(self) -> Dict[str, RustChannelStats]: ...
+    # sum of get_metrics_snapshot() counters over all channels
+    def get_job_totals(self) -> RustJobStats: ...
+    def reset_metrics(self) -> None: ...
+    # backpressured time micros, None if not written. Buffer is dropped if not delivered within ttl_ms
+    def write_bytes(self, channel_id: str, b: bytes, block: bool, timeout_ms: int, retry_step_micros: int, ttl_ms: Optional[int] = None) -> Optional[int]: ...
+    # control buffer sent ahead of queued data and delivered on arrival, not ordered relative to regular buffers
+    def write_priority_bytes(self, channel_id: str, b: bytes, block: bool, timeout_ms: int, retry_step_micros: int) -> Optional[int]: ...
+    # after queued data, marks channel complete on reader side. Nothing should be written to channel afterwards
+    def write_eof(self, channel_id: str, block: bool, timeout_ms: int, retry_step_micros: int) -> Optional[int]: ...
+    # writes to every channel, each full channel is retried until timeout_ms. Returns channel_ids not written to
+    def broadcast(self, b: bytes, timeout_ms: int, retry_step_micros: int) -> List[str]: ...
+    # channel_id picked by configured partitioner
+    def partition(self, key: Optional[bytes]) -> str: ...
+    # (channel_id, backpressured time micros), None if not written
+    def write_bytes_by_key(self, key: Optional[bytes], b: bytes, block: bool, timeout_ms: int, retry_step_micros: int) -> Optional[Tuple[str, int]]: ...
+    # channel_id -> buffers sent but not acked yet
+    def get_in_flight(self) -> Dict[str, int]: ...
+    # nothing written after this call is older than event_time_wm, carried to readers in buffer metadata
+    def advance_event_time_watermark(self, event_time_wm: int) -> None: ...
+    # channel_id -> buffers not acked yet, including not yet sent
+    def get_queue_depths(self) -> Dict[str, int]: ...
+    # channels whose reader shut down on close, writes to them raise
+    def get_closed_channels(self) -> List[str]: ...
+    # waits up to timeout_ms for everything written to be acked, returns number of buffers still unacked
+    def flush(self, timeout_ms: int) -> int: ...
+    # graceful shutdown: drain rejects new writes and waits for acks, True if everything was delivered.
+    # stop then joins io threads and returns number of buffers left undelivered
+    def drain(self, timeout_ms: int) -> bool: ...
+    def stop(self) -> int: ...
+    # between start and stop or close. A second start is a no-op
+    def is_running(self) -> bool: ...
+    def get_name(self) -> str: ...
+    def get_handler_type(self) -> RustIOHandlerType: ...
+    # channel_id -> (is_remote, address_summary)
+    def get_channel_addresses(self) -> Dict[str, Tuple[bool, str]]: ...
+    def __getattr__(self, name: str) -> Any: ...
+
+
+class RustTransferSender:
+    # peer node_id -> cumulative stats
+    def get_metrics_snapshot(self) -> Dict[str, RustChannelStats]: ...
+    # sum of get_metrics_snapshot() counters over all channels
+    def get_job_totals(self) -> RustJobStats: ...
+    def get_name(self) -> str: ...
+    def get_handler_type(self) -> RustIOHandlerType: ...
+    # channel_id -> (is_remote, address_summary)
+    def get_channel_addresses(self) -> Dict[str, Tuple[bool, str]]: ...
+    def __getattr__(self, name: str) -> Any: ...
+
+
+class RustTransferReceiver:
+    # peer node_id -> cumulative stats
+    def get_metrics_snapshot(self) -> Dict[str, RustChannelStats]: ...
+    # sum of get_metrics_snapshot() counters over all channels
+    def get_job_totals(self) -> RustJobStats: ...
+    def get_name(self) -> str: ...
+    def get_handler_type(self) -> RustIOHandlerType: ...
+    # channel_id -> (is_remote, address_summary)
+    def get_channel_addresses(self) -> Dict[str, Tuple[bool, str]]: ...
+    def __getattr__(self, name: str) -> Any: ...
+
+
+def __getattr__(name: str) -> Any: ...
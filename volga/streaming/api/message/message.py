@@ -1,37 +1,50 @@
-from typing import Any, Optional
+from typing import Any, Dict, Optional
 
 from volga.streaming.runtime.network_deprecated.channel import ChannelMessage
 
 from decimal import Decimal
 
+# bound on total size of header keys and values per message, headers are sent with every message
+MAX_HEADERS_BYTES = 4096
+
+Headers = Dict[str, str]
+
+
+def validate_headers(headers: Headers):
+    size = sum(len(k.encode()) + len(v.encode()) for k, v in headers.items())
+    if size > MAX_HEADERS_BYTES:
+        raise ValueError(f'Headers take {size} bytes, max is {MAX_HEADERS_BYTES}')
+
 
 class Record:
     # Data record in data stream
 
-    def __init__(self, value: Any, event_time: Optional[Decimal] = None, source_emit_ts: Optional[int] = None):
+    # headers - per message metadata (trace id, content type, etc.) kept out of value, e.g. for routing
+    def __init__(self, value: Any, event_time: Optional[Decimal] = None, source_emit_ts: Optional[int] = None, headers: Optional[Headers] = None):
         self.value = value
         self.stream_name = None
         self.event_time = event_time
         self.source_emit_ts = source_emit_ts
+        self.headers = {} if headers is None else headers
 
     def __repr__(self):
-        return f'Record(value={self.value}, stream_name={self.stream_name}, event_time={self.event_time}, source_emit_ts={self.source_emit_ts})'
+        return f'Record(value={self.value}, stream_name={self.stream_name}, event_time={self.event_time}, source_emit_ts={self.source_emit_ts}, headers={self.headers})'
 
     def __eq__(self, other):
         if type(self) is type(other):
-            return (self.stream_name, self.value, self.event_time, self.source_emit_ts) == (other.stream_name, other.value, other.event_time, other.source_emit_ts)
+            return (self.stream_name, self.value, self.event_time, self.source_emit_ts, self.headers) == (other.stream_name, other.value, other.event_time, other.source_emit_ts, other.headers)
         return False
 
     def __hash__(self):
         return hash((self.stream_name, self.value, self.event_time, self.source_emit_ts))
 
     def to_channel_message(self) -> ChannelMessage:
-        return {
+        return _with_headers({
             'value': self.value,
             'stream_name': self.stream_name,
             'event_time': self.event_time,
             'source_emit_ts': self.source_emit_ts
-        }
+        }, self.headers)
 
     def set_stream_name(self, stream_name):
         self.stream_name = stream_name
@@ -40,21 +53,22 @@ class Record:
 class KeyRecord(Record):
     # Data record in a keyed data stream
 
-    def __init__(self, key: Any, value: Any, event_time: Optional[Decimal] = None, source_emit_ts: Optional[int] = None):
-        super().__init__(value=value, event_time=event_time, source_emit_ts=source_emit_ts)
+    def __init__(self, key: Any, value: Any, event_time: Optional[Decimal] = None, source_emit_ts: Optional[int] = None, headers: Optional[Headers] = None):
+        super().__init__(value=value, event_time=event_time, source_emit_ts=source_emit_ts, headers=headers)
         self.key = key
 
     def __repr__(self):
-        return f'KeyRecord(key={self.key}, value={self.value}, stream_name={self.stream_name}, event_time={self.event_time}, source_emit_ts={self.source_emit_ts})'
+        return f'KeyRecord(key={self.key}, value={self.value}, stream_name={self.stream_name}, event_time={self.event_time}, source_emit_ts={self.source_emit_ts}, headers={self.headers})'
 
     def __eq__(self, other):
         if type(self) is type(other):
-            return (self.stream_name, self.key, self.value, self.event_time, self.source_emit_ts) == (
+            return (self.stream_name, self.key, self.value, self.event_time, self.source_emit_ts, self.headers) == (
                 other.stream_name,
                 other.key,
                 other.value,
                 other.event_time,
-                other.source_emit_ts
+                other.source_emit_ts,
+                other.headers
             )
         return False
 
@@ -63,13 +77,21 @@ class KeyRecord(Record):
 
     # TODO we should have proper ser/de
     def to_channel_message(self):
-        return {
+        return _with_headers({
             'key': self.key,
             'value': self.value,
             'stream_name': self.stream_name,
             'event_time': self.event_time,
             'source_emit_ts': self.source_emit_ts
-        }
+        }, self.headers)
+
+
+# headers are omitted when empty so messages without them do not pay for the extra field
+def _with_headers(channel_message: ChannelMessage, headers: Headers) -> ChannelMessage:
+    if len(headers) != 0:
+        validate_headers(headers)
+        channel_message['headers'] = headers
+    return channel_message
 
 
 # TODO we should have proper ser/de
@@ -79,13 +101,15 @@ def record_from_channel_message(channel_message: ChannelMessage) -> Record:
             key=channel_message['key'],
             value=channel_message['value'],
             event_time=channel_message['event_time'],
-            source_emit_ts=channel_message['source_emit_ts']
+            source_emit_ts=channel_message['source_emit_ts'],
+            headers=channel_message.get('headers')
         )
     else:
         record = Record(
             value=channel_message['value'],
             event_time=channel_message['event_time'],
-            source_emit_ts=channel_message['source_emit_ts']
+            source_emit_ts=channel_message['source_emit_ts'],
+            headers=channel_message.get('headers')
         )
 
     record.set_stream_name(channel_message['stream_name'])
@@ -0,0 +1,29 @@
+import unittest
+
+import msgpack
+
+from volga.streaming.api.message.message import KeyRecord, MAX_HEADERS_BYTES, Record, record_from_channel_message
+
+
+class TestMessage(unittest.TestCase):
+
+    def test_headers(self):
+        record = KeyRecord(key='k', value=1, source_emit_ts=0, headers={'trace_id': 'abc'})
+        record.set_stream_name('s')
+        message = msgpack.loads(msgpack.dumps(record.to_channel_message()))
+        assert record_from_channel_message(message) == record
+
+        # no headers - no extra field
+        record = Record(value=1)
+        record.set_stream_name('s')
+        assert 'headers' not in record.to_channel_message()
+        assert record_from_channel_message(record.to_channel_message()).headers == {}
+
+        record = Record(value=1, headers={'h': 'x' * MAX_HEADERS_BYTES})
+        with self.assertRaises(ValueError):
+            record.to_channel_message()
+
+
+if __name__ == '__main__':
+    t = TestMessage()
+    t.test_headers()
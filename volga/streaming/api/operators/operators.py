@@ -216,7 +216,7 @@ class MapOperator(StreamOperator, OneInputOperator):
         super().__init__(map_func)
 
     def process_element(self, record):
-        self.collect(Record(value=self.func.map(record.value), event_time=record.event_time, source_emit_ts=record.source_emit_ts))
+        self.collect(Record(value=self.func.map(record.value), event_time=record.event_time, source_emit_ts=record.source_emit_ts, headers=record.headers))
 
 
 class FlatMapOperator(StreamOperator, OneInputOperator):
@@ -253,7 +253,7 @@ class KeyByOperator(StreamOperator, OneInputOperator):
 
     def process_element(self, record: Record):
         key = self.func.key_by(record.value)
-        self.collect(KeyRecord(key, record.value, record.event_time, record.source_emit_ts))
+        self.collect(KeyRecord(key, record.value, record.event_time, record.source_emit_ts, record.headers))
 
 
 class ReduceOperator(StreamOperator, OneInputOperator):
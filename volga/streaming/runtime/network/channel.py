@@ -1,7 +1,7 @@
 from abc import ABC, abstractmethod
 from typing import Any, Dict, Optional, Union
 
-from volga_rust import RustLocalChannel, RustRemoteChannel
+from volga_rust import RustInMemoryChannel, RustLocalChannel, RustRemoteChannel
 
 ChannelMessage = Dict[str, Any]
 
@@ -16,7 +16,7 @@ class Channel(ABC):
         self.channel_id = channel_id
 
     @abstractmethod
-    def to_rust_channel(self) -> Union[RustLocalChannel|RustRemoteChannel]:
+    def to_rust_channel(self) -> Union[RustLocalChannel|RustRemoteChannel|RustInMemoryChannel]:
         raise NotImplementedError()
 
     def __repr__(self):
@@ -75,6 +75,13 @@ class RemoteChannel(Channel):
         )
 
 
+# connects a writer and a reader in the same process without sockets, linked by RustInMemoryTransport. For tests
+class InMemoryChannel(Channel):
+
+    def to_rust_channel(self) -> RustInMemoryChannel:
+        return RustInMemoryChannel(self.channel_id)
+
+
 def gen_ipc_addr(job_name: str, channel_id: str, node_id: Optional[str] = None) -> str:
     path = f'ipc://{IPC_DIR}/{job_name}'
     if node_id is None:
@@ -1,9 +1,9 @@
 from abc import ABC, abstractmethod
-from typing import List, Union, Optional
+from typing import Dict, List, Tuple, TypedDict, Union, Optional
 
 from volga.streaming.runtime.network.channel import Channel
-from volga.streaming.runtime.network.metrics import MetricsRecorder
-from volga.streaming.runtime.network.network_config import ZmqConfig, DEFAULT_ZMQ_CONFIG
+from volga.streaming.runtime.network.metrics import MetricsRecorder, ChannelStatsDict, JobStatsDict
+from volga.streaming.runtime.network.network_config import ZmqConfig, DEFAULT_ZMQ_CONFIG, ThreadConfig
 
 from volga_rust import RustIOLoop, RustDataWriter, RustDataReader, RustTransferSender, RustTransferReceiver
 
@@ -33,21 +33,50 @@ class IOHandler(ABC):
         # pass
         self._metrics_recorder.close()
 
+    def __enter__(self):
+        self.start()
+        return self
+
+    def __exit__(self, exc_type, exc_value, traceback):
+        self.close()
+        return False
+
     @abstractmethod
     def get_rust_io_handler(self) -> RustIOHandler:
         raise NotImplementedError()
 
+    # cumulative per channel (per peer node for transfer handlers) stats, reads counters without blocking recording
+    def get_metrics_snapshot(self) -> Dict[str, ChannelStatsDict]:
+        snapshot = self.get_rust_io_handler().get_metrics_snapshot()
+        return {channel_or_peer_id: stats.to_dict() for channel_or_peer_id, stats in snapshot.items()}
+
+    # get_metrics_snapshot() counters summed over all channels (peers), top-line throughput of the handler
+    def get_job_totals(self) -> JobStatsDict:
+        return self.get_rust_io_handler().get_job_totals().to_dict()
+
+
+# RustSocketStats.to_dict()
+class SocketStatsDict(TypedDict):
+    bytes_read: int
+    bytes_written: int
+    msgs_read: int
+    msgs_written: int
+    recv_chan_full: int
+    send_blocked: int
+
 
 class IOLoop:
 
     def __init__(
         self,
         name: str,
-        config: Optional[ZmqConfig] = DEFAULT_ZMQ_CONFIG
+        config: Optional[ZmqConfig] = DEFAULT_ZMQ_CONFIG,
+        thread_config: Optional[ThreadConfig] = None
     ):
         self.name = name
         rust_config = None if config is None else config.to_rust()
-        self._rust_io_loop = RustIOLoop(name, rust_config)
+        rust_thread_config = None if thread_config is None else thread_config.to_rust()
+        self._rust_io_loop = RustIOLoop(name, rust_config, rust_thread_config)
         self._handlers: List[IOHandler] = []
 
     def register_io_handler(self, handler: IOHandler):
@@ -70,6 +99,11 @@ class IOLoop:
             self._rust_io_loop.start()
         return res
 
+    # (handler name, channel_id, addr) -> cumulative socket counters, next to handlers' get_metrics_snapshot() these
+    # tell handler backpressure (recv_chan_full) from socket backpressure (send_blocked)
+    def get_socket_stats(self) -> Dict[Tuple[str, str, str], SocketStatsDict]:
+        return {(stats.handler, stats.channel_id, stats.addr): stats.to_dict() for stats in self._rust_io_loop.get_socket_stats()}
+
     def close(self):
         for handler in self._handlers:
             handler.close()
@@ -41,6 +41,14 @@ class DataReader(IOHandler):
             self._last_report_ts = time.time()
         return res
 
+    # for readiness probes, see RustHealthStatus
+    def health(self, recv_window_ms: int = 5000):
+        return self._rust_data_reader.health(recv_window_ms)
+
+    # channels whose writer sent EOF and all their data was read
+    def completed_channels(self) -> List[str]:
+        return self._rust_data_reader.completed_channels()
+
     def start(self):
         self._start_ts = time.time()
         super().start()
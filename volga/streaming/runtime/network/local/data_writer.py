@@ -60,6 +60,12 @@ class DataWriter(IOHandler):
                 self._last_report_ts = time.time()
         return res
 
+    # channel is picked by configured partitioner, messages with same key go to same channel with HASH partitioner
+    def try_write_message_by_key(self, message: ChannelMessage) -> bool:
+        key = msgpack.dumps(message['key']) if 'key' in message else None
+        channel_id = self._rust_data_writer.partition(key)
+        return self.try_write_message(channel_id, message)
+
     def _try_write_message(self, channel_id: str, message: ChannelMessage) -> bool:
         lock = self._lock_per_channel[channel_id]
         lock.acquire()
@@ -82,6 +88,18 @@ class DataWriter(IOHandler):
             lock.release()
             return True
 
+    # flushes channel's pending batch first, nothing should be written to channel afterwards
+    def try_write_eof(self, channel_id: str) -> bool:
+        lock = self._lock_per_channel[channel_id]
+        with lock:
+            batch = self._batch_per_channel[channel_id]
+            if len(batch) != 0:
+                b = msgpack.dumps(batch)
+                if self._rust_data_writer.write_bytes(channel_id, b, False, 0, 0) is None:
+                    return False
+                self._batch_per_channel[channel_id] = []
+            return self._rust_data_writer.write_eof(channel_id, False, 0, 0) is not None
+
     def try_flush_if_needed(self):
         for channel_id in self._lock_per_channel:
             lock = self._lock_per_channel[channel_id]
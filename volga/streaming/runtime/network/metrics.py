@@ -2,7 +2,7 @@ import enum
 import fcntl
 import time
 from threading import Thread
-from typing import Dict, Tuple
+from typing import Dict, Tuple, TypedDict
 
 import msgpack
 from ray.util.metrics import Counter
@@ -22,6 +22,54 @@ class Metric(enum.Enum):
     NUM_BYTES_RECVD = 'volga_num_bytes_recvd'
 
 
+# RustChannelStats.to_dict()
+class ChannelStatsDict(TypedDict):
+    num_buffers_sent: int
+    num_buffers_recvd: int
+    num_buffers_resent: int
+    num_bytes_sent: int
+    num_bytes_recvd: int
+    num_dup_below_wm: int
+    num_dup_ooo: int
+    num_dropped_full: int
+    throttled_micros: int
+    num_expired: int
+    num_acks_dropped: int
+    num_push_rejected: int
+    queue_depth: int
+    num_skipped: int
+    num_retransmits: int
+    num_dropped_mem: int
+    out_of_order_bytes: int
+    num_compacted: int
+    num_writer_restarts: int
+    num_force_skipped: int
+    num_decode_errors: int
+    num_overflowed: int
+    num_evicted: int
+    num_nacks_sent: int
+    num_gap_waits: int
+    out_queue_dwell_p50_micros: int
+    out_queue_dwell_p99_micros: int
+    out_queue_dwell_p999_micros: int
+    # sampled queue depths, 0 until first sample
+    in_queue_depth_p50: int
+    in_queue_depth_p99: int
+    in_queue_depth_p999: int
+
+
+# RustJobStats.to_dict()
+class JobStatsDict(TypedDict):
+    num_buffers_sent: int
+    num_buffers_recvd: int
+    num_bytes_sent: int
+    num_bytes_recvd: int
+    num_empty_read_batches: int
+    out_queue_depth_p50: int
+    out_queue_depth_p99: int
+    out_queue_depth_p999: int
+
+
 class TagKeys(enum.Enum):
     JOB_NAME = 'job_name'
     HANDLER_NAME = 'handler_name'
@@ -1,14 +1,208 @@
-from typing import Optional
+import enum
+from typing import Dict, List, Optional
 
 from pydantic import BaseModel
-from volga_rust import RustDataReaderConfig, RustDataWriterConfig, RustTransferConfig, RustZmqConfig
+from volga_rust import RustDataReaderConfig, RustDataWriterConfig, RustTransferConfig, RustZmqConfig, RustDeliveryGuarantee, RustAckStrategy, RustGapPolicy, RustFullQueuePolicy, RustDecodeErrorPolicy, RustPartitionerType, RustRateLimit, RustThreadConfig
+
+
+# see DeliveryGuarantee in rust/src/network/data_reader.rs for what each mode guarantees
+class DeliveryGuarantee(str, enum.Enum):
+    AT_LEAST_ONCE = 'at_least_once'
+    EXACTLY_ONCE = 'exactly_once'
+
+    def to_rust(self) -> RustDeliveryGuarantee:
+        if self == DeliveryGuarantee.EXACTLY_ONCE:
+            return RustDeliveryGuarantee.ExactlyOnce
+        return RustDeliveryGuarantee.AtLeastOnce
+
+
+# see AckStrategy in rust/src/network/data_reader.rs
+class AckStrategy(str, enum.Enum):
+    IMMEDIATE = 'immediate'
+    BATCHED = 'batched'
+
+    def to_rust(self) -> RustAckStrategy:
+        if self == AckStrategy.BATCHED:
+            return RustAckStrategy.Batched
+        return RustAckStrategy.Immediate
+
+
+# what an ordered channel with max_ooo_wait_ms does about a gap, see GapPolicy in rust/src/network/data_reader.rs.
+# NACK keeps asking writer to re-send, NACK_THEN_SKIP asks once and then gives up on what is missing (data loss)
+class GapPolicy(str, enum.Enum):
+    NACK = 'nack'
+    NACK_THEN_SKIP = 'nack_then_skip'
+
+    def to_rust(self) -> RustGapPolicy:
+        if self == GapPolicy.NACK:
+            return RustGapPolicy.Nack
+        return RustGapPolicy.NackThenSkip
+
+
+# what the reader's dispatcher does while out_queue is full, see FullQueuePolicy in rust/src/network/data_reader.rs.
+# SPIN keeps polling, BLOCK sleeps until the consumer reads, DROP_OLDEST evicts unread buffers to make room for new
+# ones (data loss, counted as num_evicted)
+class FullQueuePolicy(str, enum.Enum):
+    SPIN = 'spin'
+    BLOCK = 'block'
+    DROP_OLDEST = 'drop_oldest'
+
+    def to_rust(self) -> RustFullQueuePolicy:
+        if self == FullQueuePolicy.BLOCK:
+            return RustFullQueuePolicy.Block
+        if self == FullQueuePolicy.DROP_OLDEST:
+            return RustFullQueuePolicy.DropOldest
+        return RustFullQueuePolicy.Spin
+
+
+# what readers, writers and transfer receivers do with malformed bytes from a peer, see DecodeErrorPolicy in
+# rust/src/network/error.rs. SKIP drops them and counts num_decode_errors
+class DecodeErrorPolicy(str, enum.Enum):
+    SKIP = 'skip'
+    FAIL = 'fail'
+
+    def to_rust(self) -> RustDecodeErrorPolicy:
+        if self == DecodeErrorPolicy.FAIL:
+            return RustDecodeErrorPolicy.Fail
+        return RustDecodeErrorPolicy.Skip
+
+
+# how writer picks a channel for keyed writes, see rust/src/network/partitioner.rs
+class PartitionerType(str, enum.Enum):
+    ROUND_ROBIN = 'round_robin'
+    HASH = 'hash'
+    # keys stay on their channel when channels are added or removed, except ~1/N of them
+    CONSISTENT_HASH = 'consistent_hash'
+
+    def to_rust(self) -> RustPartitionerType:
+        if self == PartitionerType.HASH:
+            return RustPartitionerType.Hash
+        if self == PartitionerType.CONSISTENT_HASH:
+            return RustPartitionerType.ConsistentHash
+        return RustPartitionerType.RoundRobin
+
+
+# naming and scheduling of dispatcher/io threads, see ThreadConfig in rust/src/network/threads.rs
+class ThreadConfig(BaseModel):
+    # None - 'volga'
+    name_prefix: Optional[str] = None
+    # Linux nice value, lower is higher priority, negative values need CAP_SYS_NICE
+    nice: Optional[int] = None
+    # cpu ids to pin threads to (Linux only), thread i goes to cpu_affinity[i % len]
+    cpu_affinity: Optional[List[int]] = None
+
+    def to_rust(self) -> RustThreadConfig:
+        return RustThreadConfig(self.name_prefix, self.nice, self.cpu_affinity)
 
 
 class DataReaderConfig(BaseModel):
     output_queue_size: int
+    metrics_enabled: bool = True
+    metrics_flush_interval_ms: int = 1000
+    checkpoint_path: Optional[str] = None
+    checkpoint_interval_ms: Optional[int] = None
+    delivery_guarantee: DeliveryGuarantee = DeliveryGuarantee.AT_LEAST_ONCE
+    dedup_window: int = 0
+    # fractions of output_queue_size: writers pause once output queue reaches high watermark and resume
+    # once it drains to low watermark. None disables backpressure
+    backpressure_high_watermark: Optional[float] = None
+    backpressure_low_watermark: float = 0.5
+    # per channel bound on received but not yet dispatched buffers, None - unbounded. Small capacities keep
+    # throughput as long as consumers yield when read_bytes returns None instead of spinning
+    recv_chan_capacity: Optional[int] = None
+    # channels are split across this many dispatcher threads, order is kept per channel only
+    dispatcher_threads: int = 1
+    # channel_id -> ordered, channels not listed are ordered. Unordered channels skip reordering and deliver
+    # on arrival, so may deliver duplicates on retransmit. Not supported with EXACTLY_ONCE
+    ordered: Dict[str, bool] = {}
+    # fraction of output_queue_size at which output queue is considered full, see RustDataReader.available_capacity
+    output_queue_full_threshold: float = 1.0
+    # cap on dispatcher sleep between passes that receive nothing, bounds latency after idle periods.
+    # 0 - only yield between passes, lowest latency but keeps a core busy
+    max_idle_backoff_micros: int = 1000
+    # None - unbounded. Acks that do not fit are dropped, writer re-sends unacked buffers and they are re-acked
+    ack_chan_capacity: Optional[int] = None
+    # None - default names and OS scheduling
+    dispatcher_thread_config: Optional[ThreadConfig] = None
+    # per channel cap on bytes held out-of-order on top of the buffer count cap, None - count cap only
+    max_out_of_order_bytes: Optional[int] = None
+    # BATCHED sends up to ack_batch_size acks per message, held at most ack_batch_delay_ms. Writers must support it
+    ack_strategy: AckStrategy = AckStrategy.IMMEDIATE
+    ack_batch_size: int = 64
+    ack_batch_delay_ms: int = 5
+    # entries output queue may take over its limit when draining buffers already received out-of-order,
+    # keeps batch reads full. Payload of entries over the limit is capped by prefetch_max_bytes. 0 - strict limit
+    prefetch: int = 0
+    prefetch_max_bytes: int = 4 * 1024 * 1024
+    # hands delivered buffers to the consumer through a lock-free ring instead of a mutex shared with the dispatcher.
+    # Single dispatcher thread and AT_LEAST_ONCE only, RustDataReader.read_bytes_from is not supported
+    out_queue_ring: bool = False
+    # malformed buffers are dropped without ack (SKIP) or fail the channel (FAIL)
+    decode_error_policy: DecodeErrorPolicy = DecodeErrorPolicy.SKIP
+    # after this many dispatcher passes with nothing received a channel is polled only every
+    # idle_channel_poll_every-th pass until it receives again. 0 - poll every pass
+    idle_channel_misses: int = 0
+    idle_channel_poll_every: int = 8
+    # channel_id -> ms a missing buffer may hold back later ones before gap_policy applies.
+    # Channels not listed wait for writer's in-flight timeout only
+    max_ooo_wait_ms: Dict[str, int] = {}
+    gap_policy: GapPolicy = GapPolicy.NACK_THEN_SKIP
+    # buffers are acked to writers only once RustDataReader.commit is called with the id from read_message, so
+    # whatever the consumer did not commit is delivered again after a restart. AT_LEAST_ONCE only, not with out_queue_ring
+    manual_commit: bool = False
+    # per channel ring of this many slots for buffers held behind a gap, buffers further ahead of the watermark are
+    # dropped and re-sent by the writer, so it should cover writer's max_buffers_per_channel. 0 - unbounded map
+    out_of_order_slots: int = 0
+    # channel_id -> at most once: fire-and-forget, buffers are delivered as they arrive and never acked, lost ones are
+    # never re-delivered and duplicates are not dropped. Writer has to list the channel in its at_most_once too
+    at_most_once: Dict[str, bool] = {}
+    # BLOCK and DROP_OLDEST are not supported with out_queue_ring or output_chan, DROP_OLDEST is AT_LEAST_ONCE only
+    full_queue_policy: FullQueuePolicy = FullQueuePolicy.SPIN
 
     def to_rust(self) -> RustDataReaderConfig:
-        return RustDataReaderConfig(self.output_queue_size)
+        return RustDataReaderConfig(
+            self.output_queue_size,
+            metrics_enabled=self.metrics_enabled,
+            metrics_flush_interval_ms=self.metrics_flush_interval_ms,
+            checkpoint_path=self.checkpoint_path,
+            checkpoint_interval_ms=self.checkpoint_interval_ms,
+            delivery_guarantee=self.delivery_guarantee.to_rust(),
+            dedup_window=self.dedup_window,
+            backpressure_high_watermark=self.backpressure_high_watermark,
+            backpressure_low_watermark=self.backpressure_low_watermark,
+            recv_chan_capacity=self.recv_chan_capacity,
+            dispatcher_threads=self.dispatcher_threads,
+            ordered=self.ordered,
+            output_queue_full_threshold=self.output_queue_full_threshold,
+            max_idle_backoff_micros=self.max_idle_backoff_micros,
+            ack_chan_capacity=self.ack_chan_capacity,
+            dispatcher_thread_config=None if self.dispatcher_thread_config is None else self.dispatcher_thread_config.to_rust(),
+            max_out_of_order_bytes=self.max_out_of_order_bytes,
+            ack_strategy=self.ack_strategy.to_rust(),
+            ack_batch_size=self.ack_batch_size,
+            ack_batch_delay_ms=self.ack_batch_delay_ms,
+            prefetch=self.prefetch,
+            prefetch_max_bytes=self.prefetch_max_bytes,
+            out_queue_ring=self.out_queue_ring,
+            decode_error_policy=self.decode_error_policy.to_rust(),
+            idle_channel_misses=self.idle_channel_misses,
+            idle_channel_poll_every=self.idle_channel_poll_every,
+            max_ooo_wait_ms=self.max_ooo_wait_ms,
+            gap_policy=self.gap_policy.to_rust(),
+            manual_commit=self.manual_commit,
+            out_of_order_slots=self.out_of_order_slots,
+            at_most_once=self.at_most_once,
+            full_queue_policy=self.full_queue_policy.to_rust()
+        )
+
+
+# per channel send limit, None - not limited
+class RateLimitConfig(BaseModel):
+    bytes_per_sec: Optional[int] = None
+    buffers_per_sec: Optional[int] = None
+
+    def to_rust(self) -> RustRateLimit:
+        return RustRateLimit(self.bytes_per_sec, self.buffers_per_sec)
 
 
 class DataWriterConfig(BaseModel):
@@ -16,16 +210,55 @@ class DataWriterConfig(BaseModel):
     max_buffers_per_channel: int
     batch_size: int
     flush_period_s: float
+    metrics_enabled: bool = True
+    metrics_flush_interval_ms: int = 1000
+    retention: int = 0
+    # packs up to this many written buffers (or buffer_batch_max_bytes, 0 - no limit) into one sent buffer,
+    # partial batches are sent after buffer_batch_linger_ms. 1 disables buffer batching
+    buffer_batch_size: int = 1
+    buffer_batch_max_bytes: int = 0
+    buffer_batch_linger_ms: int = 10
+    partitioner: PartitionerType = PartitionerType.ROUND_ROBIN
+    # channel_id -> send rate limit, throttled time is reported as throttled_micros metric
+    rate_limits: Dict[str, RateLimitConfig] = {}
+    # buffers larger than this are sent as fragments of this size and reassembled by reader, 0 - no limit
+    max_buffer_size: int = 0
+    # close waits up to this long for queued buffers to be acked, the rest is dropped. 0 - no linger, drops everything unacked
+    close_linger_ms: int = 1000
+    # channel_id -> compacted, write_bytes_by_key on a compacted channel replaces a not yet sent buffer with the same key
+    compacted: Dict[str, bool] = {}
+    # malformed acks from readers are dropped (SKIP) or stop the writer's ack loop (FAIL)
+    decode_error_policy: DecodeErrorPolicy = DecodeErrorPolicy.SKIP
+    # channel_id -> at most once: buffers are released once sent and never re-sent, so lost ones are gone for good.
+    # Reader has to list the channel in its at_most_once too
+    at_most_once: Dict[str, bool] = {}
 
     def to_rust(self) -> RustDataWriterConfig:
-        return RustDataWriterConfig(self.in_flight_timeout_s, self.max_buffers_per_channel)
+        return RustDataWriterConfig(
+            self.in_flight_timeout_s,
+            self.max_buffers_per_channel,
+            metrics_enabled=self.metrics_enabled,
+            metrics_flush_interval_ms=self.metrics_flush_interval_ms,
+            retention=self.retention,
+            buffer_batch_size=self.buffer_batch_size,
+            buffer_batch_max_bytes=self.buffer_batch_max_bytes,
+            buffer_batch_linger_ms=self.buffer_batch_linger_ms,
+            partitioner=self.partitioner.to_rust(),
+            rate_limits={channel_id: rate_limit.to_rust() for channel_id, rate_limit in self.rate_limits.items()},
+            max_buffer_size=self.max_buffer_size,
+            close_linger_ms=self.close_linger_ms,
+            compacted=self.compacted,
+            decode_error_policy=self.decode_error_policy.to_rust(),
+            at_most_once=self.at_most_once
+        )
 
 
 class TransferConfig(BaseModel):
     transfer_queue_size: int
+    decode_error_policy: DecodeErrorPolicy = DecodeErrorPolicy.SKIP
 
     def to_rust(self) -> RustTransferConfig:
-        return RustTransferConfig(self.transfer_queue_size)
+        return RustTransferConfig(self.transfer_queue_size, self.decode_error_policy.to_rust())
 
 
 class ZmqConfig(BaseModel):
@@ -35,9 +268,14 @@ class ZmqConfig(BaseModel):
     rcvbuf: Optional[int]
     linger: Optional[int]
     connect_timeout_s: Optional[int]
+    # for hostnames resolving to both IPv4 and IPv6, None - use first resolved address
+    prefer_ipv6: Optional[bool] = None
+    # override sndbuf/rcvbuf for tcp connections between nodes, None - same as local sockets
+    remote_sndbuf: Optional[int] = None
+    remote_rcvbuf: Optional[int] = None
 
     def to_rust(self) -> RustZmqConfig:
-        return RustZmqConfig(self.sndhwm, self.rcvhwm, self.sndbuf, self.rcvbuf, self.linger, self.connect_timeout_s)
+        return RustZmqConfig(self.sndhwm, self.rcvhwm, self.sndbuf, self.rcvbuf, self.linger, self.connect_timeout_s, self.prefer_ipv6, self.remote_sndbuf, self.remote_rcvbuf)
 
 
 DEFAULT_DATA_READER_CONFIG = DataReaderConfig(output_queue_size=100)